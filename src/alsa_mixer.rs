@@ -1,11 +1,16 @@
+use crate::{config::VolumeCurve, volume_curve};
+use alsa::mixer::{MilliBel, Selem, SelemChannelId};
 use librespot::playback::mixer::{AudioFilter, Mixer, MixerConfig};
-use log::error;
+use log::{debug, error};
 use std::error::Error;
 
 pub struct AlsaMixer {
     pub device: String,
     pub mixer: String,
     pub linear_scaling: bool,
+    /// The `volume_curve`. Without it, the volume is scaled as before, by
+    /// `linear_scaling`.
+    pub curve: Option<VolumeCurve>,
 }
 
 impl AlsaMixer {
@@ -20,6 +25,10 @@ impl AlsaMixer {
             )
         })?;
 
+        if let Some(curve) = self.curve {
+            return set_curved_volume(&elem, curve, volume);
+        }
+
         let (min, max) = elem.get_playback_volume_range();
 
         let volume_steps = (max - min) as f64;
@@ -32,6 +41,38 @@ impl AlsaMixer {
         elem.set_playback_volume_all(normalised_volume)?;
         Ok(())
     }
+
+    /// Reads the volume, scaled back the way `set_volume` scales it, so that
+    /// reading it after setting it gives about the volume that was set.
+    pub(crate) fn read_volume(&self) -> Result<u16, Box<dyn Error>> {
+        let mixer = alsa::mixer::Mixer::new(&self.device, false)?;
+
+        let selem_id = alsa::mixer::SelemId::new(&*self.mixer, 0);
+        let elem = mixer.find_selem(&selem_id).ok_or_else(|| {
+            format!(
+                "Couldn't find selem with name '{}'.",
+                selem_id.get_name().unwrap_or("unnamed")
+            )
+        })?;
+
+        if let Some(curve) = self.curve {
+            return read_curved_volume(&elem, curve);
+        }
+
+        let (min, max) = elem.get_playback_volume_range();
+        if max <= min {
+            return Ok(u16::max_value());
+        }
+        let volume = elem.get_playback_volume(alsa::mixer::SelemChannelId::mono())?;
+
+        let fraction = ((volume - min) as f64 / (max - min) as f64).clamp(0.0, 1.0);
+        let volume = if self.linear_scaling {
+            fraction * f64::from(u16::max_value())
+        } else {
+            f64::from(u16::max_value()).powf(fraction)
+        };
+        Ok(volume.round() as u16)
+    }
 }
 
 impl Mixer for AlsaMixer {
@@ -40,6 +81,7 @@ impl Mixer for AlsaMixer {
             device: "default".to_string(),
             mixer: "Master".to_string(),
             linear_scaling: false,
+            curve: None,
         }
     }
 
@@ -48,6 +90,12 @@ impl Mixer for AlsaMixer {
     fn stop(&self) {}
 
     fn volume(&self) -> u16 {
+        if self.curve.is_some() {
+            return self.read_volume().unwrap_or_else(|e| {
+                error!("Couldn't read volume: {:?}", e);
+                0
+            });
+        }
         let selem_id = alsa::mixer::SelemId::new(&*self.mixer, 0);
         match alsa::mixer::Mixer::new(&self.device, false)
             .ok()
@@ -84,3 +132,61 @@ impl Mixer for AlsaMixer {
         None
     }
 }
+
+/// The playback range of `elem` in dB, if the driver knows it.
+fn db_range(elem: &Selem) -> Option<(f64, f64)> {
+    let (min, max) = elem.get_playback_db_range();
+    if max > min {
+        Some((f64::from(min.to_db()), f64::from(max.to_db())))
+    } else {
+        None
+    }
+}
+
+/// Sets the volume in dB when the card says what its steps are worth, so the
+/// curve sounds the same on any card. Otherwise the raw steps are assumed to
+/// change the amplitude evenly.
+fn set_curved_volume(elem: &Selem, curve: VolumeCurve, volume: u16) -> Result<(), Box<dyn Error>> {
+    let (min, max) = elem.get_playback_volume_range();
+    match db_range(elem) {
+        Some((min_db, max_db)) => {
+            let range = (max_db - min_db).min(volume_curve::LOG_RANGE_DB);
+            let db = max_db
+                + volume_curve::amplitude_to_db(volume_curve::amplitude(curve, volume, range));
+            if db < min_db {
+                elem.set_playback_volume_all(min)?;
+            } else {
+                elem.set_playback_db_all(MilliBel::from_db(db as f32), alsa::Round::Floor)?;
+            }
+        }
+        None => {
+            debug!("The mixer has no dB range, scaling its volume steps instead");
+            let amplitude = volume_curve::amplitude(curve, volume, volume_curve::LOG_RANGE_DB);
+            elem.set_playback_volume_all(min + (amplitude * (max - min) as f64).round() as i64)?;
+        }
+    }
+    Ok(())
+}
+
+/// Reads the volume that `set_curved_volume` set.
+fn read_curved_volume(elem: &Selem, curve: VolumeCurve) -> Result<u16, Box<dyn Error>> {
+    let (min, max) = elem.get_playback_volume_range();
+    if max <= min {
+        return Ok(u16::MAX);
+    }
+    let raw = elem.get_playback_volume(SelemChannelId::mono())?;
+    if raw <= min {
+        return Ok(0);
+    }
+    Ok(match db_range(elem) {
+        Some((min_db, max_db)) => {
+            let db = f64::from(elem.get_playback_vol_db(SelemChannelId::mono())?.to_db());
+            let range = (max_db - min_db).min(volume_curve::LOG_RANGE_DB);
+            volume_curve::volume(curve, volume_curve::db_to_amplitude(db - max_db), range)
+        }
+        None => {
+            let amplitude = (raw - min) as f64 / (max - min) as f64;
+            volume_curve::volume(curve, amplitude, volume_curve::LOG_RANGE_DB)
+        }
+    })
+}
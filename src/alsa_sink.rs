@@ -0,0 +1,248 @@
+use alsa::{
+    pcm::{Access, Format, Frames, HwParams, PCM},
+    Direction, ValueOr,
+};
+use librespot::playback::audio_backend::Sink;
+use log::{debug, info, warn};
+use std::io;
+
+/// The rate librespot decodes at.
+const SOURCE_RATE: u32 = 44100;
+
+/// Tried in this order, so the audio is only resampled if the device takes
+/// none of the formats at 44.1 kHz.
+const RATES: &[u32] = &[SOURCE_RATE, 48000, 88200, 96000];
+
+/// The formats tried at each rate. 24 bit samples are in 32 bit containers.
+const FORMATS: &[SampleFormat] = &[SampleFormat::S16, SampleFormat::S32, SampleFormat::S24];
+
+/// A period of about 125ms at 44.1 kHz, as librespot's alsa backend uses.
+const PERIOD_SIZE: Frames = 5512;
+const BUFFERED_PERIODS: Frames = 4;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum SampleFormat {
+    S16,
+    S24,
+    S32,
+}
+
+impl SampleFormat {
+    fn alsa(self) -> Format {
+        match self {
+            SampleFormat::S16 => Format::s16(),
+            SampleFormat::S24 => Format::s24(),
+            SampleFormat::S32 => Format::s32(),
+        }
+    }
+
+    fn push(self, sample: i16, bytes: &mut Vec<u8>) {
+        match self {
+            SampleFormat::S16 => bytes.extend_from_slice(&sample.to_ne_bytes()),
+            SampleFormat::S24 => bytes.extend_from_slice(&(i32::from(sample) << 8).to_ne_bytes()),
+            SampleFormat::S32 => bytes.extend_from_slice(&(i32::from(sample) << 16).to_ne_bytes()),
+        }
+    }
+}
+
+/// Converts stereo audio from 44.1 kHz to another rate by interpolating
+/// between neighbouring frames.
+struct Resampler {
+    /// How far the input moves for every output frame.
+    step: f64,
+    /// Where the next output frame is, in frames after the last one of the
+    /// previous input. It's negative while that frame is still needed.
+    position: f64,
+    previous: [f64; 2],
+}
+
+impl Resampler {
+    fn new(rate: u32) -> Resampler {
+        Resampler {
+            step: f64::from(SOURCE_RATE) / f64::from(rate),
+            position: 0.0,
+            previous: [0.0; 2],
+        }
+    }
+
+    fn process(&mut self, input: &[i16], output: &mut Vec<i16>) {
+        let frames = input.len() / 2;
+        if frames == 0 {
+            return;
+        }
+        let frame = |index: isize, previous: &[f64; 2]| -> [f64; 2] {
+            if index < 0 {
+                *previous
+            } else {
+                let index = index as usize * 2;
+                [f64::from(input[index]), f64::from(input[index + 1])]
+            }
+        };
+        while self.position < (frames - 1) as f64 {
+            let index = self.position.floor();
+            let fraction = self.position - index;
+            let (from, to) = (
+                frame(index as isize, &self.previous),
+                frame(index as isize + 1, &self.previous),
+            );
+            for channel in 0..2 {
+                let sample = from[channel] + (to[channel] - from[channel]) * fraction;
+                output.push(sample.round() as i16);
+            }
+            self.position += self.step;
+        }
+        self.position -= frames as f64;
+        self.previous = frame(frames as isize - 1, &self.previous);
+    }
+}
+
+struct Device {
+    pcm: PCM,
+    format: SampleFormat,
+    resampler: Option<Resampler>,
+}
+
+/// An alsa sink for `format_fallback`, for devices that don't take the 16 bit
+/// audio at 44.1 kHz that librespot's alsa backend always asks for.
+///
+/// The rates and formats are tried one after the other until the device takes
+/// one exactly. At another rate, the audio is resampled, which costs a little
+/// CPU and some quality.
+pub(crate) struct NegotiatingSink {
+    device: String,
+    open: Option<Device>,
+    samples: Vec<i16>,
+    bytes: Vec<u8>,
+}
+
+impl NegotiatingSink {
+    pub(crate) fn new(device: Option<String>) -> NegotiatingSink {
+        NegotiatingSink {
+            device: device.unwrap_or_else(|| "default".to_string()),
+            open: None,
+            samples: Vec::new(),
+            bytes: Vec::new(),
+        }
+    }
+
+    fn negotiate(&self) -> io::Result<Device> {
+        let mut last_error = None;
+        for &rate in RATES {
+            for &format in FORMATS {
+                match open(&self.device, format, rate) {
+                    Ok(pcm) => {
+                        if rate != SOURCE_RATE || format != SampleFormat::S16 {
+                            info!(
+                                "{} doesn't take 16 bit audio at 44100 Hz, using {:?} at {} Hz",
+                                self.device, format, rate
+                            );
+                        }
+                        return Ok(Device {
+                            pcm,
+                            format,
+                            resampler: if rate == SOURCE_RATE {
+                                None
+                            } else {
+                                Some(Resampler::new(rate))
+                            },
+                        });
+                    }
+                    Err(e) => {
+                        debug!(
+                            "{} rejected {:?} at {} Hz: {}",
+                            self.device, format, rate, e
+                        );
+                        last_error = Some(e);
+                    }
+                }
+            }
+        }
+        Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!(
+                "{} takes none of the formats and rates spotifyd can play: {}",
+                self.device,
+                last_error.map_or_else(String::new, |e| e.to_string())
+            ),
+        ))
+    }
+}
+
+/// Opens `device` with exactly `format` and `rate`.
+fn open(device: &str, format: SampleFormat, rate: u32) -> alsa::Result<PCM> {
+    let pcm = PCM::new(device, Direction::Playback, false)?;
+    {
+        let hwp = HwParams::any(&pcm)?;
+        hwp.set_access(Access::RWInterleaved)?;
+        hwp.set_format(format.alsa())?;
+        hwp.set_rate(rate, ValueOr::Nearest)?;
+        if hwp.get_rate()? != rate {
+            return Err(alsa::Error::unsupported("set_rate"));
+        }
+        hwp.set_channels(2)?;
+        let period_size = PERIOD_SIZE * Frames::from(rate) / Frames::from(SOURCE_RATE);
+        let period_size = hwp.set_period_size_near(period_size, ValueOr::Greater)?;
+        hwp.set_buffer_size_near(period_size * BUFFERED_PERIODS)?;
+        pcm.hw_params(&hwp)?;
+
+        let swp = pcm.sw_params_current()?;
+        swp.set_start_threshold(hwp.get_buffer_size()? - hwp.get_period_size()?)?;
+        pcm.sw_params(&swp)?;
+    }
+    Ok(pcm)
+}
+
+fn to_io_error(e: alsa::Error) -> io::Error {
+    match e.errno() {
+        Some(errno) => io::Error::from_raw_os_error(errno as i32),
+        None => io::Error::new(io::ErrorKind::InvalidData, e.to_string()),
+    }
+}
+
+impl Sink for NegotiatingSink {
+    fn start(&mut self) -> io::Result<()> {
+        if self.open.is_none() {
+            self.open = Some(self.negotiate()?);
+        }
+        Ok(())
+    }
+
+    fn stop(&mut self) -> io::Result<()> {
+        if let Some(device) = self.open.take() {
+            if let Err(e) = device.pcm.drain() {
+                warn!("Could not drain {}: {}", self.device, e);
+            }
+        }
+        Ok(())
+    }
+
+    fn write(&mut self, data: &[i16]) -> io::Result<()> {
+        let device = match self.open {
+            Some(ref mut device) => device,
+            None => return Ok(()),
+        };
+        let samples = match device.resampler {
+            Some(ref mut resampler) => {
+                self.samples.clear();
+                resampler.process(data, &mut self.samples);
+                &self.samples[..]
+            }
+            None => data,
+        };
+        self.bytes.clear();
+        for &sample in samples {
+            device.format.push(sample, &mut self.bytes);
+        }
+
+        let io = device.pcm.io();
+        let frame_len = self.bytes.len() / samples.len().max(1) * 2;
+        let mut written = 0;
+        while written < self.bytes.len() {
+            match io.writei(&self.bytes[written..]) {
+                Ok(frames) => written += frames * frame_len,
+                Err(e) => device.pcm.try_recover(e, false).map_err(to_io_error)?,
+            }
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,103 @@
+use crate::error::SetupError;
+
+/// Lists the playback devices of the alsa backend as names and descriptions,
+/// in the order their indices refer to.
+#[cfg(feature = "alsa_backend")]
+pub(crate) fn list() -> Vec<(String, Option<String>)> {
+    use alsa::{device_name::HintIter, Direction};
+
+    let hints = match HintIter::new_str(None, "pcm") {
+        Ok(hints) => hints,
+        Err(_) => return Vec::new(),
+    };
+    hints
+        .filter(|hint| hint.direction != Some(Direction::Capture))
+        .filter_map(|hint| Some((hint.name?, hint.desc)))
+        .collect()
+}
+
+#[cfg(not(feature = "alsa_backend"))]
+pub(crate) fn list() -> Vec<(String, Option<String>)> {
+    Vec::new()
+}
+
+/// Turns the configured `device` into the name the backend expects.
+///
+/// A plain number stands for the device listed with that index by
+/// `spotifyd list-devices`. A `hw:CARD,DEVICE` name with numeric indices is
+/// checked against the sound cards that exist. Anything else is passed on.
+pub(crate) fn resolve(backend: &str, device: &str) -> Result<String, SetupError> {
+    let uses_alsa = backend == "alsa" || backend == "tee";
+    if let Ok(index) = device.parse::<usize>() {
+        if !uses_alsa {
+            return Err(SetupError::ConfigInvalid(format!(
+                "device indices only work with the alsa backend, not {}",
+                backend
+            )));
+        }
+        let devices = list();
+        return match devices.into_iter().nth(index) {
+            Some((name, _)) => Ok(name),
+            None => Err(SetupError::ConfigInvalid(format!(
+                "there is no audio device with index {}, see `spotifyd list-devices`",
+                index
+            ))),
+        };
+    }
+    if uses_alsa {
+        check_hw_device(device)?;
+    }
+    Ok(device.to_string())
+}
+
+#[cfg(feature = "alsa_backend")]
+fn check_hw_device(device: &str) -> Result<(), SetupError> {
+    use alsa::{card::Card, Direction, PCM};
+
+    let indices = device
+        .strip_prefix("hw:")
+        .or_else(|| device.strip_prefix("plughw:"))
+        .and_then(|indices| {
+            let mut indices = indices.splitn(2, ',');
+            let card = indices.next()?.parse::<i32>().ok()?;
+            let device_index = match indices.next() {
+                Some(device_index) => Some(device_index.parse::<i32>().ok()?),
+                None => None,
+            };
+            Some((card, device_index))
+        });
+    let (card, device_index) = match indices {
+        Some(indices) => indices,
+        None => return Ok(()),
+    };
+    if Card::new(card).get_name().is_err() {
+        return Err(SetupError::ConfigInvalid(format!(
+            "there is no sound card with index {}",
+            card
+        )));
+    }
+    let device_index = match device_index {
+        Some(device_index) => device_index,
+        None => return Ok(()),
+    };
+    // A device that is in use still exists, so only a missing one is an error.
+    let missing = match PCM::new(device, Direction::Playback, true) {
+        Ok(_) => false,
+        Err(e) => {
+            let errno = e.errno().map(|errno| errno as i32);
+            errno == Some(libc::ENOENT) || errno == Some(libc::ENODEV)
+        }
+    };
+    if missing {
+        return Err(SetupError::ConfigInvalid(format!(
+            "sound card {} has no playback device with index {}",
+            card, device_index
+        )));
+    }
+    Ok(())
+}
+
+#[cfg(not(feature = "alsa_backend"))]
+fn check_hw_device(_: &str) -> Result<(), SetupError> {
+    Ok(())
+}
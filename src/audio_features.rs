@@ -0,0 +1,149 @@
+use crate::web_api;
+use futures::{sync::oneshot, Future};
+use librespot::core::{
+    session::Session,
+    spotify_id::{SpotifyAudioType, SpotifyId},
+};
+use log::debug;
+use rspotify::spotify::model::audio::AudioFeatures as ApiAudioFeatures;
+use serde::Serialize;
+use std::{
+    cell::RefCell,
+    collections::{HashMap, HashSet, VecDeque},
+    rc::Rc,
+};
+use tokio_core::reactor::Handle;
+
+/// The features of at most this many tracks are kept.
+const MAX_TRACKS: usize = 200;
+
+/// The Web API needs a token to look up audio features, but no particular
+/// scope.
+const SCOPE: &str = "user-read-private";
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum Mode {
+    Major,
+    Minor,
+}
+
+/// What the Web API knows about how a track sounds.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+pub(crate) struct AudioFeatures {
+    /// In beats per minute.
+    pub(crate) tempo: f32,
+    /// The pitch class of the key, from 0 for C to 11 for B, if one was found.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) key: Option<u8>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) mode: Option<Mode>,
+    /// Between 0 and 1.
+    pub(crate) energy: f32,
+}
+
+impl AudioFeatures {
+    fn from_api(features: &ApiAudioFeatures) -> AudioFeatures {
+        let key = if (0..12).contains(&features.key) {
+            Some(features.key as u8)
+        } else {
+            None
+        };
+        let mode = match key {
+            None => None,
+            Some(_) if features.mode >= 0.5 => Some(Mode::Major),
+            Some(_) => Some(Mode::Minor),
+        };
+        AudioFeatures {
+            tempo: features.tempo,
+            key,
+            mode,
+            energy: features.energy,
+        }
+    }
+}
+
+#[derive(Default)]
+struct Cache {
+    features: HashMap<SpotifyId, AudioFeatures>,
+    order: VecDeque<SpotifyId>,
+    pending: HashSet<SpotifyId>,
+    current: Option<SpotifyId>,
+}
+
+impl Cache {
+    fn insert(&mut self, track_id: SpotifyId, features: AudioFeatures) {
+        if self.features.insert(track_id, features).is_none() {
+            self.order.push_back(track_id);
+        }
+        while self.order.len() > MAX_TRACKS {
+            if let Some(oldest) = self.order.pop_front() {
+                self.features.remove(&oldest);
+            }
+        }
+    }
+}
+
+/// The audio features of recently played tracks, for `fetch_audio_features`.
+///
+/// The main loop fetches them when a track starts, and the D-Bus and status
+/// servers read them from here. Tracks the Web API has no features for, like
+/// episodes and local files, simply have none.
+#[derive(Clone, Default)]
+pub(crate) struct AudioFeatureCache {
+    cache: Rc<RefCell<Cache>>,
+}
+
+impl AudioFeatureCache {
+    pub(crate) fn new() -> AudioFeatureCache {
+        AudioFeatureCache::default()
+    }
+
+    /// Makes `track_id` the current track and fetches its features unless
+    /// they are known already.
+    pub(crate) fn track_changed(&self, session: &Session, handle: &Handle, track_id: SpotifyId) {
+        let mut cache = self.cache.borrow_mut();
+        cache.current = Some(track_id);
+        if track_id.audio_type != SpotifyAudioType::Track
+            || cache.features.contains_key(&track_id)
+            || !cache.pending.insert(track_id)
+        {
+            return;
+        }
+
+        let (tx, rx) = oneshot::channel();
+        let id = track_id.to_base62();
+        web_api::spawn_with_api(session, handle, SCOPE, move |sp| {
+            let features = sp
+                .audio_features(&id)
+                .map(|features| AudioFeatures::from_api(&features))
+                .map_err(|e| debug!("Could not fetch the audio features of {}: {}", id, e))
+                .ok();
+            let _ = tx.send(features);
+        });
+        let cache = self.cache.clone();
+        handle.spawn(rx.then(move |features| {
+            let mut cache = cache.borrow_mut();
+            cache.pending.remove(&track_id);
+            if let Ok(Some(features)) = features {
+                cache.insert(track_id, features);
+            }
+            Ok(())
+        }));
+    }
+
+    pub(crate) fn stopped(&self) {
+        self.cache.borrow_mut().current = None;
+    }
+
+    pub(crate) fn get(&self, track_id: SpotifyId) -> Option<AudioFeatures> {
+        self.cache.borrow().features.get(&track_id).copied()
+    }
+
+    /// Returns the features of the track that is playing, once they are known.
+    #[cfg(feature = "dbus_mpris")]
+    pub(crate) fn current(&self) -> Option<AudioFeatures> {
+        let current = self.cache.borrow().current;
+        current.and_then(|track_id| self.get(track_id))
+    }
+}
@@ -0,0 +1,172 @@
+use crate::{main_loop, web_api};
+use futures::{Async, Future, Stream};
+use librespot::{
+    core::{session::Session, spotify_id::SpotifyId},
+    playback::player::PlayerEvent,
+    protocol::spirc::{PlayStatus, State},
+};
+use log::{info, warn};
+use serde::Deserialize;
+use tokio_core::reactor::Handle;
+
+/// At most this many tracks of the station are played.
+const MAX_TRACKS: usize = 50;
+
+#[derive(Deserialize)]
+struct Station {
+    tracks: Vec<StationTrack>,
+}
+
+#[derive(Deserialize)]
+struct StationTrack {
+    uri: String,
+}
+
+/// Continues with similar tracks at the end of a context, but only once
+/// `autoplay_after` tracks were played.
+///
+/// librespot decides whether to autoplay when a context is loaded, so Spirc
+/// runs without autoplay and this starts the autoplay station itself. The end
+/// of a context is told from the state this device sends to the others.
+pub(crate) struct DelayedAutoplay {
+    after: u32,
+    played: u32,
+    track_id: Option<SpotifyId>,
+    states: Option<Box<dyn Stream<Item = State, Error = ()>>>,
+    last_state: Option<State>,
+    station: Option<Box<dyn Future<Item = Vec<String>, Error = ()>>>,
+}
+
+impl DelayedAutoplay {
+    pub(crate) fn new(after: u32) -> DelayedAutoplay {
+        DelayedAutoplay {
+            after,
+            played: 0,
+            track_id: None,
+            states: None,
+            last_state: None,
+            station: None,
+        }
+    }
+
+    pub(crate) fn connected(&mut self, session: &Session) {
+        self.states = Some(main_loop::own_states(session));
+        self.last_state = None;
+        self.station = None;
+    }
+
+    pub(crate) fn handle_event(&mut self, event: &PlayerEvent) {
+        match *event {
+            PlayerEvent::Playing { track_id, .. } if self.track_id != Some(track_id) => {
+                self.track_id = Some(track_id);
+                self.played += 1;
+            }
+            // Playback was stopped or moved to another device.
+            PlayerEvent::Stopped { .. } => {
+                self.track_id = None;
+                self.played = 0;
+            }
+            _ => (),
+        }
+    }
+
+    pub(crate) fn poll(&mut self, session: &Session, handle: &Handle, device_name: &str) {
+        while let Some(Ok(Async::Ready(Some(state)))) = self.states.as_mut().map(Stream::poll) {
+            let ended = match self.last_state {
+                Some(ref last) => context_ended(last, &state),
+                None => false,
+            };
+            if ended && self.played >= self.after {
+                info!(
+                    "Played {} tracks, continuing with similar ones",
+                    self.played
+                );
+                self.station = Some(station_tracks(session, state.get_context_uri()));
+            }
+            self.last_state = Some(state);
+        }
+
+        match self.station.as_mut().map(Future::poll) {
+            Some(Ok(Async::Ready(uris))) => {
+                self.station = None;
+                play(session, handle, device_name.to_string(), uris);
+            }
+            Some(Err(())) => {
+                warn!("Could not find similar tracks to continue with");
+                self.station = None;
+            }
+            _ => (),
+        }
+    }
+}
+
+/// Spirc goes back to the first track and pauses when the last one ends and
+/// repeat is off.
+fn context_ended(last: &State, state: &State) -> bool {
+    last.get_status() == PlayStatus::kPlayStatusPlay
+        && last.get_playing_track_index() + 1 == last.get_track().len() as u32
+        && state.get_playing_track_index() == 0
+        && state.get_status() != PlayStatus::kPlayStatusPlay
+        && state.get_context_uri() == last.get_context_uri()
+}
+
+/// Looks up the tracks of the autoplay station of `context_uri`, the same way
+/// Spirc does.
+fn station_tracks(
+    session: &Session,
+    context_uri: &str,
+) -> Box<dyn Future<Item = Vec<String>, Error = ()>> {
+    let mercury = session.mercury().clone();
+    let query = format!("hm://autoplay-enabled/query?uri={}", context_uri);
+    Box::new(
+        session
+            .mercury()
+            .get(query)
+            .map_err(|_| ())
+            .and_then(|response| {
+                let data = response.payload.first().ok_or(())?;
+                String::from_utf8(data.clone()).map_err(|_| ())
+            })
+            .and_then(move |station_uri| {
+                mercury
+                    .get(format!("hm://radio-apollo/v3/stations/{}", station_uri))
+                    .map_err(|_| ())
+            })
+            .and_then(|response| {
+                let data = response.payload.first().ok_or(())?;
+                let station: Station = serde_json::from_slice(data).map_err(|_| ())?;
+                Ok(station
+                    .tracks
+                    .into_iter()
+                    .map(|track| track.uri)
+                    .take(MAX_TRACKS)
+                    .collect())
+            }),
+    )
+}
+
+fn play(session: &Session, handle: &Handle, device_name: String, uris: Vec<String>) {
+    web_api::spawn_with_api(session, handle, main_loop::PLAYBACK_SCOPE, move |sp| {
+        let device_id = match sp.device() {
+            Ok(devices) => devices
+                .devices
+                .into_iter()
+                .find(|device| device.name == device_name)
+                .map(|device| device.id),
+            Err(e) => {
+                warn!("Could not list the devices: {}", e);
+                return;
+            }
+        };
+        let result = match device_id {
+            Some(device_id) => sp.start_playback(Some(device_id), None, Some(uris), None, None),
+            None => {
+                warn!("This device isn't known to Spotify, not continuing playback");
+                return;
+            }
+        };
+        if let Err(e) = result {
+            warn!("Could not continue with similar tracks: {}", e);
+        }
+    });
+}
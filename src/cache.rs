@@ -0,0 +1,567 @@
+use crate::state::State;
+use futures::Future;
+use librespot::{
+    core::{
+        session::Session,
+        spotify_id::{FileId, SpotifyId},
+    },
+    metadata::AudioItem,
+    playback::player::PlayerEvent,
+};
+use log::{debug, info, warn};
+#[cfg(feature = "status_server")]
+use serde::Serialize;
+use std::{
+    collections::HashSet,
+    fmt, fs,
+    io::{self, Write},
+    path::{Path, PathBuf},
+    thread,
+    time::{Duration, SystemTime},
+};
+use tokio_core::reactor::Handle;
+
+/// The file librespot saves the credentials to, inside the cache directory.
+pub(crate) const CREDENTIALS_FILE_NAME: &str = "credentials.json";
+
+/// The file the credentials are kept in when they are encrypted.
+pub(crate) const ENCRYPTED_CREDENTIALS_FILE_NAME: &str = "credentials.enc";
+
+/// The file librespot saves the volume to.
+const VOLUME_FILE_NAME: &str = "volume";
+
+/// The directory librespot keeps the audio files in.
+const FILES_DIR_NAME: &str = "files";
+
+/// The file that tells which tracks the audio files in the cache belong to.
+const CACHED_TRACKS_FILE_NAME: &str = "cached_tracks";
+
+/// Every audio file starts with a header of this many bytes, so anything
+/// shorter was cut off while it was being written.
+const AUDIO_HEADER_LEN: u64 = 0xa7;
+
+/// Removes the stubs of audio files left in the cache, e.g. because spotifyd
+/// was killed while saving them. The files are encrypted and their expected
+/// size isn't kept, so only files shorter than the header are found, not ones
+/// cut off later on.
+pub(crate) fn remove_stubs(cache_path: &Path) {
+    match remove_stub_files(&cache_path.join(FILES_DIR_NAME)) {
+        Ok(0) => info!("No stubs of audio files found in the cache"),
+        Ok(removed) => info!("Removed {} stubs of audio files from the cache", removed),
+        Err(e) => warn!("Could not check the audio cache: {}", e),
+    }
+}
+
+/// Returns how many files were removed. A file or directory that can't be
+/// read or removed is logged and skipped.
+fn remove_stub_files(files_path: &Path) -> io::Result<usize> {
+    let mut removed = 0;
+    if !files_path.is_dir() {
+        return Ok(removed);
+    }
+
+    for dir in fs::read_dir(files_path)? {
+        let dir = match dir {
+            Ok(dir) => dir.path(),
+            Err(e) => {
+                warn!("Could not read the audio cache: {}", e);
+                continue;
+            }
+        };
+        if !dir.is_dir() {
+            continue;
+        }
+        let files = match fs::read_dir(&dir) {
+            Ok(files) => files,
+            Err(e) => {
+                warn!("Could not read {}: {}", dir.display(), e);
+                continue;
+            }
+        };
+        for file in files {
+            let path = match file {
+                Ok(file) => file.path(),
+                Err(e) => {
+                    warn!("Could not read {}: {}", dir.display(), e);
+                    continue;
+                }
+            };
+            match fs::metadata(&path) {
+                Ok(metadata) if metadata.is_file() && metadata.len() < AUDIO_HEADER_LEN => {
+                    match fs::remove_file(&path) {
+                        Ok(()) => removed += 1,
+                        Err(e) => warn!("Could not remove {}: {}", path.display(), e),
+                    }
+                }
+                Ok(_) => (),
+                Err(e) => warn!("Could not read {}: {}", path.display(), e),
+            }
+        }
+    }
+    Ok(removed)
+}
+
+/// How often the audio cache is checked for files older than `cache_max_age`.
+const MAX_AGE_CHECK_INTERVAL: Duration = Duration::from_secs(6 * 60 * 60);
+
+/// Removes cached audio files that weren't used for `max_age`, now and then
+/// every few hours on a separate thread.
+pub(crate) fn remove_old_files_periodically(cache_path: &Path, max_age: Duration) {
+    let files_path = cache_path.join(FILES_DIR_NAME);
+    thread::spawn(move || loop {
+        match remove_old_files(&files_path, max_age) {
+            Ok((0, _)) => debug!("No audio files in the cache are too old"),
+            Ok((removed, bytes)) => info!(
+                "Removed {} old files from the audio cache, freeing {:.1} MB",
+                removed,
+                bytes as f64 / 1_000_000.0
+            ),
+            Err(e) => warn!("Could not remove old files from the audio cache: {}", e),
+        }
+        thread::sleep(MAX_AGE_CHECK_INTERVAL);
+    });
+}
+
+/// Returns how many files were removed and their size.
+fn remove_old_files(files_path: &Path, max_age: Duration) -> io::Result<(usize, u64)> {
+    let mut removed = 0;
+    let mut bytes = 0;
+    let now = SystemTime::now();
+    for file in audio_files(files_path)? {
+        if now.duration_since(file.last_used).unwrap_or_default() > max_age {
+            fs::remove_file(&file.path)?;
+            removed += 1;
+            bytes += file.len;
+        }
+    }
+    Ok((removed, bytes))
+}
+
+/// How often the audio cache is checked for being bigger than
+/// `max_cache_size`. A few tracks fit into the cache in the meantime.
+const MAX_SIZE_CHECK_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Files written to more recently than this are left in the cache, as they may
+/// belong to the track that is playing and still being downloaded.
+const IN_USE_PERIOD: Duration = Duration::from_secs(5 * 60);
+
+/// Keeps the audio cache below `max_size` bytes, by removing the files that
+/// were used the longest time ago, every minute on a separate thread. Files
+/// that are still being written to are kept.
+pub(crate) fn limit_size_periodically(cache_path: &Path, max_size: u64) {
+    let files_path = cache_path.join(FILES_DIR_NAME);
+    thread::spawn(move || loop {
+        match remove_least_recently_used(&files_path, max_size) {
+            Ok((0, _)) => (),
+            Ok((removed, bytes)) => info!(
+                "The audio cache is bigger than max_cache_size, removed {} files that weren't \
+                 played for the longest time, freeing {:.1} MB",
+                removed,
+                bytes as f64 / 1_000_000.0
+            ),
+            Err(e) => warn!("Could not make the audio cache smaller: {}", e),
+        }
+        thread::sleep(MAX_SIZE_CHECK_INTERVAL);
+    });
+}
+
+/// Returns how many files were removed and their size.
+fn remove_least_recently_used(files_path: &Path, max_size: u64) -> io::Result<(usize, u64)> {
+    let mut files = audio_files(files_path)?;
+    let mut size: u64 = files.iter().map(|file| file.len).sum();
+    let mut removed = 0;
+    let mut bytes = 0;
+    let now = SystemTime::now();
+    files.sort_by_key(|file| file.last_used);
+    for file in files {
+        if size <= max_size {
+            break;
+        }
+        if now.duration_since(file.modified).unwrap_or_default() < IN_USE_PERIOD {
+            continue;
+        }
+        fs::remove_file(&file.path)?;
+        size -= file.len;
+        removed += 1;
+        bytes += file.len;
+    }
+    Ok((removed, bytes))
+}
+
+/// Removes all audio files from the cache, for `clear-cache`, leaving the
+/// credentials, the volume and the state alone. Returns how many files were
+/// removed and their size.
+pub(crate) fn clear(cache_path: &Path) -> io::Result<(usize, u64)> {
+    let mut removed = 0;
+    let mut bytes = 0;
+    for file in audio_files(&cache_path.join(FILES_DIR_NAME))? {
+        fs::remove_file(&file.path)?;
+        removed += 1;
+        bytes += file.len;
+    }
+    match fs::remove_file(cache_path.join(CACHED_TRACKS_FILE_NAME)) {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+        _ => (),
+    }
+    Ok((removed, bytes))
+}
+
+/// An audio file in the cache.
+struct AudioFile {
+    path: PathBuf,
+    len: u64,
+    /// When the file was last read or written, so on filesystems mounted with
+    /// `noatime` only writes count.
+    last_used: SystemTime,
+    modified: SystemTime,
+}
+
+/// Lists the audio files in the cache, which librespot keeps in a directory
+/// for each first two characters of their ids.
+fn audio_files(files_path: &Path) -> io::Result<Vec<AudioFile>> {
+    let mut files = Vec::new();
+    if !files_path.is_dir() {
+        return Ok(files);
+    }
+
+    for dir in fs::read_dir(files_path)? {
+        let dir = dir?;
+        if !dir.file_type()?.is_dir() {
+            continue;
+        }
+        for file in fs::read_dir(dir.path())? {
+            let file = file?;
+            let metadata = file.metadata()?;
+            if !metadata.is_file() {
+                continue;
+            }
+            let (last_used, modified) = match (metadata.accessed(), metadata.modified()) {
+                (Ok(accessed), Ok(modified)) => (accessed.max(modified), modified),
+                (Ok(time), Err(_)) | (Err(_), Ok(time)) => (time, time),
+                (Err(_), Err(_)) => continue,
+            };
+            files.push(AudioFile {
+                path: file.path(),
+                len: metadata.len(),
+                last_used,
+                modified,
+            });
+        }
+    }
+    Ok(files)
+}
+
+/// How much is in the cache directory.
+#[derive(Debug, Default)]
+#[cfg_attr(feature = "status_server", derive(Serialize))]
+pub(crate) struct CacheStats {
+    /// All files, including the credentials and the state.
+    files: u64,
+    size_bytes: u64,
+    audio_files: u64,
+    audio_size_bytes: u64,
+    /// Audio files written since spotifyd started. librespot doesn't count
+    /// cache hits and misses, but each of these was a miss.
+    audio_files_since_start: u64,
+}
+
+impl CacheStats {
+    /// Walks the cache directory, so this takes longer the bigger the cache.
+    pub(crate) fn collect(cache_path: &Path, started_at: SystemTime) -> io::Result<CacheStats> {
+        let mut stats = CacheStats::default();
+        let files_path = cache_path.join(FILES_DIR_NAME);
+        let mut dirs = vec![cache_path.to_path_buf()];
+        while let Some(dir) = dirs.pop() {
+            for entry in fs::read_dir(&dir)? {
+                let entry = entry?;
+                let metadata = entry.metadata()?;
+                if metadata.is_dir() {
+                    dirs.push(entry.path());
+                    continue;
+                }
+                stats.files += 1;
+                stats.size_bytes += metadata.len();
+                if dir.starts_with(&files_path) {
+                    stats.audio_files += 1;
+                    stats.audio_size_bytes += metadata.len();
+                    if matches!(metadata.modified(), Ok(modified) if modified >= started_at) {
+                        stats.audio_files_since_start += 1;
+                    }
+                }
+            }
+        }
+        Ok(stats)
+    }
+
+    /// Describes the cache for people, for `cache-stats`.
+    pub(crate) fn report(&self, max_size: Option<u64>) -> String {
+        let mut report = format!(
+            "{} files, {:.1} MB\n{} of them are audio files, {:.1} MB",
+            self.files,
+            self.size_bytes as f64 / 1_000_000.0,
+            self.audio_files,
+            self.audio_size_bytes as f64 / 1_000_000.0
+        );
+        if let Some(max_size) = max_size {
+            report += &format!(
+                " of the {:.1} MB max_cache_size allows",
+                max_size as f64 / 1_000_000.0
+            );
+        }
+        report
+    }
+}
+
+impl fmt::Display for CacheStats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "files={} size_bytes={} audio_files={} audio_size_bytes={} audio_files_since_start={}",
+            self.files,
+            self.size_bytes,
+            self.audio_files,
+            self.audio_size_bytes,
+            self.audio_files_since_start
+        )
+    }
+}
+
+/// Keeps track of which tracks have an audio file in the cache, for
+/// `cached-tracks`.
+///
+/// The audio files are named after their own ids, with nothing that tells
+/// which track they belong to. So once a track was played, or skipped, its
+/// files are looked up, and the track is written to `cached_tracks` in the
+/// cache directory, along with the file, if one of them was saved. Tracks
+/// played before this was added aren't known.
+pub(crate) struct CacheIndex {
+    cache_path: PathBuf,
+}
+
+impl CacheIndex {
+    pub(crate) fn new(cache_path: PathBuf) -> CacheIndex {
+        CacheIndex { cache_path }
+    }
+
+    pub(crate) fn handle_event(&self, event: &PlayerEvent, session: &Session, handle: &Handle) {
+        let track_id = match *event {
+            PlayerEvent::EndOfTrack { track_id, .. } => track_id,
+            PlayerEvent::Changed { old_track_id, .. } => old_track_id,
+            _ => return,
+        };
+        let cache_path = self.cache_path.clone();
+        handle.spawn(
+            AudioItem::get_audio_item(session, track_id)
+                .map_err(move |_| debug!("Could not look up the files of {}", track_id.to_uri()))
+                .map(move |item| {
+                    let file = item
+                        .files
+                        .values()
+                        .find(|&&file| audio_file_path(&cache_path, file).is_file());
+                    if let Some(&file) = file {
+                        if let Err(e) = add_cached_track(&cache_path, track_id, file) {
+                            warn!("Could not write to the list of cached tracks: {}", e);
+                        }
+                    }
+                }),
+        );
+    }
+}
+
+pub(crate) fn audio_file_path(cache_path: &Path, file: FileId) -> PathBuf {
+    let name = file.to_base16();
+    cache_path
+        .join(FILES_DIR_NAME)
+        .join(&name[0..2])
+        .join(&name[2..])
+}
+
+fn add_cached_track(cache_path: &Path, track_id: SpotifyId, file: FileId) -> io::Result<()> {
+    let path = cache_path.join(CACHED_TRACKS_FILE_NAME);
+    let line = format!("{} {}\n", track_id.to_uri(), file.to_base16());
+    if matches!(fs::read_to_string(&path), Ok(content) if content.contains(&line)) {
+        return Ok(());
+    }
+    fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)?
+        .write_all(line.as_bytes())
+}
+
+/// Returns the URIs of the tracks that have an audio file in the cache, in
+/// the order they were first saved. Tracks whose files were removed since,
+/// e.g. by `cache_max_age`, are left out.
+pub(crate) fn cached_tracks(cache_path: &Path) -> io::Result<Vec<String>> {
+    let content = match fs::read_to_string(cache_path.join(CACHED_TRACKS_FILE_NAME)) {
+        Ok(content) => content,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e),
+    };
+    let mut seen = HashSet::new();
+    Ok(content
+        .lines()
+        .filter_map(|line| {
+            let mut words = line.split_whitespace();
+            let (uri, file) = (words.next()?, words.next()?);
+            let file = hex::decode(file).ok()?;
+            if file.len() != 20 || !seen.insert(uri) {
+                return None;
+            }
+            let mut id = [0; 20];
+            id.copy_from_slice(&file);
+            if audio_file_path(cache_path, FileId(id)).is_file() {
+                Some(uri.to_string())
+            } else {
+                seen.remove(uri);
+                None
+            }
+        })
+        .collect())
+}
+
+/// Removes the cached credentials, encrypted or not, leaving the audio cache
+/// alone. Returns the path of the removed file, or `None` if there weren't any
+/// credentials.
+pub(crate) fn remove_credentials(cache_path: &Path) -> io::Result<Option<PathBuf>> {
+    let mut removed = None;
+    for name in &[CREDENTIALS_FILE_NAME, ENCRYPTED_CREDENTIALS_FILE_NAME] {
+        let path = cache_path.join(name);
+        match fs::remove_file(&path) {
+            Ok(()) => removed = Some(path),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => (),
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(removed)
+}
+
+/// Moves the credentials, the volume and spotifyd's state from an old cache
+/// directory to `cache_path`, along with the audio files if `audio` is set.
+/// Nothing is moved unless `cache_path` is still empty, so this only happens
+/// once.
+pub(crate) fn migrate(from: &Path, cache_path: &Path, audio: bool) {
+    if !from.is_dir() {
+        info!("No cache to migrate at {:?}", from);
+        return;
+    }
+    match is_empty(cache_path) {
+        Ok(true) => (),
+        Ok(false) => return,
+        Err(e) => {
+            warn!("Could not check whether {:?} is empty: {}", cache_path, e);
+            return;
+        }
+    }
+
+    let mut paths = vec![
+        from.join(CREDENTIALS_FILE_NAME),
+        from.join(ENCRYPTED_CREDENTIALS_FILE_NAME),
+        from.join(VOLUME_FILE_NAME),
+        State::path(from),
+    ];
+    if audio {
+        paths.push(from.join(FILES_DIR_NAME));
+    }
+    for path in paths.into_iter().filter(|path| path.exists()) {
+        let target = cache_path.join(path.file_name().unwrap());
+        match move_path(&path, &target) {
+            Ok(()) => info!("Migrated {:?} to {:?}", path, target),
+            Err(e) => warn!("Could not migrate {:?} to {:?}: {}", path, target, e),
+        }
+    }
+}
+
+/// Returns whether a cache directory has nothing in it but an empty `files`
+/// directory, which librespot creates as soon as the cache is set up.
+fn is_empty(cache_path: &Path) -> io::Result<bool> {
+    if !cache_path.exists() {
+        return Ok(true);
+    }
+    for entry in fs::read_dir(cache_path)? {
+        let entry = entry?;
+        if entry.file_name() != FILES_DIR_NAME || fs::read_dir(entry.path())?.next().is_some() {
+            return Ok(false);
+        }
+    }
+    Ok(true)
+}
+
+fn move_path(from: &Path, to: &Path) -> io::Result<()> {
+    if to.is_dir() {
+        // An empty directory is in the way; the check above made sure of that.
+        fs::remove_dir(to)?;
+    } else if let Some(parent) = to.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    // Renaming fails if the directories are on different filesystems.
+    if fs::rename(from, to).is_ok() {
+        return Ok(());
+    }
+    copy_path(from, to)?;
+    if from.is_dir() {
+        fs::remove_dir_all(from)
+    } else {
+        fs::remove_file(from)
+    }
+}
+
+fn copy_path(from: &Path, to: &Path) -> io::Result<()> {
+    if !from.is_dir() {
+        return fs::copy(from, to).map(|_| ());
+    }
+    fs::create_dir_all(to)?;
+    for entry in fs::read_dir(from)? {
+        let entry = entry?;
+        copy_path(&entry.path(), &to.join(entry.file_name()))?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs::{File, FileTimes};
+
+    fn write_file(path: &Path, len: usize, age: Duration) {
+        let file = File::create(path).unwrap();
+        file.set_len(len as u64).unwrap();
+        let time = SystemTime::now() - age;
+        file.set_times(FileTimes::new().set_accessed(time).set_modified(time))
+            .unwrap();
+    }
+
+    #[test]
+    fn test_remove_least_recently_used() {
+        let files_path =
+            std::env::temp_dir().join(format!("spotifyd-cache-test-{}", std::process::id()));
+        let dir = files_path.join("ab");
+        fs::create_dir_all(&dir).unwrap();
+        let hour = Duration::from_secs(60 * 60);
+        write_file(&dir.join("oldest"), 100, 3 * hour);
+        write_file(&dir.join("older"), 100, 2 * hour);
+        write_file(&dir.join("old"), 100, hour);
+        write_file(&dir.join("playing"), 100, Duration::from_secs(0));
+
+        assert_eq!(
+            remove_least_recently_used(&files_path, 400).unwrap(),
+            (0, 0)
+        );
+        assert_eq!(
+            remove_least_recently_used(&files_path, 250).unwrap(),
+            (2, 200)
+        );
+        assert!(!dir.join("oldest").exists());
+        assert!(!dir.join("older").exists());
+        assert!(dir.join("old").exists());
+
+        // The file that is still written to is kept, even if that isn't enough.
+        assert_eq!(
+            remove_least_recently_used(&files_path, 0).unwrap(),
+            (1, 100)
+        );
+        assert!(dir.join("playing").exists());
+
+        fs::remove_dir_all(&files_path).unwrap();
+    }
+}
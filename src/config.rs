@@ -1,23 +1,43 @@
 use crate::{
+    cache,
     error::{Error as CrateError, ParseError},
-    process::run_program,
+    metadata::MetadataFetch,
+    placeholders,
+    process::{run_program, EventPrograms},
+    state::State,
     utils,
 };
+use chrono::{
+    format::{Item, StrftimeItems},
+    Local, SecondsFormat, Utc,
+};
 use color_eyre::Report;
 use gethostname::gethostname;
 use librespot::{
     core::{cache::Cache, config::DeviceType as LSDeviceType, config::SessionConfig, version},
     playback::config::{Bitrate as LSBitrate, PlayerConfig},
 };
-use log::{error, info, warn};
-use serde::{de::Error, de::Unexpected, Deserialize, Deserializer};
+use log::{error, info, warn, LevelFilter};
+use rand::RngCore;
+use rspotify::spotify::senum::Country;
+use serde::{de::Error, de::Unexpected, Deserialize, Deserializer, Serialize, Serializer};
 use sha1::{Digest, Sha1};
-use std::{fmt, fs, path::PathBuf, str::FromStr, string::ToString};
+use std::{
+    fmt, fs,
+    net::IpAddr,
+    path::{Path, PathBuf},
+    str::FromStr,
+    string::ToString,
+    time::Duration,
+};
 use structopt::{clap::AppSettings, StructOpt};
 use url::Url;
 
 const CONFIG_FILE_NAME: &str = "spotifyd.conf";
 
+/// The `volume_step` used when none is set, in percent.
+const DEFAULT_VOLUME_STEP: u8 = 5;
+
 static BACKEND_VALUES: &[&str] = &[
     #[cfg(feature = "alsa_backend")]
     "alsa",
@@ -27,16 +47,18 @@ static BACKEND_VALUES: &[&str] = &[
     "portaudio",
     #[cfg(feature = "rodio_backend")]
     "rodio",
+    "tee",
 ];
 
 /// The backend used by librespot
-#[derive(Clone, Copy, Debug, Deserialize, PartialEq, StructOpt)]
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Serialize, StructOpt)]
 #[serde(rename_all = "lowercase")]
 pub enum Backend {
     Alsa,
     PortAudio,
     PulseAudio,
     Rodio,
+    Tee,
 }
 
 impl FromStr for Backend {
@@ -48,6 +70,7 @@ impl FromStr for Backend {
             "portaudio" => Ok(Backend::PortAudio),
             "pulseaudio" => Ok(Backend::PulseAudio),
             "rodio" => Ok(Backend::Rodio),
+            "tee" => Ok(Backend::Tee),
             _ => unreachable!(),
         }
     }
@@ -60,6 +83,237 @@ impl ToString for Backend {
             Backend::PortAudio => "portaudio".to_string(),
             Backend::PulseAudio => "pulseaudio".to_string(),
             Backend::Rodio => "rodio".to_string(),
+            Backend::Tee => "tee".to_string(),
+        }
+    }
+}
+
+static TRACK_ERROR_ACTION_VALUES: &[&str] = &["skip", "retry", "stop"];
+
+/// What happens when a track can't be loaded
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Serialize, StructOpt)]
+#[serde(rename_all = "lowercase")]
+pub enum TrackErrorAction {
+    Skip,
+    Retry,
+    Stop,
+}
+
+impl FromStr for TrackErrorAction {
+    type Err = ParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "skip" => Ok(TrackErrorAction::Skip),
+            "retry" => Ok(TrackErrorAction::Retry),
+            "stop" => Ok(TrackErrorAction::Stop),
+            _ => unreachable!(),
+        }
+    }
+}
+
+static DOWNMIX_VALUES: &[&str] = &["stereo", "mono"];
+
+/// The channels the audio is played with
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Serialize, StructOpt)]
+#[serde(rename_all = "lowercase")]
+pub enum Downmix {
+    Stereo,
+    Mono,
+}
+
+impl FromStr for Downmix {
+    type Err = ParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "stereo" => Ok(Downmix::Stereo),
+            "mono" => Ok(Downmix::Mono),
+            _ => unreachable!(),
+        }
+    }
+}
+
+static DEVICE_LOST_ACTION_VALUES: &[&str] = &["stop", "reopen", "fallback"];
+
+/// What happens when the audio device goes away during playback
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Serialize, StructOpt)]
+#[serde(rename_all = "lowercase")]
+pub enum DeviceLostAction {
+    /// Pause playback.
+    Stop,
+    /// Pause playback and resume it once the device is back.
+    Reopen,
+    /// Keep playing with `fallback_backend`.
+    Fallback,
+}
+
+impl FromStr for DeviceLostAction {
+    type Err = ParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "stop" => Ok(DeviceLostAction::Stop),
+            "reopen" => Ok(DeviceLostAction::Reopen),
+            "fallback" => Ok(DeviceLostAction::Fallback),
+            _ => unreachable!(),
+        }
+    }
+}
+
+static DEVICE_POLICY_VALUES: &[&str] = &["release", "keep-open"];
+
+/// Whether the audio device is closed while nothing plays
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Serialize, StructOpt)]
+#[serde(rename_all = "kebab-case")]
+pub enum DevicePolicy {
+    /// Close the device when playback pauses or stops.
+    Release,
+    /// Keep the device open from the first track on.
+    KeepOpen,
+}
+
+impl FromStr for DevicePolicy {
+    type Err = ParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "release" => Ok(DevicePolicy::Release),
+            "keep-open" => Ok(DevicePolicy::KeepOpen),
+            _ => unreachable!(),
+        }
+    }
+}
+
+static CONTEXT_VOLUME_VALUES: &[&str] = &["type", "uri"];
+
+/// What the volume is remembered for
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Serialize, StructOpt)]
+#[serde(rename_all = "lowercase")]
+pub enum ContextVolume {
+    /// Music and podcasts each get their own volume.
+    Type,
+    /// Every playlist, album or show gets its own volume.
+    Uri,
+}
+
+impl FromStr for ContextVolume {
+    type Err = ParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "type" => Ok(ContextVolume::Type),
+            "uri" => Ok(ContextVolume::Uri),
+            _ => unreachable!(),
+        }
+    }
+}
+
+/// How log lines are timestamped
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) struct LogTimestamp {
+    format: TimestampFormat,
+    utc: bool,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum TimestampFormat {
+    Rfc3339,
+    /// Seconds since the Unix epoch, with milliseconds.
+    Epoch,
+    Strftime(String),
+}
+
+impl LogTimestamp {
+    /// Returns `None` if `format` isn't a valid strftime format.
+    fn new(format: &str, utc: bool) -> Option<LogTimestamp> {
+        let format = match format {
+            "rfc3339" => TimestampFormat::Rfc3339,
+            "epoch" => TimestampFormat::Epoch,
+            _ if StrftimeItems::new(format).any(|item| item == Item::Error) => return None,
+            _ => TimestampFormat::Strftime(format.to_string()),
+        };
+        Some(LogTimestamp { format, utc })
+    }
+
+    pub(crate) fn now(&self) -> String {
+        match self.format {
+            TimestampFormat::Rfc3339 if self.utc => {
+                Utc::now().to_rfc3339_opts(SecondsFormat::Millis, true)
+            }
+            TimestampFormat::Rfc3339 => Local::now().to_rfc3339_opts(SecondsFormat::Millis, true),
+            TimestampFormat::Epoch => {
+                let now = Utc::now();
+                format!("{}.{:03}", now.timestamp(), now.timestamp_subsec_millis())
+            }
+            TimestampFormat::Strftime(ref format) if self.utc => {
+                Utc::now().format(format).to_string()
+            }
+            TimestampFormat::Strftime(ref format) => Local::now().format(format).to_string(),
+        }
+    }
+}
+
+static LOG_LEVEL_VALUES: &[&str] = &["error", "warn", "info", "debug", "trace"];
+
+/// How much is logged
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Serialize, StructOpt)]
+#[serde(rename_all = "lowercase")]
+pub enum LogLevel {
+    Error,
+    Warn,
+    Info,
+    Debug,
+    Trace,
+}
+
+impl FromStr for LogLevel {
+    type Err = ParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "error" => Ok(LogLevel::Error),
+            "warn" => Ok(LogLevel::Warn),
+            "info" => Ok(LogLevel::Info),
+            "debug" => Ok(LogLevel::Debug),
+            "trace" => Ok(LogLevel::Trace),
+            _ => unreachable!(),
+        }
+    }
+}
+
+impl From<LogLevel> for LevelFilter {
+    fn from(level: LogLevel) -> Self {
+        match level {
+            LogLevel::Error => LevelFilter::Error,
+            LogLevel::Warn => LevelFilter::Warn,
+            LogLevel::Info => LevelFilter::Info,
+            LogLevel::Debug => LevelFilter::Debug,
+            LogLevel::Trace => LevelFilter::Trace,
+        }
+    }
+}
+
+static DUPLICATE_ACTION_VALUES: &[&str] = &["error", "rename", "allow"];
+
+/// What happens when another device is announced with the same name
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Serialize, StructOpt)]
+#[serde(rename_all = "lowercase")]
+pub enum DuplicateAction {
+    Error,
+    Rename,
+    Allow,
+}
+
+impl FromStr for DuplicateAction {
+    type Err = ParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "error" => Ok(DuplicateAction::Error),
+            "rename" => Ok(DuplicateAction::Rename),
+            "allow" => Ok(DuplicateAction::Allow),
+            _ => unreachable!(),
         }
     }
 }
@@ -72,7 +326,7 @@ static VOLUME_CONTROLLER_VALUES: &[&str] = &[
     "alsa_linear",
 ];
 
-#[derive(Clone, Copy, Debug, Deserialize, PartialEq, StructOpt)]
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Serialize, StructOpt)]
 #[serde(rename_all = "snake_case")]
 pub enum VolumeController {
     Alsa,
@@ -94,6 +348,40 @@ impl FromStr for VolumeController {
     }
 }
 
+/// How the volume set in Spotify Connect maps onto the loudness, for
+/// `volume_curve`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) enum VolumeCurve {
+    Linear,
+    /// Every step changes the loudness by the same number of decibels.
+    Log,
+    /// The amplitude is the volume raised to this power.
+    Exponent(f64),
+}
+
+/// Splits an entry of `fallback_backends` like "alsa:hw:DAC" into the backend
+/// and the device, which is the default one if there is none.
+fn parse_backend_entry(entry: &str) -> (String, Option<String>) {
+    let mut parts = entry.splitn(2, ':');
+    let backend = parts.next().unwrap_or_default().to_string();
+    let device = parts.next().filter(|device| !device.is_empty());
+    (backend, device.map(str::to_string))
+}
+
+/// Parses a `volume_curve` like "linear", "log" or "2".
+fn parse_volume_curve(curve: &str) -> Option<VolumeCurve> {
+    match curve {
+        "linear" => Some(VolumeCurve::Linear),
+        "log" | "logarithmic" | "db" => Some(VolumeCurve::Log),
+        exponent => match exponent.parse::<f64>() {
+            Ok(exponent) if exponent.is_finite() && exponent > 0.0 => {
+                Some(VolumeCurve::Exponent(exponent))
+            }
+            _ => None,
+        },
+    }
+}
+
 static DEVICETYPE_VALUES: &[&str] = &[
     "computer",
     "tablet",
@@ -106,7 +394,7 @@ static DEVICETYPE_VALUES: &[&str] = &[
 ];
 
 // Spotify's device type (copied from it's config.rs)
-#[derive(Clone, Copy, Debug, Deserialize, PartialEq, StructOpt)]
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Serialize, StructOpt)]
 #[serde(rename_all = "snake_case")]
 pub enum DeviceType {
     Unknown = 0,
@@ -193,6 +481,17 @@ impl<'de> Deserialize<'de> for Bitrate {
     }
 }
 
+impl Serialize for Bitrate {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let bitrate: u16 = match self {
+            Bitrate::Bitrate96 => 96,
+            Bitrate::Bitrate160 => 160,
+            Bitrate::Bitrate320 => 320,
+        };
+        bitrate.serialize(serializer)
+    }
+}
+
 impl FromStr for Bitrate {
     type Err = ParseError;
 
@@ -240,13 +539,117 @@ pub struct CliConfig {
     #[structopt(long)]
     pub pid: Option<PathBuf>,
 
+    /// Prints the effective config as TOML, then exits
+    #[structopt(long)]
+    pub dump_config: bool,
+
+    /// Includes the credentials in the output of `--dump-config`
+    #[structopt(long, requires = "dump-config")]
+    pub show_secrets: bool,
+
+    /// Writes a commented config file to the given path, or to the user config file, then exits
+    #[structopt(long, value_name = "path")]
+    pub generate_config: Option<Option<PathBuf>>,
+
+    /// Overwrites an existing file with `--generate-config`
+    #[structopt(long, requires = "generate-config")]
+    pub force: bool,
+
     #[structopt(flatten)]
     pub shared_config: SharedConfigValues,
+
+    #[structopt(subcommand)]
+    pub command: Option<Command>,
+}
+
+/// Commands that run a single task instead of starting the daemon
+#[derive(Debug, StructOpt)]
+pub enum Command {
+    /// Checks that the configured credentials can log in to Spotify, then exits
+    AuthTest,
+    /// Removes the credentials stored in the cache, then exits
+    Logout,
+    /// Logs in through the browser and caches the credentials, so that no password is needed, then exits
+    Authenticate,
+    /// Lists the audio devices of the alsa backend with the indices `device` accepts, and the backends, then exits
+    ListDevices,
+    /// Gives the device a new id, so that Spotify sees it as a new device, then exits
+    ResetDeviceId,
+    /// Prints the number and size of the files in the cache, then exits
+    CacheStats,
+    /// Removes the audio files from the cache, keeping the credentials and the state, then exits
+    ClearCache,
+    /// Plays a Spotify URI on this device, then exits when playback stops. Without a URI,
+    /// resumes playback on the running spotifyd, then exits
+    Play {
+        /// The URI to play, e.g. "spotify:playlist:37i9dQZF1DXcBWIGoYBM5M"
+        uri: Option<String>,
+        /// Stop after this many seconds
+        #[structopt(long, value_name = "number", requires = "uri")]
+        duration: Option<u64>,
+    },
+    /// Pauses playback on the running spotifyd, then exits
+    Pause,
+    /// Pauses or resumes playback on the running spotifyd, then exits
+    PlayPause,
+    /// Skips to the next track on the running spotifyd, then exits
+    Next,
+    /// Goes back to the previous track on the running spotifyd, then exits
+    Previous,
+    /// Sets the volume of the running spotifyd, then exits
+    Volume {
+        /// Between 0 and 100
+        volume: u8,
+    },
+    /// Turns the volume of the running spotifyd up by `volume_step`, then exits
+    VolumeUp,
+    /// Turns the volume of the running spotifyd down by `volume_step`, then exits
+    VolumeDown,
+    /// Seeks within the current track on the running spotifyd, then exits
+    Seek {
+        /// The position in milliseconds
+        position: u32,
+    },
+    /// Adds a track to the queue of the running spotifyd, then exits
+    Queue {
+        /// The URI of the track, e.g. "spotify:track:4uLU6hMCjMI75M1A2tKUQC"
+        uri: String,
+    },
+    /// Prints what the running spotifyd is playing, then exits
+    Status {
+        /// Print the status as JSON
+        #[structopt(long)]
+        json: bool,
+    },
+    /// Makes the running spotifyd read its config again, then exits
+    Reload,
+}
+
+impl Command {
+    /// The line to send to the `control_socket` of the running spotifyd, for
+    /// the commands that are carried out by it.
+    pub fn control_line(&self) -> Option<String> {
+        match self {
+            Command::Play { uri: None, .. } => Some("play".to_string()),
+            Command::Pause => Some("pause".to_string()),
+            Command::PlayPause => Some("play-pause".to_string()),
+            Command::Next => Some("next".to_string()),
+            Command::Previous => Some("previous".to_string()),
+            Command::Volume { volume } => Some(format!("volume {}", volume)),
+            Command::VolumeUp => Some("volume up".to_string()),
+            Command::VolumeDown => Some("volume down".to_string()),
+            Command::Seek { position } => Some(format!("seek {}", position)),
+            Command::Queue { uri } => Some(format!("queue {}", uri)),
+            Command::Status { .. } => Some("status".to_string()),
+            Command::Reload => Some("reload".to_string()),
+            _ => None,
+        }
+    }
 }
 
 // A struct that holds all allowed config fields.
 // The actual config file is made up of two sections, spotifyd and global.
-#[derive(Clone, Default, Deserialize, PartialEq, StructOpt)]
+#[derive(Clone, Default, Deserialize, PartialEq, Serialize, StructOpt)]
 pub struct SharedConfigValues {
     /// The Spotify account user name
     #[structopt(conflicts_with = "username_cmd", long, short, value_name = "string")]
@@ -283,6 +686,18 @@ pub struct SharedConfigValues {
     #[cfg_attr(not(feature = "dbus_mpris"), structopt(skip), serde(skip))]
     use_mpris: Option<bool>,
 
+    /// How long MPRIS property changes are collected before they are signalled, in milliseconds
+    #[structopt(long, value_name = "number")]
+    mpris_coalesce_ms: Option<u64>,
+
+    /// How many times a track's metadata is looked up again when the lookup fails or times out
+    #[structopt(long, value_name = "number")]
+    metadata_retries: Option<u32>,
+
+    /// How long a single metadata lookup may take, in milliseconds
+    #[structopt(long, value_name = "number")]
+    metadata_timeout_ms: Option<u64>,
+
     /// A command that can be used to retrieve the Spotify account password
     #[structopt(
         conflicts_with = "password",
@@ -293,6 +708,10 @@ pub struct SharedConfigValues {
     )]
     password_cmd: Option<String>,
 
+    /// A command that prints the passphrase the cached credentials are encrypted with
+    #[structopt(long, value_name = "string")]
+    credentials_passphrase_cmd: Option<String>,
+
     /// Whether the credentials should be debugged.
     #[structopt(long)]
     #[serde(skip)]
@@ -303,15 +722,69 @@ pub struct SharedConfigValues {
     #[serde(alias = "onevent")]
     on_song_change_hook: Option<String>,
 
+    /// A command run instead of onevent when another track starts
+    #[structopt(long, value_name = "string")]
+    on_track_change: Option<String>,
+
+    /// A command run instead of onevent when playback starts or resumes
+    #[structopt(long, value_name = "string")]
+    on_play: Option<String>,
+
+    /// A command run instead of onevent when playback is paused
+    #[structopt(long, value_name = "string")]
+    on_pause: Option<String>,
+
+    /// A command run instead of onevent when playback stops
+    #[structopt(long, value_name = "string")]
+    on_stop: Option<String>,
+
+    /// A command run instead of onevent when the volume changes
+    #[structopt(long, value_name = "string")]
+    on_volume: Option<String>,
+
+    /// How many seconds a running onevent command gets to finish when spotifyd exits
+    #[structopt(long, value_name = "number")]
+    onevent_grace_period: Option<u64>,
+
+    /// How many seconds an onevent command may run before it is killed, 0 for no limit
+    #[structopt(long, value_name = "number")]
+    onevent_timeout: Option<u64>,
+
     /// The cache path used to store credentials and music file artifacts
     #[structopt(long, parse(from_os_str), short, value_name = "string")]
     cache_path: Option<PathBuf>,
 
+    /// The directory used for temporary files, instead of the system one
+    #[structopt(long, parse(from_os_str), value_name = "string")]
+    tmp_dir: Option<PathBuf>,
+
     /// Disable the use of audio cache
     #[structopt(long)]
     #[serde(default)]
     no_audio_cache: bool,
 
+    /// Remove the stubs of audio files, shorter than their header, from the cache at startup
+    #[structopt(long)]
+    #[serde(default)]
+    cache_remove_stubs: bool,
+
+    /// Remove cached audio files that weren't used for this long, e.g. "30d" (h, d or w)
+    #[structopt(long, value_name = "string")]
+    cache_max_age: Option<String>,
+
+    /// Remove the audio files played the longest time ago once the audio cache is bigger than this, e.g. "2G" (K, M or G)
+    #[structopt(long, value_name = "string")]
+    max_cache_size: Option<String>,
+
+    /// An old cache directory to move the credentials and state from if cache_path is empty
+    #[structopt(long, value_name = "string")]
+    migrate_cache: Option<PathBuf>,
+
+    /// Also move the audio files when migrating the cache
+    #[structopt(long)]
+    #[serde(default)]
+    migrate_audio_cache: bool,
+
     /// The audio backend to use
     #[structopt(long, short, possible_values = &BACKEND_VALUES, value_name = "string")]
     backend: Option<Backend>,
@@ -321,10 +794,53 @@ pub struct SharedConfigValues {
     #[serde(alias = "volume-control")]
     volume_controller: Option<VolumeController>,
 
+    /// How the volume maps onto loudness: "linear", "log" or an exponent like "2"
+    #[structopt(long, value_name = "string")]
+    volume_curve: Option<String>,
+
     /// The audio device
     #[structopt(long, value_name = "string")]
     device: Option<String>,
 
+    /// Play the audio in stereo, or mix it down to mono on both channels
+    #[structopt(long, possible_values = &DOWNMIX_VALUES, value_name = "string")]
+    downmix: Option<Downmix>,
+
+    /// What to do when the audio device goes away during playback
+    #[structopt(long, possible_values = &DEVICE_LOST_ACTION_VALUES, value_name = "string")]
+    on_device_lost: Option<DeviceLostAction>,
+
+    /// Whether to close the audio device while playback is paused or stopped
+    #[structopt(long, possible_values = &DEVICE_POLICY_VALUES, value_name = "string")]
+    device_policy: Option<DevicePolicy>,
+
+    /// Try other sample formats and rates when the alsa device rejects 16 bit audio at 44.1 kHz
+    #[structopt(long)]
+    #[serde(default)]
+    format_fallback: bool,
+
+    /// Open the audio device at startup, to fail right away when it is missing
+    #[structopt(long)]
+    #[serde(default)]
+    check_audio_device: bool,
+
+    /// Discard the audio instead of failing to start when no audio device can be opened
+    #[structopt(long)]
+    #[serde(default)]
+    allow_null_fallback: bool,
+
+    /// The backend to switch to with `on_device_lost = "fallback"`, using its default device
+    #[structopt(long, possible_values = &BACKEND_VALUES, value_name = "string")]
+    fallback_backend: Option<Backend>,
+
+    /// The backends to try in order with `on_device_lost = "fallback"`, like "pulseaudio" or "alsa:hw:DAC"
+    #[structopt(long, number_of_values = 1, value_name = "backend[:device]")]
+    fallback_backends: Option<Vec<String>>,
+
+    /// The file or FIFO the tee backend copies the audio to
+    #[structopt(long, value_name = "string")]
+    tee_device: Option<String>,
+
     /// The control device
     #[structopt(long, value_name = "string")]
     control: Option<String>,
@@ -333,7 +849,16 @@ pub struct SharedConfigValues {
     #[structopt(long, value_name = "string")]
     mixer: Option<String>,
 
-    /// The device name displayed in Spotify
+    /// Poll the alsa mixer and tell Spotify Connect about volume changes made outside of spotifyd
+    #[structopt(long)]
+    #[serde(default)]
+    mixer_watch: bool,
+
+    /// How often the alsa mixer is polled with mixer_watch, in milliseconds
+    #[structopt(long, value_name = "number")]
+    mixer_watch_ms: Option<u64>,
+
+    /// The device name displayed in Spotify, which can contain {hostname}, {user}, {uuid} and {ip}
     #[structopt(long, short, value_name = "string")]
     device_name: Option<String>,
 
@@ -341,10 +866,18 @@ pub struct SharedConfigValues {
     #[structopt(long, short = "B", possible_values = &BITRATE_VALUES, value_name = "number")]
     bitrate: Option<Bitrate>,
 
+    /// The bitrate used instead while NetworkManager reports a metered connection
+    #[structopt(long, possible_values = &BITRATE_VALUES, value_name = "number")]
+    metered_bitrate: Option<Bitrate>,
+
     /// Initial volume between 0 and 100
     #[structopt(long, value_name = "initial_volume")]
     initial_volume: Option<String>,
 
+    /// How much the volume up and volume down commands change the volume, from 1 to 100
+    #[structopt(long, value_name = "number")]
+    volume_step: Option<u8>,
+
     /// Enable to normalize the volume during playback
     #[structopt(long)]
     #[serde(default)]
@@ -354,14 +887,46 @@ pub struct SharedConfigValues {
     #[structopt(long, value_name = "number")]
     normalisation_pregain: Option<f32>,
 
+    /// A gain in dB applied to all audio of this device, on top of the volume and the normalisation
+    #[structopt(long, value_name = "number", allow_hyphen_values = true)]
+    device_gain: Option<f32>,
+
+    /// Fade the audio in on play and out on pause over this many milliseconds
+    #[structopt(long, value_name = "number")]
+    fade_ms: Option<u64>,
+
     /// The port used for the Spotify Connect discovery
     #[structopt(long, value_name = "number")]
     zeroconf_port: Option<u16>,
 
+    /// What to do when another device on the network already has this device name
+    #[structopt(long, possible_values = &DUPLICATE_ACTION_VALUES, value_name = "string")]
+    on_duplicate: Option<DuplicateAction>,
+
+    /// What to do when a track fails to load
+    #[structopt(long, possible_values = &TRACK_ERROR_ACTION_VALUES, value_name = "string")]
+    on_track_error: Option<TrackErrorAction>,
+
+    /// How many times a track is retried with `on_track_error = "retry"` before it is skipped
+    #[structopt(long, value_name = "number")]
+    track_error_retries: Option<u8>,
+
+    /// Stop playback once this many tracks in a row couldn't be played, e.g. because they aren't available in the region
+    #[structopt(long, value_name = "number")]
+    context_unplayable_after: Option<u32>,
+
     /// The proxy used to connect to spotify's servers
     #[structopt(long, value_name = "string")]
     proxy: Option<String>,
 
+    /// A PEM bundle of the certificate authorities to trust instead of the system ones
+    #[structopt(long, parse(from_os_str), value_name = "string")]
+    ca_cert: Option<PathBuf>,
+
+    /// The local address to connect to spotify's servers from, on a device with several
+    #[structopt(long, value_name = "address")]
+    bind_address: Option<IpAddr>,
+
     /// The device type shown to clients
     #[structopt(long, possible_values = &DEVICETYPE_VALUES, value_name = "string")]
     device_type: Option<DeviceType>,
@@ -370,9 +935,177 @@ pub struct SharedConfigValues {
     #[structopt(long)]
     #[serde(default)]
     autoplay: bool,
+
+    /// Only autoplay at the end of a context once this many tracks were played
+    #[structopt(long, value_name = "number")]
+    autoplay_after: Option<u32>,
+
+    /// Turn shuffle on whenever a new context starts
+    #[structopt(long)]
+    #[serde(default)]
+    force_shuffle: bool,
+
+    /// Turn repeat on whenever a new context starts
+    #[structopt(long)]
+    #[serde(default)]
+    force_repeat: bool,
+
+    /// Restart the daemon instead of exiting when it panics
+    #[structopt(long)]
+    #[serde(default)]
+    restart_on_panic: bool,
+
+    /// Exit once this device wasn't the active Connect device for this many minutes
+    #[structopt(long, value_name = "number")]
+    exit_on_idle: Option<u64>,
+
+    /// Remember the device name set from a Spotify client across restarts
+    #[structopt(long)]
+    #[serde(default)]
+    persist_device_name: bool,
+
+    /// The nice value to run with, from -20 (highest priority) to 19
+    #[structopt(long, value_name = "number", allow_hyphen_values = true)]
+    nice: Option<i32>,
+
+    /// Use real-time scheduling (SCHED_FIFO) with this priority, from 1 to 99
+    #[structopt(long, value_name = "number")]
+    rt_priority: Option<i32>,
+
+    /// Ignore MPRIS playback commands until playback is transferred to spotifyd
+    #[structopt(long)]
+    #[serde(default)]
+    passive_startup: bool,
+
+    /// Make this the active device whenever no device is playing, so clients play here by default
+    #[structopt(long)]
+    #[serde(default)]
+    prefer_active: bool,
+
+    /// Continue the last track where it was stopped when it is played again after a restart
+    #[structopt(long)]
+    #[serde(default)]
+    resume_playback: bool,
+
+    /// Log in again when the system wakes up from sleep, instead of waiting for the connection to time out
+    #[structopt(long)]
+    #[serde(default)]
+    reconnect_on_resume: bool,
+
+    /// Continue the track that was playing at the same position after the connection to Spotify was lost, on by default
+    #[structopt(long)]
+    resume_after_reconnect: Option<bool>,
+
+    /// Pause playback while the login session is locked
+    #[structopt(long)]
+    #[serde(default)]
+    pause_on_lock: bool,
+
+    /// Resume playback paused by pause_on_lock when the session is unlocked
+    #[structopt(long)]
+    #[serde(default)]
+    resume_on_unlock: bool,
+
+    /// Pause playback when the headphones are unplugged from the sound card
+    #[structopt(long)]
+    #[serde(default)]
+    pause_on_jack_unplug: bool,
+
+    /// Resume playback paused by pause_on_jack_unplug when the headphones are plugged in again
+    #[structopt(long)]
+    #[serde(default)]
+    resume_on_jack_replug: bool,
+
+    /// Skip tracks and episodes marked as explicit
+    #[structopt(long)]
+    #[serde(default)]
+    filter_explicit: bool,
+
+    /// Remember the volume per context type or per context URI and restore it when it plays again
+    #[structopt(long, possible_values = &CONTEXT_VOLUME_VALUES, value_name = "string")]
+    context_volume: Option<ContextVolume>,
+
+    /// Keep the local volume when a client takes over playback, instead of the volume it sends along
+    #[structopt(long)]
+    #[serde(default)]
+    ignore_connect_volume: bool,
+
+    /// The country whose versions of tracks are shown, as an ISO 3166-1 alpha-2 code
+    #[structopt(long, value_name = "string")]
+    market: Option<String>,
+
+    /// The language used for metadata, e.g. "ja" or "ja_JP"
+    #[structopt(long, value_name = "string")]
+    locale: Option<String>,
+
+    /// The address to serve the playback status on as JSON, e.g. "127.0.0.1:5040"
+    #[structopt(long, value_name = "string")]
+    status_addr: Option<String>,
+
+    /// The address to serve the HTTP API for controlling playback on, e.g. "127.0.0.1:5041"
+    #[structopt(long, value_name = "string")]
+    http_api_address: Option<String>,
+
+    /// The token requests to the HTTP API have to send as "Authorization: Bearer <token>"
+    #[structopt(long, value_name = "string")]
+    http_api_token: Option<String>,
+
+    /// An origin of web pages that may use the HTTP API, like "http://192.168.1.5:8080"
+    #[structopt(long, number_of_values = 1, value_name = "string")]
+    http_api_origins: Option<Vec<String>>,
+
+    /// The address to serve Prometheus metrics on at /metrics, and a health check at /healthz, e.g. "0.0.0.0:5042"
+    #[structopt(long, value_name = "string")]
+    metrics_address: Option<String>,
+
+    /// A file to add a line of JSON to for every track played to the end
+    #[structopt(long, parse(from_os_str), value_name = "string")]
+    play_history_file: Option<PathBuf>,
+
+    /// The API key of the Last.fm API account to scrobble with
+    #[structopt(long, value_name = "string")]
+    lastfm_api_key: Option<String>,
+
+    /// The shared secret of the Last.fm API account
+    #[structopt(long, value_name = "string")]
+    lastfm_api_secret: Option<String>,
+
+    /// The session key of the Last.fm user to scrobble for
+    #[structopt(long, value_name = "string")]
+    lastfm_session_key: Option<String>,
+
+    /// The user token of the ListenBrainz account to submit listens to
+    #[structopt(long, value_name = "string")]
+    listenbrainz_token: Option<String>,
+
+    /// Include the tempo, key and energy of the current track in the D-Bus and status metadata
+    #[structopt(long)]
+    #[serde(default)]
+    fetch_audio_features: bool,
+
+    /// A unix socket to listen on for commands, e.g. "queue spotify:track:…"
+    #[structopt(long, parse(from_os_str), value_name = "string")]
+    control_socket: Option<PathBuf>,
+
+    /// A Spotify URI to play at startup if no client takes over playback
+    #[structopt(long, value_name = "string")]
+    autostart_uri: Option<String>,
+
+    /// Prefix log lines with a timestamp: "rfc3339", "epoch" or a strftime format
+    #[structopt(long, value_name = "string")]
+    log_timestamp: Option<String>,
+
+    /// Use UTC instead of local time in log timestamps
+    #[structopt(long)]
+    #[serde(default)]
+    log_timestamp_utc: bool,
+
+    /// How much to log: "error", "warn", "info", "debug" or "trace". --verbose logs everything
+    #[structopt(long, possible_values = &LOG_LEVEL_VALUES, value_name = "string")]
+    log_level: Option<LogLevel>,
 }
 
-#[derive(Debug, Default, Deserialize)]
+#[derive(Debug, Default, Deserialize, Serialize)]
 pub struct FileConfig {
     global: Option<SharedConfigValues>,
     spotifyd: Option<SharedConfigValues>,
@@ -429,29 +1162,120 @@ impl fmt::Debug for SharedConfigValues {
 
         let username_cmd_value = extract_credential!(&self.username_cmd);
 
+        let credentials_passphrase_cmd_value =
+            extract_credential!(&self.credentials_passphrase_cmd);
+
+        let lastfm_api_secret_value = extract_credential!(&self.lastfm_api_secret);
+
+        let lastfm_session_key_value = extract_credential!(&self.lastfm_session_key);
+
+        let listenbrainz_token_value = extract_credential!(&self.listenbrainz_token);
+
+        let http_api_token_value = extract_credential!(&self.http_api_token);
+
         f.debug_struct("SharedConfigValues")
             .field("username", &username_value)
             .field("username_cmd", &username_cmd_value)
             .field("password", &password_value)
             .field("password_cmd", &password_cmd_value)
+            .field(
+                "credentials_passphrase_cmd",
+                &credentials_passphrase_cmd_value,
+            )
             .field("use_keyring", &self.use_keyring)
             .field("use_mpris", &self.use_mpris)
+            .field("mpris_coalesce_ms", &self.mpris_coalesce_ms)
+            .field("metadata_retries", &self.metadata_retries)
+            .field("metadata_timeout_ms", &self.metadata_timeout_ms)
             .field("on_song_change_hook", &self.on_song_change_hook)
+            .field("on_track_change", &self.on_track_change)
+            .field("on_play", &self.on_play)
+            .field("on_pause", &self.on_pause)
+            .field("on_stop", &self.on_stop)
+            .field("on_volume", &self.on_volume)
+            .field("onevent_grace_period", &self.onevent_grace_period)
+            .field("onevent_timeout", &self.onevent_timeout)
             .field("cache_path", &self.cache_path)
+            .field("tmp_dir", &self.tmp_dir)
             .field("no-audio-cache", &self.no_audio_cache)
+            .field("cache_remove_stubs", &self.cache_remove_stubs)
+            .field("cache_max_age", &self.cache_max_age)
+            .field("max_cache_size", &self.max_cache_size)
+            .field("migrate_cache", &self.migrate_cache)
+            .field("migrate_audio_cache", &self.migrate_audio_cache)
             .field("backend", &self.backend)
+            .field("downmix", &self.downmix)
+            .field("on_device_lost", &self.on_device_lost)
+            .field("device_policy", &self.device_policy)
+            .field("format_fallback", &self.format_fallback)
+            .field("check_audio_device", &self.check_audio_device)
+            .field("allow_null_fallback", &self.allow_null_fallback)
+            .field("fallback_backend", &self.fallback_backend)
+            .field("fallback_backends", &self.fallback_backends)
             .field("volume_controller", &self.volume_controller)
+            .field("volume_curve", &self.volume_curve)
             .field("device", &self.device)
+            .field("tee_device", &self.tee_device)
             .field("control", &self.control)
             .field("mixer", &self.mixer)
+            .field("mixer_watch", &self.mixer_watch)
+            .field("mixer_watch_ms", &self.mixer_watch_ms)
             .field("device_name", &self.device_name)
             .field("bitrate", &self.bitrate)
+            .field("metered_bitrate", &self.metered_bitrate)
             .field("initial_volume", &self.initial_volume)
+            .field("volume_step", &self.volume_step)
             .field("volume_normalisation", &self.volume_normalisation)
             .field("normalisation_pregain", &self.normalisation_pregain)
+            .field("device_gain", &self.device_gain)
+            .field("fade_ms", &self.fade_ms)
             .field("zeroconf_port", &self.zeroconf_port)
+            .field("on_duplicate", &self.on_duplicate)
+            .field("on_track_error", &self.on_track_error)
+            .field("track_error_retries", &self.track_error_retries)
+            .field("context_unplayable_after", &self.context_unplayable_after)
             .field("proxy", &self.proxy)
+            .field("ca_cert", &self.ca_cert)
+            .field("bind_address", &self.bind_address)
             .field("device_type", &self.device_type)
+            .field("autoplay_after", &self.autoplay_after)
+            .field("force_shuffle", &self.force_shuffle)
+            .field("force_repeat", &self.force_repeat)
+            .field("restart_on_panic", &self.restart_on_panic)
+            .field("exit_on_idle", &self.exit_on_idle)
+            .field("persist_device_name", &self.persist_device_name)
+            .field("passive_startup", &self.passive_startup)
+            .field("prefer_active", &self.prefer_active)
+            .field("nice", &self.nice)
+            .field("rt_priority", &self.rt_priority)
+            .field("resume_playback", &self.resume_playback)
+            .field("reconnect_on_resume", &self.reconnect_on_resume)
+            .field("resume_after_reconnect", &self.resume_after_reconnect)
+            .field("pause_on_lock", &self.pause_on_lock)
+            .field("resume_on_unlock", &self.resume_on_unlock)
+            .field("pause_on_jack_unplug", &self.pause_on_jack_unplug)
+            .field("resume_on_jack_replug", &self.resume_on_jack_replug)
+            .field("filter_explicit", &self.filter_explicit)
+            .field("context_volume", &self.context_volume)
+            .field("ignore_connect_volume", &self.ignore_connect_volume)
+            .field("market", &self.market)
+            .field("locale", &self.locale)
+            .field("status_addr", &self.status_addr)
+            .field("http_api_address", &self.http_api_address)
+            .field("http_api_token", &http_api_token_value)
+            .field("http_api_origins", &self.http_api_origins)
+            .field("metrics_address", &self.metrics_address)
+            .field("play_history_file", &self.play_history_file)
+            .field("lastfm_api_key", &self.lastfm_api_key)
+            .field("lastfm_api_secret", &lastfm_api_secret_value)
+            .field("lastfm_session_key", &lastfm_session_key_value)
+            .field("listenbrainz_token", &listenbrainz_token_value)
+            .field("fetch_audio_features", &self.fetch_audio_features)
+            .field("control_socket", &self.control_socket)
+            .field("autostart_uri", &self.autostart_uri)
+            .field("log_timestamp", &self.log_timestamp)
+            .field("log_timestamp_utc", &self.log_timestamp_utc)
+            .field("log_level", &self.log_level)
             .finish()
     }
 }
@@ -501,26 +1325,114 @@ impl SharedConfigValues {
             username_cmd,
             password,
             password_cmd,
+            credentials_passphrase_cmd,
             normalisation_pregain,
+            device_gain,
+            fade_ms,
             bitrate,
+            metered_bitrate,
             initial_volume,
+            volume_step,
             device_name,
             mixer,
+            mixer_watch_ms,
             control,
             device,
+            tee_device,
+            downmix,
+            on_device_lost,
+            device_policy,
+            fallback_backend,
+            fallback_backends,
             volume_controller,
+            volume_curve,
             cache_path,
+            cache_max_age,
+            max_cache_size,
+            migrate_cache,
+            tmp_dir,
             on_song_change_hook,
+            on_track_change,
+            on_play,
+            on_pause,
+            on_stop,
+            on_volume,
+            onevent_grace_period,
+            onevent_timeout,
             zeroconf_port,
+            on_duplicate,
+            nice,
+            rt_priority,
+            on_track_error,
+            track_error_retries,
+            context_unplayable_after,
+            context_volume,
             proxy,
+            ca_cert,
+            bind_address,
+            control_socket,
             device_type,
-            use_mpris
+            autoplay_after,
+            use_mpris,
+            mpris_coalesce_ms,
+            resume_after_reconnect,
+            exit_on_idle,
+            metadata_retries,
+            metadata_timeout_ms,
+            market,
+            locale,
+            autostart_uri,
+            status_addr,
+            http_api_address,
+            http_api_token,
+            http_api_origins,
+            metrics_address,
+            play_history_file,
+            lastfm_api_key,
+            lastfm_api_secret,
+            lastfm_session_key,
+            listenbrainz_token,
+            log_timestamp,
+            log_level
         );
 
         // Handles boolean merging.
         self.use_keyring |= other.use_keyring;
         self.volume_normalisation |= other.volume_normalisation;
         self.no_audio_cache |= other.no_audio_cache;
+        self.cache_remove_stubs |= other.cache_remove_stubs;
+        self.migrate_audio_cache |= other.migrate_audio_cache;
+        self.restart_on_panic |= other.restart_on_panic;
+        self.persist_device_name |= other.persist_device_name;
+        self.passive_startup |= other.passive_startup;
+        self.prefer_active |= other.prefer_active;
+        self.resume_playback |= other.resume_playback;
+        self.reconnect_on_resume |= other.reconnect_on_resume;
+        self.pause_on_lock |= other.pause_on_lock;
+        self.resume_on_unlock |= other.resume_on_unlock;
+        self.pause_on_jack_unplug |= other.pause_on_jack_unplug;
+        self.resume_on_jack_replug |= other.resume_on_jack_replug;
+        self.filter_explicit |= other.filter_explicit;
+        self.ignore_connect_volume |= other.ignore_connect_volume;
+        self.mixer_watch |= other.mixer_watch;
+        self.format_fallback |= other.format_fallback;
+        self.check_audio_device |= other.check_audio_device;
+        self.allow_null_fallback |= other.allow_null_fallback;
+        self.log_timestamp_utc |= other.log_timestamp_utc;
+        self.fetch_audio_features |= other.fetch_audio_features;
+        self.force_shuffle |= other.force_shuffle;
+        self.force_repeat |= other.force_repeat;
+    }
+
+    /// The timestamp to prefix log lines with. This is read before the rest of
+    /// the config, as the logger is already running by then.
+    pub(crate) fn log_timestamp(&self) -> Option<LogTimestamp> {
+        let format = self.log_timestamp.as_deref()?;
+        let timestamp = LogTimestamp::new(format, self.log_timestamp_utc);
+        if timestamp.is_none() {
+            warn!("Ignoring invalid log_timestamp \"{}\"", format);
+        }
+        timestamp
     }
 }
 
@@ -552,51 +1464,544 @@ pub(crate) fn get_config_file() -> Option<PathBuf> {
     }
 }
 
-fn device_id(name: &str) -> String {
+/// Where `--generate-config` writes to without a path.
+#[cfg(unix)]
+pub(crate) fn default_config_path() -> Option<PathBuf> {
+    let xdg_dirs = xdg::BaseDirectories::with_prefix("spotifyd").ok()?;
+    Some(xdg_dirs.get_config_home().join(CONFIG_FILE_NAME))
+}
+
+#[cfg(target_os = "windows")]
+pub(crate) fn default_config_path() -> Option<PathBuf> {
+    let mut path = PathBuf::from(std::env::var_os("APPDATA")?);
+    path.push(CONFIG_FILE_NAME);
+    Some(path)
+}
+
+#[cfg(unix)]
+fn default_cache_path() -> Option<PathBuf> {
+    let xdg_dirs = xdg::BaseDirectories::with_prefix("spotifyd").ok()?;
+    Some(xdg_dirs.get_cache_home())
+}
+
+#[cfg(target_os = "windows")]
+fn default_cache_path() -> Option<PathBuf> {
+    None
+}
+
+/// The options in the config written by `--generate-config`, with the comment
+/// above each and an example value. Options `template_values` sets are
+/// written with that value, the others are commented out with the example.
+const TEMPLATE_OPTIONS: &[(&str, &str, &str)] = &[
+    (
+        "username",
+        "The Spotify account to log in with. Without it, the device can still\n\
+         be used by clients on the same network.",
+        "\"username\"",
+    ),
+    (
+        "password_cmd",
+        "A command that prints the password, so that it isn't kept in this file.",
+        "\"pass spotify\"",
+    ),
+    (
+        "device_name",
+        "The name shown in Spotify clients.",
+        "\"Kitchen\"",
+    ),
+    (
+        "backend",
+        "The audio backend. Run `spotifyd --help` to see the ones available.",
+        "\"alsa\"",
+    ),
+    (
+        "device",
+        "The audio device. Run `spotifyd list-devices` to see the ones the alsa\n\
+         backend can use.",
+        "\"default\"",
+    ),
+    (
+        "volume_controller",
+        "How the volume is changed: \"softvol\" scales the audio, \"alsa\" and\n\
+         \"alsa_linear\" set the alsa mixer.",
+        "\"alsa\"",
+    ),
+    (
+        "volume_curve",
+        "How the volume maps onto loudness: \"linear\", \"log\" for steps of\n\
+         equal loudness, or an exponent like \"2\".",
+        "\"log\"",
+    ),
+    (
+        "bitrate",
+        "The bitrate of the streamed audio: 96, 160 or 320.",
+        "320",
+    ),
+    (
+        "initial_volume",
+        "The volume at startup, from 0 to 100.",
+        "\"90\"",
+    ),
+    (
+        "volume_normalisation",
+        "Plays all tracks at about the same loudness.",
+        "true",
+    ),
+    (
+        "cache_path",
+        "Where the credentials and the audio are cached. Without it, nothing is\n\
+         cached and clients have to log in again after each start.",
+        "\"/var/cache/spotifyd\"",
+    ),
+    (
+        "no_audio_cache",
+        "Only caches the credentials, not the audio.",
+        "true",
+    ),
+    (
+        "max_cache_size",
+        "The most space the cached audio may take. The files played the longest\n\
+         time ago are removed to stay below it.",
+        "\"2G\"",
+    ),
+    (
+        "device_type",
+        "The icon shown in Spotify clients.",
+        "\"speaker\"",
+    ),
+    (
+        "on_song_change_hook",
+        "A command run on each playback event, with the event in $PLAYER_EVENT.",
+        "\"notify-send \\\"$PLAYER_EVENT\\\"\"",
+    ),
+    (
+        "zeroconf_port",
+        "The port clients on the same network connect to. Without it, a\n\
+         random one is used.",
+        "1234",
+    ),
+];
+
+/// The values written to the config by `--generate-config`, which are safe to
+/// start with.
+fn template_values() -> SharedConfigValues {
+    SharedConfigValues {
+        device_name: Some("Spotifyd".to_string()),
+        backend: BACKEND_VALUES.first().and_then(|name| name.parse().ok()),
+        volume_controller: Some(VolumeController::SoftVolume),
+        bitrate: Some(Bitrate::Bitrate160),
+        cache_path: default_cache_path(),
+        device_type: Some(DeviceType::Speaker),
+        ..Default::default()
+    }
+}
+
+/// Returns the commented config written by `--generate-config`.
+pub(crate) fn config_template() -> String {
+    let table = |values: &SharedConfigValues| match toml::Value::try_from(values) {
+        Ok(toml::Value::Table(table)) => table,
+        _ => toml::value::Table::new(),
+    };
+    let values = table(&template_values());
+    let defaults = table(&SharedConfigValues::default());
+
+    let mut template = String::from(
+        "# A config for spotifyd, written by `spotifyd --generate-config`.\n\
+         # Remove the # in front of an option to set it. Run `spotifyd --help`\n\
+         # to see all options.\n\n[global]\n",
+    );
+    for (name, comment, example) in TEMPLATE_OPTIONS {
+        template.push('\n');
+        for line in comment.lines() {
+            template.push_str(&format!("# {}\n", line));
+        }
+        match values
+            .get(*name)
+            .filter(|value| defaults.get(*name) != Some(value))
+        {
+            Some(value) => template.push_str(&format!("{} = {}\n", name, value)),
+            None => template.push_str(&format!("# {} = {}\n", name, example)),
+        }
+    }
+    template
+}
+
+/// Writes the config template to `path`, unless something is there already
+/// and `force` isn't set.
+pub(crate) fn write_config_template(path: &Path, force: bool) -> std::io::Result<()> {
+    use std::io::Write;
+
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    fs::OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .create_new(!force)
+        .open(path)?
+        .write_all(config_template().as_bytes())
+}
+
+/// Returns whether `name` can be used as the name shown in Spotify clients.
+pub(crate) fn is_valid_device_name(name: &str) -> bool {
+    !name.trim().is_empty() && !name.chars().any(char::is_whitespace)
+}
+
+/// Checks for an ISO 639 language code, optionally followed by an ISO 3166-1
+/// country code, like "ja" or "pt_BR".
+fn is_valid_locale(locale: &str) -> bool {
+    let mut parts = locale.splitn(2, &['_', '-'][..]);
+    let language = parts.next().unwrap_or_default();
+    (2..=3).contains(&language.len())
+        && language.chars().all(|c| c.is_ascii_lowercase())
+        && parts
+            .all(|country| country.len() == 2 && country.chars().all(|c| c.is_ascii_uppercase()))
+}
+
+/// Checks for a URI like "spotify:playlist:37i9dQZF1DXcBWIGoYBM5M".
+pub(crate) fn is_valid_spotify_uri(uri: &str) -> bool {
+    let mut parts = uri.split(':');
+    parts.next() == Some("spotify") && parts.clone().count() >= 2 && parts.all(|p| !p.is_empty())
+}
+
+/// Parses a size like "800K", "500M" or "2G" into bytes, counting in thousands
+/// like the sizes that are logged. A number by itself is in bytes.
+fn parse_size(size: &str) -> Option<u64> {
+    let (count, unit) = match size.chars().last()? {
+        'K' | 'k' => (&size[..size.len() - 1], 1_000),
+        'M' | 'm' => (&size[..size.len() - 1], 1_000_000),
+        'G' | 'g' => (&size[..size.len() - 1], 1_000_000_000),
+        _ => (size, 1),
+    };
+    count.parse::<u64>().ok()?.checked_mul(unit)
+}
+
+/// Parses an age like "12h", "30d" or "2w".
+fn parse_age(age: &str) -> Option<Duration> {
+    let unit = match age.chars().last()? {
+        'h' => 60 * 60,
+        'd' => 24 * 60 * 60,
+        'w' => 7 * 24 * 60 * 60,
+        _ => return None,
+    };
+    let count = age[..age.len() - 1].parse::<u64>().ok()?;
+    Some(Duration::from_secs(count.checked_mul(unit)?))
+}
+
+pub(crate) fn device_id(name: &str) -> String {
     hex::encode(&Sha1::digest(name.as_bytes()))
 }
 
+/// Returns a random device id, for when the device should be seen as a new one.
+pub(crate) fn new_device_id() -> String {
+    let mut id = [0u8; 20];
+    rand::thread_rng().fill_bytes(&mut id);
+    hex::encode(&id)
+}
+
+fn is_valid_device_id(id: &str) -> bool {
+    id.len() == 40 && id.chars().all(|c| c.is_ascii_hexdigit())
+}
+
+/// Returns the device id saved in the state file at `path`. On the first run,
+/// the id derived from `name` is saved.
+fn persisted_device_id(path: &Path, name: &str) -> String {
+    let mut state = State::load(path);
+    if let Some(id) = state.device_id.take().filter(|id| is_valid_device_id(id)) {
+        return id;
+    }
+    let id = device_id(name);
+    state.device_id = Some(id.clone());
+    if let Err(e) = state.save(path) {
+        warn!("Could not save the device id to {}: {}", path.display(), e);
+    }
+    id
+}
+
+/// Where played tracks are scrobbled to.
+#[derive(Clone)]
+#[cfg_attr(not(feature = "scrobble"), allow(unused))]
+pub(crate) enum ScrobbleService {
+    LastFm {
+        api_key: String,
+        api_secret: String,
+        session_key: String,
+    },
+    ListenBrainz {
+        token: String,
+    },
+}
+
+impl fmt::Display for ScrobbleService {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            ScrobbleService::LastFm { .. } => "Last.fm",
+            ScrobbleService::ListenBrainz { .. } => "ListenBrainz",
+        })
+    }
+}
+
+#[derive(Clone)]
 pub(crate) struct SpotifydConfig {
+    /// What the config was made from, to tell what changed on a reload.
+    pub(crate) values: SharedConfigValues,
     pub(crate) username: Option<String>,
     pub(crate) password: Option<String>,
+    pub(crate) credentials_passphrase_cmd: Option<String>,
     #[allow(unused)]
     pub(crate) use_keyring: bool,
     pub(crate) use_mpris: bool,
+    pub(crate) mpris_coalesce_window: Duration,
+    pub(crate) metadata_fetch: MetadataFetch,
     pub(crate) cache: Option<Cache>,
+    pub(crate) cache_path: Option<PathBuf>,
+    pub(crate) tmp_dir: Option<PathBuf>,
+    pub(crate) cache_remove_stubs: bool,
+    pub(crate) cache_max_age: Option<Duration>,
+    /// In bytes.
+    pub(crate) max_cache_size: Option<u64>,
     pub(crate) backend: Option<String>,
+    pub(crate) downmix: Downmix,
+    pub(crate) device_gain: Option<f32>,
+    pub(crate) fade: Option<Duration>,
+    pub(crate) on_device_lost: DeviceLostAction,
+    pub(crate) device_policy: DevicePolicy,
+    pub(crate) format_fallback: bool,
+    pub(crate) check_audio_device: bool,
+    pub(crate) allow_null_fallback: bool,
+    /// The backends of `fallback_backends`, with the device each one uses.
+    pub(crate) fallback_backends: Vec<(String, Option<String>)>,
     pub(crate) audio_device: Option<String>,
+    pub(crate) tee_device: Option<String>,
     #[allow(unused)]
     pub(crate) control_device: Option<String>,
     #[allow(unused)]
     pub(crate) mixer: Option<String>,
     #[allow(unused)]
+    pub(crate) mixer_watch: Option<Duration>,
+    #[allow(unused)]
     pub(crate) volume_controller: VolumeController,
+    pub(crate) volume_curve: Option<VolumeCurve>,
     pub(crate) initial_volume: Option<u16>,
+    /// The step of the volume up and volume down commands, between 0 and 65535.
+    pub(crate) volume_step: u16,
     pub(crate) device_name: String,
     pub(crate) player_config: PlayerConfig,
+    pub(crate) metered_bitrate: Option<LSBitrate>,
     pub(crate) session_config: SessionConfig,
-    pub(crate) onevent: Option<String>,
+    pub(crate) onevent: EventPrograms,
+    pub(crate) onevent_grace_period: Duration,
+    pub(crate) onevent_timeout: Option<Duration>,
     pub(crate) pid: Option<String>,
     pub(crate) shell: String,
     pub(crate) zeroconf_port: Option<u16>,
+    pub(crate) on_duplicate: DuplicateAction,
+    pub(crate) nice: Option<i32>,
+    pub(crate) rt_priority: Option<i32>,
+    pub(crate) on_track_error: TrackErrorAction,
+    pub(crate) track_error_retries: u8,
+    pub(crate) context_unplayable_after: Option<u32>,
     pub(crate) device_type: String,
     pub(crate) autoplay: bool,
+    pub(crate) autoplay_after: Option<u32>,
+    pub(crate) force_shuffle: bool,
+    pub(crate) force_repeat: bool,
+    pub(crate) restart_on_panic: bool,
+    pub(crate) log_level: LevelFilter,
+    pub(crate) exit_on_idle: Option<Duration>,
+    pub(crate) persist_device_name: bool,
+    pub(crate) state_path: Option<PathBuf>,
+    pub(crate) market: Option<Country>,
+    pub(crate) locale: Option<String>,
+    pub(crate) autostart_uri: Option<String>,
+    pub(crate) status_addr: Option<String>,
+    pub(crate) http_api_address: Option<String>,
+    pub(crate) http_api_token: Option<String>,
+    pub(crate) http_api_origins: Vec<String>,
+    pub(crate) metrics_address: Option<String>,
+    pub(crate) play_history_file: Option<PathBuf>,
+    pub(crate) scrobble_services: Vec<ScrobbleService>,
+    pub(crate) fetch_audio_features: bool,
+    pub(crate) passive_startup: bool,
+    pub(crate) prefer_active: bool,
+    pub(crate) resume_playback: bool,
+    pub(crate) reconnect_on_resume: bool,
+    pub(crate) resume_after_reconnect: bool,
+    pub(crate) pause_on_lock: bool,
+    pub(crate) resume_on_unlock: bool,
+    pub(crate) pause_on_jack_unplug: bool,
+    pub(crate) resume_on_jack_replug: bool,
+    pub(crate) filter_explicit: bool,
+    pub(crate) context_volume: Option<ContextVolume>,
+    pub(crate) ignore_connect_volume: bool,
+    pub(crate) ca_cert: Option<PathBuf>,
+    pub(crate) bind_address: Option<IpAddr>,
+    pub(crate) control_socket: Option<PathBuf>,
+}
+
+/// Reads the config file again, along with the same command line, for
+/// `SIGHUP` and the `reload` command.
+///
+/// This doesn't change anything outside of spotifyd: the state file is only
+/// read, and neither the cache migration nor the commands that look up the
+/// credentials are run, as the running session keeps the ones it logged in
+/// with.
+pub(crate) fn reload() -> Result<SpotifydConfig, Report> {
+    let mut cli_config = CliConfig::from_args();
+    cli_config.load_config_file_values()?;
+    let values = cli_config.shared_config.clone();
+    let shared_config = &mut cli_config.shared_config;
+    shared_config.migrate_cache = None;
+    let mut config = read_internal_config(cli_config, true);
+    config.values = values;
+    Ok(config)
+}
+
+/// Returns the names of the options that differ between `old` and `new`, as
+/// they are written in the config file.
+pub(crate) fn changed_options(old: &SharedConfigValues, new: &SharedConfigValues) -> Vec<String> {
+    let table = |values: &SharedConfigValues| match toml::Value::try_from(values) {
+        Ok(toml::Value::Table(table)) => table,
+        _ => toml::value::Table::new(),
+    };
+    let (old, new) = (table(old), table(new));
+    let mut changed: Vec<String> = old
+        .keys()
+        .chain(new.keys())
+        .filter(|option| old.get(*option) != new.get(*option))
+        .cloned()
+        .collect();
+    changed.sort();
+    changed.dedup();
+    changed
+}
+
+/// Turns the merged config values into a config file with the same effect,
+/// with the defaults filled in and the values that are ignored left out.
+/// Credentials are taken out unless `show_secrets` is set.
+pub(crate) fn effective_config(
+    mut values: SharedConfigValues,
+    config: &SpotifydConfig,
+    show_secrets: bool,
+) -> Result<String, toml::ser::Error> {
+    if !show_secrets {
+        for credential in &mut [
+            &mut values.username,
+            &mut values.username_cmd,
+            &mut values.password,
+            &mut values.password_cmd,
+            &mut values.credentials_passphrase_cmd,
+            &mut values.lastfm_api_secret,
+            &mut values.lastfm_session_key,
+            &mut values.listenbrainz_token,
+            &mut values.http_api_token,
+        ] {
+            if credential.is_some() {
+                **credential = Some("taken out for privacy".to_string());
+            }
+        }
+    }
+    values.device_name = Some(config.device_name.clone());
+    values.backend = values.backend.or(Some(Backend::Alsa));
+    values.bitrate = values.bitrate.or(Some(Bitrate::Bitrate160));
+    values.volume_controller = values
+        .volume_controller
+        .or(Some(VolumeController::SoftVolume));
+    values.device_type = values.device_type.or(Some(DeviceType::Speaker));
+    values.nice = config.nice;
+    values.rt_priority = config.rt_priority;
+    values.market = values.market.filter(|_| config.market.is_some());
+    values.locale = config.locale.clone();
+    values.autostart_uri = config.autostart_uri.clone();
+    values.autoplay_after = config.autoplay_after;
+    values.device_gain = config.device_gain;
+    values.cache_max_age = values
+        .cache_max_age
+        .filter(|_| config.cache_max_age.is_some());
+    values.max_cache_size = values
+        .max_cache_size
+        .filter(|_| config.max_cache_size.is_some());
+
+    toml::to_string(&FileConfig {
+        global: Some(values),
+        spotifyd: None,
+    })
 }
 
 pub(crate) fn get_internal_config(config: CliConfig) -> SpotifydConfig {
+    read_internal_config(config, false)
+}
+
+fn read_internal_config(config: CliConfig, reloading: bool) -> SpotifydConfig {
+    let values = config.shared_config.clone();
     let audio_cache = !config.shared_config.no_audio_cache;
 
-    let cache = config
+    let cache_path = config.shared_config.cache_path;
+    // This has to happen before the cache and the state are read below.
+    if let Some(ref from) = config.shared_config.migrate_cache {
+        match cache_path {
+            Some(ref path) => cache::migrate(from, path, config.shared_config.migrate_audio_cache),
+            None => warn!("migrate_cache is set, but no cache_path is configured"),
+        }
+    }
+    let state_path = cache_path.as_deref().map(State::path);
+    // `{uuid}` is made up at the start, whether it is used or not, so that it
+    // is already there when an option that uses it is reloaded.
+    if let (Some(ref path), false) = (&state_path, reloading) {
+        placeholders::install_id(Some(path));
+    }
+
+    let cache = cache_path.clone().map(|path| Cache::new(path, audio_cache));
+
+    let cache_max_age = config
         .shared_config
-        .cache_path
-        .map(PathBuf::from)
-        .map(|path| Cache::new(path, audio_cache));
+        .cache_max_age
+        .as_deref()
+        .and_then(|age| {
+            let parsed = parse_age(age);
+            if parsed.is_none() {
+                warn!(
+                    "Ignoring cache_max_age \"{}\", it must be a number followed by h, d or w",
+                    age
+                );
+            }
+            parsed
+        })
+        .filter(|_| {
+            if !audio_cache {
+                warn!("cache_max_age is set, but the audio cache is disabled");
+            }
+            audio_cache
+        });
+
+    let max_cache_size = config
+        .shared_config
+        .max_cache_size
+        .as_deref()
+        .and_then(|size| {
+            let parsed = parse_size(size);
+            if parsed.is_none() {
+                warn!(
+                    "Ignoring max_cache_size \"{}\", it must be a number, optionally followed by K, M or G",
+                    size
+                );
+            }
+            parsed
+        })
+        .filter(|_| {
+            if !audio_cache {
+                warn!("max_cache_size is set, but the audio cache is disabled");
+            }
+            audio_cache
+        });
 
     let bitrate: LSBitrate = config
         .shared_config
         .bitrate
         .unwrap_or(Bitrate::Bitrate160)
         .into();
+    let metered_bitrate = config.shared_config.metered_bitrate.map(Into::into);
 
     let backend = config
         .shared_config
@@ -622,17 +2027,130 @@ pub(crate) fn get_internal_config(config: CliConfig) -> SpotifydConfig {
         .flatten()
         .map(|volume| (volume as i32 * 0xFFFF / 100) as u16);
 
+    let fallback_backends = match (
+        config.shared_config.fallback_backends,
+        config.shared_config.fallback_backend,
+    ) {
+        (Some(backends), fallback_backend) => {
+            if fallback_backend.is_some() {
+                warn!("Ignoring fallback_backend, as fallback_backends is set");
+            }
+            backends
+                .iter()
+                .map(|entry| parse_backend_entry(entry))
+                .collect()
+        }
+        (None, Some(backend)) => vec![(backend.to_string(), None)],
+        (None, None) => Vec::new(),
+    };
+
+    let volume_curve = config
+        .shared_config
+        .volume_curve
+        .as_deref()
+        .and_then(|curve| {
+            let parsed = parse_volume_curve(curve);
+            if parsed.is_none() {
+                warn!(
+                    "Ignoring volume_curve \"{}\", it must be \"linear\", \"log\" or a positive number",
+                    curve
+                );
+            }
+            parsed
+        });
+
+    let volume_step = match config.shared_config.volume_step {
+        Some(step) if (1..=100).contains(&step) => step,
+        Some(_) => {
+            warn!("Ignoring volume_step, it must be in the range 1-100");
+            DEFAULT_VOLUME_STEP
+        }
+        None => DEFAULT_VOLUME_STEP,
+    };
+    let volume_step = (u32::from(volume_step) * 0xFFFF / 100) as u16;
+
     let device_name = config
         .shared_config
         .device_name
-        .filter(|s| !s.trim().is_empty())
-        .filter(|s| !s.chars().any(char::is_whitespace))
+        .map(|name| placeholders::expand("device_name", &name, state_path.as_deref()))
+        .filter(|s| is_valid_device_name(s))
         .unwrap_or_else(|| format!("{}@{}", "Spotifyd", gethostname().to_string_lossy()));
 
-    let device_id = device_id(&device_name);
+    // The id is derived from the configured name on the first run and kept in
+    // the state file from then on, so that neither renaming the device nor
+    // changing the configured name makes it show up as a new one.
+    let device_id = match state_path {
+        Some(ref path) if reloading => State::load(path)
+            .device_id
+            .filter(|id| is_valid_device_id(id))
+            .unwrap_or_else(|| device_id(&device_name)),
+        Some(ref path) => persisted_device_id(path, &device_name),
+        None => device_id(&device_name),
+    };
+
+    let persist_device_name = config.shared_config.persist_device_name;
+    let device_name = match state_path {
+        Some(ref path) if persist_device_name => State::load(path)
+            .device_name
+            .filter(|s| is_valid_device_name(s))
+            .unwrap_or(device_name),
+        _ => device_name,
+    };
+
+    let tee_device = config
+        .shared_config
+        .tee_device
+        .map(|device| placeholders::expand("tee_device", &device, state_path.as_deref()));
+    let status_addr = config
+        .shared_config
+        .status_addr
+        .map(|addr| placeholders::expand("status_addr", &addr, state_path.as_deref()));
+    let http_api_address = config
+        .shared_config
+        .http_api_address
+        .map(|addr| placeholders::expand("http_api_address", &addr, state_path.as_deref()));
+    let metrics_address = config
+        .shared_config
+        .metrics_address
+        .map(|addr| placeholders::expand("metrics_address", &addr, state_path.as_deref()));
+
+    let mut scrobble_services = Vec::new();
+    match (
+        config.shared_config.lastfm_api_key,
+        config.shared_config.lastfm_api_secret,
+        config.shared_config.lastfm_session_key,
+    ) {
+        (Some(api_key), Some(api_secret), Some(session_key)) => {
+            scrobble_services.push(ScrobbleService::LastFm {
+                api_key,
+                api_secret,
+                session_key,
+            })
+        }
+        (None, None, None) => (),
+        _ => warn!(
+            "Scrobbling to Last.fm needs lastfm_api_key, lastfm_api_secret and \
+             lastfm_session_key, ignoring them"
+        ),
+    }
+    if let Some(token) = config.shared_config.listenbrainz_token {
+        scrobble_services.push(ScrobbleService::ListenBrainz { token });
+    }
 
     let normalisation_pregain = config.shared_config.normalisation_pregain.unwrap_or(0.0f32);
 
+    // Beyond these, a typo is more likely than a deliberate choice.
+    let device_gain = config.shared_config.device_gain.filter(|gain| {
+        let valid = (-30.0..=12.0).contains(gain);
+        if !valid {
+            warn!(
+                "Ignoring device_gain {}, it has to be between -30 and 12 dB",
+                gain
+            );
+        }
+        valid && *gain != 0.0
+    });
+
     let autoplay = config.shared_config.autoplay;
 
     let device_type = config
@@ -649,15 +2167,70 @@ pub(crate) fn get_internal_config(config: CliConfig) -> SpotifydConfig {
 
     #[cfg(unix)]
     let shell = utils::get_shell().unwrap_or_else(|| {
-        info!("Unable to identify shell. Defaulting to \"sh\".");
+        if !reloading {
+            info!("Unable to identify shell. Defaulting to \"sh\".");
+        }
         "sh".to_string()
     });
 
     #[cfg(windows)]
     let shell = utils::get_shell().unwrap();
 
+    // An unknown market or locale is left to Spotify, which picks them based on the
+    // account and the IP address.
+    let market = config.shared_config.market.and_then(|market| {
+        Country::from_str(&market.to_uppercase())
+            .map_err(|_| warn!("Ignoring unknown market \"{}\"", market))
+            .ok()
+    });
+
+    let locale = config.shared_config.locale.filter(|locale| {
+        let valid = is_valid_locale(locale);
+        if !valid {
+            warn!("Ignoring invalid locale \"{}\"", locale);
+        }
+        valid
+    });
+
+    let nice = config.shared_config.nice.filter(|nice| {
+        let valid = (-20..=19).contains(nice);
+        if !valid {
+            warn!("Ignoring nice {}, it has to be between -20 and 19", nice);
+        }
+        valid
+    });
+
+    let rt_priority = config.shared_config.rt_priority.filter(|priority| {
+        let valid = (1..=99).contains(priority);
+        if !valid {
+            warn!(
+                "Ignoring rt_priority {}, it has to be between 1 and 99",
+                priority
+            );
+        }
+        valid
+    });
+
+    let context_unplayable_after = config
+        .shared_config
+        .context_unplayable_after
+        .filter(|&after| {
+            if after == 0 {
+                warn!("Ignoring context_unplayable_after 0, it has to be at least 1");
+            }
+            after > 0
+        });
+
+    let autostart_uri = config.shared_config.autostart_uri.filter(|uri| {
+        let valid = is_valid_spotify_uri(uri);
+        if !valid {
+            warn!("Ignoring invalid autostart_uri \"{}\"", uri);
+        }
+        valid
+    });
+
     let mut username = config.shared_config.username;
-    if username.is_none() {
+    if username.is_none() && !reloading {
         info!("No username specified. Checking username_cmd");
         match config.shared_config.username_cmd {
             Some(ref cmd) => match run_program(&shell, cmd) {
@@ -669,7 +2242,7 @@ pub(crate) fn get_internal_config(config: CliConfig) -> SpotifydConfig {
     }
 
     let mut password = config.shared_config.password;
-    if password.is_none() {
+    if password.is_none() && !reloading {
         info!("No password specified. Checking password_cmd");
 
         match config.shared_config.password_cmd {
@@ -692,21 +2265,72 @@ pub(crate) fn get_internal_config(config: CliConfig) -> SpotifydConfig {
             }
             Err(err) => error!("Invalid proxy URL: {}", err),
         },
-        None => info!("No proxy specified"),
+        None if !reloading => info!("No proxy specified"),
+        None => (),
     }
     SpotifydConfig {
+        values,
         username,
         password,
+        credentials_passphrase_cmd: config.shared_config.credentials_passphrase_cmd,
         use_keyring: config.shared_config.use_keyring,
         use_mpris: config.shared_config.use_mpris.unwrap_or(true),
+        mpris_coalesce_window: Duration::from_millis(
+            config.shared_config.mpris_coalesce_ms.unwrap_or(100),
+        ),
+        metadata_fetch: MetadataFetch {
+            retries: config.shared_config.metadata_retries.unwrap_or(2),
+            timeout: Duration::from_millis(
+                config
+                    .shared_config
+                    .metadata_timeout_ms
+                    .unwrap_or(5000)
+                    .max(1),
+            ),
+        },
         cache,
+        cache_path,
+        tmp_dir: config.shared_config.tmp_dir,
+        cache_remove_stubs: config.shared_config.cache_remove_stubs,
+        cache_max_age,
+        max_cache_size,
         backend: Some(backend),
+        downmix: config.shared_config.downmix.unwrap_or(Downmix::Stereo),
+        device_gain,
+        fade: config
+            .shared_config
+            .fade_ms
+            .filter(|&ms| ms > 0)
+            .map(Duration::from_millis),
+        on_device_lost: config
+            .shared_config
+            .on_device_lost
+            .unwrap_or(DeviceLostAction::Stop),
+        format_fallback: config.shared_config.format_fallback,
+        check_audio_device: config.shared_config.check_audio_device,
+        allow_null_fallback: config.shared_config.allow_null_fallback,
+        device_policy: config
+            .shared_config
+            .device_policy
+            .unwrap_or(DevicePolicy::Release),
+        fallback_backends,
         audio_device: config.shared_config.device,
+        tee_device,
         control_device: config.shared_config.control,
         mixer: config.shared_config.mixer,
+        mixer_watch: if config.shared_config.mixer_watch {
+            Some(Duration::from_millis(
+                config.shared_config.mixer_watch_ms.unwrap_or(500).max(1),
+            ))
+        } else {
+            None
+        },
         volume_controller,
+        volume_curve,
         initial_volume,
+        volume_step,
         device_name,
+        metered_bitrate,
         player_config: PlayerConfig {
             bitrate,
             normalisation: config.shared_config.volume_normalisation,
@@ -719,12 +2343,83 @@ pub(crate) fn get_internal_config(config: CliConfig) -> SpotifydConfig {
             proxy: proxy_url,
             ap_port: Some(443),
         },
-        onevent: config.shared_config.on_song_change_hook,
+        onevent: EventPrograms {
+            onevent: config.shared_config.on_song_change_hook,
+            on_track_change: config.shared_config.on_track_change,
+            on_play: config.shared_config.on_play,
+            on_pause: config.shared_config.on_pause,
+            on_stop: config.shared_config.on_stop,
+            on_volume: config.shared_config.on_volume,
+        },
+        onevent_grace_period: Duration::from_secs(
+            config.shared_config.onevent_grace_period.unwrap_or(2),
+        ),
+        onevent_timeout: match config.shared_config.onevent_timeout.unwrap_or(30) {
+            0 => None,
+            seconds => Some(Duration::from_secs(seconds)),
+        },
         pid,
         shell,
         zeroconf_port: config.shared_config.zeroconf_port,
+        on_duplicate: config
+            .shared_config
+            .on_duplicate
+            .unwrap_or(DuplicateAction::Allow),
+        nice,
+        rt_priority,
+        on_track_error: config
+            .shared_config
+            .on_track_error
+            .unwrap_or(TrackErrorAction::Skip),
+        track_error_retries: config.shared_config.track_error_retries.unwrap_or(2),
+        context_unplayable_after,
         device_type,
         autoplay,
+        autoplay_after: config.shared_config.autoplay_after.filter(|_| autoplay),
+        force_shuffle: config.shared_config.force_shuffle,
+        force_repeat: config.shared_config.force_repeat,
+        restart_on_panic: config.shared_config.restart_on_panic,
+        log_level: if config.verbose {
+            LevelFilter::Trace
+        } else {
+            config
+                .shared_config
+                .log_level
+                .map_or(LevelFilter::Info, LevelFilter::from)
+        },
+        exit_on_idle: config
+            .shared_config
+            .exit_on_idle
+            .filter(|&minutes| minutes > 0)
+            .map(|minutes| Duration::from_secs(minutes * 60)),
+        persist_device_name,
+        state_path,
+        market,
+        locale,
+        autostart_uri,
+        status_addr,
+        http_api_address,
+        http_api_token: config.shared_config.http_api_token,
+        http_api_origins: config.shared_config.http_api_origins.unwrap_or_default(),
+        metrics_address,
+        play_history_file: config.shared_config.play_history_file,
+        scrobble_services,
+        fetch_audio_features: config.shared_config.fetch_audio_features,
+        passive_startup: config.shared_config.passive_startup,
+        prefer_active: config.shared_config.prefer_active,
+        resume_playback: config.shared_config.resume_playback,
+        reconnect_on_resume: config.shared_config.reconnect_on_resume,
+        resume_after_reconnect: config.shared_config.resume_after_reconnect.unwrap_or(true),
+        pause_on_lock: config.shared_config.pause_on_lock,
+        resume_on_unlock: config.shared_config.resume_on_unlock,
+        pause_on_jack_unplug: config.shared_config.pause_on_jack_unplug,
+        resume_on_jack_replug: config.shared_config.resume_on_jack_replug,
+        filter_explicit: config.shared_config.filter_explicit,
+        context_volume: config.shared_config.context_volume,
+        ignore_connect_volume: config.shared_config.ignore_connect_volume,
+        ca_cert: config.shared_config.ca_cert,
+        bind_address: config.shared_config.bind_address,
+        control_socket: config.shared_config.control_socket,
     }
 }
 
@@ -757,4 +2452,164 @@ mod tests {
         spotifyd_section.username = Some("testUserName".to_string());
         assert_eq!(merged_config, spotifyd_section);
     }
+
+    #[test]
+    fn test_config_template() {
+        let parse = |template: &str| -> toml::value::Table {
+            let config: FileConfig = toml::from_str(template).unwrap();
+            match toml::Value::try_from(config.global.unwrap()).unwrap() {
+                toml::Value::Table(table) => table,
+                _ => unreachable!(),
+            }
+        };
+        let template = config_template();
+        assert_eq!(parse(&template)["device_name"].as_str(), Some("Spotifyd"));
+
+        // Every option has to be one spotifyd knows, with a valid example.
+        let examples: String = TEMPLATE_OPTIONS
+            .iter()
+            .map(|(name, _, example)| format!("{} = {}\n", name, example))
+            .collect();
+        let table = parse(&format!("[global]\n{}", examples));
+        for (name, _, _) in TEMPLATE_OPTIONS {
+            assert!(table.contains_key(*name), "{} isn't an option", name);
+        }
+    }
+
+    #[test]
+    fn test_locale_validation() {
+        assert!(is_valid_locale("ja"));
+        assert!(is_valid_locale("pt_BR"));
+        assert!(is_valid_locale("en-GB"));
+        assert!(!is_valid_locale(""));
+        assert!(!is_valid_locale("JA"));
+        assert!(!is_valid_locale("english"));
+        assert!(!is_valid_locale("pt_bra"));
+    }
+
+    #[test]
+    fn test_spotify_uri_validation() {
+        assert!(is_valid_spotify_uri(
+            "spotify:playlist:37i9dQZF1DXcBWIGoYBM5M"
+        ));
+        assert!(is_valid_spotify_uri(
+            "spotify:user:name:playlist:37i9dQZF1DXcBWIGoYBM5M"
+        ));
+        assert!(!is_valid_spotify_uri("spotify:playlist"));
+        assert!(!is_valid_spotify_uri("spotify:playlist:"));
+        assert!(!is_valid_spotify_uri(
+            "https://open.spotify.com/playlist/37i9dQZF1DXcBWIGoYBM5M"
+        ));
+    }
+
+    #[test]
+    fn test_age_parsing() {
+        assert_eq!(parse_age("12h"), Some(Duration::from_secs(12 * 60 * 60)));
+        assert_eq!(
+            parse_age("30d"),
+            Some(Duration::from_secs(30 * 24 * 60 * 60))
+        );
+        assert_eq!(
+            parse_age("2w"),
+            Some(Duration::from_secs(14 * 24 * 60 * 60))
+        );
+        assert_eq!(parse_age("30"), None);
+        assert_eq!(parse_age("d"), None);
+        assert_eq!(parse_age("-1d"), None);
+        assert_eq!(parse_age("1.5d"), None);
+    }
+
+    #[test]
+    fn test_backend_entry_parsing() {
+        assert_eq!(
+            parse_backend_entry("alsa:hw:CARD=DAC,DEV=0"),
+            ("alsa".to_string(), Some("hw:CARD=DAC,DEV=0".to_string()))
+        );
+        assert_eq!(
+            parse_backend_entry("pulseaudio"),
+            ("pulseaudio".to_string(), None)
+        );
+        assert_eq!(parse_backend_entry("rodio:"), ("rodio".to_string(), None));
+    }
+
+    #[test]
+    fn test_volume_curve_parsing() {
+        assert_eq!(parse_volume_curve("linear"), Some(VolumeCurve::Linear));
+        assert_eq!(parse_volume_curve("db"), Some(VolumeCurve::Log));
+        assert_eq!(parse_volume_curve("2.5"), Some(VolumeCurve::Exponent(2.5)));
+        assert_eq!(parse_volume_curve("0"), None);
+        assert_eq!(parse_volume_curve("-1"), None);
+        assert_eq!(parse_volume_curve("inf"), None);
+        assert_eq!(parse_volume_curve("loud"), None);
+    }
+
+    #[test]
+    fn test_size_parsing() {
+        assert_eq!(parse_size("800K"), Some(800_000));
+        assert_eq!(parse_size("500m"), Some(500_000_000));
+        assert_eq!(parse_size("2G"), Some(2_000_000_000));
+        assert_eq!(parse_size("4096"), Some(4096));
+        assert_eq!(parse_size("G"), None);
+        assert_eq!(parse_size("1.5G"), None);
+        assert_eq!(parse_size("2T"), None);
+    }
+
+    #[test]
+    fn test_log_timestamp_formats() {
+        let epoch = LogTimestamp::new("epoch", false).unwrap();
+        assert!(epoch.now().parse::<f64>().is_ok());
+
+        let rfc3339 = LogTimestamp::new("rfc3339", true).unwrap();
+        assert!(rfc3339.now().ends_with('Z'));
+
+        let custom = LogTimestamp::new("%Y-%m-%d", true).unwrap();
+        assert_eq!(custom.now().len(), 10);
+
+        assert!(LogTimestamp::new("%Y-%m-%Q", false).is_none());
+    }
+
+    #[test]
+    fn test_placeholders() {
+        let hostname = gethostname().to_string_lossy().into_owned();
+        assert_eq!(
+            placeholders::expand("device_name", "Kiosk {hostname}", None),
+            format!("Kiosk {}", hostname)
+        );
+        assert_eq!(
+            placeholders::expand("device_name", "{nope} {hostname", None),
+            "{nope} {hostname"
+        );
+
+        let dir = std::env::temp_dir().join(format!("spotifyd-test-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let state_path = State::path(&dir);
+        let uuid = placeholders::expand("device_name", "{uuid}", Some(&state_path));
+        assert_eq!(uuid.len(), 36);
+        assert_eq!(
+            placeholders::expand("device_name", "{uuid}", Some(&state_path)),
+            uuid
+        );
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_changed_options() {
+        let old = SharedConfigValues {
+            device_name: Some("Kitchen".to_string()),
+            bitrate: Some(Bitrate::Bitrate160),
+            ..Default::default()
+        };
+        assert!(changed_options(&old, &old.clone()).is_empty());
+
+        let new = SharedConfigValues {
+            device_name: Some("Living room".to_string()),
+            volume_normalisation: true,
+            on_song_change_hook: Some("notify".to_string()),
+            ..old.clone()
+        };
+        assert_eq!(
+            changed_options(&old, &new),
+            vec!["device_name", "on_song_change_hook", "volume_normalisation"]
+        );
+    }
 }
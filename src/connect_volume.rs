@@ -0,0 +1,71 @@
+use crate::main_loop;
+use librespot::playback::player::PlayerEvent;
+use log::info;
+use std::time::{Duration, Instant};
+
+/// Volume changes this soon after playback was transferred here come with the
+/// transfer rather than from someone turning the volume.
+const TRANSFER_WINDOW: Duration = Duration::from_secs(3);
+
+/// Keeps the local volume when a client takes over playback, for
+/// `ignore_connect_volume`.
+///
+/// Clients send the volume they last played at along with a transfer. Spirc
+/// can't be told to ignore it, so the volume is set back once it was changed.
+pub(crate) struct ConnectVolume {
+    active: bool,
+    /// The volume to keep, between 0 and 65535.
+    local: Option<u16>,
+    /// The volume the mixer was last set to.
+    current: Option<u16>,
+    transferred_at: Option<Instant>,
+}
+
+impl ConnectVolume {
+    pub(crate) fn new() -> ConnectVolume {
+        ConnectVolume {
+            active: false,
+            local: None,
+            current: None,
+            transferred_at: None,
+        }
+    }
+
+    /// Returns the volume to go back to if `event` is part of a transfer that
+    /// changed it.
+    pub(crate) fn handle_event(&mut self, event: &PlayerEvent) -> Option<u16> {
+        let active = main_loop::is_active_after(event, self.active);
+        let became_active = active && !self.active;
+        self.active = active;
+        if became_active {
+            self.transferred_at = Some(Instant::now());
+            // Some clients send the volume before the tracks.
+            if self.current != self.local {
+                return self.restore();
+            }
+        }
+
+        let volume = match *event {
+            PlayerEvent::VolumeSet { volume } => volume,
+            _ => return None,
+        };
+        self.current = Some(volume);
+        let transferring = matches!(
+            self.transferred_at,
+            Some(at) if at.elapsed() < TRANSFER_WINDOW
+        );
+        if self.local.is_none() || (self.active && !transferring) {
+            self.local = Some(volume);
+            None
+        } else if self.active && self.local != Some(volume) {
+            self.restore()
+        } else {
+            None
+        }
+    }
+
+    fn restore(&self) -> Option<u16> {
+        info!("Ignoring the volume the client connected with");
+        self.local
+    }
+}
@@ -0,0 +1,183 @@
+use crate::{
+    config::ContextVolume,
+    state::{RememberedVolume, State},
+    web_api,
+};
+use futures::{sync::oneshot, Async, Future};
+use librespot::{
+    core::{
+        session::Session,
+        spotify_id::{SpotifyAudioType, SpotifyId},
+    },
+    playback::player::PlayerEvent,
+};
+use log::{debug, warn};
+use std::{path::PathBuf, thread, time::Duration};
+use tokio_core::reactor::Handle;
+
+/// At most this many contexts are remembered. The one used least recently is
+/// forgotten first.
+const MAX_REMEMBERED: usize = 100;
+/// How often the Web API is asked for the context before giving up. It only
+/// learns about a new track a moment after the player does.
+const LOOKUP_ATTEMPTS: u32 = 3;
+const LOOKUP_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Remembers the volume each context was last played at and switches back to
+/// it when the context plays again.
+///
+/// Contexts that weren't played before get the volume spotifyd started with.
+pub(crate) struct ContextVolumes {
+    mode: ContextVolume,
+    state_path: PathBuf,
+    remembered: Vec<RememberedVolume>,
+    /// The volume the first connection started at.
+    global: Option<u16>,
+    volume: Option<u16>,
+    context: Option<String>,
+    track_id: Option<SpotifyId>,
+    lookup: Option<oneshot::Receiver<String>>,
+}
+
+impl ContextVolumes {
+    pub(crate) fn new(mode: ContextVolume, state_path: PathBuf) -> ContextVolumes {
+        ContextVolumes {
+            mode,
+            remembered: State::load(&state_path).context_volumes,
+            state_path,
+            global: None,
+            volume: None,
+            context: None,
+            track_id: None,
+            lookup: None,
+        }
+    }
+
+    /// Called with the volume a new connection starts at. Only the first one
+    /// counts, as reconnecting shouldn't change the volume contexts fall back to.
+    pub(crate) fn connected(&mut self, volume: u16) {
+        self.global = self.global.or(Some(volume));
+    }
+
+    /// Returns the volume to switch to, if a track from another context has
+    /// started.
+    pub(crate) fn handle_event(
+        &mut self,
+        event: &PlayerEvent,
+        session: &Session,
+        handle: &Handle,
+    ) -> Option<u16> {
+        let track_id = match *event {
+            PlayerEvent::VolumeSet { volume } => {
+                self.volume = Some(volume);
+                if let Some(context) = self.context.clone() {
+                    self.remember(context, volume);
+                }
+                return None;
+            }
+            PlayerEvent::Started { track_id, .. } => track_id,
+            PlayerEvent::Changed { new_track_id, .. } => new_track_id,
+            _ => return None,
+        };
+        if self.track_id == Some(track_id) {
+            return None;
+        }
+        self.track_id = Some(track_id);
+        match self.mode {
+            ContextVolume::Type => self.switch(type_key(track_id)),
+            ContextVolume::Uri => {
+                self.lookup = Some(lookup_context(session, handle, track_id));
+                None
+            }
+        }
+    }
+
+    /// Returns the volume to switch to once the context of a new track is known.
+    pub(crate) fn poll(&mut self) -> Option<u16> {
+        match self.lookup.as_mut().map(Future::poll) {
+            Some(Ok(Async::Ready(context))) => {
+                self.lookup = None;
+                self.switch(context)
+            }
+            Some(Err(_)) => {
+                self.lookup = None;
+                None
+            }
+            _ => None,
+        }
+    }
+
+    fn switch(&mut self, context: String) -> Option<u16> {
+        if self.context.as_ref() == Some(&context) {
+            return None;
+        }
+        debug!("Playing from {}", context);
+        let volume = self
+            .remembered
+            .iter()
+            .find(|remembered| remembered.context == context)
+            .map(|remembered| remembered.volume)
+            .or(self.global);
+        self.context = Some(context);
+        volume.filter(|&volume| Some(volume) != self.volume)
+    }
+
+    fn remember(&mut self, context: String, volume: u16) {
+        self.remembered
+            .retain(|remembered| remembered.context != context);
+        self.remembered.push(RememberedVolume { context, volume });
+        if self.remembered.len() > MAX_REMEMBERED {
+            self.remembered.remove(0);
+        }
+
+        let mut state = State::load(&self.state_path);
+        state.context_volumes = self.remembered.clone();
+        if let Err(e) = state.save(&self.state_path) {
+            warn!(
+                "Could not save the volume to {}: {}",
+                self.state_path.display(),
+                e
+            );
+        }
+    }
+}
+
+fn type_key(track_id: SpotifyId) -> String {
+    match track_id.audio_type {
+        SpotifyAudioType::Podcast => "podcast",
+        _ => "music",
+    }
+    .to_string()
+}
+
+/// Asks the Web API which context `track_id` is playing from. Falls back to
+/// the type of the track if it can't tell, like for episodes, which the
+/// client can't read the playback state of.
+fn lookup_context(
+    session: &Session,
+    handle: &Handle,
+    track_id: SpotifyId,
+) -> oneshot::Receiver<String> {
+    let (tx, rx) = oneshot::channel();
+    let id = track_id.to_base62();
+    web_api::spawn_with_api(session, handle, "user-read-playback-state", move |sp| {
+        for _ in 0..LOOKUP_ATTEMPTS {
+            thread::sleep(LOOKUP_INTERVAL);
+            let playing = match sp.current_playback(None) {
+                Ok(Some(playing)) => playing,
+                Ok(None) => continue,
+                Err(_) => break,
+            };
+            if playing.item.and_then(|item| item.id).as_ref() != Some(&id) {
+                continue;
+            }
+            if let Some(context) = playing.context {
+                let _ = tx.send(context.uri);
+                return;
+            }
+            break;
+        }
+        let _ = tx.send(type_key(track_id));
+    });
+    rx
+}
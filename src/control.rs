@@ -0,0 +1,228 @@
+use futures::{
+    sync::{
+        mpsc::{self, UnboundedReceiver, UnboundedSender},
+        oneshot,
+    },
+    Future,
+};
+use log::{info, warn};
+use std::{io, path::Path};
+
+/// How long a command sent to a running spotifyd may take to be answered.
+#[cfg(unix)]
+const REQUEST_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(10);
+
+/// What the control socket, the HTTP API and the D-Bus methods ask the main
+/// loop to do.
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) enum Command {
+    Play,
+    Pause,
+    PlayPause,
+    Next,
+    Previous,
+    /// Seek to a position in the current track, in milliseconds.
+    Seek(u32),
+    /// Set the volume, between 0 and 100.
+    Volume(u8),
+    /// Turn the volume up or down by `volume_step`.
+    VolumeUp,
+    VolumeDown,
+    /// Add a track to the queue.
+    Queue(String),
+    /// Report what is playing, as JSON.
+    Status,
+    /// Report how much is in the cache.
+    CacheStats,
+    /// List the tracks that have an audio file in the cache.
+    CachedTracks,
+    /// Read the config again and apply what can change while running.
+    Reload,
+}
+
+impl Command {
+    /// Parses a line sent to the control socket, like `queue spotify:track:…`.
+    pub(crate) fn parse(line: &str) -> Result<Command, String> {
+        let mut words = line.split_whitespace();
+        match (words.next(), words.next(), words.next()) {
+            (Some("play"), None, _) => Ok(Command::Play),
+            (Some("pause"), None, _) => Ok(Command::Pause),
+            (Some("play-pause"), None, _) => Ok(Command::PlayPause),
+            (Some("next"), None, _) => Ok(Command::Next),
+            (Some("previous"), None, _) => Ok(Command::Previous),
+            (Some(command @ "play"), _, _)
+            | (Some(command @ "pause"), _, _)
+            | (Some(command @ "play-pause"), _, _)
+            | (Some(command @ "next"), _, _)
+            | (Some(command @ "previous"), _, _) => Err(format!("usage: {}", command)),
+            (Some("seek"), Some(position), None) => position
+                .parse()
+                .map(Command::Seek)
+                .map_err(|_| "usage: seek <milliseconds>".to_string()),
+            (Some("seek"), _, _) => Err("usage: seek <milliseconds>".to_string()),
+            (Some("volume"), Some("up"), None) => Ok(Command::VolumeUp),
+            (Some("volume"), Some("down"), None) => Ok(Command::VolumeDown),
+            (Some("volume"), Some(volume), None) => match volume.parse() {
+                Ok(volume) if volume <= 100 => Ok(Command::Volume(volume)),
+                _ => Err("usage: volume <0-100|up|down>".to_string()),
+            },
+            (Some("volume"), _, _) => Err("usage: volume <0-100|up|down>".to_string()),
+            (Some("queue"), Some(uri), None) => Ok(Command::Queue(uri.to_string())),
+            (Some("queue"), _, _) => Err("usage: queue <uri>".to_string()),
+            (Some("cache-stats"), None, _) => Ok(Command::CacheStats),
+            (Some("cache-stats"), _, _) => Err("usage: cache-stats".to_string()),
+            (Some("cached-tracks"), None, _) => Ok(Command::CachedTracks),
+            (Some("cached-tracks"), _, _) => Err("usage: cached-tracks".to_string()),
+            (Some("status"), None, _) => Ok(Command::Status),
+            (Some("status"), _, _) => Err("usage: status".to_string()),
+            (Some("reload"), None, _) => Ok(Command::Reload),
+            (Some("reload"), _, _) => Err("usage: reload".to_string()),
+            (Some(command), _, _) => Err(format!("unknown command {}", command)),
+            (None, _, _) => Err("no command given".to_string()),
+        }
+    }
+}
+
+/// A command, with where to send the result once the main loop handled it.
+/// Commands that report something answer with a line of text.
+pub(crate) type Request = (Command, oneshot::Sender<Result<String, String>>);
+
+pub(crate) type RequestSender = UnboundedSender<Request>;
+
+pub(crate) fn channel() -> (RequestSender, UnboundedReceiver<Request>) {
+    mpsc::unbounded()
+}
+
+/// Sends `command` to the main loop and resolves with its result.
+pub(crate) fn send(
+    requests: &RequestSender,
+    command: Command,
+) -> Box<dyn Future<Item = String, Error = String>> {
+    let (tx, rx) = oneshot::channel();
+    if requests.unbounded_send((command, tx)).is_err() {
+        return Box::new(futures::future::err(
+            "spotifyd is shutting down".to_string(),
+        ));
+    }
+    Box::new(
+        rx.map_err(|_| "spotifyd is shutting down".to_string())
+            .and_then(|result| result),
+    )
+}
+
+/// Listens on the unix socket at `path` for one command per line and answers
+/// each with `ok`, followed by what the command reports if anything, or
+/// `error: …`.
+///
+/// Connections are served on a thread of their own, as they block while they
+/// wait for the main loop. The socket is only accessible to the user running
+/// spotifyd, as anyone who can connect controls playback.
+#[cfg(unix)]
+pub(crate) fn listen(path: &Path, requests: RequestSender) -> io::Result<()> {
+    use std::{
+        fs,
+        io::{BufRead, BufReader, Write},
+        os::unix::{fs::PermissionsExt, net::UnixListener},
+        thread,
+    };
+
+    // A socket left over from a crash would make binding fail.
+    if let Err(e) = fs::remove_file(path) {
+        if e.kind() != io::ErrorKind::NotFound {
+            return Err(e);
+        }
+    }
+    let listener = UnixListener::bind(path)?;
+    fs::set_permissions(path, fs::Permissions::from_mode(0o600))?;
+    info!("Listening for commands on {}", path.display());
+
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            let stream = match stream {
+                Ok(stream) => stream,
+                Err(e) => {
+                    warn!("Could not accept a control connection: {}", e);
+                    continue;
+                }
+            };
+            let requests = requests.clone();
+            thread::spawn(move || {
+                let mut writer = match stream.try_clone() {
+                    Ok(writer) => writer,
+                    Err(e) => return warn!("Control connection failed: {}", e),
+                };
+                for line in BufReader::new(stream).lines() {
+                    let line = match line {
+                        Ok(line) => line,
+                        Err(_) => break,
+                    };
+                    if line.trim().is_empty() {
+                        continue;
+                    }
+                    let result =
+                        Command::parse(&line).and_then(|command| send(&requests, command).wait());
+                    let answer = match result {
+                        Ok(ref output) if output.is_empty() => "ok\n".to_string(),
+                        Ok(output) => format!("ok {}\n", output),
+                        Err(e) => format!("error: {}\n", e),
+                    };
+                    if writer.write_all(answer.as_bytes()).is_err() {
+                        break;
+                    }
+                }
+            });
+        }
+    });
+    Ok(())
+}
+
+#[cfg(not(unix))]
+pub(crate) fn listen(path: &Path, _: RequestSender) -> io::Result<()> {
+    warn!(
+        "control_socket is only supported on unix, not listening on {}",
+        path.display()
+    );
+    Ok(())
+}
+
+/// Sends the command `line` to the spotifyd listening on the control socket
+/// at `path`, and returns what it reported.
+#[cfg(unix)]
+pub(crate) fn request(path: &Path, line: &str) -> Result<String, String> {
+    use std::{
+        io::{BufRead, BufReader, Write},
+        os::unix::net::UnixStream,
+    };
+
+    let not_running = |e: io::Error| {
+        format!(
+            "could not reach spotifyd on {}, is it running? {}",
+            path.display(),
+            e
+        )
+    };
+    let mut stream = UnixStream::connect(path).map_err(not_running)?;
+    stream
+        .set_read_timeout(Some(REQUEST_TIMEOUT))
+        .map_err(not_running)?;
+    writeln!(stream, "{}", line).map_err(not_running)?;
+    let mut answer = String::new();
+    BufReader::new(stream)
+        .read_line(&mut answer)
+        .map_err(|e| format!("spotifyd didn't answer: {}", e))?;
+    let answer = answer.trim_end();
+    if answer == "ok" {
+        Ok(String::new())
+    } else if let Some(output) = answer.strip_prefix("ok ") {
+        Ok(output.to_string())
+    } else if let Some(e) = answer.strip_prefix("error: ") {
+        Err(e.to_string())
+    } else {
+        Err(format!("spotifyd answered {:?}", answer))
+    }
+}
+
+#[cfg(not(unix))]
+pub(crate) fn request(_: &Path, _: &str) -> Result<String, String> {
+    Err("control_socket is only supported on unix".to_string())
+}
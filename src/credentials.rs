@@ -0,0 +1,382 @@
+use crate::{
+    cache::{CREDENTIALS_FILE_NAME, ENCRYPTED_CREDENTIALS_FILE_NAME},
+    error::SetupError,
+    process::run_program,
+};
+use aes_ctr::{
+    stream_cipher::{generic_array::GenericArray, NewStreamCipher, SyncStreamCipher},
+    Aes256Ctr,
+};
+use hmac::{Hmac, Mac};
+#[cfg(feature = "dbus_keyring")]
+use keyring::{Keyring, KeyringError};
+use librespot::core::authentication::Credentials;
+use log::{info, warn};
+use rand::RngCore;
+use sha2::Sha256;
+use std::{
+    fs, io,
+    path::{Path, PathBuf},
+};
+
+/// Starts every encrypted credentials file, so that other formats can follow.
+const MAGIC: &[u8] = b"spotifyd-credentials-1\n";
+const SALT_LEN: usize = 16;
+const NONCE_LEN: usize = 16;
+const TAG_LEN: usize = 32;
+#[cfg(not(test))]
+const KEY_ITERATIONS: usize = 100_000;
+/// Unoptimized test builds take seconds for the real number.
+#[cfg(test)]
+const KEY_ITERATIONS: usize = 1_000;
+
+/// The keyring service the credentials are kept under with `use_keyring`. The
+/// password is kept under `spotifyd`.
+#[cfg(feature = "dbus_keyring")]
+const KEYRING_SERVICE: &str = "spotifyd-credentials";
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// Reads and writes the credentials cached by librespot.
+///
+/// librespot always saves the credentials in cleartext. With a passphrase, they
+/// are encrypted with AES-256-CTR and authenticated with HMAC-SHA256 right after
+/// each login, and the cleartext file is removed. The keys are derived from the
+/// passphrase with PBKDF2.
+///
+/// With `use_keyring`, they are moved to the keyring of the OS instead, under
+/// the path of the cache, so that nothing is left on disk.
+#[derive(Clone)]
+pub(crate) struct CredentialStore {
+    cache_path: PathBuf,
+    passphrase: Option<String>,
+    #[cfg(feature = "dbus_keyring")]
+    keyring: bool,
+}
+
+impl CredentialStore {
+    /// Runs `passphrase_cmd` to get the passphrase, if there is one.
+    pub(crate) fn new(
+        cache_path: PathBuf,
+        passphrase_cmd: Option<&str>,
+        shell: &str,
+    ) -> Result<CredentialStore, SetupError> {
+        let passphrase = match passphrase_cmd {
+            Some(cmd) => {
+                let passphrase = run_program(shell, cmd).map_err(|e| {
+                    SetupError::ConfigInvalid(format!("credentials_passphrase_cmd failed: {}", e))
+                })?;
+                let passphrase = passphrase.trim_end_matches(&['\r', '\n'][..]).to_string();
+                if passphrase.is_empty() {
+                    return Err(SetupError::ConfigInvalid(
+                        "credentials_passphrase_cmd returned an empty passphrase".to_string(),
+                    ));
+                }
+                Some(passphrase)
+            }
+            None => None,
+        };
+        Ok(CredentialStore {
+            cache_path,
+            passphrase,
+            #[cfg(feature = "dbus_keyring")]
+            keyring: false,
+        })
+    }
+
+    /// Keeps the credentials in the keyring rather than in the cache.
+    #[cfg(feature = "dbus_keyring")]
+    pub(crate) fn in_keyring(self) -> CredentialStore {
+        if self.passphrase.is_some() {
+            warn!("The credentials are kept in the keyring, credentials_passphrase_cmd isn't used");
+        }
+        CredentialStore {
+            keyring: true,
+            ..self
+        }
+    }
+
+    /// Returns the cached credentials, if there are any.
+    pub(crate) fn load(&self) -> Result<Option<Credentials>, SetupError> {
+        #[cfg(feature = "dbus_keyring")]
+        {
+            if self.keyring {
+                return self.load_from_keyring();
+            }
+        }
+        let encrypted_path = self.cache_path.join(ENCRYPTED_CREDENTIALS_FILE_NAME);
+        let passphrase = match self.passphrase {
+            Some(ref passphrase) => passphrase,
+            None if encrypted_path.exists() => {
+                warn!(
+                    "The cached credentials are encrypted, but no credentials_passphrase_cmd is set"
+                );
+                return Ok(None);
+            }
+            None => return Ok(self.load_cleartext()),
+        };
+
+        match fs::read(&encrypted_path) {
+            Ok(data) => {
+                let plaintext = decrypt(passphrase, &data).ok_or_else(|| {
+                    SetupError::ConfigInvalid(format!(
+                        "could not decrypt {}, the passphrase may be wrong",
+                        encrypted_path.display()
+                    ))
+                })?;
+                serde_json::from_slice(&plaintext).map(Some).map_err(|e| {
+                    SetupError::ConfigInvalid(format!(
+                        "{} holds no credentials: {}",
+                        encrypted_path.display(),
+                        e
+                    ))
+                })
+            }
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                // Credentials cached before a passphrase was set are
+                // encrypted from now on.
+                let credentials = self.load_cleartext();
+                if credentials.is_some() {
+                    self.secure();
+                }
+                Ok(credentials)
+            }
+            Err(e) => Err(SetupError::ConfigInvalid(format!(
+                "could not read {}: {}",
+                encrypted_path.display(),
+                e
+            ))),
+        }
+    }
+
+    /// Encrypts the credentials librespot saved after logging in, or moves
+    /// them to the keyring, and removes the cleartext file. Does nothing
+    /// without a passphrase or the keyring.
+    pub(crate) fn secure(&self) {
+        #[cfg(feature = "dbus_keyring")]
+        {
+            if self.keyring {
+                self.move_to_keyring();
+                return;
+            }
+        }
+        let passphrase = match self.passphrase {
+            Some(ref passphrase) => passphrase,
+            None => return,
+        };
+        let cleartext_path = self.cleartext_path();
+        let plaintext = match fs::read(&cleartext_path) {
+            Ok(plaintext) => plaintext,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return,
+            Err(e) => {
+                warn!("Could not read the cached credentials: {}", e);
+                return;
+            }
+        };
+        let encrypted_path = self.cache_path.join(ENCRYPTED_CREDENTIALS_FILE_NAME);
+        let result = write_private(&encrypted_path, &encrypt(passphrase, &plaintext))
+            .and_then(|()| fs::remove_file(&cleartext_path));
+        match result {
+            Ok(()) => info!("Encrypted the cached credentials"),
+            Err(e) => warn!("Could not encrypt the cached credentials: {}", e),
+        }
+    }
+
+    #[cfg(feature = "dbus_keyring")]
+    fn load_from_keyring(&self) -> Result<Option<Credentials>, SetupError> {
+        let account = self.cache_path.to_string_lossy();
+        match Keyring::new(KEYRING_SERVICE, &account).get_password() {
+            Ok(json) => serde_json::from_str(&json).map(Some).map_err(|e| {
+                SetupError::ConfigInvalid(format!(
+                    "the keyring holds no credentials for {}: {}",
+                    account, e
+                ))
+            }),
+            Err(KeyringError::NoPasswordFound) => {
+                // Credentials cached before use_keyring was set are moved
+                // to the keyring.
+                let credentials = self.load_cleartext();
+                if credentials.is_some() {
+                    self.move_to_keyring();
+                }
+                Ok(credentials)
+            }
+            Err(e) => Err(SetupError::ConfigInvalid(format!(
+                "could not read the credentials from the keyring: {}",
+                e
+            ))),
+        }
+    }
+
+    #[cfg(feature = "dbus_keyring")]
+    fn move_to_keyring(&self) {
+        let cleartext_path = self.cleartext_path();
+        let json = match fs::read_to_string(&cleartext_path) {
+            Ok(json) => json,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return,
+            Err(e) => {
+                warn!("Could not read the cached credentials: {}", e);
+                return;
+            }
+        };
+        let account = self.cache_path.to_string_lossy();
+        let result = Keyring::new(KEYRING_SERVICE, &account)
+            .set_password(&json)
+            .map_err(|e| e.to_string())
+            .and_then(|()| fs::remove_file(&cleartext_path).map_err(|e| e.to_string()));
+        match result {
+            Ok(()) => info!("Moved the cached credentials to the keyring"),
+            Err(e) => warn!(
+                "Could not move the cached credentials to the keyring: {}",
+                e
+            ),
+        }
+    }
+
+    fn load_cleartext(&self) -> Option<Credentials> {
+        let data = fs::read(self.cleartext_path()).ok()?;
+        serde_json::from_slice(&data)
+            .map_err(|e| warn!("Could not read the cached credentials: {}", e))
+            .ok()
+    }
+
+    fn cleartext_path(&self) -> PathBuf {
+        self.cache_path.join(CREDENTIALS_FILE_NAME)
+    }
+}
+
+/// Removes the credentials kept in the keyring for the cache at `cache_path`,
+/// for `spotifyd logout`. Returns whether there were any.
+#[cfg(feature = "dbus_keyring")]
+pub(crate) fn remove_from_keyring(cache_path: &Path) -> Result<bool, SetupError> {
+    let account = cache_path.to_string_lossy();
+    match Keyring::new(KEYRING_SERVICE, &account).delete_password() {
+        Ok(()) => Ok(true),
+        Err(KeyringError::NoPasswordFound) => Ok(false),
+        Err(e) => Err(SetupError::ConfigInvalid(format!(
+            "could not remove the credentials from the keyring: {}",
+            e
+        ))),
+    }
+}
+
+/// Derives the encryption key and the authentication key.
+fn derive_keys(passphrase: &str, salt: &[u8]) -> ([u8; 32], [u8; 32]) {
+    let mut keys = [0u8; 64];
+    pbkdf2::pbkdf2::<HmacSha256>(passphrase.as_bytes(), salt, KEY_ITERATIONS, &mut keys);
+    let mut encryption_key = [0u8; 32];
+    let mut authentication_key = [0u8; 32];
+    encryption_key.copy_from_slice(&keys[..32]);
+    authentication_key.copy_from_slice(&keys[32..]);
+    (encryption_key, authentication_key)
+}
+
+fn mac(authentication_key: &[u8], data: &[u8]) -> HmacSha256 {
+    let mut mac = HmacSha256::new_varkey(authentication_key).expect("HMAC takes keys of any size");
+    mac.input(data);
+    mac
+}
+
+/// Returns the magic, the salt, the nonce, the ciphertext and a tag over all
+/// of them.
+fn encrypt(passphrase: &str, plaintext: &[u8]) -> Vec<u8> {
+    let mut salt = [0u8; SALT_LEN];
+    let mut nonce = [0u8; NONCE_LEN];
+    rand::thread_rng().fill_bytes(&mut salt);
+    rand::thread_rng().fill_bytes(&mut nonce);
+    let (encryption_key, authentication_key) = derive_keys(passphrase, &salt);
+
+    let mut data = Vec::with_capacity(MAGIC.len() + SALT_LEN + NONCE_LEN + plaintext.len());
+    data.extend_from_slice(MAGIC);
+    data.extend_from_slice(&salt);
+    data.extend_from_slice(&nonce);
+    let start = data.len();
+    data.extend_from_slice(plaintext);
+    Aes256Ctr::new(
+        GenericArray::from_slice(&encryption_key),
+        GenericArray::from_slice(&nonce),
+    )
+    .apply_keystream(&mut data[start..]);
+    let tag = mac(&authentication_key, &data).result().code();
+    data.extend_from_slice(&tag);
+    data
+}
+
+/// Returns `None` if `data` wasn't encrypted with `passphrase` or was changed.
+fn decrypt(passphrase: &str, data: &[u8]) -> Option<Vec<u8>> {
+    if data.len() < MAGIC.len() + SALT_LEN + NONCE_LEN + TAG_LEN || !data.starts_with(MAGIC) {
+        return None;
+    }
+    let (data, tag) = data.split_at(data.len() - TAG_LEN);
+    let salt = &data[MAGIC.len()..MAGIC.len() + SALT_LEN];
+    let nonce = &data[MAGIC.len() + SALT_LEN..MAGIC.len() + SALT_LEN + NONCE_LEN];
+    let (encryption_key, authentication_key) = derive_keys(passphrase, salt);
+    mac(&authentication_key, data).verify(tag).ok()?;
+
+    let mut plaintext = data[MAGIC.len() + SALT_LEN + NONCE_LEN..].to_vec();
+    Aes256Ctr::new(
+        GenericArray::from_slice(&encryption_key),
+        GenericArray::from_slice(nonce),
+    )
+    .apply_keystream(&mut plaintext);
+    Some(plaintext)
+}
+
+#[cfg(unix)]
+fn write_private(path: &Path, data: &[u8]) -> io::Result<()> {
+    use std::{io::Write, os::unix::fs::OpenOptionsExt};
+
+    fs::OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .mode(0o600)
+        .open(path)?
+        .write_all(data)
+}
+
+#[cfg(not(unix))]
+fn write_private(path: &Path, data: &[u8]) -> io::Result<()> {
+    fs::write(path, data)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PLAINTEXT: &[u8] = br#"{"username":"user","auth_type":1,"auth_data":[1,2,3]}"#;
+
+    #[test]
+    fn test_round_trip() {
+        let data = encrypt("passphrase", PLAINTEXT);
+        assert!(data.starts_with(MAGIC));
+        assert_eq!(decrypt("passphrase", &data).as_deref(), Some(PLAINTEXT));
+        assert_eq!(decrypt("wrong passphrase", &data), None);
+    }
+
+    #[test]
+    fn test_changed_data() {
+        let data = encrypt("passphrase", PLAINTEXT);
+        let ciphertext_start = MAGIC.len() + SALT_LEN + NONCE_LEN;
+
+        let mut changed = data.clone();
+        changed[ciphertext_start] ^= 1;
+        assert_eq!(decrypt("passphrase", &changed), None);
+
+        let mut changed = data.clone();
+        *changed.last_mut().unwrap() ^= 1;
+        assert_eq!(decrypt("passphrase", &changed), None);
+
+        let mut changed = data;
+        changed[0] = b'S';
+        assert_eq!(decrypt("passphrase", &changed), None);
+    }
+
+    #[test]
+    fn test_truncated_data() {
+        let data = encrypt("passphrase", PLAINTEXT);
+        for &len in &[0, MAGIC.len(), MAGIC.len() + SALT_LEN + 1, data.len() - 1] {
+            assert_eq!(decrypt("passphrase", &data[..len]), None);
+        }
+    }
+}
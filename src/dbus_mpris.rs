@@ -1,30 +1,63 @@
+use crate::{
+    audio_features::{AudioFeatureCache, AudioFeatures, Mode},
+    control::{self, Command, RequestSender},
+    event_hooks::Playback,
+    main_loop,
+    metadata::{fetch_metadata_retrying, MetadataFetch, TrackMetadata},
+    normalisation::Normalisation,
+    web_api,
+};
 use chrono::prelude::*;
 use dbus::{
     arg::{RefArg, Variant},
-    tree::{Access, MethodErr},
-    BusType, Connection, MessageItem, MessageItemArray, NameFlag, Signature,
+    stdintf::org_freedesktop_dbus::PropertiesPropertiesChanged,
+    tree::{Access, EmitsChangedSignal, MethodErr},
+    BusType, Connection, Message, MessageItem, MessageItemArray, NameFlag, Path, SignalArgs,
+    Signature,
 };
 use dbus_tokio::{
     tree::{AFactory, ATree, ATreeServer},
     AConnection,
 };
-use futures::{sync::oneshot, Async, Future, Poll, Stream};
+use futures::{
+    sync::{mpsc::UnboundedReceiver, oneshot},
+    Async, Future, Poll, Stream,
+};
 use librespot::{
     connect::spirc::Spirc,
     core::{
         keymaster::{get_token, Token as LibrespotToken},
         mercury::MercuryError,
         session::Session,
+        spotify_id::SpotifyId,
     },
+    playback::player::PlayerEvent,
+    protocol::spirc::{PlayStatus, State as ConnectState, TrackRef},
 };
 use log::{info, warn};
 use percent_encoding::{utf8_percent_encode, NON_ALPHANUMERIC};
 use rspotify::spotify::{
-    client::Spotify, model::offset::for_position, oauth2::TokenInfo as RspotifyToken, senum::*,
+    client::Spotify,
+    model::{
+        artist::SimplifiedArtist,
+        image::Image,
+        offset::{for_position, Offset},
+        playlist::SimplifiedPlaylist,
+        track::FullTrack,
+    },
+    oauth2::TokenInfo as RspotifyToken,
+    senum::*,
     util::datetime_to_timestamp,
 };
-use std::{collections::HashMap, env, rc::Rc, thread};
-use tokio_core::reactor::Handle;
+use std::{
+    cell::{Cell, RefCell},
+    collections::HashMap,
+    convert::TryFrom,
+    rc::Rc,
+    thread,
+    time::Duration,
+};
+use tokio_core::reactor::{Handle, Timeout};
 
 pub struct DbusServer {
     session: Session,
@@ -33,10 +66,38 @@ pub struct DbusServer {
     api_token: RspotifyToken,
     token_request: Option<Box<dyn Future<Item = LibrespotToken, Error = MercuryError>>>,
     dbus_future: Option<Box<dyn Future<Item = (), Error = ()>>>,
+    connection: Option<Rc<Connection>>,
     device_name: String,
+    market: Option<Country>,
+    player_events: UnboundedReceiver<PlayerEvent>,
+    /// The capabilities clients were last told about.
+    advertised_capabilities: Capabilities,
+    /// The states this device sends to the others, which carry the modes.
+    states: Box<dyn Stream<Item = ConnectState, Error = ()>>,
+    modes: Option<Modes>,
+    advertised_modes: Option<Modes>,
+    now_playing: Rc<RefCell<NowPlaying>>,
+    metadata_request: Option<Box<dyn Future<Item = TrackMetadata, Error = ()>>>,
+    metadata_fetch: MetadataFetch,
+    /// The tracks that were looked up completely, so they aren't again.
+    metadata_cache: HashMap<SpotifyId, TrackMetadata>,
+    advertised_status: PlaybackStatus,
+    advertised_tracks: Vec<SpotifyId>,
+    advertised_context: Option<String>,
+    /// Whether the metadata changed since clients were last told about it.
+    metadata_changed: bool,
+    /// Changes are collected for this long and then sent in a single signal.
+    coalesce_window: Duration,
+    signal_timeout: Option<Timeout>,
+    /// Whether playback commands are passed on to Spotify.
+    commands_enabled: Rc<Cell<bool>>,
+    normalisation: Rc<Cell<Normalisation>>,
+    /// Whether this is the active Connect device.
+    active: Rc<Cell<bool>>,
+    audio_features: Option<AudioFeatureCache>,
+    control_sender: RequestSender,
 }
 
-const CLIENT_ID: &str = "2c1ea588dfbc4a989e2426f8385297c3";
 const SCOPE: &str = "user-read-playback-state,user-read-private,\
                      user-read-email,playlist-read-private,user-library-read,user-library-modify,\
                      user-top-read,playlist-read-collaborative,playlist-modify-public,\
@@ -44,13 +105,307 @@ const SCOPE: &str = "user-read-playback-state,user-read-private,\
                      user-read-currently-playing,user-modify-playback-state,\
                      user-read-recently-played";
 
+/// The only playback rate supported by Spotify.
+const PLAYBACK_RATE: f64 = 1.0;
+
+/// How many tracks `metadata_cache` holds before it is emptied.
+const METADATA_CACHE_SIZE: usize = 100;
+
+const PLAYER_INTERFACE: &str = "org.mpris.MediaPlayer2.Player";
+
+const TRACKLIST_INTERFACE: &str = "org.mpris.MediaPlayer2.TrackList";
+
+const PLAYLISTS_INTERFACE: &str = "org.mpris.MediaPlayer2.Playlists";
+
+const MPRIS_PATH: &str = "/org/mpris/MediaPlayer2";
+
+/// What the `TrackList` reports while nothing is loaded.
+const NO_TRACK: &str = "/org/mpris/MediaPlayer2/TrackList/NoTrack";
+
+/// How many tracks the `TrackList` holds, the current one and those after it.
+const TRACKLIST_SIZE: usize = 25;
+
+/// How many tracks the Web API describes in a single request.
+const TRACKS_PER_REQUEST: usize = 50;
+
+/// How many playlists the Web API lists in a single request.
+const PLAYLISTS_PER_REQUEST: u32 = 50;
+
+/// Reports spotifyd's own state, which MPRIS has no place for.
+const DIAGNOSTICS_INTERFACE: &str = "rs.spotifyd.Diagnostics";
+
+/// Lists the other Spotify Connect devices and moves playback to them.
+const DEVICES_INTERFACE: &str = "rs.spotifyd.Devices";
+
+/// Adds tracks to the queue, which MPRIS has no method for.
+const QUEUE_INTERFACE: &str = "rs.spotifyd.Queue";
+
+/// Returns the object path identifying a track in MPRIS. It only depends on
+/// the track, so it stays the same for as long as the track plays.
+fn track_object_path(id: Option<&str>, uri: &str) -> Path<'static> {
+    let path = match id {
+        // Spotify ids are base62, which is valid in an object path as is.
+        Some(id) => format!("/rs/spotifyd/track/{}", id),
+        // Local files have no id, and their uri can contain any character.
+        None => format!("/rs/spotifyd/track/local/{}", hex::encode(uri)),
+    };
+    Path::new(path).expect("track object paths are always valid")
+}
+
+fn playlist_object_path(id: &str) -> Path<'static> {
+    // Playlist ids are base62 as well.
+    Path::new(format!("/rs/spotifyd/playlist/{}", id))
+        .expect("playlist object paths are always valid")
+}
+
+/// Returns the id in the object path of a track or a playlist, made by
+/// `track_object_path` or `playlist_object_path`.
+fn id_in_path<'a>(path: &'a Path, kind: &str) -> Option<&'a str> {
+    path.strip_prefix("/rs/spotifyd/")?
+        .strip_prefix(kind)?
+        .strip_prefix('/')
+        .filter(|id| !id.contains('/'))
+}
+
+/// The MPRIS capabilities that depend on what is currently playing.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+struct Capabilities {
+    can_play: bool,
+    can_pause: bool,
+    can_seek: bool,
+    can_go_next: bool,
+    can_go_previous: bool,
+}
+
+impl Capabilities {
+    /// Reads the capabilities from a state of this device. Nothing can be
+    /// controlled until a track is loaded here.
+    fn read(state: &ConnectState) -> Capabilities {
+        let index = state.get_playing_track_index() as usize;
+        let tracks = state.get_track();
+        if state.get_status() == PlayStatus::kPlayStatusStop || index >= tracks.len() {
+            return Capabilities::default();
+        }
+        Capabilities {
+            can_play: true,
+            can_pause: true,
+            can_seek: true,
+            // Spirc keeps the queue and the rest of the context after the
+            // current track, and autoplay adds to it before the last one. At
+            // the end, it only goes on with repeat.
+            can_go_next: index + 1 < tracks.len() || state.get_repeat(),
+            // Going back restarts the current track, so this always works.
+            can_go_previous: true,
+        }
+    }
+
+    fn changed_since(&self, old: &Capabilities) -> HashMap<String, Variant<Box<dyn RefArg>>> {
+        let mut changed = HashMap::new();
+        let properties = [
+            ("CanPlay", self.can_play, old.can_play),
+            ("CanPause", self.can_pause, old.can_pause),
+            ("CanSeek", self.can_seek, old.can_seek),
+            ("CanGoNext", self.can_go_next, old.can_go_next),
+            ("CanGoPrevious", self.can_go_previous, old.can_go_previous),
+        ];
+        for &(name, new, old) in properties.iter() {
+            if new != old {
+                changed.insert(name.to_string(), Variant(Box::new(new) as Box<dyn RefArg>));
+            }
+        }
+        changed
+    }
+}
+
+/// The shuffle and repeat modes, as Spirc reports them.
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct Modes {
+    shuffle: bool,
+    repeat: bool,
+}
+
+impl Modes {
+    fn changed_since(&self, old: Option<Modes>) -> HashMap<String, Variant<Box<dyn RefArg>>> {
+        let mut changed: HashMap<String, Variant<Box<dyn RefArg>>> = HashMap::new();
+        if old.map(|old| old.shuffle) != Some(self.shuffle) {
+            changed.insert("Shuffle".to_string(), Variant(Box::new(self.shuffle)));
+        }
+        if old.map(|old| old.repeat) != Some(self.repeat) {
+            // librespot can only repeat the whole context.
+            let loop_status = if self.repeat { "Playlist" } else { "None" };
+            changed.insert(
+                "LoopStatus".to_string(),
+                Variant(Box::new(loop_status.to_string())),
+            );
+        }
+        changed
+    }
+}
+
+/// What MPRIS reports as the `PlaybackStatus`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum PlaybackStatus {
+    Playing,
+    Paused,
+    Stopped,
+}
+
+impl PlaybackStatus {
+    fn as_str(self) -> &'static str {
+        match self {
+            PlaybackStatus::Playing => "Playing",
+            PlaybackStatus::Paused => "Paused",
+            PlaybackStatus::Stopped => "Stopped",
+        }
+    }
+}
+
+/// What this device has loaded, as Spirc reports it in its states.
+///
+/// librespot loads the track of a session it takes over even when it stays
+/// paused, so this knows about it long before the first play, when the Web
+/// API only starts to report it.
+struct NowPlaying {
+    status: PlaybackStatus,
+    track: Option<SpotifyId>,
+    /// Looked up with librespot once the track is loaded.
+    metadata: Option<TrackMetadata>,
+    /// Where in the track playback is, from the player events.
+    playback: Option<Playback>,
+    /// The current track and the ones after it, for the `TrackList`.
+    tracks: Vec<SpotifyId>,
+    context_uri: Option<String>,
+    capabilities: Capabilities,
+}
+
+impl NowPlaying {
+    fn position_us(&self) -> i64 {
+        self.playback
+            .as_ref()
+            .map_or(0, |playback| i64::from(playback.position_ms()) * 1000)
+    }
+
+    fn duration_us(&self) -> Option<i64> {
+        self.metadata
+            .as_ref()
+            .map(|metadata| i64::from(metadata.duration_ms) * 1000)
+    }
+
+    fn track_path(&self) -> Path<'static> {
+        match self.track {
+            Some(track) => track_object_path(Some(&track.to_base62()), &track.to_uri()),
+            None => Path::new(NO_TRACK).expect("NO_TRACK is a valid object path"),
+        }
+    }
+
+    /// Returns the tracks of the `TrackList` described by `state`. A track
+    /// that comes up again is only listed once, as its object path would be
+    /// the same.
+    fn read_tracks(state: &ConnectState) -> Vec<SpotifyId> {
+        let mut tracks = Vec::new();
+        let upcoming = state
+            .get_track()
+            .iter()
+            .skip(state.get_playing_track_index() as usize);
+        for track in upcoming {
+            if let Some(id) = track_id(track) {
+                if !tracks.contains(&id) {
+                    tracks.push(id);
+                }
+            }
+            if tracks.len() >= TRACKLIST_SIZE {
+                break;
+            }
+        }
+        tracks
+    }
+
+    /// Returns the status and the current track described by `state`.
+    fn read(&self, state: &ConnectState) -> (PlaybackStatus, Option<SpotifyId>) {
+        let track = state
+            .get_track()
+            .get(state.get_playing_track_index() as usize)
+            .and_then(track_id);
+        let status = match (state.get_status(), track) {
+            (PlayStatus::kPlayStatusStop, _) | (_, None) => PlaybackStatus::Stopped,
+            (PlayStatus::kPlayStatusPlay, _) => PlaybackStatus::Playing,
+            (PlayStatus::kPlayStatusPause, _) => PlaybackStatus::Paused,
+            // Loading keeps whatever was going on, a new track starts paused.
+            (PlayStatus::kPlayStatusLoading, _) => match self.status {
+                PlaybackStatus::Stopped => PlaybackStatus::Paused,
+                status => status,
+            },
+        };
+        (status, track)
+    }
+}
+
+fn track_id(track: &TrackRef) -> Option<SpotifyId> {
+    SpotifyId::from_raw(track.get_gid())
+        .or_else(|_| SpotifyId::from_uri(track.get_uri()))
+        .ok()
+}
+
+/// Describes a track with what librespot knows about it, for when the Web API
+/// doesn't know about it yet.
+fn local_metadata(
+    track_id: SpotifyId,
+    metadata: &TrackMetadata,
+) -> HashMap<String, Variant<Box<dyn RefArg>>> {
+    let mut m: HashMap<String, Variant<Box<dyn RefArg>>> = HashMap::new();
+    m.insert(
+        "mpris:trackid".to_string(),
+        Variant(Box::new(MessageItem::ObjectPath(track_object_path(
+            Some(&track_id.to_base62()),
+            &metadata.uri,
+        )))),
+    );
+    m.insert(
+        "mpris:length".to_string(),
+        Variant(Box::new(MessageItem::Int64(
+            i64::from(metadata.duration_ms) * 1000,
+        ))),
+    );
+    m.insert(
+        "xesam:title".to_string(),
+        Variant(Box::new(MessageItem::Str(metadata.title.clone()))),
+    );
+    if let Some(ref album) = metadata.album {
+        m.insert(
+            "xesam:album".to_string(),
+            Variant(Box::new(MessageItem::Str(album.clone()))),
+        );
+    }
+    if let Some(ref cover_url) = metadata.cover_url {
+        m.insert(
+            "mpris:artUrl".to_string(),
+            Variant(Box::new(MessageItem::Str(cover_url.clone()))),
+        );
+    }
+    m.insert(
+        "xesam:artist".to_string(),
+        Variant(Box::new(metadata.artists.clone())),
+    );
+    m
+}
+
 impl DbusServer {
+    #[allow(clippy::too_many_arguments)]
     pub fn new(
         session: Session,
         handle: Handle,
         spirc: Rc<Spirc>,
         device_name: String,
+        market: Option<Country>,
+        player_events: UnboundedReceiver<PlayerEvent>,
+        passive_startup: bool,
+        coalesce_window: Duration,
+        normalisation: Rc<Cell<Normalisation>>,
+        audio_features: Option<AudioFeatureCache>,
+        control_sender: RequestSender,
+        metadata_fetch: MetadataFetch,
     ) -> DbusServer {
+        let states = main_loop::own_states(&session);
         DbusServer {
             session,
             handle,
@@ -58,7 +413,37 @@ impl DbusServer {
             api_token: RspotifyToken::default(),
             token_request: None,
             dbus_future: None,
+            connection: None,
             device_name,
+            market,
+            player_events,
+            advertised_capabilities: Capabilities::default(),
+            states,
+            modes: None,
+            advertised_modes: None,
+            now_playing: Rc::new(RefCell::new(NowPlaying {
+                status: PlaybackStatus::Stopped,
+                track: None,
+                metadata: None,
+                playback: None,
+                tracks: Vec::new(),
+                context_uri: None,
+                capabilities: Capabilities::default(),
+            })),
+            advertised_tracks: Vec::new(),
+            advertised_context: None,
+            metadata_request: None,
+            metadata_fetch,
+            metadata_cache: HashMap::new(),
+            advertised_status: PlaybackStatus::Stopped,
+            metadata_changed: false,
+            coalesce_window,
+            signal_timeout: None,
+            normalisation,
+            commands_enabled: Rc::new(Cell::new(!passive_startup)),
+            active: Rc::new(Cell::new(false)),
+            audio_features,
+            control_sender,
         }
     }
 
@@ -69,6 +454,242 @@ impl DbusServer {
             None => true,
         }
     }
+
+    /// Checks whether the capabilities or the modes changed with a new state,
+    /// and lets clients know if they did.
+    ///
+    /// Everything that changes within `coalesce_window` goes out in a single
+    /// `PropertiesChanged` signal. The position changes all the time, so it is
+    /// never signalled; clients read it when they need it.
+    fn poll_capabilities(&mut self) {
+        while let Ok(Async::Ready(Some(event))) = self.player_events.poll() {
+            self.active
+                .set(main_loop::is_active_after(&event, self.active.get()));
+            // The player only loads tracks once playback was transferred here.
+            if let PlayerEvent::Loading { .. } = event {
+                self.commands_enabled.set(true);
+            }
+            // Spirc doesn't always say so when another device takes over.
+            if let PlayerEvent::Stopped { .. } = event {
+                self.set_now_playing(PlaybackStatus::Stopped, None);
+                self.now_playing.borrow_mut().capabilities = Capabilities::default();
+                self.schedule_signal();
+            }
+            let seeked = {
+                let mut now_playing = self.now_playing.borrow_mut();
+                let seeked =
+                    matches!(now_playing.playback, Some(ref playback) if playback.is_seek(&event));
+                now_playing.playback = Playback::after(&event, now_playing.playback.take());
+                seeked
+            };
+            if seeked {
+                self.send_seeked();
+            }
+            // A lookup that failed, or found only some of it, is tried again
+            // whenever something happens to the track.
+            let incomplete = {
+                let now_playing = self.now_playing.borrow();
+                now_playing.track.is_some()
+                    && !matches!(now_playing.metadata, Some(ref metadata) if metadata.complete)
+            };
+            if incomplete && self.metadata_request.is_none() {
+                self.request_metadata();
+            }
+        }
+
+        while let Ok(Async::Ready(Some(state))) = self.states.poll() {
+            self.modes = Some(Modes {
+                shuffle: state.get_shuffle(),
+                repeat: state.get_repeat(),
+            });
+            let (status, track) = self.now_playing.borrow().read(&state);
+            self.set_now_playing(status, track);
+            {
+                let mut now_playing = self.now_playing.borrow_mut();
+                now_playing.tracks = NowPlaying::read_tracks(&state);
+                now_playing.capabilities = Capabilities::read(&state);
+                let context_uri = state.get_context_uri();
+                now_playing.context_uri = if context_uri.is_empty() {
+                    None
+                } else {
+                    Some(context_uri.to_string())
+                };
+            }
+            self.schedule_signal();
+        }
+
+        match self.metadata_request.as_mut().map(Future::poll) {
+            Some(Ok(Async::Ready(metadata))) => {
+                self.metadata_request = None;
+                let mut now_playing = self.now_playing.borrow_mut();
+                if metadata.complete {
+                    if self.metadata_cache.len() >= METADATA_CACHE_SIZE {
+                        self.metadata_cache.clear();
+                    }
+                    if let Some(track) = now_playing.track {
+                        self.metadata_cache.insert(track, metadata.clone());
+                    }
+                } else {
+                    info!("Only some of the metadata of the loaded track was found");
+                }
+                now_playing.metadata = Some(match now_playing.metadata.take() {
+                    Some(found) => found.merge(metadata),
+                    None => metadata,
+                });
+                drop(now_playing);
+                self.metadata_changed = true;
+                self.schedule_signal();
+            }
+            Some(Ok(Async::NotReady)) | None => (),
+            Some(Err(())) => {
+                self.metadata_request = None;
+                info!("Could not look up the metadata of the loaded track");
+            }
+        }
+
+        if let Some(Ok(Async::Ready(()))) = self.signal_timeout.as_mut().map(Future::poll) {
+            self.signal_timeout = None;
+            self.send_changed_properties();
+        }
+    }
+
+    /// Starts looking up the metadata when another track is loaded.
+    fn set_now_playing(&mut self, status: PlaybackStatus, track: Option<SpotifyId>) {
+        let mut now_playing = self.now_playing.borrow_mut();
+        now_playing.status = status;
+        if now_playing.track == track {
+            return;
+        }
+        now_playing.track = track;
+        now_playing.metadata = track.and_then(|track| self.metadata_cache.get(&track).cloned());
+        self.metadata_changed = true;
+        self.metadata_request = None;
+        if now_playing.track.is_some() && now_playing.metadata.is_none() {
+            drop(now_playing);
+            self.request_metadata();
+        }
+    }
+
+    fn request_metadata(&mut self) {
+        self.metadata_request = self.now_playing.borrow().track.map(|track| {
+            fetch_metadata_retrying(&self.session, &self.handle, track, self.metadata_fetch)
+        });
+    }
+
+    fn schedule_signal(&mut self) {
+        let unchanged = {
+            let now_playing = self.now_playing.borrow();
+            now_playing.capabilities == self.advertised_capabilities
+                && self.modes == self.advertised_modes
+                && now_playing.status == self.advertised_status
+                && now_playing.tracks == self.advertised_tracks
+                && now_playing.context_uri == self.advertised_context
+                && !self.metadata_changed
+        };
+        if self.signal_timeout.is_some() || unchanged {
+            return;
+        }
+        if self.coalesce_window > Duration::from_millis(0) {
+            if let Ok(timeout) = Timeout::new(self.coalesce_window, &self.handle) {
+                self.signal_timeout = Some(timeout);
+                return;
+            }
+        }
+        self.send_changed_properties();
+    }
+
+    fn send_changed_properties(&mut self) {
+        let now_playing = self.now_playing.borrow();
+        let mut changed = PropertiesPropertiesChanged {
+            interface_name: PLAYER_INTERFACE.to_string(),
+            changed_properties: now_playing
+                .capabilities
+                .changed_since(&self.advertised_capabilities),
+            invalidated_properties: Vec::new(),
+        };
+        if let Some(modes) = self.modes {
+            changed
+                .changed_properties
+                .extend(modes.changed_since(self.advertised_modes));
+        }
+        if now_playing.status != self.advertised_status {
+            changed.changed_properties.insert(
+                "PlaybackStatus".to_string(),
+                Variant(Box::new(now_playing.status.as_str().to_string())),
+            );
+        }
+        // Metadata is only sent once it is known, or empty once nothing is
+        // loaded: the lookup takes a moment after a new track is loaded.
+        if self.metadata_changed {
+            match (now_playing.track, now_playing.metadata.as_ref()) {
+                (Some(track), Some(metadata)) => {
+                    changed.changed_properties.insert(
+                        "Metadata".to_string(),
+                        Variant(Box::new(local_metadata(track, metadata))),
+                    );
+                    self.metadata_changed = false;
+                }
+                (None, _) => {
+                    let empty: HashMap<String, Variant<Box<dyn RefArg>>> = HashMap::new();
+                    changed
+                        .changed_properties
+                        .insert("Metadata".to_string(), Variant(Box::new(empty)));
+                    self.metadata_changed = false;
+                }
+                (Some(_), None) => (),
+            }
+        }
+        self.advertised_capabilities = now_playing.capabilities;
+        self.advertised_modes = self.modes;
+        self.advertised_status = now_playing.status;
+        // The Web API is only asked for the name of the playlist when a client
+        // wants to know it.
+        if now_playing.context_uri != self.advertised_context {
+            self.advertised_context = now_playing.context_uri.clone();
+            let invalidated = PropertiesPropertiesChanged {
+                interface_name: PLAYLISTS_INTERFACE.to_string(),
+                changed_properties: HashMap::new(),
+                invalidated_properties: vec!["ActivePlaylist".to_string()],
+            };
+            self.send(invalidated.to_emit_message(&MPRIS_PATH.into()));
+        }
+        if now_playing.tracks != self.advertised_tracks {
+            self.advertised_tracks = now_playing.tracks.clone();
+            let tracks: Vec<Path> = now_playing
+                .tracks
+                .iter()
+                .map(|track| track_object_path(Some(&track.to_base62()), &track.to_uri()))
+                .collect();
+            let replaced =
+                Message::new_signal(MPRIS_PATH, TRACKLIST_INTERFACE, "TrackListReplaced")
+                    .expect("TrackListReplaced is a valid signal")
+                    .append2(tracks, now_playing.track_path());
+            self.send(replaced);
+        }
+        drop(now_playing);
+        // Changes that were undone within the window aren't worth a signal.
+        if changed.changed_properties.is_empty() {
+            return;
+        }
+        self.send(changed.to_emit_message(&MPRIS_PATH.into()));
+    }
+
+    /// Tells clients that playback jumped to another position, which they
+    /// can't tell from the `Position` otherwise.
+    fn send_seeked(&self) {
+        let seeked = Message::new_signal(MPRIS_PATH, PLAYER_INTERFACE, "Seeked")
+            .expect("Seeked is a valid signal")
+            .append1(self.now_playing.borrow().position_us());
+        self.send(seeked);
+    }
+
+    fn send(&self, signal: Message) {
+        if let Some(ref connection) = self.connection {
+            if connection.send(signal).is_err() {
+                warn!("Failed to send a DBus signal");
+            }
+        }
+    }
 }
 
 impl Future for DbusServer {
@@ -84,22 +705,32 @@ impl Future for DbusServer {
                         .access_token(&token.access_token)
                         .expires_in(token.expires_in)
                         .expires_at(datetime_to_timestamp(token.expires_in));
-                    self.dbus_future = Some(create_dbus_server(
+                    let (connection, dbus_future) = create_dbus_server(
                         self.handle.clone(),
                         self.api_token.clone(),
                         self.spirc.clone(),
                         self.device_name.clone(),
-                    ));
+                        self.market,
+                        self.commands_enabled.clone(),
+                        self.normalisation.clone(),
+                        self.session.clone(),
+                        self.active.clone(),
+                        self.audio_features.clone(),
+                        self.control_sender.clone(),
+                        self.now_playing.clone(),
+                    );
+                    self.connection = Some(connection);
+                    self.dbus_future = Some(dbus_future);
                     got_new_token = true;
                 }
             } else {
-                // This is more meant as a fast hotfix than anything else!
-                let client_id =
-                    env::var("SPOTIFYD_CLIENT_ID").unwrap_or_else(|_| CLIENT_ID.to_string());
-                self.token_request = Some(get_token(&self.session, &client_id, SCOPE));
+                self.token_request = Some(get_token(&self.session, &web_api::client_id(), SCOPE));
+            }
+        } else if self.dbus_future.is_some() {
+            self.poll_capabilities();
+            if let Some(ref mut fut) = self.dbus_future {
+                return fut.poll();
             }
-        } else if let Some(ref mut fut) = self.dbus_future {
-            return fut.poll();
         }
 
         if got_new_token {
@@ -110,30 +741,327 @@ impl Future for DbusServer {
     }
 }
 
+/// While spotifyd isn't the active device, librespot passes playback commands
+/// on to the device that is. With `passive_startup`, they are ignored until
+/// playback is transferred to spotifyd, so it doesn't take over other devices.
+fn check_commands_enabled(commands_enabled: &Cell<bool>, message: &Message) -> bool {
+    let enabled = commands_enabled.get();
+    if !enabled {
+        info!(
+            "Ignoring {} until playback is transferred to this device",
+            message.member().map_or_else(String::new, |m| m.to_string())
+        );
+    }
+    enabled
+}
+
+/// Describes the normalisation of the current track. Only `Enabled` and
+/// `Checked` are always present.
+fn normalisation_status(normalisation: Normalisation) -> HashMap<String, Variant<Box<dyn RefArg>>> {
+    let mut status: HashMap<String, Variant<Box<dyn RefArg>>> = HashMap::new();
+    let enabled = normalisation != Normalisation::Disabled;
+    status.insert("Enabled".to_string(), Variant(Box::new(enabled)));
+    match normalisation {
+        Normalisation::Disabled | Normalisation::Unknown => {
+            status.insert("Checked".to_string(), Variant(Box::new(false)));
+        }
+        Normalisation::Checked {
+            data_present,
+            track_gain_db,
+            track_peak,
+            factor,
+        } => {
+            status.insert("Checked".to_string(), Variant(Box::new(true)));
+            status.insert("DataPresent".to_string(), Variant(Box::new(data_present)));
+            status.insert(
+                "TrackGainDb".to_string(),
+                Variant(Box::new(f64::from(track_gain_db))),
+            );
+            status.insert(
+                "TrackPeak".to_string(),
+                Variant(Box::new(f64::from(track_peak))),
+            );
+            status.insert("Factor".to_string(), Variant(Box::new(f64::from(factor))));
+        }
+    }
+    status
+}
+
+/// Describes how the current track sounds. Everything is left out when
+/// `fetch_audio_features` is off or the features aren't known.
+fn audio_features_status(
+    features: Option<AudioFeatures>,
+) -> HashMap<String, Variant<Box<dyn RefArg>>> {
+    let mut status: HashMap<String, Variant<Box<dyn RefArg>>> = HashMap::new();
+    if let Some(features) = features {
+        status.insert(
+            "Tempo".to_string(),
+            Variant(Box::new(f64::from(features.tempo))),
+        );
+        status.insert(
+            "Energy".to_string(),
+            Variant(Box::new(f64::from(features.energy))),
+        );
+        if let Some(key) = features.key {
+            status.insert("Key".to_string(), Variant(Box::new(i32::from(key))));
+        }
+        if let Some(mode) = features.mode {
+            let mode = match mode {
+                Mode::Major => "major",
+                Mode::Minor => "minor",
+            };
+            status.insert("Mode".to_string(), Variant(Box::new(mode.to_string())));
+        }
+    }
+    status
+}
+
 fn create_spotify_api(token: &RspotifyToken) -> Spotify {
     Spotify::default().access_token(&token.access_token).build()
 }
 
+fn position_ms(position_us: i64) -> u32 {
+    u32::try_from(position_us / 1000).unwrap_or(u32::MAX)
+}
+
+/// What a method of the tree answers with, once the Web API has.
+type MethodReply = Box<dyn Future<Item = Vec<Message>, Error = MethodErr>>;
+
+/// Answers a method with `mret` if `result` succeeds.
+fn reply_when<T: 'static>(
+    mret: Message,
+    result: Box<dyn Future<Item = T, Error = String>>,
+) -> MethodReply {
+    Box::new(
+        result
+            .map(|_| vec![mret])
+            .map_err(|e| MethodErr::failed(&e)),
+    )
+}
+
+/// Runs `f` with the Web API on another thread, as its requests block.
+fn with_api<T, F>(token: &RspotifyToken, f: F) -> Box<dyn Future<Item = T, Error = String>>
+where
+    T: Send + 'static,
+    F: FnOnce(&Spotify) -> Result<T, String> + Send + 'static,
+{
+    let (tx, rx) = oneshot::channel();
+    let token = token.clone();
+    thread::spawn(move || {
+        let _ = tx.send(f(&create_spotify_api(&token)));
+    });
+    Box::new(rx.map_err(|e| e.to_string()).and_then(|result| result))
+}
+
+/// Describes a track with what the Web API knows about it.
+fn web_metadata(track: FullTrack) -> HashMap<String, Variant<Box<dyn RefArg>>> {
+    let mut m: HashMap<String, Variant<Box<dyn RefArg>>> = HashMap::new();
+    m.insert(
+        "mpris:trackid".to_string(),
+        Variant(Box::new(MessageItem::ObjectPath(track_object_path(
+            track.id.as_deref(),
+            &track.uri,
+        )))),
+    );
+    m.insert(
+        "mpris:length".to_string(),
+        Variant(Box::new(MessageItem::Int64(
+            i64::from(track.duration_ms) * 1000,
+        ))),
+    );
+    if let Some(image) = track.album.images.first() {
+        m.insert(
+            "mpris:artUrl".to_string(),
+            Variant(Box::new(MessageItem::Str(image.url.clone()))),
+        );
+    }
+    m.insert(
+        "xesam:title".to_string(),
+        Variant(Box::new(MessageItem::Str(track.name))),
+    );
+    m.insert(
+        "xesam:album".to_string(),
+        Variant(Box::new(MessageItem::Str(track.album.name))),
+    );
+    let names = |artists: &[SimplifiedArtist]| {
+        MessageItem::Array(
+            MessageItemArray::new(
+                artists
+                    .iter()
+                    .map(|a| MessageItem::Str(a.name.to_string()))
+                    .collect::<Vec<_>>(),
+                Signature::new("as").unwrap(),
+            )
+            .unwrap(),
+        )
+    };
+    m.insert(
+        "xesam:artist".to_string(),
+        Variant(Box::new(names(&track.artists))),
+    );
+    m.insert(
+        "xesam:albumArtist".to_string(),
+        Variant(Box::new(names(&track.album.artists))),
+    );
+    m.insert(
+        "xesam:autoRating".to_string(),
+        Variant(Box::new(MessageItem::Double(
+            f64::from(track.popularity) / 100.0,
+        ))),
+    );
+    m.insert(
+        "xesam:trackNumber".to_string(),
+        Variant(Box::new(MessageItem::UInt32(track.track_number))),
+    );
+    m.insert(
+        "xesam:discNumber".to_string(),
+        Variant(Box::new(MessageItem::Int32(track.disc_number))),
+    );
+    m.insert(
+        "xesam:url".to_string(),
+        Variant(Box::new(MessageItem::Str(
+            track
+                .external_urls
+                .iter()
+                .next()
+                .map_or("", |(_, v)| v)
+                .to_string(),
+        ))),
+    );
+    m
+}
+
+/// Looks up the tracks with the given ids, in the same order.
+fn fetch_tracks(
+    sp: &Spotify,
+    ids: &[String],
+    market: Option<Country>,
+) -> Result<Vec<FullTrack>, String> {
+    let mut tracks = Vec::with_capacity(ids.len());
+    for ids in ids.chunks(TRACKS_PER_REQUEST) {
+        let found = sp
+            .tracks(ids.iter().map(String::as_str).collect(), market)
+            .map_err(|e| format!("could not look up the tracks: {}", e))?;
+        tracks.extend(found.tracks);
+    }
+    Ok(tracks)
+}
+
+/// Returns all playlists of the user, in the order they have them in.
+fn fetch_playlists(sp: &Spotify) -> Result<Vec<SimplifiedPlaylist>, String> {
+    let mut playlists = Vec::new();
+    loop {
+        let page = sp
+            .current_user_playlists(PLAYLISTS_PER_REQUEST, playlists.len() as u32)
+            .map_err(|e| format!("could not list the playlists: {}", e))?;
+        let last = page.next.is_none() || page.items.is_empty();
+        playlists.extend(page.items);
+        if last {
+            return Ok(playlists);
+        }
+    }
+}
+
+/// Returns the id of the playlist `uri` stands for, also in the
+/// `spotify:user:<user>:playlist:<id>` form of older clients.
+fn playlist_id(uri: &str) -> Option<&str> {
+    let mut parts = uri.rsplitn(2, ":playlist:");
+    match (parts.next(), parts.next()) {
+        (Some(id), Some(_)) => Some(id),
+        _ => None,
+    }
+}
+
+/// Describes a playlist the way MPRIS does, with its first image as the icon.
+fn playlist_item(id: &str, name: String, images: &[Image]) -> (Path<'static>, String, String) {
+    let icon = images
+        .first()
+        .map_or_else(String::new, |image| image.url.clone());
+    (playlist_object_path(id), name, icon)
+}
+
+/// Starts playback on this device. Spirc can't load a context by itself, so
+/// this goes through the Web API.
+fn play_here(
+    sp: &Spotify,
+    session: &Session,
+    device_name: &str,
+    context_uri: Option<String>,
+    uris: Option<Vec<String>>,
+    offset: Option<Offset>,
+) -> Result<(), String> {
+    let device_id = main_loop::wait_for_device(sp, session, device_name)?;
+    sp.start_playback(Some(device_id), context_uri, uris, offset, None)
+        .map_err(|e| format!("could not start playback: {}", e))
+}
+
+/// Returns the id, name, type and whether it is active for every device.
+fn list_devices(sp: &Spotify) -> Result<Vec<(String, String, String, bool)>, String> {
+    let devices = sp
+        .device()
+        .map_err(|e| format!("could not list the devices: {}", e))?;
+    Ok(devices
+        .devices
+        .into_iter()
+        .map(|d| (d.id, d.name, format!("{:?}", d._type), d.is_active))
+        .collect())
+}
+
+/// Moves playback to another device. rspotify's `transfer_playback` sends a
+/// misspelt request, so this resumes playback on the device instead, which
+/// transfers it all the same.
+fn transfer_playback(sp: &Spotify, device_id: &str) -> Result<(), String> {
+    let devices = sp
+        .device()
+        .map_err(|e| format!("could not list the devices: {}", e))?;
+    match devices.devices.iter().find(|d| d.id == device_id) {
+        Some(device) if device.is_restricted => Err(format!(
+            "\"{}\" doesn't accept commands through the Web API",
+            device.name
+        )),
+        Some(_) => sp
+            .start_playback(Some(device_id.to_string()), None, None, None, None)
+            .map_err(|e| format!("could not transfer playback: {}", e)),
+        None => Err(format!(
+            "there is no device with id {}, it may be offline",
+            device_id
+        )),
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
 fn create_dbus_server(
     handle: Handle,
     api_token: RspotifyToken,
     spirc: Rc<Spirc>,
     device_name: String,
-) -> Box<dyn Future<Item = (), Error = ()>> {
+    market: Option<Country>,
+    commands_enabled: Rc<Cell<bool>>,
+    normalisation: Rc<Cell<Normalisation>>,
+    session: Session,
+    active: Rc<Cell<bool>>,
+    audio_features: Option<AudioFeatureCache>,
+    control_sender: RequestSender,
+    now_playing: Rc<RefCell<NowPlaying>>,
+) -> (Rc<Connection>, Box<dyn Future<Item = (), Error = ()>>) {
     macro_rules! spotify_api_method {
         ([ $sp:ident, $device:ident $(, $m:ident: $t:ty)*] $f:expr) => {
             {
                 let device_name = utf8_percent_encode(&device_name, NON_ALPHANUMERIC).to_string();
                 let token = api_token.clone();
+                let commands_enabled = commands_enabled.clone();
                 move |m| {
                     let (p, c) = oneshot::channel();
                     let token = token.clone();
                     let device_name = device_name.clone();
+                    let enabled = check_commands_enabled(&commands_enabled, &m.msg);
                     $(let $m: Result<$t,_> = m.msg.read1();)*
                     thread::spawn(move || {
-                        let $sp = create_spotify_api(&token);
-                        let $device = Some(device_name);
-                        let _ = $f;
+                        if enabled {
+                            let $sp = create_spotify_api(&token);
+                            let $device = Some(device_name);
+                            let _ = $f;
+                        }
                         let _ = p.send(());
                     });
                     let mret = m.msg.method_return();
@@ -202,17 +1130,19 @@ fn create_dbus_server(
         .property::<bool, _>("HasTrackList", ())
         .access(Access::Read)
         .on_get(|iter, _| {
-            iter.append(false);
+            iter.append(true);
             Ok(())
         });
 
-    let property_identity = f
-        .property::<String, _>("Identity", ())
-        .access(Access::Read)
-        .on_get(|iter, _| {
-            iter.append("Spotifyd".to_string());
-            Ok(())
-        });
+    let property_identity = {
+        let identity = device_name.clone();
+        f.property::<String, _>("Identity", ())
+            .access(Access::Read)
+            .on_get(move |iter, _| {
+                iter.append(identity.clone());
+                Ok(())
+            })
+    };
 
     let property_supported_uri_schemes = f
         .property::<Vec<String>, _>("SupportedUriSchemes", ())
@@ -260,74 +1190,134 @@ fn create_dbus_server(
     // https://specifications.freedesktop.org/mpris-spec/latest/Player_Interface.html
     let method_next = {
         let local_spirc = spirc.clone();
+        let commands_enabled = commands_enabled.clone();
         f.amethod("Next", (), move |m| {
-            local_spirc.next();
+            if check_commands_enabled(&commands_enabled, &m.msg) {
+                local_spirc.next();
+            }
             Ok(vec![m.msg.method_return()])
         })
     };
 
     let method_previous = {
         let local_spirc = spirc.clone();
+        let commands_enabled = commands_enabled.clone();
         f.amethod("Previous", (), move |m| {
-            local_spirc.prev();
+            if check_commands_enabled(&commands_enabled, &m.msg) {
+                local_spirc.prev();
+            }
             Ok(vec![m.msg.method_return()])
         })
     };
 
     let method_pause = {
         let local_spirc = spirc.clone();
+        let commands_enabled = commands_enabled.clone();
         f.method("Pause", (), move |m| {
-            local_spirc.pause();
+            if check_commands_enabled(&commands_enabled, &m.msg) {
+                local_spirc.pause();
+            }
             Ok(vec![m.msg.method_return()])
         })
     };
 
     let method_play_pause = {
         let local_spirc = spirc.clone();
+        let commands_enabled = commands_enabled.clone();
         f.amethod("PlayPause", (), move |m| {
-            local_spirc.play_pause();
+            if check_commands_enabled(&commands_enabled, &m.msg) {
+                local_spirc.play_pause();
+            }
             Ok(vec![m.msg.method_return()])
         })
     };
 
     let method_play = {
         let local_spirc = spirc.clone();
+        let commands_enabled = commands_enabled.clone();
         f.method("Play", (), move |m| {
-            local_spirc.play();
+            if check_commands_enabled(&commands_enabled, &m.msg) {
+                local_spirc.play();
+            }
             Ok(vec![m.msg.method_return()])
         })
     };
 
     let method_stop = {
-        let local_spirc = spirc;
+        let local_spirc = spirc.clone();
+        let commands_enabled = commands_enabled.clone();
         f.amethod("Stop", (), move |m| {
             // TODO: add real stop implementation.
-            local_spirc.pause();
+            if check_commands_enabled(&commands_enabled, &m.msg) {
+                local_spirc.pause();
+            }
             Ok(vec![m.msg.method_return()])
         })
     };
 
-    let method_seek = f.amethod(
-        "Seek",
-        (),
-        spotify_api_method!([sp, device, pos: u32]{
-            if let Ok(p) = pos {
-                if let Ok(Some(playing)) = sp.current_user_playing_track() {
-                    let _ = sp.seek_track(playing.progress_ms.unwrap_or(0) + p, device);
+    // Positions are in microseconds. Where playback is comes from the player
+    // events, and seeks go through the main loop like those of
+    // `control_socket`.
+    let method_seek = {
+        let local_spirc = spirc;
+        let commands_enabled = commands_enabled.clone();
+        let control_sender = control_sender.clone();
+        let now_playing = now_playing.clone();
+        f.amethod("Seek", (), move |m| -> MethodReply {
+            let mret = m.msg.method_return();
+            let offset: i64 = match m.msg.read1() {
+                Ok(offset) => offset,
+                Err(e) => return Box::new(futures::future::err(MethodErr::from(e))),
+            };
+            let now_playing = now_playing.borrow();
+            if now_playing.track.is_none() || !check_commands_enabled(&commands_enabled, m.msg) {
+                return Box::new(futures::future::ok(vec![mret]));
+            }
+            let position = now_playing.position_us().saturating_add(offset).max(0);
+            match now_playing.duration_us() {
+                // Seeking past the end goes to the next track.
+                Some(duration) if position > duration => {
+                    local_spirc.next();
+                    Box::new(futures::future::ok(vec![mret]))
                 }
+                _ => reply_when(
+                    mret,
+                    control::send(&control_sender, Command::Seek(position_ms(position))),
+                ),
             }
-        }),
-    );
+        })
+        .inarg::<i64, _>("Offset")
+    };
 
-    let method_set_position = f.amethod(
-        "SetPosition",
-        (),
-        spotify_api_method!([sp, device, pos: u32]
-            if let Ok(p) = pos {
-                let _ = sp.seek_track(p, device);
+    let method_set_position = {
+        let commands_enabled = commands_enabled.clone();
+        let control_sender = control_sender.clone();
+        let now_playing = now_playing.clone();
+        f.amethod("SetPosition", (), move |m| -> MethodReply {
+            let mret = m.msg.method_return();
+            let (track, position): (Path, i64) = match m.msg.read2() {
+                Ok(args) => args,
+                Err(e) => return Box::new(futures::future::err(MethodErr::from(e))),
+            };
+            let now_playing = now_playing.borrow();
+            // A position for a track that isn't loaded anymore, or outside of
+            // the track, is ignored.
+            let ignored = now_playing.track.is_none()
+                || track != now_playing.track_path()
+                || position < 0
+                || matches!(now_playing.duration_us(), Some(duration) if position > duration)
+                || !check_commands_enabled(&commands_enabled, m.msg);
+            if ignored {
+                return Box::new(futures::future::ok(vec![mret]));
             }
-        ),
-    );
+            reply_when(
+                mret,
+                control::send(&control_sender, Command::Seek(position_ms(position))),
+            )
+        })
+        .inarg::<Path, _>("TrackId")
+        .inarg::<i64, _>("Position")
+    };
 
     let method_open_uri = f.amethod(
         "OpenUri",
@@ -354,45 +1344,43 @@ fn create_dbus_server(
         ),
     );
 
-    let property_playback_status = f
-        .property::<String, _>("PlaybackStatus", ())
-        .access(Access::Read)
-        .on_get(spotify_api_property!([sp, _device]
-                    if let Ok(Some(player)) = sp.current_playback(None) {
-                        let device_name = utf8_percent_encode(&player.device.name, NON_ALPHANUMERIC).to_string();
-                        if device_name == _device.unwrap() {
-                            if let Ok(Some(track)) = sp.current_user_playing_track() {
-                                if track.is_playing {
-                                    "Playing"
-                                } else {
-                                    "Paused"
-                                }
-                            } else {
-                                "Stopped"
-                            }
-                        } else {
-                            "Stopped"
-                        }
-                    } else {
-                        "Stopped"
-                    }.to_string()));
+    // Spirc's state is known right away, also for a paused session that was
+    // taken over at startup, which the Web API only reports after a while.
+    let property_playback_status = {
+        let now_playing = now_playing.clone();
+        f.property::<String, _>("PlaybackStatus", ())
+            .access(Access::Read)
+            .on_get(move |iter, _| {
+                iter.append(now_playing.borrow().status.as_str().to_string());
+                Ok(())
+            })
+    };
 
     let property_shuffle = f
         .property::<bool, _>("Shuffle", ())
         .access(Access::Read)
         .on_get(spotify_api_property!([sp, _device]
-            if let Ok(Some(player)) = sp.current_playback(None) {
+            if let Ok(Some(player)) = sp.current_playback(market) {
                 player.shuffle_state
             } else {
                 false
             }
         ));
 
+    // Spotify doesn't support variable playback speeds, so the spec requires us to
+    // report a fixed rate of 1.0 and to ignore any attempt to change it.
     let property_rate = f
         .property::<f64, _>("Rate", ())
-        .access(Access::Read)
+        .access(Access::ReadWrite)
         .on_get(|iter, _| {
-            iter.append(1.0);
+            iter.append(PLAYBACK_RATE);
+            Ok(())
+        })
+        .on_set(|iter, _| {
+            let rate: f64 = iter.read()?;
+            if (rate - PLAYBACK_RATE).abs() > f64::EPSILON {
+                info!("Ignoring request to set the playback rate to {}", rate);
+            }
             Ok(())
         });
 
@@ -400,7 +1388,7 @@ fn create_dbus_server(
         .property::<f64, _>("Volume", ())
         .access(Access::Read)
         .on_get(spotify_api_property!([sp, _device]
-            if let Ok(Some(player)) = sp.current_playback(None) {
+            if let Ok(Some(player)) = sp.current_playback(market) {
                 player.device.volume_percent as f64
             } else {
                 0.0
@@ -411,7 +1399,7 @@ fn create_dbus_server(
         .property::<f64, _>("MaximumRate", ())
         .access(Access::Read)
         .on_get(|iter, _| {
-            iter.append(1.0);
+            iter.append(PLAYBACK_RATE);
             Ok(())
         });
 
@@ -419,7 +1407,7 @@ fn create_dbus_server(
         .property::<f64, _>("MinimumRate", ())
         .access(Access::Read)
         .on_get(|iter, _| {
-            iter.append(1.0);
+            iter.append(PLAYBACK_RATE);
             Ok(())
         });
 
@@ -427,7 +1415,7 @@ fn create_dbus_server(
         .property::<String, _>("LoopStatus", ())
         .access(Access::Read)
         .on_get(spotify_api_property!([sp, _device]
-            if let Ok(Some(player)) = sp.current_playback(None) {
+            if let Ok(Some(player)) = sp.current_playback(market) {
                 match player.repeat_state {
                     RepeatState::Off => "None",
                     RepeatState::Track => "Track",
@@ -438,125 +1426,76 @@ fn create_dbus_server(
             }.to_string()
         ));
 
-    let property_position = f
-        .property::<i64, _>("Position", ())
-        .access(Access::Read)
-        .on_get(spotify_api_property!([sp, _device]
-            if let Ok(Some(pos)) =
-                sp.current_playback(None)
-                .map(|maybe_player| maybe_player.and_then(|p| p.progress_ms)) {
-                i64::from(pos) * 1000
-            } else {
-                0
-            }
-        ));
+    let property_position = {
+        let now_playing = now_playing.clone();
+        f.property::<i64, _>("Position", ())
+            .access(Access::Read)
+            .on_get(move |iter, _| {
+                iter.append(now_playing.borrow().position_us());
+                Ok(())
+            })
+    };
 
     let property_metadata = f
         .property::<HashMap<String, Variant<Box<dyn RefArg>>>, _>("Metadata", ())
         .access(Access::Read)
-        .on_get(spotify_api_property!([sp, _device] {
-            let mut m = HashMap::new();
-            let v = sp.current_user_playing_track();
-
-            if let Ok(Some(playing)) = v {
-                if let Some(track) = playing.item {
-                    m.insert("mpris:trackid".to_string(), Variant(Box::new(
-                        MessageItem::Str(
-                            track.uri
-                        )) as Box<dyn RefArg>));
-
-                    m.insert("mpris:length".to_string(), Variant(Box::new(
-                        MessageItem::Int64(
-                            i64::from(track.duration_ms) * 1000
-                        )) as Box<dyn RefArg>));
-
-                    m.insert("mpris:artUrl".to_string(), Variant(Box::new(
-                        MessageItem::Str(
-                            track.album.images
-                                .first()
-                                .unwrap().url.clone()
-                        )) as Box<dyn RefArg>));
-
-                    m.insert("xesam:title".to_string(), Variant(Box::new(
-                        MessageItem::Str(
-                            track.name
-                        )) as Box<dyn RefArg>));
-
-                    m.insert("xesam:album".to_string(), Variant(Box::new(
-                        MessageItem::Str(
-                            track.album.name
-                        )) as Box<dyn RefArg>));
-
-                    m.insert("xesam:artist".to_string(), Variant(Box::new(
-                        MessageItem::Array(MessageItemArray::new(
-                            track.artists
-                                .iter()
-                                .map(|a| MessageItem::Str(a.name.to_string()))
-                                .collect::<Vec<_>>(), Signature::new("as").unwrap()
-                        ).unwrap())) as Box<dyn RefArg>));
-
-                    m.insert("xesam:albumArtist".to_string(), Variant(Box::new(
-                        MessageItem::Array(MessageItemArray::new(
-                            track.album.artists
-                                .iter()
-                                .map(|a| MessageItem::Str(a.name.to_string()))
-                                .collect::<Vec<_>>(), Signature::new("as").unwrap()
-                        ).unwrap())) as Box<dyn RefArg>));
-
-                    m.insert("xesam:autoRating".to_string(), Variant(Box::new(
-                        MessageItem::Double(
-                            f64::from(track.popularity) / 100.0
-                        )) as Box<dyn RefArg>));
-
-                    m.insert("xesam:trackNumber".to_string(), Variant(Box::new(
-                        MessageItem::UInt32(
-                            track.track_number
-                        )) as Box<dyn RefArg>));
-
-                    m.insert("xesam:discNumber".to_string(), Variant(Box::new(
-                        MessageItem::Int32(
-                            track.disc_number
-                        )) as Box<dyn RefArg>));
-
-                    m.insert("xesam:url".to_string(), Variant(Box::new(
-                        MessageItem::Str(
-                            track.external_urls
-                                .iter()
-                                .next()
-                                .map_or("", |(_, v)| &v)
-                                .to_string()
-                        )) as Box<dyn RefArg>));
+        .on_get({
+            let now_playing = now_playing.clone();
+            spotify_api_property!([sp, _device] {
+                let mut m = HashMap::new();
+                let now_playing = now_playing.borrow();
+                let uri = now_playing.track.map(|track| track.to_uri());
+                // Nothing is loaded here, so there is nothing to describe.
+                let v = if uri.is_some() { sp.current_user_playing_track() } else { Ok(None) };
+
+                if let Ok(Some(playing)) = v {
+                    // The Web API can still be on the track before.
+                    if let Some(track) = playing.item.filter(|track| Some(&track.uri) == uri.as_ref()) {
+                        m = web_metadata(track);
+                    }
+                } else if uri.is_some() {
+                    info!("Couldn't fetch metadata from spotify: {:?}", v);
                 }
-            } else {
-                info!("Couldn't fetch metadata from spotify: {:?}", v);
-            }
 
-            m
-        }));
+                if m.is_empty() {
+                    if let (Some(track), Some(metadata)) = (now_playing.track, now_playing.metadata.as_ref()) {
+                        m = local_metadata(track, metadata);
+                    }
+                }
 
-    let property_can_play = f
-        .property::<bool, _>("CanPlay", ())
-        .access(Access::Read)
-        .on_get(|iter, _| {
-            iter.append(true);
-            Ok(())
+                m
+            })
         });
 
-    let property_can_pause = f
-        .property::<bool, _>("CanPause", ())
-        .access(Access::Read)
-        .on_get(|iter, _| {
-            iter.append(true);
-            Ok(())
-        });
+    let property_can_play = {
+        let now_playing = now_playing.clone();
+        f.property::<bool, _>("CanPlay", ())
+            .access(Access::Read)
+            .on_get(move |iter, _| {
+                iter.append(now_playing.borrow().capabilities.can_play);
+                Ok(())
+            })
+    };
 
-    let property_can_seek = f
-        .property::<bool, _>("CanSeek", ())
-        .access(Access::Read)
-        .on_get(|iter, _| {
-            iter.append(true);
-            Ok(())
-        });
+    let property_can_pause = {
+        let now_playing = now_playing.clone();
+        f.property::<bool, _>("CanPause", ())
+            .access(Access::Read)
+            .on_get(move |iter, _| {
+                iter.append(now_playing.borrow().capabilities.can_pause);
+                Ok(())
+            })
+    };
+
+    let property_can_seek = {
+        let now_playing = now_playing.clone();
+        f.property::<bool, _>("CanSeek", ())
+            .access(Access::Read)
+            .on_get(move |iter, _| {
+                iter.append(now_playing.borrow().capabilities.can_seek);
+                Ok(())
+            })
+    };
 
     let property_can_control = f
         .property::<bool, _>("CanControl", ())
@@ -566,24 +1505,28 @@ fn create_dbus_server(
             Ok(())
         });
 
-    let property_can_go_previous = f
-        .property::<bool, _>("CanGoPrevious", ())
-        .access(Access::Read)
-        .on_get(|iter, _| {
-            iter.append(true);
-            Ok(())
-        });
+    let property_can_go_previous = {
+        let now_playing = now_playing.clone();
+        f.property::<bool, _>("CanGoPrevious", ())
+            .access(Access::Read)
+            .on_get(move |iter, _| {
+                iter.append(now_playing.borrow().capabilities.can_go_previous);
+                Ok(())
+            })
+    };
 
-    let property_can_go_next = f
-        .property::<bool, _>("CanGoNext", ())
-        .access(Access::Read)
-        .on_get(|iter, _| {
-            iter.append(true);
-            Ok(())
-        });
+    let property_can_go_next = {
+        let now_playing = now_playing.clone();
+        f.property::<bool, _>("CanGoNext", ())
+            .access(Access::Read)
+            .on_get(move |iter, _| {
+                iter.append(now_playing.borrow().capabilities.can_go_next);
+                Ok(())
+            })
+    };
 
     let media_player2_player_interface = f
-        .interface("org.mpris.MediaPlayer2.Player", ())
+        .interface(PLAYER_INTERFACE, ())
         .add_m(method_next)
         .add_m(method_previous)
         .add_m(method_pause)
@@ -607,13 +1550,381 @@ fn create_dbus_server(
         .add_p(property_can_control)
         .add_p(property_can_go_next)
         .add_p(property_can_go_previous)
-        .add_p(property_shuffle);
+        .add_p(property_shuffle)
+        .add_s(f.signal("Seeked", ()).sarg::<i64, _>("Position"));
+
+    // The following methods and properties are part of the MediaPlayer2.TrackList interface.
+    // https://specifications.freedesktop.org/mpris-spec/latest/Track_List_Interface.html
+    //
+    // It lists the current track and those after it, as Spirc has them.
+    // Spotify can't put a track anywhere else than next in its queue, so the
+    // tracks can't be edited.
+    let property_tracks = {
+        let now_playing = now_playing.clone();
+        f.property::<Vec<Path>, _>("Tracks", ())
+            .access(Access::Read)
+            .emits_changed(EmitsChangedSignal::Invalidates)
+            .on_get(move |iter, _| {
+                let tracks: Vec<Path> = now_playing
+                    .borrow()
+                    .tracks
+                    .iter()
+                    .map(|track| track_object_path(Some(&track.to_base62()), &track.to_uri()))
+                    .collect();
+                iter.append(tracks);
+                Ok(())
+            })
+    };
+
+    let property_can_edit_tracks = f
+        .property::<bool, _>("CanEditTracks", ())
+        .access(Access::Read)
+        .on_get(|iter, _| {
+            iter.append(false);
+            Ok(())
+        });
+
+    let method_get_tracks_metadata = {
+        let token = api_token.clone();
+        f.amethod("GetTracksMetadata", (), move |m| -> MethodReply {
+            let mret = m.msg.method_return();
+            let ids: Vec<String> = match m.msg.read1::<Vec<Path>>() {
+                Ok(paths) => paths
+                    .iter()
+                    .filter_map(|path| id_in_path(path, "track"))
+                    .map(str::to_string)
+                    .collect(),
+                Err(e) => return Box::new(futures::future::err(MethodErr::from(e))),
+            };
+            let tracks = with_api(&token, move |sp| fetch_tracks(sp, &ids, market));
+            Box::new(
+                tracks
+                    .map(|tracks| {
+                        let metadata: Vec<_> = tracks.into_iter().map(web_metadata).collect();
+                        vec![mret.append1(metadata)]
+                    })
+                    .map_err(|e| MethodErr::failed(&e)),
+            )
+        })
+        .inarg::<Vec<Path>, _>("TrackIds")
+        .outarg::<Vec<HashMap<&str, Variant<Box<dyn RefArg>>>>, _>("Metadata")
+    };
+
+    let method_add_track = f
+        .amethod("AddTrack", (), |m| Ok(vec![m.msg.method_return()]))
+        .inarg::<&str, _>("Uri")
+        .inarg::<Path, _>("AfterTrack")
+        .inarg::<bool, _>("SetAsCurrent");
+
+    let method_remove_track = f
+        .amethod("RemoveTrack", (), |m| Ok(vec![m.msg.method_return()]))
+        .inarg::<Path, _>("TrackId");
+
+    // Spirc can only skip one track at a time, so the context is played again
+    // from the track through the Web API.
+    let method_go_to = {
+        let token = api_token.clone();
+        let commands_enabled = commands_enabled.clone();
+        let now_playing = now_playing.clone();
+        let device_name = device_name.clone();
+        let session = session.clone();
+        f.amethod("GoTo", (), move |m| -> MethodReply {
+            let mret = m.msg.method_return();
+            let path: Path = match m.msg.read1() {
+                Ok(path) => path,
+                Err(e) => return Box::new(futures::future::err(MethodErr::from(e))),
+            };
+            let now_playing = now_playing.borrow();
+            let index = id_in_path(&path, "track")
+                .and_then(|id| SpotifyId::from_base62(id).ok())
+                .and_then(|id| now_playing.tracks.iter().position(|&track| track == id));
+            let index = match index {
+                Some(index) if check_commands_enabled(&commands_enabled, m.msg) => index,
+                // Tracks that aren't in the list anymore can't be gone to.
+                _ => return Box::new(futures::future::ok(vec![mret])),
+            };
+            let uri = now_playing.tracks[index].to_uri();
+            let (context_uri, uris, offset) = match now_playing.context_uri {
+                Some(ref context_uri) => (
+                    Some(context_uri.clone()),
+                    None,
+                    Some(Offset {
+                        position: None,
+                        uri: Some(uri),
+                    }),
+                ),
+                None => (
+                    None,
+                    Some(
+                        now_playing.tracks[index..]
+                            .iter()
+                            .map(SpotifyId::to_uri)
+                            .collect(),
+                    ),
+                    None,
+                ),
+            };
+            let device_name = device_name.clone();
+            let session = session.clone();
+            reply_when(
+                mret,
+                with_api(&token, move |sp| {
+                    play_here(sp, &session, &device_name, context_uri, uris, offset)
+                }),
+            )
+        })
+        .inarg::<Path, _>("TrackId")
+    };
+
+    let tracklist_interface = f
+        .interface(TRACKLIST_INTERFACE, ())
+        .add_m(method_get_tracks_metadata)
+        .add_m(method_add_track)
+        .add_m(method_remove_track)
+        .add_m(method_go_to)
+        .add_p(property_tracks)
+        .add_p(property_can_edit_tracks)
+        .add_s(
+            f.signal("TrackListReplaced", ())
+                .sarg::<Vec<Path>, _>("Tracks")
+                .sarg::<Path, _>("CurrentTrack"),
+        );
+
+    // The following methods and properties are part of the MediaPlayer2.Playlists interface.
+    // https://specifications.freedesktop.org/mpris-spec/latest/Playlists_Interface.html
+    //
+    // These are the playlists of the user, as the Web API lists them.
+    let method_activate_playlist = {
+        let token = api_token.clone();
+        let commands_enabled = commands_enabled.clone();
+        let device_name = device_name.clone();
+        let session = session.clone();
+        f.amethod("ActivatePlaylist", (), move |m| -> MethodReply {
+            let mret = m.msg.method_return();
+            let id = match m.msg.read1::<Path>() {
+                Ok(path) => id_in_path(&path, "playlist").map(str::to_string),
+                Err(e) => return Box::new(futures::future::err(MethodErr::from(e))),
+            };
+            let id = match id {
+                Some(id) => id,
+                None => {
+                    return Box::new(futures::future::err(MethodErr::invalid_arg(&"PlaylistId")))
+                }
+            };
+            if !check_commands_enabled(&commands_enabled, m.msg) {
+                return Box::new(futures::future::ok(vec![mret]));
+            }
+            let device_name = device_name.clone();
+            let session = session.clone();
+            reply_when(
+                mret,
+                with_api(&token, move |sp| {
+                    let uri = format!("spotify:playlist:{}", id);
+                    play_here(sp, &session, &device_name, Some(uri), None, None)
+                }),
+            )
+        })
+        .inarg::<Path, _>("PlaylistId")
+    };
+
+    let method_get_playlists = {
+        let token = api_token.clone();
+        f.amethod("GetPlaylists", (), move |m| -> MethodReply {
+            let mret = m.msg.method_return();
+            let (index, max_count, order, reverse): (u32, u32, String, bool) = match m.msg.read4() {
+                Ok(args) => args,
+                Err(e) => return Box::new(futures::future::err(MethodErr::from(e))),
+            };
+            let playlists = with_api(&token, move |sp| {
+                let mut playlists = fetch_playlists(sp)?;
+                // Orderings only lists these two, any other keeps the user's.
+                if order == "Alphabetical" {
+                    playlists.sort_by_key(|playlist| playlist.name.to_lowercase());
+                }
+                if reverse {
+                    playlists.reverse();
+                }
+                Ok(playlists
+                    .into_iter()
+                    .skip(index as usize)
+                    .take(max_count as usize)
+                    .map(|playlist| (playlist.id, playlist.name, playlist.images))
+                    .collect::<Vec<_>>())
+            });
+            Box::new(
+                playlists
+                    .map(|playlists| {
+                        let playlists: Vec<_> = playlists
+                            .into_iter()
+                            .map(|(id, name, images)| playlist_item(&id, name, &images))
+                            .collect();
+                        vec![mret.append1(playlists)]
+                    })
+                    .map_err(|e| MethodErr::failed(&e)),
+            )
+        })
+        .inarg::<u32, _>("Index")
+        .inarg::<u32, _>("MaxCount")
+        .inarg::<&str, _>("Order")
+        .inarg::<bool, _>("ReverseOrder")
+        .outarg::<Vec<(Path, &str, &str)>, _>("Playlists")
+    };
+
+    let property_playlist_count = f
+        .property::<u32, _>("PlaylistCount", ())
+        .access(Access::Read)
+        .on_get(spotify_api_property!([sp, _device]
+            sp.current_user_playlists(1, 0).map_or(0, |page| page.total)
+        ));
+
+    let property_orderings = f
+        .property::<Vec<String>, _>("Orderings", ())
+        .access(Access::Read)
+        .on_get(|iter, _| {
+            iter.append(vec!["User".to_string(), "Alphabetical".to_string()]);
+            Ok(())
+        });
+
+    let property_active_playlist = {
+        let now_playing = now_playing.clone();
+        f.property::<(bool, (Path, String, String)), _>("ActivePlaylist", ())
+            .access(Access::Read)
+            .on_get(spotify_api_property!([sp, _device] {
+                let id = now_playing
+                    .borrow()
+                    .context_uri
+                    .as_deref()
+                    .and_then(playlist_id)
+                    .map(str::to_string);
+                match id.and_then(|id| sp.playlist(&id, None, market).ok()) {
+                    Some(playlist) => (
+                        true,
+                        playlist_item(&playlist.id, playlist.name, &playlist.images),
+                    ),
+                    None => (
+                        false,
+                        (
+                            Path::new("/").expect("/ is a valid object path"),
+                            String::new(),
+                            String::new(),
+                        ),
+                    ),
+                }
+            }))
+    };
+
+    let playlists_interface = f
+        .interface(PLAYLISTS_INTERFACE, ())
+        .add_m(method_activate_playlist)
+        .add_m(method_get_playlists)
+        .add_p(property_playlist_count)
+        .add_p(property_orderings)
+        .add_p(property_active_playlist);
+
+    let method_get_normalisation = f
+        .amethod("GetNormalisation", (), move |m| {
+            let status = normalisation_status(normalisation.get());
+            Ok(vec![m.msg.method_return().append1(status)])
+        })
+        .outarg::<HashMap<&str, Variant<Box<dyn RefArg>>>, _>("status");
+
+    let device_id = session.device_id().to_owned();
+    let method_get_device = f
+        .amethod("GetDevice", (), move |m| {
+            let mut status: HashMap<String, Variant<Box<dyn RefArg>>> = HashMap::new();
+            status.insert("Id".to_string(), Variant(Box::new(device_id.clone())));
+            status.insert("Active".to_string(), Variant(Box::new(active.get())));
+            Ok(vec![m.msg.method_return().append1(status)])
+        })
+        .outarg::<HashMap<&str, Variant<Box<dyn RefArg>>>, _>("status");
+
+    let method_get_audio_features = f
+        .amethod("GetAudioFeatures", (), move |m| {
+            let features = audio_features.as_ref().and_then(AudioFeatureCache::current);
+            Ok(vec![m
+                .msg
+                .method_return()
+                .append1(audio_features_status(features))])
+        })
+        .outarg::<HashMap<&str, Variant<Box<dyn RefArg>>>, _>("features");
+
+    let diagnostics_interface = f
+        .interface(DIAGNOSTICS_INTERFACE, ())
+        .add_m(method_get_normalisation)
+        .add_m(method_get_device)
+        .add_m(method_get_audio_features);
+
+    let method_list_devices = {
+        let token = api_token.clone();
+        f.amethod("ListDevices", (), move |m| {
+            let (p, c) = oneshot::channel();
+            let token = token.clone();
+            thread::spawn(move || {
+                let _ = p.send(list_devices(&create_spotify_api(&token)));
+            });
+            let mret = m.msg.method_return();
+            c.map_err(|e| MethodErr::failed(&e))
+                .and_then(|devices| match devices {
+                    Ok(devices) => Ok(vec![mret.append1(devices)]),
+                    Err(e) => Err(MethodErr::failed(&e)),
+                })
+        })
+        .outarg::<Vec<(&str, &str, &str, bool)>, _>("devices")
+    };
+
+    let method_transfer_to = {
+        let token = api_token.clone();
+        f.amethod("TransferTo", (), move |m| {
+            let (p, c) = oneshot::channel();
+            let token = token.clone();
+            let device_id: Result<String, _> = m.msg.read1();
+            thread::spawn(move || {
+                let result = match device_id {
+                    Ok(device_id) => transfer_playback(&create_spotify_api(&token), &device_id),
+                    Err(e) => Err(e.to_string()),
+                };
+                let _ = p.send(result);
+            });
+            let mret = m.msg.method_return();
+            c.map_err(|e| MethodErr::failed(&e))
+                .and_then(|result| match result {
+                    Ok(()) => Ok(vec![mret]),
+                    Err(e) => Err(MethodErr::failed(&e)),
+                })
+        })
+        .inarg::<&str, _>("device_id")
+    };
+
+    let devices_interface = f
+        .interface(DEVICES_INTERFACE, ())
+        .add_m(method_list_devices)
+        .add_m(method_transfer_to);
+
+    let method_add_to_queue = f
+        .amethod("AddToQueue", (), move |m| {
+            let mret = m.msg.method_return();
+            let result = match m.msg.read1::<&str>() {
+                Ok(uri) => control::send(&control_sender, Command::Queue(uri.to_string())),
+                Err(e) => Box::new(futures::future::err(e.to_string())),
+            };
+            result
+                .map(|_| vec![mret])
+                .map_err(|e| MethodErr::failed(&e))
+        })
+        .inarg::<&str, _>("uri");
+
+    let queue_interface = f.interface(QUEUE_INTERFACE, ()).add_m(method_add_to_queue);
 
     let tree = f.tree(ATree::new()).add(
-        f.object_path("/org/mpris/MediaPlayer2", ())
+        f.object_path(MPRIS_PATH, ())
             .introspectable()
             .add(media_player2_interface)
-            .add(media_player2_player_interface),
+            .add(media_player2_player_interface)
+            .add(tracklist_interface)
+            .add(playlists_interface)
+            .add(diagnostics_interface)
+            .add(devices_interface)
+            .add(queue_interface),
     );
 
     tree.set_registered(&connection, true)
@@ -623,15 +1934,16 @@ fn create_dbus_server(
         .expect("Failed to create async dbus connection");
 
     let server = ATreeServer::new(
-        connection,
+        connection.clone(),
         Box::new(tree),
         async_connection
             .messages()
             .expect("Failed to unwrap async messages"),
     );
 
-    Box::new(server.for_each(|message| {
+    let server = server.for_each(|message| {
         warn!("Unhandled DBus message: {:?}", message);
         Ok(())
-    }))
+    });
+    (connection, Box::new(server))
 }
@@ -0,0 +1,241 @@
+use crate::{config::DeviceLostAction, main_loop::SinkBuilder};
+use futures::{
+    sync::mpsc::{self, UnboundedReceiver, UnboundedSender},
+    Async, Future, Stream,
+};
+use librespot::{connect::spirc::Spirc, playback::audio_backend::Sink};
+use log::{error, info, warn};
+use std::{
+    io,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+use tokio_core::reactor::{Handle, Timeout};
+
+/// How often a lost device is looked for, with `on_device_lost = "reopen"` or
+/// while playing on a fallback.
+const REOPEN_INTERVAL: Duration = Duration::from_secs(2);
+
+/// A backend used when the audio device is lost, with what to call it.
+pub(crate) type Fallback = (String, SinkBuilder);
+
+/// A sink that survives its audio device going away.
+///
+/// librespot panics when a sink that failed to write can't be stopped, which
+/// is what happens when a USB device is unplugged. This drops the broken sink
+/// instead and, depending on `on_device_lost`, switches to the next fallback
+/// backend that works or has playback paused by the main loop. While on a
+/// fallback, the backends before it are tried again every `REOPEN_INTERVAL`,
+/// and playback goes back to the first one that is back. The player keeps its
+/// position either way, as only the sink under it changes.
+///
+/// With `device_policy = "keep-open"`, the device is only closed when it is
+/// lost, rather than whenever playback pauses or stops.
+struct DeviceLostSink {
+    open: SinkBuilder,
+    fallbacks: Vec<Fallback>,
+    sink: Option<Box<dyn Sink>>,
+    /// The index of the fallback being played on, if the sink is one.
+    on_fallback: Option<usize>,
+    /// When the backends before the fallback were last tried.
+    tried_at: Instant,
+    keep_open: bool,
+    lost: UnboundedSender<()>,
+}
+
+impl DeviceLostSink {
+    fn lose_device(&mut self) {
+        self.sink = None;
+        self.on_fallback = None;
+        let _ = self.lost.unbounded_send(());
+    }
+
+    /// The player panics when stopping fails, so errors are only logged.
+    fn close(&mut self) {
+        if let Some(mut sink) = self.sink.take() {
+            if let Err(e) = sink.stop() {
+                error!("Could not stop the audio device: {}", e);
+            }
+        }
+    }
+
+    /// Switches to the first fallback from `first` on that can be opened.
+    fn start_fallback(&mut self, first: usize) -> io::Result<()> {
+        let mut error = io::Error::new(io::ErrorKind::NotFound, "no fallback left");
+        for (index, (name, open)) in self.fallbacks.iter().enumerate().skip(first) {
+            warn!("Switching to the {}", name);
+            let mut sink = open();
+            match sink.start() {
+                Ok(()) => {
+                    self.sink = Some(sink);
+                    self.on_fallback = Some(index);
+                    self.tried_at = Instant::now();
+                    return Ok(());
+                }
+                Err(e) => {
+                    error!("Could not open the {}: {}", name, e);
+                    error = e;
+                }
+            }
+        }
+        Err(error)
+    }
+
+    /// Goes back to the configured device, or to an earlier fallback, once it
+    /// can be opened again.
+    fn return_from_fallback(&mut self, current: usize) {
+        self.tried_at = Instant::now();
+        let back = std::iter::once((None, "configured audio device", &self.open))
+            .chain(
+                self.fallbacks[..current]
+                    .iter()
+                    .enumerate()
+                    .map(|(index, (name, open))| (Some(index), name.as_str(), open)),
+            )
+            .find_map(|(index, name, open)| {
+                let mut sink = open();
+                sink.start().ok().map(|()| {
+                    info!("The {} is back, switching to it", name);
+                    (index, sink)
+                })
+            });
+        if let Some((index, sink)) = back {
+            self.close();
+            self.sink = Some(sink);
+            self.on_fallback = index;
+        }
+    }
+}
+
+impl Sink for DeviceLostSink {
+    fn start(&mut self) -> io::Result<()> {
+        if self.keep_open {
+            if self.sink.is_some() && self.on_fallback.is_none() {
+                return Ok(());
+            }
+            // The fallback is only kept until the configured device is back.
+            self.close();
+        }
+        // Every start tries the configured device first, so that playback
+        // goes back to it once it has returned.
+        let mut sink = (self.open)();
+        match sink.start() {
+            Ok(()) => {
+                self.sink = Some(sink);
+                self.on_fallback = None;
+                Ok(())
+            }
+            Err(e) if !self.fallbacks.is_empty() => {
+                error!("Could not open the audio device: {}", e);
+                self.start_fallback(0)
+            }
+            Err(e) => Err(e),
+        }
+    }
+
+    fn stop(&mut self) -> io::Result<()> {
+        if !self.keep_open {
+            self.close();
+        }
+        Ok(())
+    }
+
+    fn write(&mut self, data: &[i16]) -> io::Result<()> {
+        if let Some(current) = self.on_fallback {
+            if self.tried_at.elapsed() >= REOPEN_INTERVAL {
+                self.return_from_fallback(current);
+            }
+        }
+        let result = match self.sink {
+            Some(ref mut sink) => sink.write(data),
+            // Whatever is written until playback is paused is dropped.
+            None => return Ok(()),
+        };
+        if let Err(e) = result {
+            error!("The audio device was lost: {}", e);
+            // The broken sink is dropped without being stopped.
+            self.sink = None;
+            let next = self.on_fallback.map_or(0, |index| index + 1);
+            if next >= self.fallbacks.len() {
+                self.lose_device();
+            } else if let Err(e) = self.start_fallback(next).and_then(|()| self.write(data)) {
+                error!("Could not use a fallback backend either: {}", e);
+                self.lose_device();
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Wraps `open` so that losing the audio device doesn't bring down the
+/// player. The returned receiver is told whenever playback should pause.
+pub(crate) fn wrap(
+    open: SinkBuilder,
+    fallbacks: Vec<Fallback>,
+    keep_open: bool,
+) -> (SinkBuilder, UnboundedReceiver<()>) {
+    let (lost, lost_events) = mpsc::unbounded();
+    let builder: SinkBuilder = Arc::new(move || {
+        Box::new(DeviceLostSink {
+            open: open.clone(),
+            fallbacks: fallbacks.clone(),
+            sink: None,
+            on_fallback: None,
+            tried_at: Instant::now(),
+            keep_open,
+            lost: lost.clone(),
+        })
+    });
+    (builder, lost_events)
+}
+
+/// Pauses playback when the audio device is lost and, with
+/// `on_device_lost = "reopen"`, resumes it once the device is back.
+pub(crate) struct DeviceWatch {
+    action: DeviceLostAction,
+    open: SinkBuilder,
+    lost_events: UnboundedReceiver<()>,
+    retry: Option<Timeout>,
+}
+
+impl DeviceWatch {
+    pub(crate) fn new(
+        action: DeviceLostAction,
+        open: SinkBuilder,
+        lost_events: UnboundedReceiver<()>,
+    ) -> DeviceWatch {
+        DeviceWatch {
+            action,
+            open,
+            lost_events,
+            retry: None,
+        }
+    }
+
+    pub(crate) fn poll(&mut self, handle: &Handle, spirc: Option<&Spirc>) {
+        while let Ok(Async::Ready(Some(()))) = self.lost_events.poll() {
+            if let Some(spirc) = spirc {
+                spirc.pause();
+            }
+            if self.action == DeviceLostAction::Reopen {
+                info!("Playback paused until the audio device is back");
+                self.retry = Timeout::new(REOPEN_INTERVAL, handle).ok();
+            } else {
+                info!("Playback paused");
+            }
+        }
+
+        while let Some(Ok(Async::Ready(()))) = self.retry.as_mut().map(Future::poll) {
+            let mut sink = (self.open)();
+            if sink.start().and_then(|()| sink.stop()).is_ok() {
+                info!("The audio device is back, resuming playback");
+                self.retry = None;
+                if let Some(spirc) = spirc {
+                    spirc.play();
+                }
+            } else {
+                self.retry = Timeout::new(REOPEN_INTERVAL, handle).ok();
+            }
+        }
+    }
+}
@@ -0,0 +1,46 @@
+use crate::main_loop::SinkBuilder;
+use librespot::playback::audio_backend::Sink;
+use std::{io, sync::Arc};
+
+/// -3dB, so that a signal that is the same on both channels keeps its power.
+const MONO_GAIN: f32 = 0.707_945_8;
+
+/// A sink that plays the sum of both channels on each of them.
+struct MonoSink {
+    sink: Box<dyn Sink>,
+    buffer: Vec<i16>,
+}
+
+impl Sink for MonoSink {
+    fn start(&mut self) -> io::Result<()> {
+        self.sink.start()
+    }
+
+    fn stop(&mut self) -> io::Result<()> {
+        self.sink.stop()
+    }
+
+    fn write(&mut self, data: &[i16]) -> io::Result<()> {
+        self.buffer.clear();
+        for frame in data.chunks(2) {
+            let sum = frame.iter().map(|&sample| f32::from(sample)).sum::<f32>();
+            let mono = (sum * MONO_GAIN)
+                .round()
+                .max(f32::from(i16::MIN))
+                .min(f32::from(i16::MAX)) as i16;
+            self.buffer.extend(frame.iter().map(|_| mono));
+        }
+        self.sink.write(&self.buffer)
+    }
+}
+
+/// Wraps `open` so that the audio is mixed down to mono before it reaches
+/// the backend.
+pub(crate) fn mono(open: SinkBuilder) -> SinkBuilder {
+    Arc::new(move || {
+        Box::new(MonoSink {
+            sink: open(),
+            buffer: Vec::new(),
+        })
+    })
+}
@@ -1,6 +1,7 @@
 use std::{
     error::Error as StdError,
     fmt::{self, Display},
+    io,
 };
 
 #[derive(Clone, Debug)]
@@ -115,3 +116,64 @@ pub(crate) enum Message {
     String(String),
     Error(Box<dyn std::error::Error + 'static>),
 }
+
+/// Failures that stop the daemon. Each one exits with its own code, so that init
+/// systems and monitoring can tell them apart.
+#[derive(Debug)]
+pub(crate) enum SetupError {
+    AuthFailed(String),
+    NetworkUnavailable(io::Error),
+    AudioDeviceUnavailable(io::Error),
+    ConfigInvalid(String),
+    DiscoveryFailed(io::Error),
+    PlaybackFailed(String),
+    /// The player thread panicked, which ends playback for good.
+    PlayerStopped,
+}
+
+impl SetupError {
+    /// The exit code used for this failure. Any other error exits with 1.
+    pub(crate) fn exit_code(&self) -> i32 {
+        match self {
+            SetupError::ConfigInvalid(_) => 2,
+            SetupError::AuthFailed(_) => 3,
+            SetupError::NetworkUnavailable(_) => 4,
+            SetupError::AudioDeviceUnavailable(_) => 5,
+            SetupError::DiscoveryFailed(_) => 6,
+            SetupError::PlaybackFailed(_) => 7,
+            SetupError::PlayerStopped => 8,
+        }
+    }
+}
+
+impl Display for SetupError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SetupError::AuthFailed(reason) => write!(f, "authentication failed: {}", reason),
+            SetupError::NetworkUnavailable(_) => write!(f, "could not connect to Spotify"),
+            SetupError::AudioDeviceUnavailable(_) => write!(
+                f,
+                "could not open the audio device, check backend and device, \
+                 or set allow_null_fallback to run without audio"
+            ),
+            SetupError::ConfigInvalid(reason) => write!(f, "invalid configuration: {}", reason),
+            SetupError::DiscoveryFailed(_) => write!(f, "could not start the discovery service"),
+            SetupError::PlaybackFailed(reason) => write!(f, "playback failed: {}", reason),
+            SetupError::PlayerStopped => write!(f, "the player stopped after a crash"),
+        }
+    }
+}
+
+impl StdError for SetupError {
+    fn source(&self) -> Option<&(dyn StdError + 'static)> {
+        match self {
+            SetupError::NetworkUnavailable(e)
+            | SetupError::AudioDeviceUnavailable(e)
+            | SetupError::DiscoveryFailed(e) => Some(e),
+            SetupError::AuthFailed(_)
+            | SetupError::ConfigInvalid(_)
+            | SetupError::PlaybackFailed(_)
+            | SetupError::PlayerStopped => None,
+        }
+    }
+}
@@ -0,0 +1,704 @@
+use crate::{
+    main_loop,
+    metadata::{self, TrackMetadata},
+    process::{self, Child, EventPrograms},
+    track_error::ContextUnplayable,
+};
+use futures::{future::Either, Async, Future, Stream};
+use librespot::{
+    core::{session::Session, spotify_id::SpotifyId},
+    playback::player::PlayerEvent,
+    protocol::spirc::State,
+};
+use log::{error, warn};
+use serde::Serialize;
+use std::{
+    collections::{HashMap, VecDeque},
+    time::{Duration, Instant},
+};
+use tokio_core::reactor::{Handle, Timeout};
+
+/// How often a running onevent command is checked for having exited.
+const CHECK_INTERVAL: Duration = Duration::from_millis(200);
+
+/// How many events may wait for the command of an earlier one to exit. The
+/// oldest are dropped beyond that.
+const MAX_QUEUED: usize = 32;
+
+/// How far the position may be from where playback should be before a new
+/// position counts as a seek. The player also reports the position when it
+/// fell behind by more than a second.
+const SEEK_THRESHOLD_MS: i64 = 2000;
+
+/// What an onevent command is told about an event, as JSON in
+/// `PLAYER_EVENT_JSON` and on its stdin. The environment variables hold the
+/// same, without the title, the artists and the album.
+#[derive(Default, Serialize)]
+struct Payload {
+    event: &'static str,
+    track_id: Option<String>,
+    old_track_id: Option<String>,
+    uri: Option<String>,
+    title: Option<String>,
+    artists: Option<Vec<String>>,
+    album: Option<String>,
+    duration_ms: Option<u32>,
+    position_ms: Option<u32>,
+    play_request_id: Option<u64>,
+    volume: Option<u16>,
+    old_volume: Option<u16>,
+    shuffle: Option<bool>,
+    repeat: Option<bool>,
+    skipped_tracks: Option<u32>,
+}
+
+impl Payload {
+    fn named(event: &'static str) -> Payload {
+        Payload {
+            event,
+            ..Default::default()
+        }
+    }
+
+    fn env(&self) -> HashMap<&'static str, String> {
+        let mut env = HashMap::new();
+        env.insert("PLAYER_EVENT", self.event.to_string());
+        let numbers = [
+            ("DURATION_MS", self.duration_ms.map(u64::from)),
+            ("POSITION_MS", self.position_ms.map(u64::from)),
+            ("PLAY_REQUEST_ID", self.play_request_id),
+            ("VOLUME", self.volume.map(u64::from)),
+            ("OLD_VOLUME", self.old_volume.map(u64::from)),
+            ("SKIPPED_TRACKS", self.skipped_tracks.map(u64::from)),
+        ];
+        for &(name, value) in &numbers {
+            if let Some(value) = value {
+                env.insert(name, value.to_string());
+            }
+        }
+        let texts = [
+            ("TRACK_ID", &self.track_id),
+            ("OLD_TRACK_ID", &self.old_track_id),
+        ];
+        for &(name, value) in &texts {
+            if let Some(value) = value {
+                env.insert(name, value.clone());
+            }
+        }
+        let flags = [("SHUFFLE", self.shuffle), ("REPEAT", self.repeat)];
+        for &(name, value) in &flags {
+            if let Some(value) = value {
+                env.insert(name, value.to_string());
+            }
+        }
+        env
+    }
+}
+
+/// Looks up the metadata of a track, resolving with `None` if it can't be
+/// found in time.
+type Lookup = Box<dyn Future<Item = Option<TrackMetadata>, Error = ()>>;
+
+/// An event waiting for its command to be run.
+struct Pending {
+    command: String,
+    payload: Payload,
+    track_id: Option<SpotifyId>,
+}
+
+/// Where playback was at the last player event, to tell a seek from playback
+/// going on.
+pub(crate) struct Playback {
+    play_request_id: u64,
+    playing: bool,
+    position_ms: u32,
+    at: Instant,
+}
+
+impl Playback {
+    /// Returns where playback is after `event`, given where it was before.
+    pub(crate) fn after(event: &PlayerEvent, before: Option<Playback>) -> Option<Playback> {
+        match *event {
+            PlayerEvent::Playing {
+                play_request_id,
+                position_ms,
+                ..
+            }
+            | PlayerEvent::Paused {
+                play_request_id,
+                position_ms,
+                ..
+            } => Some(Playback {
+                play_request_id,
+                playing: matches!(*event, PlayerEvent::Playing { .. }),
+                position_ms,
+                at: Instant::now(),
+            }),
+            PlayerEvent::Started { .. }
+            | PlayerEvent::Loading { .. }
+            | PlayerEvent::Changed { .. }
+            | PlayerEvent::Stopped { .. }
+            | PlayerEvent::EndOfTrack { .. }
+            | PlayerEvent::Unavailable { .. } => None,
+            _ => before,
+        }
+    }
+
+    /// Whether `event` reports playback somewhere else in the same track than
+    /// it would have got to by itself.
+    pub(crate) fn is_seek(&self, event: &PlayerEvent) -> bool {
+        let (play_request_id, playing, position_ms) = match *event {
+            PlayerEvent::Playing {
+                play_request_id,
+                position_ms,
+                ..
+            } => (play_request_id, true, position_ms),
+            PlayerEvent::Paused {
+                play_request_id,
+                position_ms,
+                ..
+            } => (play_request_id, false, position_ms),
+            _ => return false,
+        };
+        if self.play_request_id != play_request_id || self.playing != playing {
+            return false;
+        }
+        (i64::from(position_ms) - i64::from(self.position_ms())).abs() > SEEK_THRESHOLD_MS
+    }
+
+    pub(crate) fn position_ms(&self) -> u32 {
+        if self.playing {
+            self.position_ms
+                .saturating_add(self.at.elapsed().as_millis() as u32)
+        } else {
+            self.position_ms
+        }
+    }
+}
+
+/// Runs the onevent commands, one at a time and in order, without holding up
+/// the main loop while they run. Events that arrive meanwhile wait in a
+/// queue, and a command that runs longer than `onevent_timeout` is killed.
+///
+/// Besides the player events, it tells the commands about seeks, changes of
+/// the shuffle and repeat modes, this device becoming the active one or not,
+/// and the connection to Spotify being lost. The title, the artists and the
+/// album of the track are looked up before its command runs.
+pub(crate) struct EventHooks {
+    programs: EventPrograms,
+    shell: String,
+    grace_period: Duration,
+    timeout: Option<Duration>,
+    metadata_timeout: Duration,
+    handle: Handle,
+    session: Option<Session>,
+    states: Option<Box<dyn Stream<Item = State, Error = ()>>>,
+    queue: VecDeque<Pending>,
+    metadata: Option<(SpotifyId, Option<TrackMetadata>)>,
+    lookup: Option<(SpotifyId, Lookup)>,
+    running: Option<(Child, Instant)>,
+    check: Option<Timeout>,
+    current_track: Option<SpotifyId>,
+    playback: Option<Playback>,
+    active: bool,
+    volume: Option<u16>,
+    shuffle: Option<bool>,
+    repeat: Option<bool>,
+}
+
+impl EventHooks {
+    pub(crate) fn new(
+        programs: EventPrograms,
+        shell: String,
+        grace_period: Duration,
+        timeout: Option<Duration>,
+        metadata_timeout: Duration,
+        handle: &Handle,
+    ) -> EventHooks {
+        EventHooks {
+            programs,
+            shell,
+            grace_period,
+            timeout,
+            metadata_timeout,
+            handle: handle.clone(),
+            session: None,
+            states: None,
+            queue: VecDeque::new(),
+            metadata: None,
+            lookup: None,
+            running: None,
+            check: None,
+            current_track: None,
+            playback: None,
+            active: false,
+            volume: None,
+            shuffle: None,
+            repeat: None,
+        }
+    }
+
+    /// Uses the commands of a reloaded config from the next event on.
+    pub(crate) fn set_programs(
+        &mut self,
+        programs: EventPrograms,
+        grace_period: Duration,
+        timeout: Option<Duration>,
+    ) {
+        self.programs = programs;
+        self.grace_period = grace_period;
+        self.timeout = timeout;
+    }
+
+    pub(crate) fn connected(&mut self, session: &Session) {
+        self.session = Some(session.clone());
+        self.states = Some(main_loop::own_states(session));
+        self.shuffle = None;
+        self.repeat = None;
+    }
+
+    pub(crate) fn connection_lost(&mut self) {
+        self.session = None;
+        self.states = None;
+        self.lookup = None;
+        self.playback = None;
+        self.active = false;
+        let command = self.programs.onevent.clone();
+        self.push(command, Payload::named("connection_lost"), None);
+    }
+
+    /// Queues the commands for `event`. If playback stopped because the
+    /// context couldn't be played, that replaces the event.
+    pub(crate) fn handle_event(
+        &mut self,
+        event: &PlayerEvent,
+        unplayable: Option<&ContextUnplayable>,
+    ) {
+        let was_active = self.active;
+        self.active = main_loop::is_active_after(event, was_active);
+        if self.active && !was_active {
+            let command = self.programs.onevent.clone();
+            self.push(command, Payload::named("active"), None);
+        }
+
+        match unplayable {
+            Some(unplayable) => {
+                let payload = Payload {
+                    track_id: Some(unplayable.first_track_id.to_base62()),
+                    skipped_tracks: Some(unplayable.skipped),
+                    ..Payload::named("context_unplayable")
+                };
+                let command = self.programs.onevent.clone();
+                self.push(command, payload, Some(unplayable.first_track_id));
+            }
+            None => {
+                let (mut payload, track_id) = player_payload(event);
+                let mut command = self.programs.for_event(event).map(str::to_string);
+                if matches!(self.playback, Some(ref playback) if playback.is_seek(event)) {
+                    // `on_play` and `on_pause` still get the event, only
+                    // `onevent` gets a seek instead.
+                    if command != self.programs.onevent {
+                        self.push(command, player_payload(event).0, track_id);
+                    }
+                    payload.event = "seek";
+                    command = self.programs.onevent.clone();
+                }
+                if let PlayerEvent::VolumeSet { volume } = *event {
+                    payload.old_volume = self.volume.replace(volume);
+                }
+                self.push(command, payload, track_id);
+            }
+        }
+
+        self.playback = Playback::after(event, self.playback.take());
+        match *event {
+            PlayerEvent::Stopped { .. } => self.current_track = None,
+            PlayerEvent::VolumeSet { .. } => (),
+            _ => self.current_track = player_payload(event).1.or(self.current_track),
+        }
+
+        if was_active && !self.active {
+            let command = self.programs.onevent.clone();
+            self.push(command, Payload::named("inactive"), None);
+        }
+    }
+
+    pub(crate) fn poll(&mut self) {
+        while let Some(Ok(Async::Ready(Some(state)))) = self.states.as_mut().map(Stream::poll) {
+            let (shuffle, repeat) = (state.get_shuffle(), state.get_repeat());
+            let changes = [
+                ("shuffle", self.shuffle.replace(shuffle), shuffle),
+                ("repeat", self.repeat.replace(repeat), repeat),
+            ];
+            for &(event, old, new) in &changes {
+                if matches!(old, Some(old) if old != new) {
+                    let payload = Payload {
+                        shuffle: Some(shuffle),
+                        repeat: Some(repeat),
+                        ..Payload::named(event)
+                    };
+                    let command = self.programs.onevent.clone();
+                    self.push(command, payload, None);
+                }
+            }
+        }
+
+        if let Some((mut child, started)) = self.running.take() {
+            match child.try_wait() {
+                Ok(None) if !matches!(self.timeout, Some(timeout) if started.elapsed() >= timeout) =>
+                {
+                    self.running = Some((child, started));
+                    self.check_again();
+                    return;
+                }
+                Ok(None) => {
+                    warn!(
+                        "An onevent command is still running after {}s, killing it",
+                        started.elapsed().as_secs()
+                    );
+                    child.terminate();
+                }
+                Err(e) => error!("{}", e),
+                Ok(Some(())) => (),
+            }
+        }
+
+        while let Some(pending) = self.queue.front() {
+            if let Some(track_id) = pending.track_id {
+                if !self.look_up(track_id) {
+                    return;
+                }
+            }
+            let mut pending = self.queue.pop_front().expect("the queue isn't empty");
+            let metadata = match (pending.track_id, &self.metadata) {
+                (Some(track_id), Some((id, Some(metadata)))) if *id == track_id => Some(metadata),
+                _ => None,
+            };
+            if let Some(metadata) = metadata {
+                let payload = &mut pending.payload;
+                payload.uri = Some(metadata.uri.clone());
+                payload.title = Some(metadata.title.clone());
+                payload.artists = Some(metadata.artists.clone());
+                payload.album = metadata.album.clone();
+                payload.duration_ms = payload.duration_ms.or(Some(metadata.duration_ms));
+            }
+            let json = serde_json::to_string(&pending.payload).unwrap_or_default();
+            match process::spawn_program_with_payload(
+                &self.shell,
+                &pending.command,
+                pending.payload.env(),
+                &json,
+            ) {
+                Ok(child) => {
+                    self.running = Some((child, Instant::now()));
+                    self.check_again();
+                    return;
+                }
+                Err(e) => error!("{}", e),
+            }
+        }
+    }
+
+    /// Gives the command that is still running, if any, a moment to finish
+    /// before spotifyd exits. The events still waiting are dropped.
+    pub(crate) fn finish(&mut self) {
+        self.queue.clear();
+        if let Some((child, _)) = self.running.take() {
+            child.finish(self.grace_period);
+        }
+    }
+
+    fn push(&mut self, command: Option<String>, mut payload: Payload, track_id: Option<SpotifyId>) {
+        let command = match command {
+            Some(command) => command,
+            None => return,
+        };
+        let track_id = track_id.or(self.current_track);
+        if payload.track_id.is_none() {
+            payload.track_id = track_id.map(|id| id.to_base62());
+        }
+        if self.queue.len() >= MAX_QUEUED {
+            if let Some(dropped) = self.queue.pop_front() {
+                warn!(
+                    "Too many events are waiting for the onevent command, dropping the \"{}\" event",
+                    dropped.payload.event
+                );
+            }
+        }
+        self.queue.push_back(Pending {
+            command,
+            payload,
+            track_id,
+        });
+    }
+
+    /// Returns whether the metadata of `track_id` is known, or can't be, and
+    /// looks it up otherwise.
+    fn look_up(&mut self, track_id: SpotifyId) -> bool {
+        if matches!(self.metadata, Some((id, _)) if id == track_id) {
+            return true;
+        }
+        let session = match self.session {
+            Some(ref session) => session,
+            None => return true,
+        };
+        if !matches!(self.lookup, Some((id, _)) if id == track_id) {
+            let lookup = metadata::fetch_partial_metadata(session, track_id);
+            let lookup: Lookup = match Timeout::new(self.metadata_timeout, &self.handle) {
+                Ok(timeout) => Box::new(lookup.select2(timeout).then(|result| match result {
+                    Ok(Either::A((metadata, _))) => Ok(Some(metadata)),
+                    _ => Ok(None),
+                })),
+                Err(_) => Box::new(lookup.then(|result| Ok(result.ok()))),
+            };
+            self.lookup = Some((track_id, lookup));
+        }
+        let found = match self.lookup.as_mut().map(|(_, lookup)| lookup.poll()) {
+            Some(Ok(Async::NotReady)) => return false,
+            Some(Ok(Async::Ready(metadata))) => metadata,
+            _ => None,
+        };
+        self.lookup = None;
+        self.metadata = Some((track_id, found));
+        true
+    }
+
+    fn check_again(&mut self) {
+        let pending = matches!(
+            self.check.as_mut().map(Future::poll),
+            Some(Ok(Async::NotReady))
+        );
+        if !pending {
+            self.check = Timeout::new(CHECK_INTERVAL, &self.handle).ok();
+            if let Some(ref mut check) = self.check {
+                let _ = check.poll();
+            }
+        }
+    }
+}
+
+/// Returns what a player event says, and the track it is about.
+fn player_payload(event: &PlayerEvent) -> (Payload, Option<SpotifyId>) {
+    let (track_id, play_request_id, position_ms, duration_ms) = match *event {
+        PlayerEvent::Started {
+            track_id,
+            play_request_id,
+            position_ms,
+        }
+        | PlayerEvent::Loading {
+            track_id,
+            play_request_id,
+            position_ms,
+        } => (
+            Some(track_id),
+            Some(play_request_id),
+            Some(position_ms),
+            None,
+        ),
+        PlayerEvent::Playing {
+            track_id,
+            play_request_id,
+            position_ms,
+            duration_ms,
+        }
+        | PlayerEvent::Paused {
+            track_id,
+            play_request_id,
+            position_ms,
+            duration_ms,
+        } => (
+            Some(track_id),
+            Some(play_request_id),
+            Some(position_ms),
+            Some(duration_ms),
+        ),
+        PlayerEvent::Stopped {
+            track_id,
+            play_request_id,
+        }
+        | PlayerEvent::TimeToPreloadNextTrack {
+            track_id,
+            play_request_id,
+        }
+        | PlayerEvent::EndOfTrack {
+            track_id,
+            play_request_id,
+        }
+        | PlayerEvent::Unavailable {
+            track_id,
+            play_request_id,
+        } => (Some(track_id), Some(play_request_id), None, None),
+        PlayerEvent::Changed { new_track_id, .. } => (Some(new_track_id), None, None, None),
+        PlayerEvent::VolumeSet { .. } => (None, None, None, None),
+    };
+    let payload = Payload {
+        track_id: track_id.map(|id| id.to_base62()),
+        old_track_id: match *event {
+            PlayerEvent::Changed { old_track_id, .. } => Some(old_track_id.to_base62()),
+            _ => None,
+        },
+        play_request_id,
+        position_ms,
+        duration_ms,
+        volume: match *event {
+            PlayerEvent::VolumeSet { volume } => Some(volume),
+            _ => None,
+        },
+        ..Payload::named(process::event_name(event))
+    };
+    (payload, track_id)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio_core::reactor::Core;
+
+    fn track() -> SpotifyId {
+        SpotifyId::from_base62("4uLU6hMCjMI75M1A2tKUQC").unwrap()
+    }
+
+    fn playing(play_request_id: u64, position_ms: u32) -> PlayerEvent {
+        PlayerEvent::Playing {
+            play_request_id,
+            track_id: track(),
+            position_ms,
+            duration_ms: 180_000,
+        }
+    }
+
+    fn paused(play_request_id: u64, position_ms: u32) -> PlayerEvent {
+        PlayerEvent::Paused {
+            play_request_id,
+            track_id: track(),
+            position_ms,
+            duration_ms: 180_000,
+        }
+    }
+
+    fn event_hooks(handle: &Handle, programs: EventPrograms) -> EventHooks {
+        EventHooks::new(
+            programs,
+            "sh".to_string(),
+            Duration::from_secs(1),
+            None,
+            Duration::from_secs(1),
+            handle,
+        )
+    }
+
+    #[test]
+    fn test_is_seek() {
+        let playback = Playback::after(&paused(1, 60_000), None).unwrap();
+        assert!(!playback.is_seek(&paused(1, 60_000)));
+        assert!(!playback.is_seek(&paused(1, 61_000)));
+        assert!(playback.is_seek(&paused(1, 90_000)));
+        assert!(playback.is_seek(&paused(1, 10_000)));
+        // Resuming and another track aren't seeks.
+        assert!(!playback.is_seek(&playing(1, 90_000)));
+        assert!(!playback.is_seek(&paused(2, 90_000)));
+        assert!(!playback.is_seek(&PlayerEvent::VolumeSet { volume: 0 }));
+
+        // While playing, the position moves on by itself.
+        let playback = Playback {
+            at: Instant::now() - Duration::from_secs(10),
+            ..Playback::after(&playing(1, 60_000), None).unwrap()
+        };
+        assert!(!playback.is_seek(&playing(1, 70_000)));
+        assert!(playback.is_seek(&playing(1, 60_000)));
+    }
+
+    #[test]
+    fn test_payload() {
+        let (payload, track_id) = player_payload(&paused(7, 1_500));
+        assert_eq!(track_id, Some(track()));
+        let env = payload.env();
+        assert_eq!(env["PLAYER_EVENT"], "pause");
+        assert_eq!(env["TRACK_ID"], "4uLU6hMCjMI75M1A2tKUQC");
+        assert_eq!(env["PLAY_REQUEST_ID"], "7");
+        assert_eq!(env["POSITION_MS"], "1500");
+        assert_eq!(env["DURATION_MS"], "180000");
+        assert!(!env.contains_key("VOLUME"));
+        assert!(!env.contains_key("SHUFFLE"));
+
+        let payload = Payload {
+            title: Some("Title".to_string()),
+            artists: Some(vec!["One".to_string(), "Two".to_string()]),
+            shuffle: Some(true),
+            volume: Some(100),
+            ..Payload::named("shuffle")
+        };
+        let env = payload.env();
+        assert_eq!(env["SHUFFLE"], "true");
+        assert_eq!(env["VOLUME"], "100");
+        assert!(!env.contains_key("TITLE"));
+        let json: serde_json::Value =
+            serde_json::from_str(&serde_json::to_string(&payload).unwrap()).unwrap();
+        assert_eq!(json["event"], "shuffle");
+        assert_eq!(json["title"], "Title");
+        assert_eq!(json["artists"], serde_json::json!(["One", "Two"]));
+        assert_eq!(json["volume"], 100);
+        assert!(json["track_id"].is_null());
+    }
+
+    #[test]
+    fn test_drops_oldest() {
+        let core = Core::new().unwrap();
+        let mut hooks = event_hooks(&core.handle(), EventPrograms::default());
+        for volume in 0..MAX_QUEUED as u16 + 3 {
+            let payload = Payload {
+                volume: Some(volume),
+                ..Payload::named("volumeset")
+            };
+            hooks.push(Some("true".to_string()), payload, None);
+        }
+        assert_eq!(hooks.queue.len(), MAX_QUEUED);
+        assert_eq!(hooks.queue.front().unwrap().payload.volume, Some(3));
+        assert_eq!(
+            hooks.queue.back().unwrap().payload.volume,
+            Some(MAX_QUEUED as u16 + 2)
+        );
+
+        // Events without a command aren't queued.
+        hooks.push(None, Payload::named("volumeset"), None);
+        assert_eq!(hooks.queue.len(), MAX_QUEUED);
+    }
+
+    #[test]
+    fn test_seek_runs_on_play() {
+        let core = Core::new().unwrap();
+        let programs = EventPrograms {
+            onevent: Some("onevent".to_string()),
+            on_play: Some("on_play".to_string()),
+            ..Default::default()
+        };
+        let mut hooks = event_hooks(&core.handle(), programs);
+        hooks.playback = Playback::after(&playing(1, 60_000), None);
+        hooks.handle_event(&playing(1, 10_000), None);
+        let queued: Vec<_> = hooks
+            .queue
+            .iter()
+            .map(|pending| (pending.command.as_str(), pending.payload.event))
+            .collect();
+        assert!(queued.contains(&("on_play", "play")));
+        assert!(queued.contains(&("onevent", "seek")));
+
+        // Without `on_play`, only the seek is run.
+        let mut hooks = event_hooks(
+            &core.handle(),
+            EventPrograms {
+                onevent: Some("onevent".to_string()),
+                ..Default::default()
+            },
+        );
+        hooks.playback = Playback::after(&playing(1, 60_000), None);
+        hooks.handle_event(&playing(1, 10_000), None);
+        let queued: Vec<_> = hooks
+            .queue
+            .iter()
+            .map(|pending| pending.payload.event)
+            .filter(|&event| event == "seek" || event == "play")
+            .collect();
+        assert_eq!(queued, ["seek"]);
+    }
+}
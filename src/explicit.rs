@@ -0,0 +1,110 @@
+use futures::{future, Async, Future};
+use librespot::{
+    connect::spirc::Spirc,
+    core::{
+        session::Session,
+        spotify_id::{SpotifyAudioType, SpotifyId},
+    },
+    metadata::{Episode, Metadata, Track},
+    playback::player::PlayerEvent,
+    protocol,
+};
+use log::{info, warn};
+
+/// Skips explicit tracks and episodes.
+///
+/// librespot doesn't know about the account's explicit content setting, so
+/// this only runs when `filter_explicit` is set. The flag is read from the
+/// metadata once a track starts, so the first moments of it may be heard.
+pub(crate) struct ExplicitFilter {
+    current: Option<SpotifyId>,
+    check: Option<Box<dyn Future<Item = (SpotifyId, bool), Error = ()>>>,
+    /// Explicit tracks skipped since the last one that was allowed.
+    skipped: Vec<SpotifyId>,
+}
+
+impl ExplicitFilter {
+    pub(crate) fn new() -> ExplicitFilter {
+        ExplicitFilter {
+            current: None,
+            check: None,
+            skipped: Vec::new(),
+        }
+    }
+
+    pub(crate) fn handle_event(&mut self, event: &PlayerEvent, session: &Session) {
+        let track_id = match *event {
+            PlayerEvent::Started { track_id, .. } => track_id,
+            PlayerEvent::Changed { new_track_id, .. } => new_track_id,
+            PlayerEvent::Stopped { .. } => {
+                self.current = None;
+                self.check = None;
+                self.skipped.clear();
+                return;
+            }
+            _ => return,
+        };
+        if self.current == Some(track_id) {
+            return;
+        }
+        self.current = Some(track_id);
+        self.check = Some(Box::new(
+            is_explicit(session, track_id).map(move |explicit| (track_id, explicit)),
+        ));
+    }
+
+    pub(crate) fn poll(&mut self, spirc: &Spirc) {
+        let (track_id, explicit) = match self.check.as_mut().map(Future::poll) {
+            Some(Ok(Async::NotReady)) | None => return,
+            Some(Ok(Async::Ready(result))) => result,
+            Some(Err(())) => {
+                self.check = None;
+                return;
+            }
+        };
+        self.check = None;
+        if self.current != Some(track_id) {
+            return;
+        }
+        if !explicit {
+            self.skipped.clear();
+            return;
+        }
+        // Coming across a skipped track again means the context has wrapped
+        // around without anything that could be played.
+        if self.skipped.contains(&track_id) {
+            warn!("Every track in the context is explicit, stopping playback");
+            self.skipped.clear();
+            spirc.pause();
+            return;
+        }
+        info!("Skipping explicit track {}", track_id.to_base62());
+        self.skipped.push(track_id);
+        spirc.next();
+    }
+}
+
+fn is_explicit(session: &Session, id: SpotifyId) -> Box<dyn Future<Item = bool, Error = ()>> {
+    match id.audio_type {
+        // librespot's `Track` leaves out the flag, so the metadata is
+        // requested the same way and parsed here.
+        SpotifyAudioType::Track => Box::new(
+            session
+                .mercury()
+                .get(Track::request_url(id))
+                .map_err(|_| ())
+                .and_then(|response| {
+                    let data = response.payload.first().ok_or(())?;
+                    protobuf::parse_from_bytes::<protocol::metadata::Track>(data)
+                        .map(|track| track.get_explicit())
+                        .map_err(|_| ())
+                }),
+        ),
+        SpotifyAudioType::Podcast => Box::new(
+            Episode::get(session, id)
+                .map(|episode| episode.explicit)
+                .map_err(|_| ()),
+        ),
+        SpotifyAudioType::NonPlayable => Box::new(future::ok(false)),
+    }
+}
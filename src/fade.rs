@@ -0,0 +1,137 @@
+use crate::main_loop::SinkBuilder;
+use librespot::playback::{audio_backend::Sink, player::PlayerEvent};
+use std::{
+    collections::VecDeque,
+    io,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+/// Stereo frames per second, as librespot decodes them.
+const FRAMES_PER_SECOND: u64 = 44100;
+
+/// Tells the sink in the player's thread about volume changes, which the
+/// main loop hears about.
+#[derive(Default)]
+pub(crate) struct FadeControl {
+    /// Set when the volume was changed, so a fade in is called off.
+    volume_changed: AtomicBool,
+    /// Whether the volume is at 0, in which case there is nothing to fade.
+    muted: AtomicBool,
+}
+
+impl FadeControl {
+    pub(crate) fn handle_event(&self, event: &PlayerEvent) {
+        if let PlayerEvent::VolumeSet { volume } = *event {
+            self.muted.store(volume == 0, Ordering::Relaxed);
+            self.volume_changed.store(true, Ordering::Relaxed);
+        }
+    }
+}
+
+/// A sink that fades the audio in when playback starts or resumes, and out
+/// when it is paused or stopped, for `fade_ms`.
+///
+/// librespot stops the sink as soon as playback is paused, with nothing left
+/// to fade out. So the last `fade_ms` of audio is held back, and played with
+/// the volume going down once the sink is stopped. Everything is heard that
+/// much later.
+struct FadeSink {
+    sink: Box<dyn Sink>,
+    control: Arc<FadeControl>,
+    frames: usize,
+    delayed: VecDeque<i16>,
+    /// The factor the next frame is scaled by, between 0 and 1.
+    gain: f32,
+    buffer: Vec<i16>,
+}
+
+impl FadeSink {
+    /// Moves `samples` samples from the delayed audio to the buffer, scaling
+    /// them while the gain moves towards `target` by `step` per frame.
+    fn ramp(&mut self, samples: usize, target: f32, step: f32) {
+        self.buffer.clear();
+        let mut frame = 0;
+        for sample in self.delayed.drain(..samples) {
+            self.buffer.push(
+                (f32::from(sample) * self.gain)
+                    .round()
+                    .clamp(f32::from(i16::MIN), f32::from(i16::MAX)) as i16,
+            );
+            frame += 1;
+            // Both channels of a frame are scaled the same.
+            if frame % 2 == 0 {
+                self.gain = if target > self.gain {
+                    (self.gain + step).min(target)
+                } else {
+                    (self.gain - step).max(target)
+                };
+            }
+        }
+    }
+}
+
+impl Sink for FadeSink {
+    fn start(&mut self) -> io::Result<()> {
+        self.delayed.clear();
+        self.control.volume_changed.store(false, Ordering::Relaxed);
+        self.gain = if self.control.muted.load(Ordering::Relaxed) {
+            1.0
+        } else {
+            0.0
+        };
+        self.sink.start()
+    }
+
+    fn stop(&mut self) -> io::Result<()> {
+        let samples = self.delayed.len();
+        if self.control.muted.load(Ordering::Relaxed) || samples < 2 {
+            self.ramp(samples, self.gain, 0.0);
+        } else {
+            let step = self.gain / (samples / 2) as f32;
+            self.ramp(samples, 0.0, step);
+        }
+        if !self.buffer.is_empty() {
+            self.sink.write(&self.buffer)?;
+        }
+        self.sink.stop()
+    }
+
+    fn write(&mut self, data: &[i16]) -> io::Result<()> {
+        // Turning the volume up or down while the audio fades in would be
+        // undone bit by bit, so the fade ends there.
+        if self.control.volume_changed.swap(false, Ordering::Relaxed) {
+            self.gain = 1.0;
+        }
+        self.delayed.extend(data);
+        let held_back = self.frames * 2;
+        if self.delayed.len() <= held_back {
+            return Ok(());
+        }
+        let samples = (self.delayed.len() - held_back) & !1;
+        self.ramp(samples, 1.0, 1.0 / self.frames as f32);
+        self.sink.write(&self.buffer)
+    }
+}
+
+/// Wraps `open` so that the audio fades in and out over `duration`.
+pub(crate) fn apply(
+    open: SinkBuilder,
+    duration: Duration,
+    control: Arc<FadeControl>,
+) -> SinkBuilder {
+    let frames = (duration.as_millis() as u64 * FRAMES_PER_SECOND / 1000).max(1) as usize;
+    Arc::new(move || {
+        Box::new(FadeSink {
+            sink: open(),
+            control: control.clone(),
+            frames,
+            delayed: VecDeque::with_capacity(frames * 2),
+            gain: 1.0,
+            buffer: Vec::new(),
+        })
+    })
+}
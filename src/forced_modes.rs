@@ -0,0 +1,77 @@
+use crate::{main_loop, web_api};
+use futures::{Async, Stream};
+use librespot::{core::session::Session, playback::player::PlayerEvent, protocol::spirc::State};
+use log::{info, warn};
+use rspotify::spotify::senum::RepeatState;
+use tokio_core::reactor::Handle;
+
+/// Turns shuffle or repeat on whenever a new context starts, for
+/// `force_shuffle` and `force_repeat`.
+///
+/// Clients send their own modes along with a context, so the forced ones are
+/// set right after through the Web API. They can still be turned off until
+/// the next context starts. New contexts are told from the state this device
+/// sends to the others.
+pub(crate) struct ForcedModes {
+    shuffle: bool,
+    repeat: bool,
+    states: Option<Box<dyn Stream<Item = State, Error = ()>>>,
+    context_uri: Option<String>,
+}
+
+impl ForcedModes {
+    pub(crate) fn new(shuffle: bool, repeat: bool) -> ForcedModes {
+        ForcedModes {
+            shuffle,
+            repeat,
+            states: None,
+            context_uri: None,
+        }
+    }
+
+    pub(crate) fn connected(&mut self, session: &Session) {
+        self.states = Some(main_loop::own_states(session));
+        self.context_uri = None;
+    }
+
+    pub(crate) fn handle_event(&mut self, event: &PlayerEvent) {
+        // Playing the same context again after it stopped counts as new.
+        if let PlayerEvent::Stopped { .. } = *event {
+            self.context_uri = None;
+        }
+    }
+
+    pub(crate) fn poll(&mut self, session: &Session, handle: &Handle) {
+        while let Some(Ok(Async::Ready(Some(state)))) = self.states.as_mut().map(Stream::poll) {
+            let context_uri = state.get_context_uri();
+            if context_uri.is_empty()
+                || state.get_track().is_empty()
+                || self.context_uri.as_deref() == Some(context_uri)
+            {
+                continue;
+            }
+            self.context_uri = Some(context_uri.to_string());
+            let shuffle = self.shuffle && !state.get_shuffle();
+            let repeat = self.repeat && !state.get_repeat();
+            if shuffle || repeat {
+                info!("Forcing the playback modes of {}", context_uri);
+                force(session, handle, shuffle, repeat);
+            }
+        }
+    }
+}
+
+fn force(session: &Session, handle: &Handle, shuffle: bool, repeat: bool) {
+    web_api::spawn_with_api(session, handle, "user-modify-playback-state", move |sp| {
+        if shuffle {
+            if let Err(e) = sp.shuffle(true, None) {
+                warn!("Could not turn shuffle on: {}", e);
+            }
+        }
+        if repeat {
+            if let Err(e) = sp.repeat(RepeatState::Context, None) {
+                warn!("Could not turn repeat on: {}", e);
+            }
+        }
+    });
+}
@@ -0,0 +1,45 @@
+use crate::main_loop::SinkBuilder;
+use librespot::playback::audio_backend::Sink;
+use std::{io, sync::Arc};
+
+/// A sink that scales every sample by a constant factor.
+struct GainSink {
+    sink: Box<dyn Sink>,
+    factor: f32,
+    buffer: Vec<i16>,
+}
+
+impl Sink for GainSink {
+    fn start(&mut self) -> io::Result<()> {
+        self.sink.start()
+    }
+
+    fn stop(&mut self) -> io::Result<()> {
+        self.sink.stop()
+    }
+
+    fn write(&mut self, data: &[i16]) -> io::Result<()> {
+        let factor = self.factor;
+        self.buffer.clear();
+        self.buffer.extend(data.iter().map(|&sample| {
+            (f32::from(sample) * factor)
+                .round()
+                .max(f32::from(i16::MIN))
+                .min(f32::from(i16::MAX)) as i16
+        }));
+        self.sink.write(&self.buffer)
+    }
+}
+
+/// Wraps `open` so that the audio is amplified by `db` before it reaches the
+/// backend, on top of the volume and the normalisation.
+pub(crate) fn apply(open: SinkBuilder, db: f32) -> SinkBuilder {
+    let factor = 10f32.powf(db / 20.0);
+    Arc::new(move || {
+        Box::new(GainSink {
+            sink: open(),
+            factor,
+            buffer: Vec::new(),
+        })
+    })
+}
@@ -0,0 +1,82 @@
+use crate::metadata::fetch_metadata;
+use chrono::{SecondsFormat, Utc};
+use futures::Future;
+use librespot::{core::session::Session, playback::player::PlayerEvent};
+use log::warn;
+use serde::Serialize;
+use std::{
+    fs,
+    io::{self, Write},
+    path::{Path, PathBuf},
+};
+use tokio_core::reactor::Handle;
+
+/// Once the history is this big, it's moved to `<file>.1`, replacing the
+/// history before, and a new one is started.
+const MAX_HISTORY_BYTES: u64 = 10 * 1024 * 1024;
+
+/// A line of the history.
+#[derive(Serialize)]
+struct PlayedTrack {
+    /// When the track ended, in RFC 3339.
+    played_at: String,
+    uri: String,
+    title: String,
+    artists: Vec<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    album: Option<String>,
+    duration_ms: u32,
+}
+
+/// Writes a line of JSON to `play_history_file` for every track played to
+/// the end. Skipped tracks aren't written.
+pub(crate) struct PlayHistory {
+    path: PathBuf,
+}
+
+impl PlayHistory {
+    pub(crate) fn new(path: PathBuf) -> PlayHistory {
+        PlayHistory { path }
+    }
+
+    pub(crate) fn handle_event(&self, event: &PlayerEvent, session: &Session, handle: &Handle) {
+        let track_id = match *event {
+            PlayerEvent::EndOfTrack { track_id, .. } => track_id,
+            _ => return,
+        };
+        let played_at = Utc::now().to_rfc3339_opts(SecondsFormat::Secs, true);
+        let path = self.path.clone();
+        handle.spawn(
+            fetch_metadata(session, track_id)
+                .map_err(move |()| warn!("Could not look up {} for the history", track_id.to_uri()))
+                .map(move |metadata| {
+                    let track = PlayedTrack {
+                        played_at,
+                        uri: metadata.uri,
+                        title: metadata.title,
+                        artists: metadata.artists,
+                        album: metadata.album,
+                        duration_ms: metadata.duration_ms,
+                    };
+                    if let Err(e) = append(&path, &track) {
+                        warn!("Could not write to {}: {}", path.display(), e);
+                    }
+                }),
+        );
+    }
+}
+
+fn append(path: &Path, track: &PlayedTrack) -> io::Result<()> {
+    if matches!(fs::metadata(path), Ok(metadata) if metadata.len() >= MAX_HISTORY_BYTES) {
+        let mut rotated = path.as_os_str().to_owned();
+        rotated.push(".1");
+        fs::rename(path, rotated)?;
+    }
+    let mut line = serde_json::to_string(track)?;
+    line.push('\n');
+    fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)?
+        .write_all(line.as_bytes())
+}
@@ -0,0 +1,303 @@
+use crate::{
+    audio_features::AudioFeatureCache,
+    control::{self, Command, RequestSender},
+    http_server::{self, Reply},
+    now_playing::{self, DeviceName, NowPlaying},
+};
+use futures::{
+    sync::mpsc::{self, UnboundedReceiver, UnboundedSender},
+    Future, Stream,
+};
+use librespot::{core::session::Session, playback::player::PlayerEvent};
+use log::info;
+use sha1::{Digest, Sha1};
+use std::{cell::RefCell, io, rc::Rc};
+use tokio_core::{
+    net::{TcpListener, TcpStream},
+    reactor::Handle,
+};
+use tokio_io::io::write_all;
+
+/// Appended to the key of a WebSocket handshake before it is hashed.
+const WEBSOCKET_GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+
+/// The WebSocket connections that are sent the player events.
+type Clients = Rc<RefCell<Vec<UnboundedSender<String>>>>;
+
+/// Everything a request can be answered from.
+struct Api {
+    now_playing: Rc<RefCell<NowPlaying>>,
+    audio_features: Option<AudioFeatureCache>,
+    requests: RequestSender,
+    clients: Clients,
+    /// The token requests have to send as `Authorization: Bearer <token>`.
+    token: Option<String>,
+    /// The origins of the pages that may use the API from a browser.
+    origins: Vec<String>,
+}
+
+impl Api {
+    fn status(&self, event: &str) -> String {
+        serde_json::json!({
+            "event": event,
+            "status": self.now_playing.borrow().snapshot(self.audio_features.as_ref()),
+        })
+        .to_string()
+    }
+}
+
+/// Serves the HTTP API on `listener`: what is playing on `GET /status`, playback
+/// commands as `POST` requests, and the player events as a WebSocket stream
+/// on `/events`.
+///
+/// Without a `token`, anyone who can connect controls playback. Requests made
+/// by pages in a browser are refused unless they come from one of `origins`.
+#[allow(clippy::too_many_arguments)]
+pub(crate) fn serve(
+    listener: TcpListener,
+    handle: &Handle,
+    device_name: DeviceName,
+    device_id: String,
+    events: UnboundedReceiver<(Session, PlayerEvent)>,
+    audio_features: Option<AudioFeatureCache>,
+    requests: RequestSender,
+    token: Option<String>,
+    origins: Vec<String>,
+) -> io::Result<()> {
+    let addr = listener.local_addr()?;
+    info!("Serving the HTTP API on http://{}/", addr);
+
+    let (changes_tx, changes) = mpsc::unbounded::<String>();
+    let now_playing = now_playing::track(
+        handle,
+        device_name,
+        device_id,
+        events,
+        Box::new(move |event| {
+            let _ = changes_tx.unbounded_send(event.to_string());
+        }),
+    );
+    let api = Rc::new(Api {
+        now_playing,
+        audio_features,
+        requests,
+        clients: Rc::new(RefCell::new(Vec::new())),
+        token,
+        origins,
+    });
+
+    let broadcast_api = api.clone();
+    handle.spawn(changes.for_each(move |event| {
+        let message = broadcast_api.status(&event);
+        broadcast_api
+            .clients
+            .borrow_mut()
+            .retain(|client| client.unbounded_send(message.clone()).is_ok());
+        Ok(())
+    }));
+
+    http_server::serve(listener, handle, "the HTTP API", move |socket, request| {
+        respond(socket, &request, &api)
+    });
+    Ok(())
+}
+
+/// Answers a single request.
+fn respond(socket: TcpStream, request: &str, api: &Api) -> Reply {
+    // Browsers send the origin of the page making the request, including for
+    // WebSockets. Other clients usually don't.
+    let cors = match header(request, "origin") {
+        Some(origin) if api.origins.iter().any(|allowed| allowed == origin) => format!(
+            "Access-Control-Allow-Origin: {}\r\n\
+             Access-Control-Allow-Headers: Authorization\r\n\
+             Vary: Origin\r\n",
+            origin
+        ),
+        Some(_) => {
+            return reply(
+                socket,
+                "403 Forbidden",
+                "",
+                error("requests from this origin aren't allowed"),
+            )
+        }
+        None => String::new(),
+    };
+    let mut words = request.split_whitespace();
+    let (method, target) = match (words.next(), words.next()) {
+        (Some(method), Some(target)) => (method, target),
+        _ => return reply(socket, "400 Bad Request", &cors, error("invalid request")),
+    };
+    // Browsers ask before sending the token from another origin, without it.
+    if method == "OPTIONS" {
+        return reply(socket, "204 No Content", &cors, String::new());
+    }
+    if let Some(ref token) = api.token {
+        let authorized = match header(request, "authorization") {
+            Some(authorization) => match authorization.strip_prefix("Bearer ") {
+                Some(sent) => same_secret(sent.trim(), token),
+                None => false,
+            },
+            None => false,
+        };
+        if !authorized {
+            return reply(
+                socket,
+                "401 Unauthorized",
+                &cors,
+                error("send the http_api_token as Authorization: Bearer <token>"),
+            );
+        }
+    }
+    let mut target = target.splitn(2, '?');
+    let path = target.next().unwrap_or_default();
+    let query = target.next().unwrap_or_default();
+
+    match (method, path) {
+        ("GET", "/events") => match header(request, "sec-websocket-key") {
+            Some(key) => stream_events(socket, key, api),
+            None => reply(
+                socket,
+                "400 Bad Request",
+                &cors,
+                error("/events is a WebSocket endpoint"),
+            ),
+        },
+        ("GET", "/") | ("GET", "/status") => reply(socket, "200 OK", &cors, api.status("status")),
+        ("POST", _) => match command(path, query) {
+            Ok(command) => {
+                let cors = cors.clone();
+                Box::new(
+                    control::send(&api.requests, command).then(move |result| match result {
+                        Ok(_) => reply(socket, "200 OK", &cors, r#"{"ok":true}"#.to_string()),
+                        Err(e) => reply(socket, "503 Service Unavailable", &cors, error(&e)),
+                    }),
+                )
+            }
+            Err(Some(e)) => reply(socket, "400 Bad Request", &cors, error(&e)),
+            Err(None) => reply(socket, "404 Not Found", &cors, error("not found")),
+        },
+        ("GET", _) => reply(socket, "404 Not Found", &cors, error("not found")),
+        _ => reply(
+            socket,
+            "405 Method Not Allowed",
+            &cors,
+            error("only GET, POST and OPTIONS are supported"),
+        ),
+    }
+}
+
+/// Returns the command a `POST` to `path` stands for, an error if its
+/// parameters are wrong, or `None` if there is no such command.
+fn command(path: &str, query: &str) -> Result<Command, Option<String>> {
+    let number = |name: &str| -> Result<u32, Option<String>> {
+        query
+            .split('&')
+            .find_map(|pair| {
+                let mut pair = pair.splitn(2, '=');
+                match (pair.next(), pair.next()) {
+                    (Some(key), Some(value)) if key == name => value.parse().ok(),
+                    _ => None,
+                }
+            })
+            .ok_or_else(|| Some(format!("{} needs ?{}=<number>", path, name)))
+    };
+    match path {
+        "/play" => Ok(Command::Play),
+        "/pause" => Ok(Command::Pause),
+        "/play-pause" => Ok(Command::PlayPause),
+        "/next" => Ok(Command::Next),
+        "/previous" => Ok(Command::Previous),
+        "/reload" => Ok(Command::Reload),
+        "/seek" => number("position_ms").map(Command::Seek),
+        "/volume-up" => Ok(Command::VolumeUp),
+        "/volume-down" => Ok(Command::VolumeDown),
+        "/volume" => match number("percent")? {
+            percent if percent <= 100 => Ok(Command::Volume(percent as u8)),
+            _ => Err(Some("the volume is between 0 and 100 percent".to_string())),
+        },
+        _ => Err(None),
+    }
+}
+
+/// Completes the WebSocket handshake and sends the status each time something
+/// changes, starting with the current one.
+fn stream_events(socket: TcpStream, key: &str, api: &Api) -> Reply {
+    let accept = base64::encode(Sha1::digest(
+        format!("{}{}", key, WEBSOCKET_GUID).as_bytes(),
+    ));
+    let handshake = format!(
+        "HTTP/1.1 101 Switching Protocols\r\n\
+         Upgrade: websocket\r\n\
+         Connection: Upgrade\r\n\
+         Sec-WebSocket-Accept: {}\r\n\r\n",
+        accept
+    );
+    let (sender, messages) = mpsc::unbounded();
+    let _ = sender.unbounded_send(api.status("status"));
+    api.clients.borrow_mut().push(sender);
+    // Nothing sent by the client is read. A closed connection is noticed once
+    // writing to it fails, and it is then forgotten.
+    Box::new(
+        write_all(socket, handshake.into_bytes())
+            .and_then(|(socket, _)| {
+                messages
+                    .map_err(|()| io::Error::from(io::ErrorKind::BrokenPipe))
+                    .fold(socket, |socket, message| {
+                        write_all(socket, text_frame(&message)).map(|(socket, _)| socket)
+                    })
+            })
+            .map(drop),
+    )
+}
+
+/// Wraps `text` in an unmasked WebSocket text frame, as servers send them.
+fn text_frame(text: &str) -> Vec<u8> {
+    let payload = text.as_bytes();
+    let mut frame = vec![0x81];
+    match payload.len() {
+        len if len < 126 => frame.push(len as u8),
+        len if len <= usize::from(u16::MAX) => {
+            frame.push(126);
+            frame.extend_from_slice(&(len as u16).to_be_bytes());
+        }
+        len => {
+            frame.push(127);
+            frame.extend_from_slice(&(len as u64).to_be_bytes());
+        }
+    }
+    frame.extend_from_slice(payload);
+    frame
+}
+
+/// Returns the value of the header `name`, given in lower case.
+pub(crate) fn header<'a>(request: &'a str, name: &str) -> Option<&'a str> {
+    request.lines().skip(1).find_map(|line| {
+        let mut line = line.splitn(2, ':');
+        match (line.next(), line.next()) {
+            (Some(key), Some(value)) if key.trim().eq_ignore_ascii_case(name) => Some(value.trim()),
+            _ => None,
+        }
+    })
+}
+
+/// Compares the token sent with the configured one, taking as long whatever
+/// part of it is wrong.
+fn same_secret(sent: &str, token: &str) -> bool {
+    sent.len() == token.len()
+        && sent
+            .bytes()
+            .zip(token.bytes())
+            .fold(0, |difference, (a, b)| difference | (a ^ b))
+            == 0
+}
+
+fn error(message: &str) -> String {
+    serde_json::json!({ "error": message }).to_string()
+}
+
+/// Sends `body` as JSON, after the extra `headers`, each ending in a line break.
+fn reply(socket: TcpStream, status: &str, headers: &str, body: String) -> Reply {
+    let headers = format!("{}Allow: GET, POST, OPTIONS\r\n", headers);
+    http_server::reply(socket, status, "application/json", &headers, body)
+}
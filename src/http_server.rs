@@ -0,0 +1,88 @@
+use futures::{
+    future::{self, Loop},
+    Future, Stream,
+};
+use log::warn;
+use std::{io, rc::Rc};
+use tokio_core::{
+    net::{TcpListener, TcpStream},
+    reactor::Handle,
+};
+use tokio_io::io::{read, write_all};
+
+/// How long the head of a request may be.
+const MAX_HEAD: usize = 16 * 1024;
+
+pub(crate) type Reply = Box<dyn Future<Item = (), Error = io::Error>>;
+
+/// Answers the requests made to `listener`, one connection each, with
+/// `respond`, which gets the head of the request. `name` is what the server
+/// is called in the logs.
+pub(crate) fn serve<F>(listener: TcpListener, handle: &Handle, name: &'static str, respond: F)
+where
+    F: Fn(TcpStream, String) -> Reply + 'static,
+{
+    let respond = Rc::new(respond);
+    let connections_handle = handle.clone();
+    let connections = listener
+        .incoming()
+        .for_each(move |(socket, _)| {
+            let respond = respond.clone();
+            let connection = read_request(socket)
+                .and_then(move |(socket, request)| respond(socket, request))
+                .map_err(move |e| warn!("A request to {} failed: {}", name, e));
+            connections_handle.spawn(connection);
+            Ok(())
+        })
+        .map_err(move |e| warn!("Stopped serving {}: {}", name, e));
+    handle.spawn(connections);
+}
+
+/// Reads the head of a request, up to the empty line that ends it. The
+/// servers take their parameters from the URL, so a body isn't read.
+fn read_request(socket: TcpStream) -> impl Future<Item = (TcpStream, String), Error = io::Error> {
+    future::loop_fn((socket, Vec::new()), |(socket, mut head)| {
+        read(socket, vec![0; 2048]).and_then(move |(socket, buffer, len)| {
+            if len == 0 {
+                return Err(io::ErrorKind::UnexpectedEof.into());
+            }
+            head.extend_from_slice(&buffer[..len]);
+            match head.windows(4).position(|window| window == b"\r\n\r\n") {
+                Some(end) => {
+                    head.truncate(end);
+                    let head = String::from_utf8_lossy(&head).into_owned();
+                    Ok(Loop::Break((socket, head)))
+                }
+                None if head.len() > MAX_HEAD => Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "the request is too long",
+                )),
+                None => Ok(Loop::Continue((socket, head))),
+            }
+        })
+    })
+}
+
+/// Sends `body` and closes the connection. The extra `headers` each end in a
+/// line break.
+pub(crate) fn reply(
+    socket: TcpStream,
+    status: &str,
+    content_type: &str,
+    headers: &str,
+    body: String,
+) -> Reply {
+    let response = format!(
+        "HTTP/1.1 {}\r\n\
+         Content-Type: {}\r\n\
+         Content-Length: {}\r\n\
+         {}\
+         Connection: close\r\n\r\n{}",
+        status,
+        content_type,
+        body.len(),
+        headers,
+        body
+    );
+    Box::new(write_all(socket, response.into_bytes()).map(drop))
+}
@@ -0,0 +1,58 @@
+use crate::main_loop;
+use futures::{Async, Future};
+use librespot::playback::player::PlayerEvent;
+use std::time::Duration;
+use tokio_core::reactor::{Handle, Timeout};
+
+/// Tells when this device wasn't the active Connect device for `after`, for
+/// `exit_on_idle`, so that spotifyd can exit and a supervisor can free what it
+/// used.
+///
+/// The time counts from startup, and from whenever playback here stops. Any
+/// player event, or a client connecting, starts it again.
+pub(crate) struct IdleExit {
+    after: Duration,
+    handle: Handle,
+    active: bool,
+    timeout: Option<Timeout>,
+}
+
+impl IdleExit {
+    pub(crate) fn new(after: Duration, handle: &Handle) -> IdleExit {
+        IdleExit {
+            after,
+            handle: handle.clone(),
+            active: false,
+            timeout: Timeout::new(after, handle).ok(),
+        }
+    }
+
+    pub(crate) fn handle_event(&mut self, event: &PlayerEvent) {
+        self.active = main_loop::is_active_after(event, self.active);
+        self.reset();
+    }
+
+    /// Starts counting again, unless this is the active device.
+    pub(crate) fn reset(&mut self) {
+        self.timeout = if self.active {
+            None
+        } else {
+            Timeout::new(self.after, &self.handle).ok()
+        };
+    }
+
+    /// Returns whether it's time to exit.
+    pub(crate) fn poll(&mut self) -> bool {
+        match self.timeout.as_mut().map(Future::poll) {
+            Some(Ok(Async::Ready(()))) => {
+                self.timeout = None;
+                true
+            }
+            _ => false,
+        }
+    }
+
+    pub(crate) fn minutes(&self) -> u64 {
+        self.after.as_secs() / 60
+    }
+}
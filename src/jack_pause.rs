@@ -0,0 +1,160 @@
+#[cfg(feature = "alsa_backend")]
+use alsa::{ctl::ElemIface, hctl::HCtl};
+use futures::{Async, Stream};
+use librespot::{connect::spirc::Spirc, playback::player::PlayerEvent};
+use log::info;
+#[cfg(not(feature = "alsa_backend"))]
+use log::warn;
+use std::time::Duration;
+use tokio_core::reactor::{Handle, Interval};
+
+/// How often the jack is looked at.
+const POLL_INTERVAL: Duration = Duration::from_millis(500);
+
+/// Tells whether headphones are plugged in.
+type ReadJack = Box<dyn Fn() -> Option<bool>>;
+
+/// Pauses playback when the headphones are unplugged, for
+/// `pause_on_jack_unplug`, so that it doesn't go on from the speakers, and
+/// resumes it once they are plugged in again with `resume_on_jack_replug`.
+///
+/// Like [`LockPause`](crate::lock::LockPause), only playback paused here is
+/// resumed.
+pub(crate) struct JackPause {
+    read: ReadJack,
+    interval: Interval,
+    resume: bool,
+    plugged: bool,
+    playing: bool,
+    paused_by_unplug: bool,
+}
+
+impl JackPause {
+    /// Returns `None` if the card of `device` doesn't report its headphone
+    /// jack, in which case there is nothing to do.
+    pub(crate) fn new(device: &str, handle: &Handle, resume: bool) -> Option<JackPause> {
+        let read = jack_reader(device)?;
+        let plugged = read()?;
+        info!(
+            "Watching the headphone jack of {}, headphones are {}",
+            device,
+            if plugged { "plugged in" } else { "unplugged" }
+        );
+        Some(JackPause {
+            read,
+            interval: Interval::new(POLL_INTERVAL, handle).ok()?,
+            resume,
+            plugged,
+            playing: false,
+            paused_by_unplug: false,
+        })
+    }
+
+    pub(crate) fn handle_event(&mut self, event: &PlayerEvent) {
+        match *event {
+            PlayerEvent::Playing { .. } => {
+                self.playing = true;
+                self.paused_by_unplug = false;
+            }
+            PlayerEvent::Paused { .. } => self.playing = false,
+            PlayerEvent::Stopped { .. } => {
+                self.playing = false;
+                self.paused_by_unplug = false;
+            }
+            _ => (),
+        }
+    }
+
+    pub(crate) fn poll(&mut self, spirc: Option<&Spirc>) {
+        while let Ok(Async::Ready(Some(()))) = self.interval.poll() {
+            let plugged = match (self.read)() {
+                Some(plugged) if plugged != self.plugged => plugged,
+                _ => continue,
+            };
+            self.plugged = plugged;
+            let spirc = match spirc {
+                Some(spirc) => spirc,
+                None => continue,
+            };
+            if !plugged && self.playing {
+                info!("The headphones were unplugged, pausing playback");
+                spirc.pause();
+                self.paused_by_unplug = true;
+            } else if !plugged {
+                info!("The headphones were unplugged, nothing is playing");
+            } else if self.resume && self.paused_by_unplug {
+                info!("The headphones were plugged in, resuming playback");
+                spirc.play();
+                self.paused_by_unplug = false;
+            } else {
+                info!("The headphones were plugged in");
+                self.paused_by_unplug = false;
+            }
+        }
+    }
+}
+
+/// Returns the control device of the card `device` plays on, as jacks belong
+/// to the card rather than to one of its devices.
+#[cfg(feature = "alsa_backend")]
+fn card_control(device: &str) -> String {
+    let card = device
+        .strip_prefix("hw:")
+        .or_else(|| device.strip_prefix("plughw:"));
+    match card {
+        Some(card) => {
+            let card = card.split(',').next().unwrap_or_default();
+            format!("hw:{}", card.strip_prefix("CARD=").unwrap_or(card))
+        }
+        None => device.to_string(),
+    }
+}
+
+/// Reads the jack controls of the card, the ones named like "Headphone Jack"
+/// or "Front Headphone Jack". Headphones are plugged in if any of them says so.
+#[cfg(feature = "alsa_backend")]
+fn jack_reader(device: &str) -> Option<ReadJack> {
+    let control = card_control(device);
+    let hctl = match HCtl::new(&control, false).and_then(|hctl| hctl.load().map(|()| hctl)) {
+        Ok(hctl) => hctl,
+        Err(e) => {
+            info!(
+                "Could not open the controls of {}, pause_on_jack_unplug does nothing: {}",
+                control, e
+            );
+            return None;
+        }
+    };
+    let has_jack = hctl.elem_iter().any(|elem| is_headphone_jack(&elem));
+    if !has_jack {
+        info!(
+            "{} doesn't report a headphone jack, pause_on_jack_unplug does nothing",
+            control
+        );
+        return None;
+    }
+    Some(Box::new(move || {
+        let mut found = None;
+        for elem in hctl.elem_iter().filter(is_headphone_jack) {
+            let plugged = elem
+                .read()
+                .map_err(|e| log::debug!("Could not read the headphone jack: {}", e))
+                .ok()
+                .and_then(|value| value.get_boolean(0))?;
+            found = Some(found.unwrap_or(false) || plugged);
+        }
+        found
+    }))
+}
+
+#[cfg(feature = "alsa_backend")]
+fn is_headphone_jack(elem: &alsa::hctl::Elem) -> bool {
+    matches!(elem.get_id(), Ok(id) if id.get_interface() == ElemIface::Card
+        && matches!(id.get_name(), Ok(name) if name.ends_with("Headphone Jack")))
+}
+
+#[cfg(not(feature = "alsa_backend"))]
+fn jack_reader(_: &str) -> Option<ReadJack> {
+    warn!("pause_on_jack_unplug needs the alsa_backend feature, ignoring it");
+    None
+}
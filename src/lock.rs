@@ -0,0 +1,190 @@
+#[cfg(feature = "dbus_mpris")]
+use dbus::{
+    arg::{RefArg, Variant},
+    BusType, Connection, Message, MessageType, Path,
+};
+#[cfg(feature = "dbus_mpris")]
+use dbus_tokio::AConnection;
+use futures::{Async, Stream};
+use librespot::{connect::spirc::Spirc, playback::player::PlayerEvent};
+use log::info;
+#[cfg(not(feature = "dbus_mpris"))]
+use log::warn;
+#[cfg(feature = "dbus_mpris")]
+use std::{collections::HashMap, rc::Rc};
+use tokio_core::reactor::Handle;
+
+#[cfg(feature = "dbus_mpris")]
+const LOGIND: &str = "org.freedesktop.login1";
+#[cfg(feature = "dbus_mpris")]
+const LOGIN_SESSION: &str = "org.freedesktop.login1.Session";
+
+/// Pauses playback while the login session is locked, for `pause_on_lock`,
+/// and resumes it on unlock with `resume_on_unlock`.
+///
+/// Only playback paused here is resumed, so pausing by hand while locked
+/// sticks.
+pub(crate) struct LockPause {
+    signals: Box<dyn Stream<Item = bool, Error = ()>>,
+    resume: bool,
+    locked: bool,
+    playing: bool,
+    paused_by_lock: bool,
+}
+
+impl LockPause {
+    /// Returns `None` if logind can't be reached, in which case there is
+    /// nothing to do.
+    pub(crate) fn new(handle: &Handle, resume: bool) -> Option<LockPause> {
+        Some(LockPause {
+            signals: signals(handle)?,
+            resume,
+            locked: false,
+            playing: false,
+            paused_by_lock: false,
+        })
+    }
+
+    pub(crate) fn handle_event(&mut self, event: &PlayerEvent) {
+        match *event {
+            PlayerEvent::Playing { .. } => {
+                self.playing = true;
+                self.paused_by_lock = false;
+            }
+            PlayerEvent::Paused { .. } => self.playing = false,
+            PlayerEvent::Stopped { .. } => {
+                self.playing = false;
+                self.paused_by_lock = false;
+            }
+            _ => (),
+        }
+    }
+
+    pub(crate) fn poll(&mut self, spirc: Option<&Spirc>) {
+        while let Ok(Async::Ready(Some(locked))) = self.signals.poll() {
+            // Lock and a change of LockedHint often both announce the same.
+            if locked == self.locked {
+                continue;
+            }
+            self.locked = locked;
+            let spirc = match spirc {
+                Some(spirc) => spirc,
+                None => continue,
+            };
+            if locked && self.playing {
+                info!("The session was locked, pausing playback");
+                spirc.pause();
+                self.paused_by_lock = true;
+            } else if locked {
+                info!("The session was locked, nothing is playing");
+            } else if self.resume && self.paused_by_lock {
+                info!("The session was unlocked, resuming playback");
+                spirc.play();
+                self.paused_by_lock = false;
+            } else {
+                info!("The session was unlocked");
+                self.paused_by_lock = false;
+            }
+        }
+    }
+}
+
+/// Returns the path of the login session spotifyd runs in, or the graphical
+/// session of its user when it runs as a service outside of one.
+#[cfg(feature = "dbus_mpris")]
+fn session_path(connection: &Connection) -> Result<Path<'static>, String> {
+    let call = |method: &str, argument: u32| -> Result<Path<'static>, String> {
+        let message = Message::new_method_call(
+            LOGIND,
+            "/org/freedesktop/login1",
+            "org.freedesktop.login1.Manager",
+            method,
+        )?
+        .append1(argument);
+        let reply = connection
+            .send_with_reply_and_block(message, 500)
+            .map_err(|e| e.to_string())?;
+        reply
+            .get1::<Path>()
+            .map(Path::into_static)
+            .ok_or_else(|| format!("logind sent no path for {}", method))
+    };
+    call("GetSessionByPID", std::process::id()).or_else(|_| {
+        use dbus::stdintf::org_freedesktop_dbus::Properties;
+
+        let user = call("GetUser", unsafe { libc::getuid() })?;
+        let (_, session) = connection
+            .with_path(LOGIND, user, 500)
+            .get::<(String, Path)>("org.freedesktop.login1.User", "Display")
+            .map_err(|e| e.to_string())?;
+        if &*session == "/" {
+            return Err("the user has no graphical session".to_string());
+        }
+        Ok(session.into_static())
+    })
+}
+
+/// Tells when the login session gets locked (`true`) and unlocked again
+/// (`false`), as announced by logind. Returns `None` if logind can't be
+/// reached.
+#[cfg(feature = "dbus_mpris")]
+fn signals(handle: &Handle) -> Option<Box<dyn Stream<Item = bool, Error = ()>>> {
+    let connection = Connection::get_private(BusType::System)
+        .map_err(|e| e.to_string())
+        .and_then(|connection| {
+            let path = session_path(&connection)?;
+            connection
+                .add_match(&format!(
+                    "type='signal',interface='{}',path='{}'",
+                    LOGIN_SESSION, path
+                ))
+                .and_then(|()| {
+                    connection.add_match(&format!(
+                        "type='signal',interface='org.freedesktop.DBus.Properties',\
+                         member='PropertiesChanged',path='{}',arg0='{}'",
+                        path, LOGIN_SESSION
+                    ))
+                })
+                .map_err(|e| e.to_string())?;
+            info!("Watching the login session {} for locks", path);
+            Ok(connection)
+        });
+    let messages = connection.and_then(|connection| {
+        AConnection::new(Rc::new(connection), handle.clone())
+            .map_err(|e| e.to_string())
+            .and_then(|connection| connection.messages().map_err(str::to_string))
+    });
+    match messages {
+        Ok(messages) => Some(Box::new(messages.filter_map(|message| {
+            if message.msg_type() != MessageType::Signal {
+                return None;
+            }
+            match message.member().as_deref() {
+                Some("Lock") => Some(true),
+                Some("Unlock") => Some(false),
+                Some("PropertiesChanged") => {
+                    let (_, changed) =
+                        message.get2::<&str, HashMap<String, Variant<Box<dyn RefArg>>>>();
+                    changed?
+                        .get("LockedHint")
+                        .and_then(|hint| hint.0.as_i64())
+                        .map(|hint| hint != 0)
+                }
+                _ => None,
+            }
+        }))),
+        Err(e) => {
+            info!(
+                "Could not listen for the session being locked, pause_on_lock does nothing: {}",
+                e
+            );
+            None
+        }
+    }
+}
+
+#[cfg(not(feature = "dbus_mpris"))]
+fn signals(_: &Handle) -> Option<Box<dyn Stream<Item = bool, Error = ()>>> {
+    warn!("pause_on_lock needs the dbus_mpris feature, ignoring it");
+    None
+}
@@ -1,35 +1,131 @@
 //#![cfg(unix)]
 
-use crate::config::CliConfig;
+use crate::{
+    config::{CliConfig, Command, LogTimestamp},
+    error::SetupError,
+    state::State,
+};
+use backtrace::Backtrace;
 use color_eyre::{eyre::Context, Help, Report, SectionExt};
 #[cfg(unix)]
 use daemonize::Daemonize;
-use log::{error, info, trace, LevelFilter};
-use std::panic;
+use log::{error, info, trace, warn, LevelFilter};
+use std::{
+    fmt, io,
+    panic::{self, AssertUnwindSafe},
+    path::PathBuf,
+    sync::{Arc, RwLock},
+    thread,
+    time::Duration,
+};
 use structopt::StructOpt;
 use tokio_core::reactor::Core;
 
 #[cfg(feature = "alsa_backend")]
 mod alsa_mixer;
+#[cfg(feature = "alsa_backend")]
+mod alsa_sink;
+mod audio_devices;
+mod audio_features;
+mod autoplay;
+mod cache;
 mod config;
+mod connect_volume;
+mod context_volume;
+mod control;
+mod credentials;
 #[cfg(feature = "dbus_mpris")]
 mod dbus_mpris;
+mod device_lost;
+mod downmix;
 mod error;
+mod event_hooks;
+mod explicit;
+mod fade;
+mod forced_modes;
+mod gain;
+mod history;
+#[cfg(feature = "http_api")]
+mod http_api;
+#[cfg(feature = "status_server")]
+mod http_server;
+mod idle_exit;
+mod jack_pause;
+mod lock;
 mod main_loop;
+mod media_controls;
+mod metadata;
+mod metrics;
+mod mixer_watch;
+mod network;
+mod normalisation;
+mod now_playing;
+mod null_sink;
+mod oauth;
+mod placeholders;
+mod prefer_active;
+mod priority;
 mod process;
+mod queue;
+mod reconnect_resume;
+mod resume;
+#[cfg(feature = "scrobble")]
+mod scrobble;
 mod setup;
+mod sleep;
+mod source_address;
+mod state;
+#[cfg(feature = "status_server")]
+mod status;
+mod systemd;
+mod tee_sink;
+mod track_error;
 mod utils;
+mod volume_curve;
+mod web_api;
+mod zeroconf;
+
+/// How long to wait before restarting the main loop after a panic.
+const RESTART_DELAY: Duration = Duration::from_secs(5);
+
+/// Set in the environment of the detached process started on Windows.
+#[cfg(target_os = "windows")]
+const CHILD_ENV_VAR: &str = "SPOTIFYD_CHILD";
 
 enum LogTarget {
     Terminal,
+    /// Keeps stdout free for output that is meant to be redirected.
+    Stderr,
     Syslog,
 }
 
-fn setup_logger(log_target: LogTarget, log_level: LevelFilter) {
-    let logger = fern::Dispatch::new().level(log_level);
+/// The timestamp log lines are prefixed with, which is only known once the
+/// config file is loaded.
+pub(crate) type LogTimestamps = Arc<RwLock<Option<LogTimestamp>>>;
+
+fn timestamped(
+    timestamps: LogTimestamps,
+) -> impl Fn(fern::FormatCallback, &fmt::Arguments, &log::Record) + Send + Sync + 'static {
+    move |out, message, _| match *timestamps.read().unwrap() {
+        Some(ref timestamp) => out.finish(format_args!("{} {}", timestamp.now(), message)),
+        None => out.finish(*message),
+    }
+}
+
+/// Sets up logging at `log_level`. Only the `log` crate's maximum level filters
+/// what is logged, so that `log::set_max_level` changes it later on.
+fn setup_logger(log_target: LogTarget, log_level: LevelFilter) -> LogTimestamps {
+    let timestamps = LogTimestamps::default();
+    let logger = fern::Dispatch::new().level(LevelFilter::Trace);
 
     let logger = match log_target {
-        LogTarget::Terminal => logger.chain(std::io::stdout()),
+        LogTarget::Terminal => logger
+            .format(timestamped(timestamps.clone()))
+            .chain(std::io::stdout()),
+        LogTarget::Stderr => logger
+            .format(timestamped(timestamps.clone()))
+            .chain(std::io::stderr()),
+        // Syslog adds its own timestamps.
         #[cfg(unix)]
         LogTarget::Syslog => {
             let log_format = syslog::Formatter3164 {
@@ -48,7 +144,7 @@ fn setup_logger(log_target: LogTarget, log_level: LevelFilter) {
             std::fs::create_dir_all(&log_file).expect("Couldn't create log dir.");
             log_file.push(".spotifyd.log");
 
-            logger.chain(
+            logger.format(timestamped(timestamps.clone())).chain(
                 std::fs::OpenOptions::new()
                     .write(true)
                     .create(true)
@@ -60,15 +156,43 @@ fn setup_logger(log_target: LogTarget, log_level: LevelFilter) {
     };
 
     logger.apply().expect("Couldn't initialize logger");
+    log::set_max_level(log_level);
+    timestamps
 }
 
-fn main() -> Result<(), Report> {
+fn main() {
+    if let Err(report) = run() {
+        let exit_code = report
+            .downcast_ref::<SetupError>()
+            .map_or(1, SetupError::exit_code);
+        eprintln!("Error: {:?}", report);
+        std::process::exit(exit_code);
+    }
+}
+
+fn run() -> Result<(), Report> {
     let mut cli_config: CliConfig = CliConfig::from_args();
 
-    let is_daemon = !cli_config.no_daemon;
+    // Subcommands only run a single task, so they always stay in the foreground.
+    let is_daemon = !cli_config.no_daemon
+        && cli_config.command.is_none()
+        && !cli_config.dump_config
+        && cli_config.generate_config.is_none();
 
-    let log_target = if is_daemon {
+    // On Windows the process in the background is a detached copy of this one, started
+    // with `--no-daemon`. Only that copy has no console and needs to log to a file.
+    #[cfg(unix)]
+    let in_background = is_daemon;
+    #[cfg(target_os = "windows")]
+    let in_background = std::env::var_os(CHILD_ENV_VAR).is_some();
+
+    let log_target = if in_background {
         LogTarget::Syslog
+    } else if cli_config.dump_config
+        || matches!(cli_config.command, Some(ref command) if command.control_line().is_some())
+    {
+        // What these print may be read by scripts.
+        LogTarget::Stderr
     } else {
         LogTarget::Terminal
     };
@@ -78,12 +202,19 @@ fn main() -> Result<(), Report> {
         LevelFilter::Info
     };
 
-    setup_logger(log_target, log_level);
+    let log_timestamps = setup_logger(log_target, log_level);
     color_eyre::install().expect("Coundn't initialize error reporting");
 
+    // The template doesn't depend on the config, which may not even exist yet.
+    if let Some(path) = cli_config.generate_config.take() {
+        return generate_config(path, cli_config.force);
+    }
+
     cli_config
         .load_config_file_values()
-        .wrap_err("could not load the config file")
+        .wrap_err(SetupError::ConfigInvalid(
+            "could not load the config file".to_string(),
+        ))
         .with_section(|| {
             concat!(
                 "the config format should be valid TOML\n",
@@ -91,14 +222,38 @@ fn main() -> Result<(), Report> {
             )
             .header("note:")
         })?;
+    *log_timestamps.write().unwrap() = cli_config.shared_config.log_timestamp();
     trace!("{:?}", &cli_config);
 
+    let command = cli_config.command.take();
+    let dump_config = if cli_config.dump_config {
+        Some((cli_config.shared_config.clone(), cli_config.show_secrets))
+    } else {
+        None
+    };
+
     // Returns the old SpotifydConfig struct used within the rest of the daemon.
     let internal_config = config::get_internal_config(cli_config);
+    log::set_max_level(internal_config.log_level);
+
+    if let Some((values, show_secrets)) = dump_config {
+        let toml = config::effective_config(values, &internal_config, show_secrets)
+            .wrap_err("could not write the config as TOML")?;
+        print!("{}", toml);
+        return Ok(());
+    }
+
+    if let Some(command) = command {
+        return run_command(command, internal_config, log_timestamps);
+    }
 
     if is_daemon {
         #[cfg(unix)]
         {
+            // systemd would only hear from and pass sockets to the process it started.
+            if cfg!(feature = "systemd") && std::env::var_os("NOTIFY_SOCKET").is_some() {
+                warn!("spotifyd is started by systemd, but daemonizes. Pass --no-daemon");
+            }
             info!("Daemonizing running instance");
 
             let mut daemonize = Daemonize::new();
@@ -122,7 +277,7 @@ fn main() -> Result<(), Report> {
 
             Command::new(std::env::current_exe().unwrap())
                 .args(args)
-                .env("SPOTIFYD_CHILD", "1")
+                .env(CHILD_ENV_VAR, "1")
                 .creation_flags(8 /* DETACHED_PROCESS */)
                 .spawn()
                 .expect("Couldn't spawn daemon");
@@ -131,25 +286,220 @@ fn main() -> Result<(), Report> {
         }
     }
 
-    panic::set_hook(Box::new(|panic_info| {
+    // This comes after daemonizing, so that the new process gets it too, and
+    // before the main loop starts the audio thread, which inherits it.
+    if let Some(nice) = internal_config.nice {
+        priority::set_nice(nice);
+    }
+
+    let restart_on_panic = internal_config.restart_on_panic;
+
+    panic::set_hook(Box::new(move |panic_info| {
+        if utils::catching_panics() {
+            return;
+        }
         error!(
-            "PANIC: Shutting down spotifyd. Error message: {}",
-            match (
-                panic_info.payload().downcast_ref::<String>(),
-                panic_info.payload().downcast_ref::<&str>(),
-            ) {
-                (Some(s), _) => &**s,
-                (_, Some(&s)) => s,
-                _ => "Unknown error type, can't produce message.",
-            }
+            "PANIC: {} spotifyd. Error message: {}",
+            if restart_on_panic {
+                "Restarting"
+            } else {
+                "Shutting down"
+            },
+            utils::panic_message(panic_info.payload())
+                .unwrap_or("Unknown error type, can't produce message.")
         );
+        error!("{:?}", Backtrace::new());
     }));
 
+    // The servers and threads that run for as long as spotifyd does are started
+    // once, on a core that is kept across restarts.
+    let mut core = Core::new().unwrap();
+    let handle = core.handle();
+    let mut internal_config = internal_config;
+    let services = setup::services(&handle, &mut internal_config, log_timestamps)?;
+
+    loop {
+        // Everything else belonging to a run of the main loop, including the session and
+        // the audio device, lives inside this closure and is torn down when it unwinds.
+        let config = internal_config.clone();
+        let result = panic::catch_unwind(AssertUnwindSafe(|| {
+            let initial_state = setup::initial_state(handle.clone(), config, &services, None)?;
+            core.run(initial_state)
+        }));
+
+        match result {
+            // A panic on the player thread only shows as the player stopping.
+            Ok(Err(SetupError::PlayerStopped)) | Err(_) if restart_on_panic => {
+                info!("Restarting in {} seconds", RESTART_DELAY.as_secs());
+                thread::sleep(RESTART_DELAY);
+            }
+            Ok(result) => return result.map_err(Report::new),
+            Err(payload) => panic::resume_unwind(payload),
+        }
+    }
+}
+
+fn generate_config(path: Option<PathBuf>, force: bool) -> Result<(), Report> {
+    let path = path.or_else(config::default_config_path).ok_or_else(|| {
+        SetupError::ConfigInvalid("could not find where to write the config".to_string())
+    })?;
+    match config::write_config_template(&path, force) {
+        Ok(()) => {
+            info!("Wrote a config to {}", path.display());
+            Ok(())
+        }
+        Err(e) if e.kind() == io::ErrorKind::AlreadyExists => {
+            Err(SetupError::ConfigInvalid(format!(
+                "{} already exists, pass --force to overwrite it",
+                path.display()
+            ))
+            .into())
+        }
+        Err(e) => Err(e).wrap_err(format!("could not write the config to {}", path.display())),
+    }
+}
+
+fn run_command(
+    command: Command,
+    config: config::SpotifydConfig,
+    log_timestamps: LogTimestamps,
+) -> Result<(), Report> {
+    if let Some(line) = command.control_line() {
+        return send_to_daemon(&command, &line, &config);
+    }
+
     let mut core = Core::new().unwrap();
     let handle = core.handle();
 
-    let initial_state = setup::initial_state(handle, internal_config);
-    core.run(initial_state).unwrap();
+    match command {
+        Command::AuthTest => {
+            let session = core.run(setup::auth_test(handle, config))?;
+            info!(
+                "Authentication succeeded for user \"{}\"",
+                session.username()
+            );
+            session.shutdown();
+        }
+        Command::Authenticate => {
+            let session = core.run(setup::authenticate(handle, config)?)?;
+            info!(
+                "Authenticated as \"{}\", spotifyd logs in with the cached credentials from now on",
+                session.username()
+            );
+            session.shutdown();
+        }
+        Command::Logout => match config.cache_path {
+            Some(ref cache_path) => {
+                #[cfg(feature = "dbus_keyring")]
+                let in_keyring =
+                    config.use_keyring && credentials::remove_from_keyring(cache_path)?;
+                #[cfg(not(feature = "dbus_keyring"))]
+                let in_keyring = false;
+                if in_keyring {
+                    info!("Removed the credentials kept in the keyring");
+                }
+                match cache::remove_credentials(cache_path)? {
+                    Some(path) => info!("Removed the cached credentials in {}", path.display()),
+                    None if in_keyring => (),
+                    None => info!("There were no cached credentials to remove"),
+                }
+            }
+            None => info!("No cache_path is configured, so no credentials are cached"),
+        },
+        Command::CacheStats => match config.cache_path {
+            Some(ref cache_path) => {
+                let stats = cache::CacheStats::collect(cache_path, std::time::SystemTime::now())
+                    .wrap_err("could not read the cache")?;
+                println!("{}", stats.report(config.max_cache_size));
+            }
+            None => info!("No cache_path is configured, so nothing is cached"),
+        },
+        Command::ClearCache => match config.cache_path {
+            Some(ref cache_path) => {
+                let (removed, bytes) =
+                    cache::clear(cache_path).wrap_err("could not clear the cache")?;
+                info!(
+                    "Removed {} audio files from the cache, freeing {:.1} MB",
+                    removed,
+                    bytes as f64 / 1_000_000.0
+                );
+            }
+            None => info!("No cache_path is configured, so nothing is cached"),
+        },
+        Command::ResetDeviceId => match config.state_path {
+            Some(ref path) => {
+                let mut state = State::load(path);
+                let id = config::new_device_id();
+                state.device_id = Some(id.clone());
+                state
+                    .save(path)
+                    .wrap_err("could not save the new device id")?;
+                info!(
+                    "The device will be announced with the id {} from now on",
+                    id
+                );
+            }
+            None => info!("No cache_path is configured, so the id is derived from the device name"),
+        },
+        Command::ListDevices => {
+            let backends: Vec<&str> = librespot::playback::audio_backend::BACKENDS
+                .iter()
+                .map(|backend| backend.0)
+                .collect();
+            info!("The backends in this build are {}", backends.join(", "));
+            let devices = audio_devices::list();
+            if devices.is_empty() {
+                info!("No audio devices were found, or the backend can't list them");
+            }
+            for (index, (name, description)) in devices.iter().enumerate() {
+                // ALSA descriptions can span several lines.
+                let description = description
+                    .as_deref()
+                    .unwrap_or_default()
+                    .replace('\n', ", ");
+                println!("{}: {} ({})", index, name, description);
+            }
+        }
+        Command::Play {
+            uri: Some(uri),
+            duration,
+        } => {
+            if !config::is_valid_spotify_uri(&uri) {
+                return Err(SetupError::ConfigInvalid(format!("invalid URI \"{}\"", uri)).into());
+            }
+            let oneshot = main_loop::Oneshot::new(uri, duration.map(Duration::from_secs));
+            let mut config = config;
+            let services = setup::services(&handle, &mut config, log_timestamps)?;
+            core.run(setup::initial_state(
+                handle,
+                config,
+                &services,
+                Some(oneshot),
+            )?)?;
+        }
+        _ => unreachable!("commands for the running spotifyd are sent to it"),
+    }
 
     Ok(())
 }
+
+/// Sends `line` to the running spotifyd over its `control_socket`, and prints
+/// what it reported.
+fn send_to_daemon(
+    command: &Command,
+    line: &str,
+    config: &config::SpotifydConfig,
+) -> Result<(), Report> {
+    let path = config.control_socket.as_ref().ok_or_else(|| {
+        SetupError::ConfigInvalid(
+            "control_socket has to be set, for both spotifyd and this command".to_string(),
+        )
+    })?;
+    let output = control::request(path, line).map_err(Report::msg)?;
+    match command {
+        Command::Status { json: false } => println!("{}", now_playing::describe(&output)),
+        _ if !output.is_empty() => println!("{}", output),
+        _ => (),
+    }
+    Ok(())
+}
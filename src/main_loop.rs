@@ -1,88 +1,483 @@
 #[cfg(feature = "dbus_mpris")]
 use crate::dbus_mpris::DbusServer;
-use crate::process::{spawn_program_on_event, Child};
-use futures::{self, Async, Future, Poll, Stream};
+use crate::{
+    audio_features::AudioFeatureCache,
+    autoplay::DelayedAutoplay,
+    cache::{self, CacheIndex, CacheStats},
+    config::{self, is_valid_device_name, SharedConfigValues},
+    connect_volume::ConnectVolume,
+    context_volume::ContextVolumes,
+    control::{Command, Request, RequestSender},
+    credentials::CredentialStore,
+    device_lost::DeviceWatch,
+    error::SetupError,
+    event_hooks::EventHooks,
+    explicit::ExplicitFilter,
+    fade::FadeControl,
+    forced_modes::ForcedModes,
+    history::PlayHistory,
+    idle_exit::IdleExit,
+    jack_pause::JackPause,
+    lock::LockPause,
+    metadata::MetadataFetch,
+    metrics::Metrics,
+    mixer_watch::MixerWatch,
+    network,
+    normalisation::{self, Normalisation},
+    now_playing::{DeviceName, NowPlaying},
+    prefer_active::PreferActive,
+    queue::{self, ConnectQueue},
+    reconnect_resume::ReconnectResume,
+    resume::PlaybackResume,
+    setup,
+    state::State,
+    systemd::Systemd,
+    track_error::TrackErrors,
+    web_api, LogTimestamps,
+};
+use futures::{self, sync::oneshot, Async, Future, Poll, Stream};
 use librespot::{
     connect::{
         discovery::DiscoveryStream,
         spirc::{Spirc, SpircTask},
     },
     core::{
+        authentication::Credentials,
         cache::Cache,
         config::{ConnectConfig, DeviceType, SessionConfig, VolumeCtrl},
         session::Session,
+        util::url_encode,
     },
     playback::{
         audio_backend::Sink,
-        config::PlayerConfig,
+        config::{Bitrate, PlayerConfig},
         mixer::Mixer,
         player::{Player, PlayerEvent},
     },
+    protocol::spirc::{Frame, MessageType, State as ConnectState},
+};
+use log::{error, info, warn};
+use rspotify::spotify::{client::Spotify, senum::Country};
+use std::{
+    cell::{Cell, RefCell},
+    path::PathBuf,
+    rc::Rc,
+    sync::Arc,
+    thread,
+    time::{Duration, SystemTime},
 };
-use log::error;
-use std::{io, rc::Rc};
-use tokio_core::reactor::Handle;
+use tokio_core::reactor::{Handle, Timeout};
 use tokio_io::IoStream;
 
 pub struct LibreSpotConnection {
-    connection: Box<dyn Future<Item = Session, Error = io::Error>>,
+    connection: Box<dyn Future<Item = Session, Error = SetupError>>,
     spirc_task: Option<SpircTask>,
     spirc: Option<Rc<Spirc>>,
-    discovery_stream: DiscoveryStream,
+    session: Option<Session>,
+    rename_requests: Option<Box<dyn Stream<Item = String, Error = ()>>>,
+    discovery_stream: Option<DiscoveryStream>,
+    /// The credentials of the last login, to log in with again.
+    credentials: Option<Credentials>,
+    /// Set while Spirc is shutting down to come back with a new name.
+    renaming: bool,
+    /// Set while Spirc is shutting down because another account logged in
+    /// through discovery.
+    handing_over: bool,
 }
 
 impl LibreSpotConnection {
     pub fn new(
-        connection: Box<dyn Future<Item = Session, Error = io::Error>>,
-        discovery_stream: DiscoveryStream,
+        connection: Box<dyn Future<Item = Session, Error = SetupError>>,
+        discovery_stream: Option<DiscoveryStream>,
+        credentials: Option<Credentials>,
     ) -> LibreSpotConnection {
         LibreSpotConnection {
             connection,
             spirc_task: None,
             spirc: None,
+            session: None,
+            rename_requests: None,
             discovery_stream,
+            credentials,
+            renaming: false,
+            handing_over: false,
         }
     }
 }
 
+/// How long a client has to take over playback before `autostart_uri` is played.
+const AUTOSTART_DELAY: Duration = Duration::from_secs(10);
+
+/// How long to wait after the system woke up before logging in again, to
+/// give the network a moment to come back.
+const RESUME_RECONNECT_DELAY: Duration = Duration::from_secs(3);
+
+/// How often this device is looked for in the Web API after connecting, once a
+/// second.
+const DEVICE_ATTEMPTS: u32 = 15;
+
+/// Opens the audio output. It is called again every time a new session starts.
+pub type SinkBuilder = Arc<dyn Fn() -> Box<dyn Sink> + Send + Sync>;
+
 pub struct AudioSetup {
     pub mixer: Box<dyn FnMut() -> Box<dyn Mixer>>,
-    pub backend: fn(Option<String>) -> Box<dyn Sink>,
-    pub audio_device: Option<String>,
+    pub backend: SinkBuilder,
 }
 
 pub struct SpotifydState {
     pub ctrl_c_stream: IoStream<()>,
     pub shutting_down: bool,
     pub cache: Option<Cache>,
+    pub credential_store: Option<CredentialStore>,
     pub device_name: String,
+    /// The name the status servers and the control socket report.
+    pub status_device_name: DeviceName,
+    pub persist_device_name: bool,
+    pub state_path: Option<PathBuf>,
+    pub playback_resume: Option<PlaybackResume>,
+    pub track_errors: TrackErrors,
+    pub normalisation: Rc<Cell<Normalisation>>,
+    pub normalisation_check: Option<Box<dyn Future<Item = Normalisation, Error = ()>>>,
+    pub audio_features: Option<AudioFeatureCache>,
+    pub explicit_filter: Option<ExplicitFilter>,
+    pub context_volumes: Option<ContextVolumes>,
+    pub connect_volume: Option<ConnectVolume>,
     pub player_event_channel: Option<futures::sync::mpsc::UnboundedReceiver<PlayerEvent>>,
-    pub player_event_program: Option<String>,
+    pub mpris_event_sender: Option<futures::sync::mpsc::UnboundedSender<PlayerEvent>>,
+    pub status_event_senders: Vec<futures::sync::mpsc::UnboundedSender<(Session, PlayerEvent)>>,
     pub dbus_mpris_server: Option<Box<dyn Future<Item = (), Error = ()>>>,
 }
 
+/// What is set up once and kept when the main loop is restarted after a panic:
+/// the servers and threads that run for as long as spotifyd does, and what
+/// systemd passed, which can only be taken from the environment once.
+pub(crate) struct Services {
+    pub(crate) systemd: Rc<RefCell<Systemd>>,
+    pub(crate) control_sender: RequestSender,
+    pub(crate) control_requests: Rc<RefCell<futures::sync::mpsc::UnboundedReceiver<Request>>>,
+    pub(crate) status_event_senders:
+        Vec<futures::sync::mpsc::UnboundedSender<(Session, PlayerEvent)>>,
+    pub(crate) audio_features: Option<AudioFeatureCache>,
+    pub(crate) metrics: Option<Arc<Metrics>>,
+    pub(crate) started_at: SystemTime,
+    pub(crate) log_timestamps: LogTimestamps,
+    pub(crate) device_name: DeviceName,
+}
+
+/// Listens for requests from Spotify clients to rename this device.
+///
+/// Spirc doesn't handle these itself, so we subscribe to the same channel it
+/// uses and pick out the rename frames addressed to us.
+fn rename_requests(session: &Session) -> Box<dyn Stream<Item = String, Error = ()>> {
+    let ident = session.device_id().to_owned();
+    Box::new(remote_frames(session).filter_map(move |mut frame| {
+        if frame.get_typ() == MessageType::kMessageTypeRename
+            && frame.get_ident() != ident
+            && frame.get_recipient().contains(&ident)
+        {
+            Some(frame.take_device_state().take_name())
+        } else {
+            None
+        }
+    }))
+}
+
+/// Whether this device is still the active Connect device after `event`.
+///
+/// The player only loads tracks once playback was transferred here, and it is
+/// stopped when playback moves to another device.
+pub(crate) fn is_active_after(event: &PlayerEvent, active: bool) -> bool {
+    match *event {
+        PlayerEvent::Loading { .. }
+        | PlayerEvent::Started { .. }
+        | PlayerEvent::Changed { .. }
+        | PlayerEvent::Playing { .. }
+        | PlayerEvent::Paused { .. } => true,
+        PlayerEvent::Stopped { .. } => false,
+        _ => active,
+    }
+}
+
+/// Subscribes to the frames Connect devices of the account send each other,
+/// including the ones Spirc sends.
+pub(crate) fn remote_frames(session: &Session) -> Box<dyn Stream<Item = Frame, Error = ()>> {
+    let uri = format!("hm://remote/user/{}/", url_encode(&session.username()));
+    Box::new(
+        session
+            .mercury()
+            .subscribe(uri)
+            .map(|stream| stream.map_err(|_| ()))
+            .map_err(|_| ())
+            .flatten_stream()
+            .filter_map(|response| {
+                let data = response.payload.first()?;
+                protobuf::parse_from_bytes::<Frame>(data).ok()
+            }),
+    )
+}
+
+/// The states this device sends to the others, which is where Spirc keeps
+/// the context, the tracks and the shuffle and repeat modes.
+pub(crate) fn own_states(session: &Session) -> Box<dyn Stream<Item = ConnectState, Error = ()>> {
+    let ident = session.device_id().to_owned();
+    Box::new(remote_frames(session).filter_map(move |mut frame| {
+        if frame.get_typ() == MessageType::kMessageTypeNotify && frame.get_ident() == ident {
+            Some(frame.take_state())
+        } else {
+            None
+        }
+    }))
+}
+
+/// Asks Spotify to send metadata in the given language.
+fn send_preferred_locale(session: &Session, locale: &str) {
+    let mut data = vec![0x0, 0x0, 0x10, 0x0, 0x02];
+    data.extend_from_slice(b"preferred-locale");
+    data.extend_from_slice(locale.as_bytes());
+    session.send_packet(0x74, data);
+}
+
+/// Seeks in the current track. Spirc has no seek command, so this goes
+/// through the Web API.
+fn seek(session: &Session, handle: &Handle, position_ms: u32) {
+    web_api::spawn_with_api(session, handle, "user-modify-playback-state", move |sp| {
+        if let Err(e) = sp.seek_track(position_ms, None) {
+            warn!("Could not seek to {}ms: {}", position_ms, e);
+        }
+    });
+}
+
+/// Sets the volume of this device, between 0 and 65535. Spirc only changes it
+/// when asked to by a client, so this goes through the Web API.
+fn set_volume(session: &Session, handle: &Handle, volume: u16) {
+    let percent = (u32::from(volume) * 100 + u32::from(u16::MAX) / 2) / u32::from(u16::MAX);
+    web_api::spawn_with_api(session, handle, "user-modify-playback-state", move |sp| {
+        if let Err(e) = sp.volume(percent as u8, None) {
+            warn!("Could not set the volume to {}%: {}", percent, e);
+        }
+    });
+}
+
+/// The Web API scope needed to start playback on this device.
+pub(crate) const PLAYBACK_SCOPE: &str = "user-read-playback-state,user-modify-playback-state";
+
+/// Plays `uri` on this device, unless playback was transferred to it in the
+/// meantime. Spirc can't load a context by itself, so this goes through the
+/// Web API.
+fn autostart(session: &Session, handle: &Handle, device_name: String, uri: String) {
+    web_api::spawn_with_api(session, handle, PLAYBACK_SCOPE, move |sp| {
+        let device = match sp.device() {
+            Ok(devices) => devices.devices.into_iter().find(|d| d.name == device_name),
+            Err(e) => {
+                warn!("Could not list the devices, not playing {}: {}", uri, e);
+                return;
+            }
+        };
+        let device_id = match device {
+            Some(ref device) if device.is_active => return,
+            Some(device) => device.id,
+            None => {
+                warn!(
+                    "This device isn't known to Spotify yet, not playing {}",
+                    uri
+                );
+                return;
+            }
+        };
+
+        info!("No client took over playback, playing {}", uri);
+        if let Err(e) = start_playback(&sp, device_id, &uri) {
+            warn!("Could not play {}: {}", uri, e);
+        }
+    });
+}
+
+fn start_playback(sp: &Spotify, device_id: String, uri: &str) -> Result<(), String> {
+    let result = if uri.starts_with("spotify:track:") {
+        sp.start_playback(
+            Some(device_id),
+            None,
+            Some(vec![uri.to_string()]),
+            None,
+            None,
+        )
+    } else {
+        sp.start_playback(Some(device_id), Some(uri.to_string()), None, None, None)
+    };
+    result.map_err(|e| e.to_string())
+}
+
+/// Returns the Web API id of this device once it shows up there, which takes a
+/// moment after Spirc has started. It gives up once `session` was shut down,
+/// as the device of a new one may go by the same name.
+pub(crate) fn wait_for_device(
+    sp: &Spotify,
+    session: &Session,
+    device_name: &str,
+) -> Result<String, String> {
+    for _ in 0..DEVICE_ATTEMPTS {
+        if session.is_invalid() {
+            return Err("spotifyd reconnected in the meantime".to_string());
+        }
+        let device = match sp.device() {
+            Ok(devices) => devices.devices.into_iter().find(|d| d.name == device_name),
+            Err(e) => return Err(format!("could not list the devices: {}", e)),
+        };
+        if let Some(device) = device {
+            return Ok(device.id);
+        }
+        thread::sleep(Duration::from_secs(1));
+    }
+    Err("this device didn't show up in the Web API".to_string())
+}
+
+/// A URI played by the `play` command. spotifyd exits once playback stops or
+/// the context has played to the end, but not when it is paused.
+pub(crate) struct Oneshot {
+    uri: String,
+    duration: Option<Duration>,
+    /// Whether the Web API accepted the request to play `uri`.
+    started: Option<oneshot::Receiver<Result<(), String>>>,
+    /// Set once `uri` was asked for, so it is only played once.
+    requested: bool,
+    playing: bool,
+    /// Set after a track ended, until the next one plays. Spirc loads the
+    /// first track paused at the end of the context.
+    track_ended: bool,
+    timeout: Option<Timeout>,
+}
+
+impl Oneshot {
+    pub(crate) fn new(uri: String, duration: Option<Duration>) -> Oneshot {
+        Oneshot {
+            uri,
+            duration,
+            started: None,
+            requested: false,
+            playing: false,
+            track_ended: false,
+            timeout: None,
+        }
+    }
+
+    /// Takes over playback with `uri` as soon as this device shows up in the
+    /// Web API, which takes a moment after Spirc has started.
+    fn start(&mut self, session: &Session, handle: &Handle, device_name: String) {
+        if self.requested {
+            return;
+        }
+        self.requested = true;
+        let (tx, rx) = oneshot::channel();
+        self.started = Some(rx);
+        let uri = self.uri.clone();
+        let waiting_session = session.clone();
+        web_api::spawn_with_api(session, handle, PLAYBACK_SCOPE, move |sp| {
+            let result = wait_for_device(&sp, &waiting_session, &device_name)
+                .and_then(|device_id| start_playback(&sp, device_id, &uri));
+            let _ = tx.send(result);
+        });
+    }
+
+    /// Returns true once playback has stopped, or the context has played to
+    /// the end. Pausing, from a client or e.g. by `pause_on_lock`, doesn't
+    /// count.
+    fn handle_event(&mut self, event: &PlayerEvent, handle: &Handle) -> bool {
+        match *event {
+            PlayerEvent::Playing { .. } => {
+                if !self.playing {
+                    self.playing = true;
+                    if let Some(duration) = self.duration {
+                        self.timeout = Timeout::new(duration, handle).ok();
+                    }
+                }
+                self.track_ended = false;
+                false
+            }
+            PlayerEvent::EndOfTrack { .. } => {
+                self.track_ended = true;
+                false
+            }
+            PlayerEvent::Paused { .. } => self.playing && self.track_ended,
+            PlayerEvent::Stopped { .. } => self.playing,
+            _ => false,
+        }
+    }
+
+    /// Returns true once the duration is up.
+    fn poll(&mut self) -> Result<bool, SetupError> {
+        match self.started.as_mut().map(Future::poll) {
+            Some(Ok(Async::Ready(Ok(())))) => {
+                info!("Playing {}", self.uri);
+                self.started = None;
+            }
+            Some(Ok(Async::Ready(Err(e)))) => {
+                return Err(SetupError::PlaybackFailed(format!(
+                    "could not play {}: {}",
+                    self.uri, e
+                )))
+            }
+            Some(Err(_)) => {
+                return Err(SetupError::PlaybackFailed(format!(
+                    "could not play {}: no Web API token",
+                    self.uri
+                )))
+            }
+            Some(Ok(Async::NotReady)) | None => (),
+        }
+        Ok(matches!(
+            self.timeout.as_mut().map(Future::poll),
+            Some(Ok(Async::Ready(())))
+        ))
+    }
+}
+
 #[cfg(feature = "dbus_mpris")]
-#[allow(clippy::unnecessary_wraps)]
+#[allow(clippy::unnecessary_wraps, clippy::too_many_arguments)]
 fn new_dbus_server(
     session: Session,
     handle: Handle,
     spirc: Rc<Spirc>,
     device_name: String,
+    market: Option<Country>,
+    player_events: futures::sync::mpsc::UnboundedReceiver<PlayerEvent>,
+    passive_startup: bool,
+    coalesce_window: Duration,
+    normalisation: Rc<Cell<Normalisation>>,
+    audio_features: Option<AudioFeatureCache>,
+    control_sender: RequestSender,
+    metadata_fetch: MetadataFetch,
 ) -> Option<Box<dyn Future<Item = (), Error = ()>>> {
     Some(Box::new(DbusServer::new(
         session,
         handle,
         spirc,
         device_name,
+        market,
+        player_events,
+        passive_startup,
+        coalesce_window,
+        normalisation,
+        audio_features,
+        control_sender,
+        metadata_fetch,
     )))
 }
 
 #[cfg(not(feature = "dbus_mpris"))]
+#[allow(clippy::too_many_arguments)]
 fn new_dbus_server(
     _: Session,
     _: Handle,
     _: Rc<Spirc>,
     _: String,
+    _: Option<Country>,
+    _: futures::sync::mpsc::UnboundedReceiver<PlayerEvent>,
+    _: bool,
+    _: Duration,
+    _: Rc<Cell<Normalisation>>,
+    _: Option<AudioFeatureCache>,
+    _: RequestSender,
+    _: MetadataFetch,
 ) -> Option<Box<dyn Future<Item = (), Error = ()>>> {
     None
 }
@@ -92,85 +487,730 @@ pub(crate) struct MainLoopState {
     pub(crate) audio_setup: AudioSetup,
     pub(crate) spotifyd_state: SpotifydState,
     pub(crate) player_config: PlayerConfig,
+    pub(crate) metered_bitrate: Option<Bitrate>,
     pub(crate) session_config: SessionConfig,
     pub(crate) handle: Handle,
     pub(crate) autoplay: bool,
     pub(crate) volume_ctrl: VolumeCtrl,
     pub(crate) initial_volume: Option<u16>,
-    pub(crate) running_event_program: Option<Child>,
-    pub(crate) shell: String,
+    pub(crate) volume_step: u16,
+    /// The volume Spirc last reported, between 0 and 65535.
+    pub(crate) volume: Option<u16>,
+    pub(crate) event_hooks: EventHooks,
+    pub(crate) systemd: Rc<RefCell<Systemd>>,
     pub(crate) device_type: DeviceType,
     pub(crate) use_mpris: bool,
+    pub(crate) mpris_coalesce_window: Duration,
+    pub(crate) metadata_fetch: MetadataFetch,
+    pub(crate) market: Option<Country>,
+    pub(crate) locale: Option<String>,
+    pub(crate) passive_startup: bool,
+    pub(crate) autostart_uri: Option<String>,
+    pub(crate) autostart_timeout: Option<Timeout>,
+    pub(crate) oneshot: Option<Oneshot>,
+    pub(crate) sleep_signals: Option<Box<dyn Stream<Item = bool, Error = ()>>>,
+    pub(crate) reload_signals: Option<Box<dyn Stream<Item = (), Error = ()>>>,
+    pub(crate) config_values: SharedConfigValues,
+    pub(crate) lock_pause: Option<LockPause>,
+    pub(crate) jack_pause: Option<JackPause>,
+    pub(crate) resume_timeout: Option<Timeout>,
+    pub(crate) reconnect_resume: Option<ReconnectResume>,
+    pub(crate) device_watch: DeviceWatch,
+    pub(crate) mixer_watch: Option<MixerWatch>,
+    pub(crate) fade_control: Option<Arc<FadeControl>>,
+    pub(crate) metrics: Option<Arc<Metrics>>,
+    pub(crate) cache_index: Option<CacheIndex>,
+    pub(crate) idle_exit: Option<IdleExit>,
+    pub(crate) delayed_autoplay: Option<DelayedAutoplay>,
+    pub(crate) forced_modes: Option<ForcedModes>,
+    pub(crate) control_sender: RequestSender,
+    pub(crate) control_requests: Rc<RefCell<futures::sync::mpsc::UnboundedReceiver<Request>>>,
+    pub(crate) connect_queue: ConnectQueue,
+    pub(crate) prefer_active: Option<PreferActive>,
+    pub(crate) play_history: Option<PlayHistory>,
+    pub(crate) cache_path: Option<PathBuf>,
+    pub(crate) now_playing: Option<Rc<RefCell<NowPlaying>>>,
+    /// For the cache files written since then.
+    pub(crate) started_at: SystemTime,
+    pub(crate) log_timestamps: LogTimestamps,
+}
+
+impl MainLoopState {
+    fn handle_command(&mut self, command: Command) -> Result<String, String> {
+        let not_connected = || "no client is connected".to_string();
+        match command {
+            Command::Play
+            | Command::Pause
+            | Command::PlayPause
+            | Command::Next
+            | Command::Previous => {
+                let spirc = self
+                    .librespot_connection
+                    .spirc
+                    .as_ref()
+                    .ok_or_else(not_connected)?;
+                match command {
+                    Command::Play => spirc.play(),
+                    Command::Pause => spirc.pause(),
+                    Command::PlayPause => spirc.play_pause(),
+                    Command::Next => spirc.next(),
+                    _ => spirc.prev(),
+                }
+                Ok(String::new())
+            }
+            Command::Seek(position_ms) => {
+                let session = self
+                    .librespot_connection
+                    .session
+                    .as_ref()
+                    .ok_or_else(not_connected)?;
+                seek(session, &self.handle, position_ms);
+                Ok(String::new())
+            }
+            Command::Volume(percent) => {
+                let session = self
+                    .librespot_connection
+                    .session
+                    .as_ref()
+                    .ok_or_else(not_connected)?;
+                let volume = (u32::from(percent) * u32::from(u16::MAX) / 100) as u16;
+                set_volume(session, &self.handle, volume);
+                Ok(String::new())
+            }
+            Command::VolumeUp | Command::VolumeDown => {
+                let session = self
+                    .librespot_connection
+                    .session
+                    .as_ref()
+                    .ok_or_else(not_connected)?;
+                let current = self
+                    .volume
+                    .ok_or_else(|| "the volume isn't known yet".to_string())?;
+                let volume = if matches!(command, Command::VolumeUp) {
+                    current.saturating_add(self.volume_step)
+                } else {
+                    current.saturating_sub(self.volume_step)
+                };
+                // Steps in quick succession add up, without waiting for Spirc
+                // to report each one.
+                self.volume = Some(volume);
+                set_volume(session, &self.handle, volume);
+                Ok(String::new())
+            }
+            Command::Queue(uri) => {
+                let track_id = queue::parse_track_uri(&uri)?;
+                let session = self
+                    .librespot_connection
+                    .session
+                    .as_ref()
+                    .ok_or_else(not_connected)?;
+                self.connect_queue
+                    .add(session, &self.handle, track_id)
+                    .map(|()| String::new())
+            }
+            Command::CacheStats => match self.cache_path {
+                Some(ref path) => CacheStats::collect(path, self.started_at)
+                    .map(|stats| stats.to_string())
+                    .map_err(|e| format!("could not read the cache: {}", e)),
+                None => Err("no cache_path is configured".to_string()),
+            },
+            Command::CachedTracks => match self.cache_path {
+                Some(ref path) => cache::cached_tracks(path)
+                    .map(|uris| uris.join(" "))
+                    .map_err(|e| format!("could not read the list of cached tracks: {}", e)),
+                None => Err("no cache_path is configured".to_string()),
+            },
+            Command::Status => match self.now_playing {
+                Some(ref now_playing) => {
+                    let snapshot = now_playing
+                        .borrow()
+                        .snapshot(self.spotifyd_state.audio_features.as_ref());
+                    serde_json::to_string(&snapshot).map_err(|e| e.to_string())
+                }
+                None => Err("the status is only kept for control_socket".to_string()),
+            },
+            Command::Reload => self.reload_config(),
+        }
+    }
+
+    /// Reads the config again and applies what changed and can be applied
+    /// while running. The rest is logged, and takes a restart.
+    fn reload_config(&mut self) -> Result<String, String> {
+        let config = config::reload().map_err(|e| format!("could not reload the config: {}", e))?;
+        let changed = config::changed_options(&self.config_values, &config.values);
+        if changed.is_empty() {
+            info!("Reloaded the config, nothing changed");
+            return Ok("nothing changed".to_string());
+        }
+
+        let (mut applied, mut later, mut restart) = (Vec::new(), Vec::new(), Vec::new());
+        for option in changed {
+            match option.as_str() {
+                "device_name"
+                | "volume_step"
+                | "on_song_change_hook"
+                | "on_track_change"
+                | "on_play"
+                | "on_pause"
+                | "on_stop"
+                | "on_volume"
+                | "onevent_grace_period"
+                | "onevent_timeout"
+                | "log_level"
+                | "log_timestamp"
+                | "log_timestamp_utc" => applied.push(option),
+                // The player is made with these when a session starts, and
+                // the volume is only set then.
+                "bitrate" | "volume_normalisation" | "normalisation_pregain" | "initial_volume" => {
+                    later.push(option)
+                }
+                _ => restart.push(option),
+            }
+        }
+
+        log::set_max_level(config.log_level);
+        *self.log_timestamps.write().unwrap() = config.values.log_timestamp();
+        self.initial_volume = config.initial_volume;
+        self.volume_step = config.volume_step;
+        self.event_hooks.set_programs(
+            config.onevent,
+            config.onevent_grace_period,
+            config.onevent_timeout,
+        );
+        self.player_config = config.player_config;
+        self.config_values = config.values;
+        if applied.iter().any(|option| option == "device_name") {
+            self.rename_device(config.device_name);
+        }
+
+        let mut summary = Vec::new();
+        if !applied.is_empty() {
+            summary.push(format!("applied {}", applied.join(", ")));
+        }
+        if !later.is_empty() {
+            warn!(
+                "{} only apply from the next time a client connects",
+                later.join(", ")
+            );
+            summary.push(format!("{} from the next connection", later.join(", ")));
+        }
+        if !restart.is_empty() {
+            warn!("Restart spotifyd to apply {}", restart.join(", "));
+            summary.push(format!("{} after a restart", restart.join(", ")));
+        }
+        let summary = summary.join("; ");
+        info!("Reloaded the config: {}", summary);
+        Ok(summary)
+    }
+
+    /// Saves what is kept across runs and shuts down. Returns whether the main
+    /// loop can end right away, which it can when there is no Spirc to wait for.
+    fn exit(&mut self) -> bool {
+        self.systemd.borrow_mut().stopping();
+        if let Some(ref resume) = self.spotifyd_state.playback_resume {
+            resume.save();
+        }
+        if self.librespot_connection.spirc.is_some() {
+            self.shutdown();
+            false
+        } else {
+            self.event_hooks.finish();
+            true
+        }
+    }
+
+    /// Asks Spirc to shut down. The main loop ends once it has.
+    fn shutdown(&mut self) {
+        if let Some(ref spirc) = self.librespot_connection.spirc {
+            spirc.shutdown();
+            self.spotifyd_state.shutting_down = true;
+        }
+    }
+
+    /// Drops the session and logs in again, for when the connection has died,
+    /// or most likely has, like while the system was asleep. Returns whether
+    /// spotifyd is logging in again. The `play` command fails instead, as it
+    /// would start over from the beginning.
+    fn reconnect(&mut self) -> Result<bool, SetupError> {
+        if self.spotifyd_state.shutting_down || self.librespot_connection.session.is_none() {
+            return Ok(false);
+        }
+        if self.oneshot.is_some() {
+            return Err(SetupError::PlaybackFailed(
+                "the connection to Spotify was lost".to_string(),
+            ));
+        }
+        let credentials = self
+            .spotifyd_state
+            .credential_store
+            .as_ref()
+            .and_then(|credential_store| credential_store.load().ok().flatten())
+            .or_else(|| self.librespot_connection.credentials.clone());
+        let credentials = match credentials {
+            Some(credentials) => credentials,
+            None => {
+                warn!("There are no credentials to log in with again");
+                return Ok(false);
+            }
+        };
+
+        info!("Reconnecting to Spotify");
+        self.systemd.borrow().status("Reconnecting to Spotify");
+        if let Some(ref metrics) = self.metrics {
+            metrics.reconnecting();
+        }
+        if let Some(ref mut resume) = self.reconnect_resume {
+            resume.disconnected();
+        }
+        self.spotifyd_state.dbus_mpris_server = None;
+        self.spotifyd_state.mpris_event_sender = None;
+        self.spotifyd_state.player_event_channel = None;
+        self.librespot_connection.rename_requests = None;
+        self.librespot_connection.spirc = None;
+        self.librespot_connection.spirc_task = None;
+        if let Some(session) = self.librespot_connection.session.take() {
+            session.shutdown();
+        }
+        self.librespot_connection.connection = setup::connect(
+            self.session_config.clone(),
+            credentials,
+            self.spotifyd_state.cache.clone(),
+            self.spotifyd_state.credential_store.clone(),
+            self.handle.clone(),
+        );
+        Ok(true)
+    }
+
+    fn rename_device(&mut self, name: String) {
+        if !is_valid_device_name(&name) {
+            warn!("Ignoring request to rename the device to \"{}\"", name);
+            return;
+        }
+        if name == self.spotifyd_state.device_name {
+            return;
+        }
+
+        info!("Renaming the device to \"{}\"", name);
+        *self.spotifyd_state.status_device_name.borrow_mut() = name.clone();
+        self.spotifyd_state.device_name = name;
+
+        if self.spotifyd_state.persist_device_name {
+            if let Some(ref path) = self.spotifyd_state.state_path {
+                let mut state = State::load(path);
+                state.device_name = Some(self.spotifyd_state.device_name.clone());
+                if let Err(e) = state.save(path) {
+                    error!("Could not save the device name: {}", e);
+                }
+            }
+        }
+
+        // Spirc can't change its name, so it says goodbye to the clients under
+        // the old one, and everything is set up again on the current session
+        // once it has.
+        if let Some(ref spirc) = self.librespot_connection.spirc {
+            spirc.shutdown();
+            self.librespot_connection.renaming = true;
+        }
+    }
 }
 
 impl Future for MainLoopState {
-    type Error = ();
+    type Error = SetupError;
     type Item = ();
 
-    fn poll(&mut self) -> Poll<(), ()> {
+    fn poll(&mut self) -> Poll<(), SetupError> {
+        self.systemd.borrow_mut().poll();
         loop {
-            if let Async::Ready(Some(creds)) =
-                self.librespot_connection.discovery_stream.poll().unwrap()
+            if let Some(Async::Ready(Some(creds))) = self
+                .librespot_connection
+                .discovery_stream
+                .as_mut()
+                .map(|stream| stream.poll().unwrap())
             {
                 if let Some(ref mut spirc) = self.librespot_connection.spirc {
                     spirc.shutdown();
+                    self.librespot_connection.handing_over = true;
                 }
                 let session_config = self.session_config.clone();
                 let cache = self.spotifyd_state.cache.clone();
+                let credential_store = self.spotifyd_state.credential_store.clone();
                 let handle = self.handle.clone();
+                self.librespot_connection.credentials = Some(creds.clone());
                 self.librespot_connection.connection =
-                    Session::connect(session_config, creds, cache, handle);
+                    setup::connect(session_config, creds, cache, credential_store, handle);
+                if let Some(ref mut idle_exit) = self.idle_exit {
+                    idle_exit.reset();
+                }
+            }
+
+            if let Some(ref mut idle_exit) = self.idle_exit {
+                if idle_exit.poll() && !self.spotifyd_state.shutting_down {
+                    info!(
+                        "This device wasn't active for {} min, exiting",
+                        idle_exit.minutes()
+                    );
+                    if self.exit() {
+                        return Ok(Async::Ready(()));
+                    }
+                }
+            }
+
+            if let Some(Ok(Async::Ready(Some(sleeping)))) =
+                self.sleep_signals.as_mut().map(Stream::poll)
+            {
+                if sleeping {
+                    info!("The system is going to sleep");
+                    self.resume_timeout = None;
+                } else {
+                    info!("The system woke up");
+                    self.resume_timeout = Timeout::new(RESUME_RECONNECT_DELAY, &self.handle).ok();
+                }
+            }
+
+            if let Some(Ok(Async::Ready(Some(())))) = self.reload_signals.as_mut().map(Stream::poll)
+            {
+                info!("Got SIGHUP, reloading the config");
+                if let Err(e) = self.reload_config() {
+                    warn!("{}", e);
+                }
+            }
+
+            if let Some(ref mut lock_pause) = self.lock_pause {
+                lock_pause.poll(self.librespot_connection.spirc.as_deref());
+            }
+
+            if let Some(ref mut jack_pause) = self.jack_pause {
+                jack_pause.poll(self.librespot_connection.spirc.as_deref());
+            }
+
+            self.device_watch
+                .poll(&self.handle, self.librespot_connection.spirc.as_deref());
+
+            if let Some(volume) = self.mixer_watch.as_mut().and_then(MixerWatch::poll) {
+                if let Some(ref session) = self.librespot_connection.session {
+                    set_volume(session, &self.handle, volume);
+                }
+            }
+
+            if let (Some(ref mut autoplay), Some(ref session)) = (
+                &mut self.delayed_autoplay,
+                &self.librespot_connection.session,
+            ) {
+                autoplay.poll(session, &self.handle, &self.spotifyd_state.device_name);
+            }
+
+            if let (Some(ref mut modes), Some(ref session)) =
+                (&mut self.forced_modes, &self.librespot_connection.session)
+            {
+                modes.poll(session, &self.handle);
+            }
+
+            if let (Some(ref mut prefer_active), Some(ref session)) =
+                (&mut self.prefer_active, &self.librespot_connection.session)
+            {
+                prefer_active.poll(session, &self.handle, &self.spotifyd_state.device_name);
             }
 
-            if let Some(mut child) = self.running_event_program.take() {
-                match child.try_wait() {
-                    // Still running...
-                    Ok(None) => self.running_event_program = Some(child),
-                    // Exited with error...
-                    Err(e) => error!("{}", e),
-                    // Exited without error...
-                    Ok(Some(_)) => (),
+            self.connect_queue.poll();
+            loop {
+                let request = self.control_requests.borrow_mut().poll();
+                match request {
+                    Ok(Async::Ready(Some((command, result)))) => {
+                        let _ = result.send(self.handle_command(command));
+                    }
+                    _ => break,
                 }
             }
-            if self.running_event_program.is_none() {
-                if let Some(ref mut player_event_channel) = self.spotifyd_state.player_event_channel
+
+            if let Some(Ok(Async::Ready(()))) = self.resume_timeout.as_mut().map(Future::poll) {
+                self.resume_timeout = None;
+                self.reconnect()?;
+            }
+
+            if let Some(ref mut resume) = self.reconnect_resume {
+                resume.poll();
+            }
+
+            self.event_hooks.poll();
+            let event = match self
+                .spotifyd_state
+                .player_event_channel
+                .as_mut()
+                .map(|channel| channel.poll().unwrap())
+            {
+                Some(Async::Ready(Some(event))) => Some(event),
+                // The player only drops its end when its thread panicked.
+                Some(Async::Ready(None)) if !self.spotifyd_state.shutting_down => {
+                    return Err(SetupError::PlayerStopped);
+                }
+                _ => None,
+            };
+            if let Some(event) = event {
+                // Spirc sets the volume on startup; anything else means a
+                // client is using the device, so autostart is called off.
+                if let PlayerEvent::VolumeSet { volume } = event {
+                    self.volume = Some(volume);
+                } else {
+                    self.autostart_uri = None;
+                    self.autostart_timeout = None;
+                }
+                if let Some(ref sender) = self.spotifyd_state.mpris_event_sender {
+                    let _ = sender.unbounded_send(event.clone());
+                }
+                if let Some(ref session) = self.librespot_connection.session {
+                    for sender in &self.spotifyd_state.status_event_senders {
+                        let _ = sender.unbounded_send((session.clone(), event.clone()));
+                    }
+                }
+                let unplayable = match self.librespot_connection.spirc {
+                    Some(ref spirc) => self.spotifyd_state.track_errors.handle_event(&event, spirc),
+                    None => None,
+                };
+                if let Some(ref mut autoplay) = self.delayed_autoplay {
+                    autoplay.handle_event(&event);
+                }
+                if let Some(ref mut modes) = self.forced_modes {
+                    modes.handle_event(&event);
+                }
+                if let Some(ref mut watch) = self.mixer_watch {
+                    watch.handle_event(&event);
+                }
+                if let (Some(ref index), Some(ref session)) =
+                    (&self.cache_index, &self.librespot_connection.session)
+                {
+                    index.handle_event(&event, session, &self.handle);
+                }
+                if let Some(ref mut idle_exit) = self.idle_exit {
+                    idle_exit.handle_event(&event);
+                }
+                if let Some(ref fade) = self.fade_control {
+                    fade.handle_event(&event);
+                }
+                if let Some(ref mut lock_pause) = self.lock_pause {
+                    lock_pause.handle_event(&event);
+                }
+                if let Some(ref mut jack_pause) = self.jack_pause {
+                    jack_pause.handle_event(&event);
+                }
+                if let Some(ref mut resume) = self.reconnect_resume {
+                    resume.handle_event(&event);
+                }
+                if let Some(ref mut prefer_active) = self.prefer_active {
+                    prefer_active.handle_event(&event);
+                }
+                if let (Some(ref history), Some(ref session)) =
+                    (&self.play_history, &self.librespot_connection.session)
                 {
-                    if let Async::Ready(Some(event)) = player_event_channel.poll().unwrap() {
-                        if let Some(ref cmd) = self.spotifyd_state.player_event_program {
-                            match spawn_program_on_event(&self.shell, cmd, event) {
-                                Ok(child) => self.running_event_program = Some(child),
-                                Err(e) => error!("{}", e),
-                            }
-                        }
+                    history.handle_event(&event, session, &self.handle);
+                }
+                if let (Some(ref mut filter), Some(ref session)) = (
+                    &mut self.spotifyd_state.explicit_filter,
+                    &self.librespot_connection.session,
+                ) {
+                    filter.handle_event(&event, session);
+                }
+                if let (Some(ref mut volumes), Some(ref session)) = (
+                    &mut self.spotifyd_state.context_volumes,
+                    &self.librespot_connection.session,
+                ) {
+                    if let Some(volume) = volumes.handle_event(&event, session, &self.handle) {
+                        set_volume(session, &self.handle, volume);
+                    }
+                }
+                if let (Some(ref mut connect_volume), Some(ref session)) = (
+                    &mut self.spotifyd_state.connect_volume,
+                    &self.librespot_connection.session,
+                ) {
+                    if let Some(volume) = connect_volume.handle_event(&event) {
+                        set_volume(session, &self.handle, volume);
+                    }
+                }
+                // Checking the normalisation of a new track costs an extra
+                // request, so it's only done if someone can see the result.
+                let new_track = match event {
+                    PlayerEvent::Started { track_id, .. } => Some(track_id),
+                    PlayerEvent::Changed { new_track_id, .. } => Some(new_track_id),
+                    _ => None,
+                }
+                .filter(|_| self.use_mpris || log::log_enabled!(log::Level::Debug));
+                if let (Some(track_id), Some(ref session)) =
+                    (new_track, &self.librespot_connection.session)
+                {
+                    if self.player_config.normalisation {
+                        self.spotifyd_state
+                            .normalisation
+                            .set(Normalisation::Unknown);
                     }
+                    self.spotifyd_state.normalisation_check =
+                        Some(normalisation::check(session, track_id, &self.player_config));
                 }
+                if let (Some(ref features), Some(ref session)) = (
+                    &self.spotifyd_state.audio_features,
+                    &self.librespot_connection.session,
+                ) {
+                    match event {
+                        PlayerEvent::Started { track_id, .. }
+                        | PlayerEvent::Loading { track_id, .. }
+                        | PlayerEvent::Playing { track_id, .. }
+                        | PlayerEvent::Paused { track_id, .. }
+                        | PlayerEvent::Changed {
+                            new_track_id: track_id,
+                            ..
+                        } => features.track_changed(session, &self.handle, track_id),
+                        PlayerEvent::Stopped { .. } => features.stopped(),
+                        _ => (),
+                    }
+                }
+                let stopped = match self.oneshot {
+                    Some(ref mut oneshot) => oneshot.handle_event(&event, &self.handle),
+                    None => false,
+                };
+                if stopped {
+                    info!("Playback stopped, exiting");
+                    self.shutdown();
+                }
+                if let Some(ref mut resume) = self.spotifyd_state.playback_resume {
+                    if let (Some(position_ms), Some(ref session)) = (
+                        resume.handle_event(&event),
+                        &self.librespot_connection.session,
+                    ) {
+                        seek(session, &self.handle, position_ms);
+                    }
+                }
+                self.event_hooks.handle_event(&event, unplayable.as_ref());
+            }
+
+            if let Some(result) = self
+                .spotifyd_state
+                .normalisation_check
+                .as_mut()
+                .map(Future::poll)
+            {
+                match result {
+                    Ok(Async::NotReady) => (),
+                    Ok(Async::Ready(normalisation)) => {
+                        self.spotifyd_state.normalisation.set(normalisation);
+                        self.spotifyd_state.normalisation_check = None;
+                    }
+                    Err(()) => self.spotifyd_state.normalisation_check = None,
+                }
+            }
+
+            if let (Some(ref mut filter), Some(ref spirc)) = (
+                &mut self.spotifyd_state.explicit_filter,
+                &self.librespot_connection.spirc,
+            ) {
+                filter.poll(spirc);
+            }
+
+            if let (Some(ref mut volumes), Some(ref session)) = (
+                &mut self.spotifyd_state.context_volumes,
+                &self.librespot_connection.session,
+            ) {
+                if let Some(volume) = volumes.poll() {
+                    set_volume(session, &self.handle, volume);
+                }
+            }
+
+            let timed_out = match self.oneshot {
+                Some(ref mut oneshot) => oneshot.poll()?,
+                None => false,
+            };
+            if timed_out {
+                info!("Played for the given duration, exiting");
+                self.shutdown();
             }
 
             if let Some(ref mut fut) = self.spotifyd_state.dbus_mpris_server {
                 let _ = fut.poll();
             }
 
-            if let Async::Ready(session) = self.librespot_connection.connection.poll().unwrap() {
+            if let Some(Ok(Async::Ready(()))) = self.autostart_timeout.as_mut().map(Future::poll) {
+                self.autostart_timeout = None;
+                if let (Some(uri), Some(ref session)) = (
+                    self.autostart_uri.take(),
+                    &self.librespot_connection.session,
+                ) {
+                    let device_name = self.spotifyd_state.device_name.clone();
+                    autostart(session, &self.handle, device_name, uri);
+                }
+            }
+
+            if let Some(Ok(Async::Ready(Some(name)))) = self
+                .librespot_connection
+                .rename_requests
+                .as_mut()
+                .map(|stream| stream.poll())
+            {
+                self.rename_device(name);
+            }
+
+            if let Async::Ready(session) = self.librespot_connection.connection.poll()? {
                 let mixer = (self.audio_setup.mixer)();
                 let audio_filter = mixer.get_audio_filter();
                 self.librespot_connection.connection = Box::new(futures::future::empty());
-                let backend = self.audio_setup.backend;
-                let audio_device = self.audio_setup.audio_device.clone();
-                let (player, event_channel) = Player::new(
-                    self.player_config.clone(),
-                    session.clone(),
-                    audio_filter,
-                    move || (backend)(audio_device),
-                );
+                self.librespot_connection.rename_requests = Some(rename_requests(&session));
+                // The session of the account that was handed over from, if its
+                // Spirc hasn't stopped yet.
+                if let Some(old) = self.librespot_connection.session.replace(session.clone()) {
+                    old.shutdown();
+                }
+                self.librespot_connection.handing_over = false;
+                if let Some(ref locale) = self.locale {
+                    send_preferred_locale(&session, locale);
+                }
+                if let Some(ref mut autoplay) = self.delayed_autoplay {
+                    autoplay.connected(&session);
+                }
+                if let Some(ref mut modes) = self.forced_modes {
+                    modes.connected(&session);
+                }
+                self.connect_queue.connected(&session);
+                self.event_hooks.connected(&session);
+                if let Some(ref mut resume) = self.reconnect_resume {
+                    let device_name = self.spotifyd_state.device_name.clone();
+                    resume.connected(&session, &self.handle, device_name);
+                }
+                if let Some(ref mut prefer_active) = self.prefer_active {
+                    prefer_active.connected(&session);
+                }
+                if let Some(ref mut oneshot) = self.oneshot {
+                    let device_name = self.spotifyd_state.device_name.clone();
+                    oneshot.start(&session, &self.handle, device_name);
+                }
+                if self.autostart_uri.is_some() && self.autostart_timeout.is_none() {
+                    self.autostart_timeout = Timeout::new(AUTOSTART_DELAY, &self.handle).ok();
+                }
+                let mut player_config = self.player_config.clone();
+                if let Some(bitrate) = self.metered_bitrate {
+                    if network::is_metered() {
+                        info!("The network is metered, streaming at {:?}", bitrate);
+                        player_config.bitrate = bitrate;
+                    }
+                }
+                if let Some(ref metrics) = self.metrics {
+                    metrics.connected(player_config.bitrate);
+                }
+                let backend = self.audio_setup.backend.clone();
+                let (player, event_channel) =
+                    Player::new(player_config, session.clone(), audio_filter, move || {
+                        backend()
+                    });
 
                 self.spotifyd_state.player_event_channel = Some(event_channel);
 
+                let volume = self.initial_volume.unwrap_or_else(|| mixer.volume());
+                if let Some(ref mut volumes) = self.spotifyd_state.context_volumes {
+                    volumes.connected(volume);
+                }
                 let (spirc, spirc_task) = Spirc::new(
                     ConnectConfig {
-                        autoplay: self.autoplay,
+                        // Autoplay that waits for a number of tracks is
+                        // started by `delayed_autoplay` instead.
+                        autoplay: self.autoplay && self.delayed_autoplay.is_none(),
                         name: self.spotifyd_state.device_name.clone(),
                         device_type: self.device_type,
-                        volume: self.initial_volume.unwrap_or_else(|| mixer.volume()),
+                        volume,
                         volume_ctrl: self.volume_ctrl.clone(),
                     },
                     session.clone(),
@@ -179,24 +1219,33 @@ impl Future for MainLoopState {
                 );
                 self.librespot_connection.spirc_task = Some(spirc_task);
                 let shared_spirc = Rc::new(spirc);
+                self.systemd.borrow_mut().ready(&format!(
+                    "Connected to Spotify as \"{}\"",
+                    self.spotifyd_state.device_name
+                ));
                 self.librespot_connection.spirc = Some(shared_spirc.clone());
 
                 if self.use_mpris {
+                    let (mpris_event_sender, mpris_events) = futures::sync::mpsc::unbounded();
+                    self.spotifyd_state.mpris_event_sender = Some(mpris_event_sender);
                     self.spotifyd_state.dbus_mpris_server = new_dbus_server(
                         session,
                         self.handle.clone(),
                         shared_spirc,
                         self.spotifyd_state.device_name.clone(),
+                        self.market,
+                        mpris_events,
+                        self.passive_startup,
+                        self.mpris_coalesce_window,
+                        self.spotifyd_state.normalisation.clone(),
+                        self.spotifyd_state.audio_features.clone(),
+                        self.control_sender.clone(),
+                        self.metadata_fetch,
                     );
                 }
             } else if let Async::Ready(_) = self.spotifyd_state.ctrl_c_stream.poll().unwrap() {
-                if !self.spotifyd_state.shutting_down {
-                    if let Some(ref spirc) = self.librespot_connection.spirc {
-                        spirc.shutdown();
-                        self.spotifyd_state.shutting_down = true;
-                    } else {
-                        return Ok(Async::Ready(()));
-                    }
+                if !self.spotifyd_state.shutting_down && self.exit() {
+                    return Ok(Async::Ready(()));
                 }
             } else if let Some(Async::Ready(_)) = self
                 .librespot_connection
@@ -204,6 +1253,51 @@ impl Future for MainLoopState {
                 .as_mut()
                 .map(|ref mut st| st.poll().unwrap())
             {
+                let renamed = match self.librespot_connection.session {
+                    Some(ref session) => {
+                        self.librespot_connection.renaming && !session.is_invalid()
+                    }
+                    None => false,
+                };
+                self.librespot_connection.renaming = false;
+                if renamed && !self.spotifyd_state.shutting_down {
+                    self.spotifyd_state.player_event_channel = None;
+                    self.librespot_connection.spirc = None;
+                    self.librespot_connection.spirc_task = None;
+                    if let Some(session) = self.librespot_connection.session.take() {
+                        self.librespot_connection.connection =
+                            Box::new(futures::future::ok(session));
+                    }
+                    continue;
+                }
+                // The new account is logging in already, so only the old one
+                // is let go of.
+                if self.librespot_connection.handing_over && !self.spotifyd_state.shutting_down {
+                    self.librespot_connection.handing_over = false;
+                    self.spotifyd_state.dbus_mpris_server = None;
+                    self.spotifyd_state.mpris_event_sender = None;
+                    self.spotifyd_state.player_event_channel = None;
+                    self.librespot_connection.rename_requests = None;
+                    self.librespot_connection.spirc = None;
+                    self.librespot_connection.spirc_task = None;
+                    if let Some(session) = self.librespot_connection.session.take() {
+                        session.shutdown();
+                    }
+                    continue;
+                }
+                let lost = matches!(
+                    self.librespot_connection.session,
+                    Some(ref session) if session.is_invalid()
+                );
+                if lost && !self.spotifyd_state.shutting_down {
+                    warn!("The connection to Spotify was lost");
+                    self.event_hooks.connection_lost();
+                    if self.reconnect()? {
+                        continue;
+                    }
+                }
+                self.systemd.borrow_mut().stopping();
+                self.event_hooks.finish();
                 return Ok(Async::Ready(()));
             } else {
                 return Ok(Async::NotReady);
@@ -0,0 +1,282 @@
+use crate::control::RequestSender;
+#[cfg(all(
+    feature = "media_controls",
+    any(target_os = "macos", target_os = "windows")
+))]
+use crate::{control::Command, metadata::fetch_partial_metadata};
+use futures::sync::mpsc::UnboundedReceiver;
+#[cfg(all(
+    feature = "media_controls",
+    any(target_os = "macos", target_os = "windows")
+))]
+use futures::{sync::oneshot, Future, Stream};
+use librespot::{core::session::Session, playback::player::PlayerEvent};
+#[cfg(all(
+    feature = "media_controls",
+    any(target_os = "macos", target_os = "windows")
+))]
+use log::debug;
+#[cfg(all(
+    feature = "media_controls",
+    any(target_os = "macos", target_os = "windows")
+))]
+use souvlaki::{
+    MediaControlEvent, MediaControls, MediaMetadata, MediaPlayback, MediaPosition, PlatformConfig,
+};
+#[cfg(all(
+    feature = "media_controls",
+    any(target_os = "macos", target_os = "windows")
+))]
+use std::{cell::Cell, cell::RefCell, rc::Rc, time::Duration};
+use tokio_core::reactor::Handle;
+#[cfg(all(
+    feature = "media_controls",
+    any(target_os = "macos", target_os = "windows")
+))]
+use tokio_core::reactor::Interval;
+
+/// How often the events of the OS are handled on the main thread.
+#[cfg(all(
+    feature = "media_controls",
+    any(target_os = "macos", target_os = "windows")
+))]
+const PUMP_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Shows what is playing in the media controls of Windows or macOS, with the
+/// cover of the album, and sends their buttons and the media keys to the main
+/// loop through `requests`. The state is kept up to date from the player
+/// events sent through `events`.
+#[cfg(all(
+    feature = "media_controls",
+    any(target_os = "macos", target_os = "windows")
+))]
+pub(crate) fn start(
+    handle: &Handle,
+    display_name: &str,
+    events: UnboundedReceiver<(Session, PlayerEvent)>,
+    requests: RequestSender,
+) -> Result<(), String> {
+    let mut controls = MediaControls::new(PlatformConfig {
+        display_name,
+        dbus_name: "spotifyd",
+        hwnd: platform::window(display_name)?,
+    })
+    .map_err(|e| format!("{:?}", e))?;
+    controls
+        .attach(move |event| {
+            if let Some(command) = command(event) {
+                // Nothing is done with the answer.
+                let (result, _) = oneshot::channel();
+                let _ = requests.unbounded_send((command, result));
+            }
+        })
+        .map_err(|e| format!("{:?}", e))?;
+    let controls = Rc::new(RefCell::new(controls));
+
+    let pump = Interval::new(PUMP_INTERVAL, handle).map_err(|e| e.to_string())?;
+    handle.spawn(
+        pump.for_each(|()| {
+            platform::pump();
+            Ok(())
+        })
+        .map_err(|_| ()),
+    );
+
+    let lookups = handle.clone();
+    let current = Rc::new(Cell::new(None));
+    handle.spawn(events.for_each(move |(session, event)| {
+        let (track_id, playback) = match event {
+            PlayerEvent::Playing {
+                track_id,
+                position_ms,
+                ..
+            } => (
+                track_id,
+                MediaPlayback::Playing {
+                    progress: Some(position(position_ms)),
+                },
+            ),
+            PlayerEvent::Paused {
+                track_id,
+                position_ms,
+                ..
+            } => (
+                track_id,
+                MediaPlayback::Paused {
+                    progress: Some(position(position_ms)),
+                },
+            ),
+            PlayerEvent::Stopped { .. } => {
+                current.set(None);
+                let mut controls = controls.borrow_mut();
+                let _ = controls.set_metadata(MediaMetadata::default());
+                let _ = controls.set_playback(MediaPlayback::Stopped);
+                return Ok(());
+            }
+            _ => return Ok(()),
+        };
+        if let Err(e) = controls.borrow_mut().set_playback(playback) {
+            debug!("Could not update the media controls: {:?}", e);
+        }
+        if current.get() == Some(track_id) {
+            return Ok(());
+        }
+        current.set(Some(track_id));
+        let current = current.clone();
+        let controls = controls.clone();
+        lookups.spawn(
+            fetch_partial_metadata(&session, track_id).map(move |metadata| {
+                // Another track may have started in the meantime.
+                if current.get() != Some(track_id) {
+                    return;
+                }
+                let artists = metadata.artists.join(", ");
+                let result = controls.borrow_mut().set_metadata(MediaMetadata {
+                    title: Some(&metadata.title),
+                    artist: Some(&artists[..]).filter(|artists| !artists.is_empty()),
+                    album: metadata.album.as_deref(),
+                    cover_url: metadata.cover_url.as_deref(),
+                    duration: Some(Duration::from_millis(u64::from(metadata.duration_ms))),
+                });
+                if let Err(e) = result {
+                    debug!("Could not show the track in the media controls: {:?}", e);
+                }
+            }),
+        );
+        Ok(())
+    }));
+    Ok(())
+}
+
+#[cfg(not(all(
+    feature = "media_controls",
+    any(target_os = "macos", target_os = "windows")
+)))]
+pub(crate) fn start(
+    _: &Handle,
+    _: &str,
+    _: UnboundedReceiver<(Session, PlayerEvent)>,
+    _: RequestSender,
+) -> Result<(), String> {
+    Err("there are only media controls on Windows and macOS, use MPRIS on Linux".to_string())
+}
+
+/// Returns what the main loop is asked to do for a button of the media
+/// controls, if anything.
+#[cfg(all(
+    feature = "media_controls",
+    any(target_os = "macos", target_os = "windows")
+))]
+fn command(event: MediaControlEvent) -> Option<Command> {
+    match event {
+        MediaControlEvent::Play => Some(Command::Play),
+        MediaControlEvent::Pause | MediaControlEvent::Stop => Some(Command::Pause),
+        MediaControlEvent::Toggle => Some(Command::PlayPause),
+        MediaControlEvent::Next => Some(Command::Next),
+        MediaControlEvent::Previous => Some(Command::Previous),
+        MediaControlEvent::SetPosition(MediaPosition(position)) => {
+            Some(Command::Seek(position.as_millis() as u32))
+        }
+        MediaControlEvent::SetVolume(volume) => Some(Command::Volume(
+            (volume.clamp(0.0, 1.0) * 100.0).round() as u8,
+        )),
+        _ => None,
+    }
+}
+
+#[cfg(all(
+    feature = "media_controls",
+    any(target_os = "macos", target_os = "windows")
+))]
+fn position(position_ms: u32) -> MediaPosition {
+    MediaPosition(Duration::from_millis(u64::from(position_ms)))
+}
+
+/// The System Media Transport Controls belong to a window, so spotifyd opens
+/// one that is never shown.
+#[cfg(all(feature = "media_controls", target_os = "windows"))]
+mod platform {
+    use std::{ffi::c_void, ffi::OsStr, io, iter, mem, os::windows::ffi::OsStrExt, ptr};
+    use winapi::um::{
+        libloaderapi::GetModuleHandleW,
+        winuser::{
+            CreateWindowExW, DefWindowProcW, DispatchMessageW, PeekMessageW, RegisterClassW,
+            TranslateMessage, MSG, PM_REMOVE, WNDCLASSW, WS_OVERLAPPEDWINDOW,
+        },
+    };
+
+    fn wide(text: &str) -> Vec<u16> {
+        OsStr::new(text)
+            .encode_wide()
+            .chain(iter::once(0))
+            .collect()
+    }
+
+    pub(super) fn window(title: &str) -> Result<Option<*mut c_void>, String> {
+        let class_name = wide("spotifyd");
+        let title = wide(title);
+        unsafe {
+            let instance = GetModuleHandleW(ptr::null());
+            let class = WNDCLASSW {
+                lpfnWndProc: Some(DefWindowProcW),
+                hInstance: instance,
+                lpszClassName: class_name.as_ptr(),
+                ..mem::zeroed()
+            };
+            // Fails if the class is registered already, which is fine.
+            RegisterClassW(&class);
+            let window = CreateWindowExW(
+                0,
+                class_name.as_ptr(),
+                title.as_ptr(),
+                WS_OVERLAPPEDWINDOW,
+                0,
+                0,
+                0,
+                0,
+                ptr::null_mut(),
+                ptr::null_mut(),
+                instance,
+                ptr::null_mut(),
+            );
+            if window.is_null() {
+                return Err(format!(
+                    "could not open a window for the media controls: {}",
+                    io::Error::last_os_error()
+                ));
+            }
+            Ok(Some(window as *mut c_void))
+        }
+    }
+
+    /// Handles the messages sent to the window, so that programs sending
+    /// messages to every window don't wait for it.
+    pub(super) fn pump() {
+        unsafe {
+            let mut message: MSG = mem::zeroed();
+            while PeekMessageW(&mut message, ptr::null_mut(), 0, 0, PM_REMOVE) != 0 {
+                TranslateMessage(&message);
+                DispatchMessageW(&message);
+            }
+        }
+    }
+}
+
+/// The remote commands of macOS are delivered on the run loop of the main
+/// thread, which the main loop runs on instead.
+#[cfg(all(feature = "media_controls", target_os = "macos"))]
+mod platform {
+    use core_foundation_sys::runloop::{kCFRunLoopDefaultMode, CFRunLoopRunInMode};
+    use std::ffi::c_void;
+
+    pub(super) fn window(_: &str) -> Result<Option<*mut c_void>, String> {
+        Ok(None)
+    }
+
+    /// Handles what is waiting on the run loop, without blocking.
+    pub(super) fn pump() {
+        unsafe {
+            CFRunLoopRunInMode(kCFRunLoopDefaultMode, 0.0, 1);
+        }
+    }
+}
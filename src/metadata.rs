@@ -0,0 +1,219 @@
+#[cfg(feature = "dbus_mpris")]
+use futures::future::{Either, Loop};
+use futures::{
+    future::{self, join_all},
+    Future,
+};
+use librespot::{
+    core::{
+        session::Session,
+        spotify_id::{SpotifyAudioType, SpotifyId},
+    },
+    metadata::{Album, Artist, AudioItem, Metadata, Track},
+};
+#[cfg(feature = "dbus_mpris")]
+use log::debug;
+use std::time::Duration;
+#[cfg(feature = "dbus_mpris")]
+use tokio_core::reactor::{Handle, Timeout};
+
+/// How long to wait before asking again after a lookup failed.
+#[cfg(feature = "dbus_mpris")]
+const RETRY_DELAY: Duration = Duration::from_secs(1);
+
+/// What is shown about a track or an episode.
+#[derive(Clone)]
+pub(crate) struct TrackMetadata {
+    pub(crate) uri: String,
+    pub(crate) title: String,
+    /// Empty for episodes.
+    pub(crate) artists: Vec<String>,
+    pub(crate) album: Option<String>,
+    /// A picture of the album, `None` for episodes.
+    #[cfg_attr(
+        not(any(
+            feature = "dbus_mpris",
+            all(
+                feature = "media_controls",
+                any(target_os = "macos", target_os = "windows")
+            )
+        )),
+        allow(dead_code)
+    )]
+    pub(crate) cover_url: Option<String>,
+    pub(crate) duration_ms: u32,
+    /// Whether all the artists and the album were found.
+    pub(crate) complete: bool,
+}
+
+impl TrackMetadata {
+    /// Keeps what either lookup found, preferring the one from `other`.
+    #[cfg(feature = "dbus_mpris")]
+    pub(crate) fn merge(self, other: TrackMetadata) -> TrackMetadata {
+        if other.complete {
+            return other;
+        }
+        TrackMetadata {
+            artists: if other.artists.len() >= self.artists.len() {
+                other.artists
+            } else {
+                self.artists
+            },
+            album: other.album.or(self.album),
+            cover_url: other.cover_url.or(self.cover_url),
+            ..other
+        }
+    }
+}
+
+/// How often, and for how long, `fetch_metadata_retrying` looks a track up.
+#[derive(Clone, Copy, Debug)]
+#[cfg_attr(not(feature = "dbus_mpris"), allow(unused))]
+pub(crate) struct MetadataFetch {
+    /// How many times a failed or incomplete lookup is tried again.
+    pub(crate) retries: u32,
+    /// How long a single lookup may take.
+    pub(crate) timeout: Duration,
+}
+
+/// Looks up the title, the artists and the album of `track_id`, failing if
+/// any of them can't be found.
+pub(crate) fn fetch_metadata(
+    session: &Session,
+    track_id: SpotifyId,
+) -> Box<dyn Future<Item = TrackMetadata, Error = ()>> {
+    Box::new(
+        fetch_partial_metadata(session, track_id).and_then(|metadata| {
+            if metadata.complete {
+                Ok(metadata)
+            } else {
+                Err(())
+            }
+        }),
+    )
+}
+
+/// Looks up `track_id` until everything was found, or `fetch.retries` more
+/// times, each within `fetch.timeout`. Whatever was found is returned, so this
+/// only fails if not even the title was.
+#[cfg(feature = "dbus_mpris")]
+pub(crate) fn fetch_metadata_retrying(
+    session: &Session,
+    handle: &Handle,
+    track_id: SpotifyId,
+    fetch: MetadataFetch,
+) -> Box<dyn Future<Item = TrackMetadata, Error = ()>> {
+    let session = session.clone();
+    let handle = handle.clone();
+    let attempts = future::loop_fn(
+        (0, None::<TrackMetadata>),
+        move |(attempt, found): (u32, Option<TrackMetadata>)| {
+            let delay: Box<dyn Future<Item = (), Error = ()>> = if attempt == 0 {
+                Box::new(future::ok(()))
+            } else {
+                match Timeout::new(RETRY_DELAY, &handle) {
+                    Ok(delay) => Box::new(delay.map_err(|_| ())),
+                    Err(_) => Box::new(future::ok(())),
+                }
+            };
+            let timeout = Timeout::new(fetch.timeout, &handle);
+            let session = session.clone();
+            delay
+                .and_then(move |()| {
+                    let lookup = fetch_partial_metadata(&session, track_id);
+                    let attempt: Box<dyn Future<Item = Option<TrackMetadata>, Error = ()>> =
+                        match timeout {
+                            Ok(timeout) => {
+                                Box::new(lookup.select2(timeout).then(|result| match result {
+                                    Ok(Either::A((metadata, _))) => Ok(Some(metadata)),
+                                    _ => Ok(None),
+                                }))
+                            }
+                            Err(_) => Box::new(lookup.then(|result| Ok(result.ok()))),
+                        };
+                    attempt
+                })
+                .map(move |metadata| {
+                    if metadata.is_none() {
+                        debug!(
+                            "Could not look up {} in time, attempt {} of {}",
+                            track_id.to_uri(),
+                            attempt + 1,
+                            fetch.retries + 1
+                        );
+                    }
+                    let found = match (found, metadata) {
+                        (Some(found), Some(metadata)) => Some(found.merge(metadata)),
+                        (found, metadata) => metadata.or(found),
+                    };
+                    match found {
+                        Some(ref metadata) if metadata.complete => Loop::Break(found),
+                        _ if attempt >= fetch.retries => Loop::Break(found),
+                        _ => Loop::Continue((attempt + 1, found)),
+                    }
+                })
+        },
+    );
+    Box::new(attempts.and_then(|found| found.ok_or(())))
+}
+
+/// Looks up `track_id`, leaving out the artists and the album if they can't
+/// be found. Only fails if the track itself can't be.
+pub(crate) fn fetch_partial_metadata(
+    session: &Session,
+    track_id: SpotifyId,
+) -> Box<dyn Future<Item = TrackMetadata, Error = ()>> {
+    let session = session.clone();
+    let item = AudioItem::get_audio_item(&session, track_id).map_err(|_| ());
+    Box::new(item.and_then(move |item| {
+        let metadata = TrackMetadata {
+            uri: item.uri,
+            title: item.name,
+            artists: Vec::new(),
+            album: None,
+            cover_url: None,
+            duration_ms: item.duration.max(0) as u32,
+            complete: track_id.audio_type != SpotifyAudioType::Track,
+        };
+        if metadata.complete {
+            return Box::new(future::ok(metadata)) as Box<dyn Future<Item = _, Error = ()>>;
+        }
+        let session = session.clone();
+        Box::new(Track::get(&session, track_id).then(move |track| {
+            let track = match track {
+                Ok(track) => track,
+                Err(_) => {
+                    return Box::new(future::ok(metadata)) as Box<dyn Future<Item = _, Error = ()>>
+                }
+            };
+            let artists = join_all(
+                track
+                    .artists
+                    .iter()
+                    .map(|&id| Artist::get(&session, id).then(|artist| Ok(artist.ok())))
+                    .collect::<Vec<_>>(),
+            );
+            let album = Album::get(&session, track.album).then(|album| Ok(album.ok()));
+            Box::new(artists.join(album).map(move |(artists, album)| {
+                let complete = album.is_some() && artists.iter().all(Option::is_some);
+                let cover_url = album.as_ref().and_then(|album: &Album| {
+                    album
+                        .covers
+                        .first()
+                        .map(|cover| format!("https://i.scdn.co/image/{}", cover.to_base16()))
+                });
+                TrackMetadata {
+                    artists: artists
+                        .into_iter()
+                        .flatten()
+                        .map(|artist: Artist| artist.name)
+                        .collect(),
+                    album: album.map(|album: Album| album.name),
+                    cover_url,
+                    complete,
+                    ..metadata
+                }
+            }))
+        }))
+    }))
+}
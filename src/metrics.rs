@@ -0,0 +1,356 @@
+use crate::main_loop::SinkBuilder;
+#[cfg(feature = "metrics")]
+use crate::{cache, http_server, main_loop, normalisation};
+#[cfg(feature = "metrics")]
+use futures::{sync::mpsc::UnboundedReceiver, Future, Stream};
+use librespot::playback::{audio_backend::Sink, config::Bitrate};
+#[cfg(feature = "metrics")]
+use librespot::{core::session::Session, metadata::AudioItem, playback::player::PlayerEvent};
+#[cfg(feature = "metrics")]
+use log::{debug, info};
+#[cfg(feature = "metrics")]
+use std::{
+    fmt::{Display, Write},
+    path::PathBuf,
+    time::SystemTime,
+};
+use std::{
+    io,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+#[cfg(feature = "metrics")]
+use tokio_core::{net::TcpListener, reactor::Handle};
+
+/// How late the audio may be before the device is taken to have run out,
+/// which covers the time it takes the player to hand over the next chunk.
+const UNDERRUN_SLACK: Duration = Duration::from_millis(100);
+
+/// What is served on `/metrics` for `metrics_address`. The main loop, the
+/// player events and the sink update it, the last one from the player thread.
+#[derive(Default)]
+#[cfg_attr(not(feature = "metrics"), allow(dead_code))]
+pub(crate) struct Metrics {
+    connected: AtomicBool,
+    /// Set after the connection to Spotify was lost, until it is back.
+    reconnecting: AtomicBool,
+    reconnects: AtomicU64,
+    playing: AtomicBool,
+    tracks_played: AtomicU64,
+    underruns: AtomicU64,
+    bitrate_kbps: AtomicU64,
+    cache_hits: AtomicU64,
+    cache_misses: AtomicU64,
+}
+
+impl Metrics {
+    pub(crate) fn connected(&self, bitrate: Bitrate) {
+        let kbps = match bitrate {
+            Bitrate::Bitrate96 => 96,
+            Bitrate::Bitrate160 => 160,
+            Bitrate::Bitrate320 => 320,
+        };
+        self.bitrate_kbps.store(kbps, Ordering::Relaxed);
+        self.connected.store(true, Ordering::Relaxed);
+        self.reconnecting.store(false, Ordering::Relaxed);
+    }
+
+    pub(crate) fn reconnecting(&self) {
+        self.connected.store(false, Ordering::Relaxed);
+        self.playing.store(false, Ordering::Relaxed);
+        self.reconnecting.store(true, Ordering::Relaxed);
+        self.reconnects.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Whether spotifyd is connected, or waiting for a client to connect,
+    /// rather than trying to get a lost connection back.
+    #[cfg(feature = "metrics")]
+    fn healthy(&self) -> bool {
+        !self.reconnecting.load(Ordering::Relaxed)
+    }
+
+    /// The bitrate of the current connection, which the player picks the file
+    /// of a track by.
+    #[cfg(feature = "metrics")]
+    fn bitrate(&self) -> Bitrate {
+        match self.bitrate_kbps.load(Ordering::Relaxed) {
+            96 => Bitrate::Bitrate96,
+            320 => Bitrate::Bitrate320,
+            _ => Bitrate::Bitrate160,
+        }
+    }
+}
+
+/// A sink that counts the times the audio device ran out of audio.
+///
+/// Writing blocks while the device is full, so the audio written is normally
+/// ahead of the clock. Once the clock catches up with it, e.g. as the track
+/// is still downloading, the device has played everything it was given.
+struct UnderrunSink {
+    sink: Box<dyn Sink>,
+    metrics: Arc<Metrics>,
+    /// When the audio written so far will have been played.
+    played_at: Option<Instant>,
+}
+
+impl Sink for UnderrunSink {
+    fn start(&mut self) -> io::Result<()> {
+        self.played_at = None;
+        self.sink.start()
+    }
+
+    fn stop(&mut self) -> io::Result<()> {
+        self.played_at = None;
+        self.sink.stop()
+    }
+
+    fn write(&mut self, data: &[i16]) -> io::Result<()> {
+        let now = Instant::now();
+        let from = match self.played_at {
+            Some(played_at) if now > played_at + UNDERRUN_SLACK => {
+                self.metrics.underruns.fetch_add(1, Ordering::Relaxed);
+                now
+            }
+            Some(played_at) => played_at.max(now),
+            None => now,
+        };
+        // The player always sends 44.1 kHz stereo.
+        let length = Duration::from_secs_f64(data.len() as f64 / 2.0 / 44_100.0);
+        self.played_at = Some(from + length);
+        self.sink.write(data)
+    }
+}
+
+/// Wraps `open` so that underruns are counted in `metrics`.
+pub(crate) fn count_underruns(open: SinkBuilder, metrics: Arc<Metrics>) -> SinkBuilder {
+    Arc::new(move || {
+        Box::new(UnderrunSink {
+            sink: open(),
+            metrics: metrics.clone(),
+            played_at: None,
+        })
+    })
+}
+
+/// Adds a metric without labels to `text`.
+#[cfg(feature = "metrics")]
+fn metric(text: &mut String, name: &str, kind: &str, help: &str, value: impl Display) {
+    let _ = write!(
+        text,
+        "# HELP {name} {help}\n# TYPE {name} {kind}\n{name} {value}\n",
+        name = name,
+        kind = kind,
+        help = help,
+        value = value
+    );
+}
+
+/// Writes the metrics in the Prometheus text format.
+#[cfg(feature = "metrics")]
+fn render(metrics: &Metrics, started_at: SystemTime, cache: bool) -> String {
+    let mut text = String::new();
+    let load = |value: &AtomicU64| value.load(Ordering::Relaxed);
+    let flag = |value: &AtomicBool| value.load(Ordering::Relaxed) as u64;
+
+    metric(
+        &mut text,
+        "spotifyd_connected",
+        "gauge",
+        "Whether spotifyd is logged in to Spotify.",
+        flag(&metrics.connected),
+    );
+    metric(
+        &mut text,
+        "spotifyd_reconnects_total",
+        "counter",
+        "The times spotifyd logged in again after the connection was lost.",
+        load(&metrics.reconnects),
+    );
+    metric(
+        &mut text,
+        "spotifyd_playing",
+        "gauge",
+        "Whether a track is loaded on this device.",
+        flag(&metrics.playing),
+    );
+    metric(
+        &mut text,
+        "spotifyd_tracks_played_total",
+        "counter",
+        "The tracks that started playing.",
+        load(&metrics.tracks_played),
+    );
+    metric(
+        &mut text,
+        "spotifyd_buffer_underruns_total",
+        "counter",
+        "The times the audio device ran out of audio, like when a track or a seek took a while to load.",
+        load(&metrics.underruns),
+    );
+    metric(
+        &mut text,
+        "spotifyd_bitrate_kbps",
+        "gauge",
+        "The bitrate audio is streamed at, 0 before the first login.",
+        load(&metrics.bitrate_kbps),
+    );
+    if cache {
+        let (hits, misses) = (load(&metrics.cache_hits), load(&metrics.cache_misses));
+        metric(
+            &mut text,
+            "spotifyd_cache_hits_total",
+            "counter",
+            "The tracks that started playing from the audio cache.",
+            hits,
+        );
+        metric(
+            &mut text,
+            "spotifyd_cache_misses_total",
+            "counter",
+            "The tracks that started playing without being in the audio cache.",
+            misses,
+        );
+        let ratio = if hits + misses > 0 {
+            hits as f64 / (hits + misses) as f64
+        } else {
+            0.0
+        };
+        metric(
+            &mut text,
+            "spotifyd_cache_hit_ratio",
+            "gauge",
+            "The part of the tracks played that came from the audio cache.",
+            ratio,
+        );
+    }
+    let started_at = started_at
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .map_or(0, |since| since.as_secs());
+    metric(
+        &mut text,
+        "spotifyd_start_time_seconds",
+        "gauge",
+        "When spotifyd started, in seconds since the Unix epoch.",
+        started_at,
+    );
+    let _ = write!(
+        text,
+        "# HELP spotifyd_build_info The version of spotifyd.\n\
+         # TYPE spotifyd_build_info gauge\n\
+         spotifyd_build_info{{version=\"{}\"}} 1\n",
+        env!("CARGO_PKG_VERSION")
+    );
+    text
+}
+
+/// Answers a single HTTP request with its status, content type and body.
+/// Only `GET /metrics` and `GET /healthz` are served.
+#[cfg(feature = "metrics")]
+fn respond(
+    request: &str,
+    metrics: &Metrics,
+    started_at: SystemTime,
+    cache: bool,
+) -> (&'static str, &'static str, String) {
+    let mut words = request.split_whitespace();
+    match (words.next(), words.next()) {
+        (Some("GET"), Some("/metrics")) => (
+            "200 OK",
+            "text/plain; version=0.0.4",
+            render(metrics, started_at, cache),
+        ),
+        (Some("GET"), Some("/healthz")) if metrics.healthy() => {
+            ("200 OK", "text/plain", "ok\n".to_string())
+        }
+        (Some("GET"), Some("/healthz")) => (
+            "503 Service Unavailable",
+            "text/plain",
+            "reconnecting to Spotify\n".to_string(),
+        ),
+        (Some("GET"), _) => ("404 Not Found", "text/plain", "not found\n".to_string()),
+        _ => (
+            "405 Method Not Allowed",
+            "text/plain",
+            "only GET is supported\n".to_string(),
+        ),
+    }
+}
+
+/// Counts the tracks played in `metrics`, and whether they were in the
+/// audio cache at `cache_path` when they started.
+#[cfg(feature = "metrics")]
+fn count_tracks(
+    handle: &Handle,
+    metrics: Arc<Metrics>,
+    events: UnboundedReceiver<(Session, PlayerEvent)>,
+    cache_path: Option<PathBuf>,
+) {
+    let lookups = handle.clone();
+    let mut active = false;
+    handle.spawn(events.for_each(move |(session, event)| {
+        active = main_loop::is_active_after(&event, active);
+        metrics.playing.store(active, Ordering::Relaxed);
+        let track_id = match event {
+            PlayerEvent::Started { track_id, .. } => track_id,
+            PlayerEvent::Changed { new_track_id, .. } => new_track_id,
+            _ => return Ok(()),
+        };
+        metrics.tracks_played.fetch_add(1, Ordering::Relaxed);
+        let cache_path = match cache_path {
+            Some(ref path) => path.clone(),
+            None => return Ok(()),
+        };
+        let metrics = metrics.clone();
+        lookups.spawn(
+            AudioItem::get_audio_item(&session, track_id)
+                .map_err(move |_| debug!("Could not look up the files of {}", track_id.to_uri()))
+                .map(move |item| {
+                    // Only the file the player picks for the bitrate counts.
+                    let formats = normalisation::preferred_formats(metrics.bitrate());
+                    let cached = formats
+                        .iter()
+                        .find_map(|format| item.files.get(format))
+                        .is_some_and(|&file| cache::audio_file_path(&cache_path, file).is_file());
+                    let counter = if cached {
+                        &metrics.cache_hits
+                    } else {
+                        &metrics.cache_misses
+                    };
+                    counter.fetch_add(1, Ordering::Relaxed);
+                }),
+        );
+        Ok(())
+    }));
+}
+
+/// Serves `metrics` on `listener`, along with the tracks played and the
+/// cache hits counted from the player events sent through `events`.
+#[cfg(feature = "metrics")]
+pub(crate) fn serve(
+    listener: TcpListener,
+    handle: &Handle,
+    metrics: Arc<Metrics>,
+    events: UnboundedReceiver<(Session, PlayerEvent)>,
+    cache_path: Option<PathBuf>,
+    started_at: SystemTime,
+) -> io::Result<()> {
+    let addr = listener.local_addr()?;
+    info!("Serving metrics on http://{}/metrics", addr);
+
+    let cache = cache_path.is_some();
+    count_tracks(handle, metrics.clone(), events, cache_path);
+
+    http_server::serve(
+        listener,
+        handle,
+        "the metrics server",
+        move |socket, request| {
+            let (status, content_type, body) = respond(&request, &metrics, started_at, cache);
+            http_server::reply(socket, status, content_type, "Allow: GET\r\n", body)
+        },
+    );
+    Ok(())
+}
@@ -0,0 +1,69 @@
+use crate::main_loop;
+use futures::{Async, Stream};
+use librespot::playback::player::PlayerEvent;
+use log::{debug, info};
+use std::{io, time::Duration};
+use tokio_core::reactor::{Handle, Interval};
+
+/// Reads the volume of the mixer, between 0 and 65535.
+pub(crate) type ReadVolume = Box<dyn Fn() -> Option<u16>>;
+
+/// Tells Spotify Connect about volume changes made to the mixer outside of
+/// spotifyd, for `mixer_watch`.
+///
+/// The mixer is read every interval. A reading that differs from the last one
+/// is sent to Spotify Connect through the Web API, but only while this device
+/// is playing, as the Web API would otherwise change the volume of another
+/// device. Spirc sets the mixer before it reports a new volume, so the mixer is
+/// read again then and the change isn't sent back.
+pub(crate) struct MixerWatch {
+    read: ReadVolume,
+    interval: Interval,
+    active: bool,
+    last: Option<u16>,
+}
+
+impl MixerWatch {
+    pub(crate) fn new(
+        read: ReadVolume,
+        every: Duration,
+        handle: &Handle,
+    ) -> io::Result<MixerWatch> {
+        Ok(MixerWatch {
+            last: read(),
+            read,
+            interval: Interval::new(every, handle)?,
+            active: false,
+        })
+    }
+
+    pub(crate) fn handle_event(&mut self, event: &PlayerEvent) {
+        self.active = main_loop::is_active_after(event, self.active);
+        if let PlayerEvent::VolumeSet { .. } = *event {
+            self.last = (self.read)();
+        }
+    }
+
+    /// Returns the volume to send to Spotify Connect, if the mixer was changed.
+    pub(crate) fn poll(&mut self) -> Option<u16> {
+        let mut changed = None;
+        while let Ok(Async::Ready(Some(()))) = self.interval.poll() {
+            let volume = match (self.read)() {
+                Some(volume) => volume,
+                None => continue,
+            };
+            if matches!(self.last, Some(last) if last != volume) {
+                if self.active {
+                    changed = Some(volume);
+                } else {
+                    debug!("The mixer volume changed while this device isn't playing");
+                }
+            }
+            self.last = Some(volume);
+        }
+        if let Some(volume) = changed {
+            info!("The mixer volume was changed to {}", volume);
+        }
+        changed
+    }
+}
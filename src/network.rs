@@ -0,0 +1,34 @@
+#[cfg(feature = "dbus_mpris")]
+use dbus::{stdintf::org_freedesktop_dbus::Properties, BusType, Connection};
+#[cfg(feature = "dbus_mpris")]
+use log::info;
+
+#[cfg(feature = "dbus_mpris")]
+const NETWORK_MANAGER: &str = "org.freedesktop.NetworkManager";
+
+/// Asks NetworkManager whether the connection in use is metered. Returns
+/// false if NetworkManager isn't running.
+#[cfg(feature = "dbus_mpris")]
+pub(crate) fn is_metered() -> bool {
+    let metered = Connection::get_private(BusType::System).and_then(|connection| {
+        connection
+            .with_path(NETWORK_MANAGER, "/org/freedesktop/NetworkManager", 500)
+            .get::<u32>(NETWORK_MANAGER, "Metered")
+    });
+    match metered {
+        // NMMetered: 1 is "yes" and 3 is "guessed yes".
+        Ok(metered) => metered == 1 || metered == 3,
+        Err(e) => {
+            info!(
+                "Could not ask NetworkManager whether the network is metered: {}",
+                e
+            );
+            false
+        }
+    }
+}
+
+#[cfg(not(feature = "dbus_mpris"))]
+pub(crate) fn is_metered() -> bool {
+    false
+}
@@ -0,0 +1,136 @@
+use futures::{future, sync::oneshot, Future};
+use librespot::{
+    audio::{AudioDecrypt, AudioFile},
+    core::{session::Session, spotify_id::SpotifyId},
+    metadata::{AudioItem, FileFormat},
+    playback::config::{Bitrate, PlayerConfig},
+};
+use log::debug;
+use std::{
+    io::{self, Read, Seek, SeekFrom},
+    thread,
+};
+
+/// Where the loudness data starts in a decrypted audio file.
+const NORMALISATION_DATA_OFFSET: u64 = 144;
+
+/// What volume normalisation does with the current track.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) enum Normalisation {
+    Disabled,
+    /// The track is still being checked, or couldn't be.
+    Unknown,
+    Checked {
+        /// Tracks without loudness data only get the pregain applied.
+        data_present: bool,
+        track_gain_db: f32,
+        track_peak: f32,
+        /// The factor the samples are multiplied with.
+        factor: f32,
+    },
+}
+
+impl Normalisation {
+    /// Works out the factor the same way librespot does.
+    fn from_data(track_gain_db: f32, track_peak: f32, pregain: f32) -> Normalisation {
+        let mut factor = 10f32.powf((track_gain_db + pregain) / 20.0);
+        if factor * track_peak > 1.0 {
+            factor = 1.0 / track_peak;
+        }
+        Normalisation::Checked {
+            data_present: track_peak > 0.0,
+            track_gain_db,
+            track_peak,
+            factor,
+        }
+    }
+}
+
+/// Reads the loudness data of a track, like the player does when it loads it.
+///
+/// librespot keeps what it finds to itself, so this opens the file separately.
+/// Only the start of the file is needed, which is at most a small extra
+/// download when the track isn't cached.
+pub(crate) fn check(
+    session: &Session,
+    track_id: SpotifyId,
+    config: &PlayerConfig,
+) -> Box<dyn Future<Item = Normalisation, Error = ()>> {
+    if !config.normalisation {
+        return Box::new(future::ok(Normalisation::Disabled));
+    }
+
+    let formats = preferred_formats(config.bitrate);
+    let pregain = config.normalisation_pregain;
+    let session = session.clone();
+    let id = track_id.to_base62();
+    let failed_id = id.clone();
+    let files = AudioItem::get_audio_item(&session, track_id)
+        .map_err(|_| ())
+        .and_then(move |audio| {
+            let file_id = match formats.iter().find_map(|format| audio.files.get(format)) {
+                Some(&file_id) => file_id,
+                None => return Box::new(future::err(())) as Box<dyn Future<Item = _, Error = ()>>,
+            };
+            let key = session
+                .audio_key()
+                .request(track_id, file_id)
+                .map_err(|_| ());
+            let file = AudioFile::open(&session, file_id, 20 * 1024, false).map_err(|_| ());
+            Box::new(key.join(file))
+        });
+    Box::new(
+        files
+            .and_then(|(key, file)| {
+                // Reading a file that is still downloading blocks.
+                let (tx, rx) = oneshot::channel();
+                thread::spawn(move || {
+                    let _ = tx.send(read_data(AudioDecrypt::new(key, file)));
+                });
+                rx.map_err(|_| ())
+                    .and_then(|data| data.map_err(|e| debug!("{}", e)))
+            })
+            .map(move |(track_gain_db, track_peak)| {
+                let normalisation = Normalisation::from_data(track_gain_db, track_peak, pregain);
+                debug!("Normalisation of track {}: {:?}", id, normalisation);
+                normalisation
+            })
+            .map_err(move |_| {
+                debug!(
+                    "Could not read the normalisation data of track {}",
+                    failed_id
+                )
+            }),
+    )
+}
+
+/// The formats the player picks from, in the same order.
+pub(crate) fn preferred_formats(bitrate: Bitrate) -> [FileFormat; 3] {
+    match bitrate {
+        Bitrate::Bitrate96 => [
+            FileFormat::OGG_VORBIS_96,
+            FileFormat::OGG_VORBIS_160,
+            FileFormat::OGG_VORBIS_320,
+        ],
+        Bitrate::Bitrate160 => [
+            FileFormat::OGG_VORBIS_160,
+            FileFormat::OGG_VORBIS_96,
+            FileFormat::OGG_VORBIS_320,
+        ],
+        Bitrate::Bitrate320 => [
+            FileFormat::OGG_VORBIS_320,
+            FileFormat::OGG_VORBIS_160,
+            FileFormat::OGG_VORBIS_96,
+        ],
+    }
+}
+
+/// Returns the track gain in dB and the track peak.
+fn read_data<T: Read + Seek>(mut file: T) -> io::Result<(f32, f32)> {
+    file.seek(SeekFrom::Start(NORMALISATION_DATA_OFFSET))?;
+    let mut data = [0; 8];
+    file.read_exact(&mut data)?;
+    let gain = f32::from_le_bytes([data[0], data[1], data[2], data[3]]);
+    let peak = f32::from_le_bytes([data[4], data[5], data[6], data[7]]);
+    Ok((gain, peak))
+}
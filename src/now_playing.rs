@@ -0,0 +1,260 @@
+use crate::{
+    audio_features::{AudioFeatureCache, AudioFeatures},
+    main_loop,
+    metadata::fetch_metadata,
+    process,
+};
+use futures::{sync::mpsc::UnboundedReceiver, Future, Stream};
+use librespot::{
+    core::{session::Session, spotify_id::SpotifyId},
+    playback::player::PlayerEvent,
+};
+use serde::{Serialize, Serializer};
+use std::{cell::RefCell, rc::Rc, time::Instant};
+use tokio_core::reactor::Handle;
+
+/// The name of the device, as status clients are told about it. The main loop
+/// changes it when the device is renamed.
+pub(crate) type DeviceName = Rc<RefCell<String>>;
+
+fn serialize_name<S: Serializer>(name: &DeviceName, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_str(&name.borrow())
+}
+
+#[derive(Clone, Copy, Serialize)]
+#[serde(rename_all = "lowercase")]
+enum Status {
+    Stopped,
+    Playing,
+    Paused,
+}
+
+/// What is playing, as it is sent to status clients.
+#[derive(Clone, Serialize)]
+pub(crate) struct NowPlaying {
+    #[serde(serialize_with = "serialize_name")]
+    device_name: DeviceName,
+    /// The id librespot announces this device with.
+    device_id: String,
+    /// Whether this is the active Connect device.
+    active: bool,
+    status: Status,
+    uri: Option<String>,
+    title: Option<String>,
+    artists: Vec<String>,
+    album: Option<String>,
+    duration_ms: Option<u32>,
+    position_ms: Option<u32>,
+    /// Between 0 and 100.
+    volume: Option<u16>,
+    /// Only present with `fetch_audio_features`, once they are known.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    audio_features: Option<AudioFeatures>,
+    #[serde(skip)]
+    track_id: Option<SpotifyId>,
+    /// When `position_ms` was reported, if the track is playing.
+    #[serde(skip)]
+    playing_since: Option<Instant>,
+}
+
+impl NowPlaying {
+    fn new(device_name: DeviceName, device_id: String) -> NowPlaying {
+        NowPlaying {
+            device_name,
+            device_id,
+            active: false,
+            status: Status::Stopped,
+            uri: None,
+            title: None,
+            artists: Vec::new(),
+            album: None,
+            duration_ms: None,
+            position_ms: None,
+            volume: None,
+            audio_features: None,
+            track_id: None,
+            playing_since: None,
+        }
+    }
+
+    fn handle_event(&mut self, event: &PlayerEvent) -> Option<SpotifyId> {
+        let mut new_track = None;
+        self.active = main_loop::is_active_after(event, self.active);
+        match *event {
+            PlayerEvent::Started {
+                track_id,
+                position_ms,
+                ..
+            }
+            | PlayerEvent::Loading {
+                track_id,
+                position_ms,
+                ..
+            } => {
+                new_track = self.set_track(track_id);
+                self.position_ms = Some(position_ms);
+                self.playing_since = None;
+            }
+            PlayerEvent::Changed { new_track_id, .. } => {
+                new_track = self.set_track(new_track_id);
+                self.position_ms = Some(0);
+                self.playing_since = None;
+            }
+            PlayerEvent::Playing {
+                track_id,
+                position_ms,
+                duration_ms,
+                ..
+            } => {
+                new_track = self.set_track(track_id);
+                self.status = Status::Playing;
+                self.duration_ms = Some(duration_ms);
+                self.position_ms = Some(position_ms);
+                self.playing_since = Some(Instant::now());
+            }
+            PlayerEvent::Paused {
+                track_id,
+                position_ms,
+                duration_ms,
+                ..
+            } => {
+                new_track = self.set_track(track_id);
+                self.status = Status::Paused;
+                self.duration_ms = Some(duration_ms);
+                self.position_ms = Some(position_ms);
+                self.playing_since = None;
+            }
+            PlayerEvent::Stopped { .. } => {
+                *self = NowPlaying {
+                    volume: self.volume,
+                    ..NowPlaying::new(self.device_name.clone(), self.device_id.clone())
+                };
+            }
+            PlayerEvent::VolumeSet { volume } => {
+                self.volume = Some((u32::from(volume) * 100 / u32::from(u16::MAX)) as u16);
+            }
+            _ => (),
+        }
+        new_track
+    }
+
+    /// Switches to another track, returning it if its metadata has to be fetched.
+    fn set_track(&mut self, track_id: SpotifyId) -> Option<SpotifyId> {
+        if self.track_id == Some(track_id) {
+            return None;
+        }
+        self.track_id = Some(track_id);
+        self.uri = None;
+        self.title = None;
+        self.artists = Vec::new();
+        self.album = None;
+        self.duration_ms = None;
+        Some(track_id)
+    }
+
+    /// Returns a copy with the position and the audio features brought up to
+    /// date.
+    pub(crate) fn snapshot(&self, audio_features: Option<&AudioFeatureCache>) -> NowPlaying {
+        let mut snapshot = self.clone();
+        snapshot.audio_features = match (audio_features, self.track_id) {
+            (Some(cache), Some(track_id)) => cache.get(track_id),
+            _ => None,
+        };
+        if let (Some(position_ms), Some(since)) = (self.position_ms, self.playing_since) {
+            snapshot.position_ms =
+                Some(position_ms.saturating_add(since.elapsed().as_millis() as u32));
+        }
+        snapshot
+    }
+}
+
+/// Keeps what is playing up to date from the player events sent through
+/// `events`, looking up the metadata of new tracks. `changed` is called with
+/// the name of the event after each one, and with `metadata` once the
+/// metadata of the current track is known.
+pub(crate) fn track(
+    handle: &Handle,
+    device_name: DeviceName,
+    device_id: String,
+    events: UnboundedReceiver<(Session, PlayerEvent)>,
+    changed: Box<dyn Fn(&str)>,
+) -> Rc<RefCell<NowPlaying>> {
+    let now_playing = Rc::new(RefCell::new(NowPlaying::new(device_name, device_id)));
+    let changed = Rc::new(changed);
+    let tracked = now_playing.clone();
+    let updates_handle = handle.clone();
+    let updates = events.for_each(move |(session, event)| {
+        let new_track = tracked.borrow_mut().handle_event(&event);
+        changed(process::event_name(&event));
+        if let Some(track_id) = new_track {
+            let state = tracked.clone();
+            let changed = changed.clone();
+            updates_handle.spawn(fetch_metadata(&session, track_id).map(move |metadata| {
+                {
+                    let mut state = state.borrow_mut();
+                    // Another track may have started in the meantime.
+                    if state.track_id != Some(track_id) {
+                        return;
+                    }
+                    state.uri = Some(metadata.uri);
+                    state.title = Some(metadata.title);
+                    state.artists = metadata.artists;
+                    state.album = metadata.album;
+                    state.duration_ms = state.duration_ms.or(Some(metadata.duration_ms));
+                }
+                changed("metadata");
+            }));
+        }
+        Ok(())
+    });
+    handle.spawn(updates);
+    now_playing
+}
+
+/// Describes a status, as it is sent as JSON, for people to read.
+pub(crate) fn describe(json: &str) -> String {
+    let status: serde_json::Value = match serde_json::from_str(json) {
+        Ok(status) => status,
+        Err(_) => return json.to_string(),
+    };
+    let text = |key: &str| status[key].as_str().map(str::to_string);
+    let time = |key: &str| {
+        status[key].as_u64().map(|ms| {
+            let seconds = ms / 1000;
+            format!("{}:{:02}", seconds / 60, seconds % 60)
+        })
+    };
+
+    let device = text("device_name").unwrap_or_default();
+    let mut lines = match status["status"].as_str() {
+        Some("playing") => vec![format!("Playing on {}", device)],
+        Some("paused") => vec![format!("Paused on {}", device)],
+        _ => vec![format!("Nothing is playing on {}", device)],
+    };
+    if let Some(track) = text("title").or_else(|| text("uri")) {
+        let artists = status["artists"]
+            .as_array()
+            .map(|artists| {
+                artists
+                    .iter()
+                    .filter_map(serde_json::Value::as_str)
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            })
+            .unwrap_or_default();
+        lines.push(match text("album") {
+            Some(album) if !artists.is_empty() => format!("{} by {}, on {}", track, artists, album),
+            None if !artists.is_empty() => format!("{} by {}", track, artists),
+            _ => track,
+        });
+    }
+    match (time("position_ms"), time("duration_ms")) {
+        (Some(position), Some(duration)) => lines.push(format!("{} / {}", position, duration)),
+        (Some(position), None) => lines.push(position),
+        _ => (),
+    }
+    if let Some(volume) = status["volume"].as_u64() {
+        lines.push(format!("Volume {}%", volume));
+    }
+    lines.join("\n")
+}
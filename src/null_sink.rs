@@ -0,0 +1,49 @@
+use librespot::playback::audio_backend::Sink;
+use std::{
+    io, thread,
+    time::{Duration, Instant},
+};
+
+/// Stereo samples per second, as librespot decodes them.
+const SAMPLES_PER_SECOND: u64 = 2 * 44100;
+
+/// A sink that throws the audio away, for `allow_null_fallback`.
+///
+/// It takes the audio as fast as it would be played, so tracks still take as
+/// long as they do, and Spotify Connect clients see them progress as usual.
+pub(crate) struct NullSink {
+    started: Option<Instant>,
+    samples: u64,
+}
+
+impl NullSink {
+    pub(crate) fn new() -> NullSink {
+        NullSink {
+            started: None,
+            samples: 0,
+        }
+    }
+}
+
+impl Sink for NullSink {
+    fn start(&mut self) -> io::Result<()> {
+        self.started = Some(Instant::now());
+        self.samples = 0;
+        Ok(())
+    }
+
+    fn stop(&mut self) -> io::Result<()> {
+        self.started = None;
+        Ok(())
+    }
+
+    fn write(&mut self, data: &[i16]) -> io::Result<()> {
+        let started = *self.started.get_or_insert_with(Instant::now);
+        self.samples += data.len() as u64;
+        let played = Duration::from_micros(self.samples * 1_000_000 / SAMPLES_PER_SECOND);
+        if let Some(ahead) = played.checked_sub(started.elapsed()) {
+            thread::sleep(ahead);
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,219 @@
+use crate::error::SetupError;
+use librespot::{core::authentication::Credentials, protocol::authentication::AuthenticationType};
+use log::{debug, info};
+use rand::{distributions::Alphanumeric, Rng};
+use reqwest::blocking::{Client, Response};
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use std::{
+    io::{self, BufRead, Read, Write},
+    net::{Ipv4Addr, TcpListener, TcpStream},
+    sync::mpsc::{self, Sender},
+    thread,
+};
+use url::Url;
+
+/// The client id of the Spotify desktop app. Tokens issued to it may log in to
+/// the access points, which isn't true of other client ids.
+const CLIENT_ID: &str = "65b708073fc0480ea92a077233ca87bd";
+const AUTHORIZE_URL: &str = "https://accounts.spotify.com/authorize";
+const TOKEN_URL: &str = "https://accounts.spotify.com/api/token";
+const PROFILE_URL: &str = "https://api.spotify.com/v1/me";
+
+/// The port the browser is sent back to. It is the only one the client id may
+/// redirect to, so it can't be changed.
+const PORT: u16 = 8898;
+
+/// What a token needs to log in to the access points with.
+const SCOPE: &str = "streaming";
+
+#[derive(Deserialize)]
+struct Token {
+    access_token: String,
+}
+
+#[derive(Deserialize)]
+struct Profile {
+    id: String,
+}
+
+#[derive(Deserialize)]
+struct TokenError {
+    error: String,
+    error_description: Option<String>,
+}
+
+/// Logs in through the browser, for `spotifyd authenticate`, with the
+/// authorization code flow and PKCE, so no client secret is needed.
+///
+/// The credentials hold an access token, which librespot can only log in with
+/// once. The access point answers the login with credentials that can be
+/// reused, and those are what gets cached.
+pub(crate) fn authenticate() -> Result<Credentials, SetupError> {
+    let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, PORT)).map_err(|e| {
+        SetupError::ConfigInvalid(format!(
+            "could not listen on port {} for the login, which is the only one Spotify sends \
+             the browser back to, stop what is listening on it first: {}",
+            PORT, e
+        ))
+    })?;
+    let redirect_uri = format!("http://127.0.0.1:{}/login", PORT);
+    let verifier = random_string(64);
+    let state = random_string(16);
+    let challenge =
+        base64::encode_config(Sha256::digest(verifier.as_bytes()), base64::URL_SAFE_NO_PAD);
+    let url = Url::parse_with_params(
+        AUTHORIZE_URL,
+        &[
+            ("client_id", CLIENT_ID),
+            ("response_type", "code"),
+            ("redirect_uri", &redirect_uri),
+            ("scope", SCOPE),
+            ("state", &state),
+            ("code_challenge_method", "S256"),
+            ("code_challenge", &challenge),
+        ],
+    )
+    .expect("the authorization URL is valid");
+
+    println!(
+        "Open this page in a browser to log in to Spotify:\n\n{}\n",
+        url
+    );
+    println!(
+        "If the browser runs on another machine, the page it ends up on won't load. \
+         Paste its address here instead."
+    );
+    let code = wait_for_code(listener, &state)?;
+
+    let client = Client::new();
+    let token: Token = parse(
+        client
+            .post(TOKEN_URL)
+            .form(&[
+                ("grant_type", "authorization_code"),
+                ("code", &code),
+                ("redirect_uri", &redirect_uri),
+                ("client_id", CLIENT_ID),
+                ("code_verifier", &verifier),
+            ])
+            .send(),
+    )?;
+    let profile: Profile = parse(
+        client
+            .get(PROFILE_URL)
+            .bearer_auth(&token.access_token)
+            .send(),
+    )?;
+    info!("Logged in to Spotify as \"{}\"", profile.id);
+
+    Ok(Credentials {
+        username: profile.id,
+        auth_type: AuthenticationType::AUTHENTICATION_SPOTIFY_TOKEN,
+        auth_data: token.access_token.into_bytes(),
+    })
+}
+
+/// Waits for the browser to be sent back with the authorization code, or for
+/// the address it was sent to to be pasted.
+fn wait_for_code(listener: TcpListener, state: &str) -> Result<String, SetupError> {
+    let (addresses, received) = mpsc::channel();
+    let redirects = addresses.clone();
+    thread::spawn(move || {
+        for stream in listener.incoming().flatten() {
+            if let Err(e) = answer_redirect(stream, &redirects) {
+                debug!("Could not answer the browser: {}", e);
+            }
+        }
+    });
+    thread::spawn(move || {
+        for line in io::stdin().lock().lines().map_while(Result::ok) {
+            let _ = addresses.send(line.trim().to_string());
+        }
+    });
+
+    for address in received {
+        let url = match Url::parse(&address) {
+            Ok(url) => url,
+            Err(_) => {
+                println!("That isn't an address, paste the whole one, with http://");
+                continue;
+            }
+        };
+        let value = |name: &str| {
+            url.query_pairs()
+                .find(|(key, _)| key == name)
+                .map(|(_, value)| value.into_owned())
+        };
+        if let Some(error) = value("error") {
+            return Err(SetupError::AuthFailed(format!(
+                "Spotify didn't allow the login: {}",
+                error
+            )));
+        }
+        match value("code") {
+            Some(code) if value("state").as_deref() == Some(state) => return Ok(code),
+            Some(_) => println!("That address belongs to another login, open the page above"),
+            None => println!("That address has no authorization code"),
+        }
+    }
+    Err(SetupError::AuthFailed(
+        "the login was cancelled".to_string(),
+    ))
+}
+
+/// Tells the browser it can be closed and passes on the address it asked for.
+fn answer_redirect(mut stream: TcpStream, redirects: &Sender<String>) -> io::Result<()> {
+    let mut buffer = [0; 4096];
+    let len = stream.read(&mut buffer)?;
+    let request = String::from_utf8_lossy(&buffer[..len]);
+    let target = request.split_whitespace().nth(1).unwrap_or_default();
+    if !target.starts_with("/login") {
+        return stream.write_all(b"HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\n\r\n");
+    }
+    let body = "spotifyd got the answer from Spotify, this page can be closed.";
+    write!(
+        stream,
+        "HTTP/1.1 200 OK\r\n\
+         Content-Type: text/plain; charset=utf-8\r\n\
+         Content-Length: {}\r\n\
+         Connection: close\r\n\r\n{}",
+        body.len(),
+        body
+    )?;
+    let _ = redirects.send(format!("http://127.0.0.1:{}{}", PORT, target));
+    Ok(())
+}
+
+/// Reads the JSON answer of Spotify, telling a rejected request apart from
+/// one that couldn't be sent.
+fn parse<T>(response: reqwest::Result<Response>) -> Result<T, SetupError>
+where
+    T: for<'de> Deserialize<'de>,
+{
+    let response = response.map_err(|e| {
+        SetupError::NetworkUnavailable(io::Error::new(io::ErrorKind::NotConnected, e))
+    })?;
+    if response.status().is_success() {
+        return response.json().map_err(|e| {
+            SetupError::AuthFailed(format!("Spotify sent an unexpected answer: {}", e))
+        });
+    }
+    let status = response.status();
+    let reason = match response.json::<TokenError>() {
+        Ok(TokenError {
+            error_description: Some(description),
+            ..
+        }) => description,
+        Ok(TokenError { error, .. }) => error,
+        Err(_) => status.to_string(),
+    };
+    Err(SetupError::AuthFailed(reason))
+}
+
+fn random_string(len: usize) -> String {
+    rand::thread_rng()
+        .sample_iter(&Alphanumeric)
+        .take(len)
+        .collect()
+}
@@ -0,0 +1,117 @@
+use crate::state::State;
+use gethostname::gethostname;
+use log::warn;
+use rand::RngCore;
+use std::{
+    env, io,
+    net::{IpAddr, Ipv4Addr, UdpSocket},
+    path::Path,
+};
+
+/// The placeholders `expand` knows about, for the docs and the warnings.
+pub(crate) const PLACEHOLDERS: &[&str] = &["hostname", "user", "uuid", "ip"];
+
+/// Replaces `{hostname}`, `{user}`, `{uuid}` and `{ip}` in the value of the
+/// option `option`.
+///
+/// `{uuid}` is made up on the first run and kept in the state file, so it
+/// stays the same for as long as the cache does. Unknown placeholders, and
+/// ones without a value on this system, are left as they are.
+pub(crate) fn expand(option: &str, value: &str, state_path: Option<&Path>) -> String {
+    let mut expanded = String::with_capacity(value.len());
+    let mut rest = value;
+    while let Some(start) = rest.find('{') {
+        let end = match rest[start..].find('}') {
+            Some(end) => start + end,
+            None => break,
+        };
+        expanded.push_str(&rest[..start]);
+        let name = &rest[start + 1..end];
+        match lookup(name, state_path) {
+            Ok(Some(replacement)) => expanded.push_str(&replacement),
+            Ok(None) => {
+                warn!(
+                    "Leaving {{{}}} in {} as it is, the placeholders are {}",
+                    name,
+                    option,
+                    PLACEHOLDERS
+                        .iter()
+                        .map(|p| format!("{{{}}}", p))
+                        .collect::<Vec<_>>()
+                        .join(", ")
+                );
+                expanded.push_str(&rest[start..=end]);
+            }
+            Err(e) => {
+                warn!("Leaving {{{}}} in {} as it is: {}", name, option, e);
+                expanded.push_str(&rest[start..=end]);
+            }
+        }
+        rest = &rest[end + 1..];
+    }
+    expanded.push_str(rest);
+    expanded
+}
+
+/// Returns the value of a placeholder, or `None` if there is no such one.
+fn lookup(name: &str, state_path: Option<&Path>) -> Result<Option<String>, String> {
+    let value = match name {
+        "hostname" => gethostname().to_string_lossy().into_owned(),
+        "user" => env::var("USER")
+            .or_else(|_| env::var("USERNAME"))
+            .map_err(|_| "the user isn't known".to_string())?,
+        "uuid" => install_id(state_path),
+        "ip" => local_ip()
+            .map_err(|e| format!("could not find the address of this device: {}", e))?
+            .to_string(),
+        _ => return Ok(None),
+    };
+    Ok(Some(value))
+}
+
+/// Returns `{uuid}`, which is saved in the state file on the first run.
+pub(crate) fn install_id(state_path: Option<&Path>) -> String {
+    let path = match state_path {
+        Some(path) => path,
+        None => {
+            warn!("{{uuid}} changes with every start without a cache_path to keep it in");
+            return new_uuid();
+        }
+    };
+    let mut state = State::load(path);
+    if let Some(ref id) = state.install_id {
+        return id.clone();
+    }
+    let id = new_uuid();
+    state.install_id = Some(id.clone());
+    if let Err(e) = state.save(path) {
+        warn!("Could not save {{uuid}} to {}: {}", path.display(), e);
+    }
+    id
+}
+
+/// Returns a random (version 4) UUID.
+fn new_uuid() -> String {
+    let mut bytes = [0u8; 16];
+    rand::thread_rng().fill_bytes(&mut bytes);
+    bytes[6] = (bytes[6] & 0x0f) | 0x40;
+    bytes[8] = (bytes[8] & 0x3f) | 0x80;
+    let hex = hex::encode(bytes);
+    format!(
+        "{}-{}-{}-{}-{}",
+        &hex[..8],
+        &hex[8..12],
+        &hex[12..16],
+        &hex[16..20],
+        &hex[20..]
+    )
+}
+
+/// Returns the address this device reaches other networks from.
+fn local_ip() -> io::Result<IpAddr> {
+    let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0))?;
+    // Connecting a UDP socket sends nothing, it only picks the route, and with
+    // it the address. This one is reserved for documentation.
+    socket.connect((Ipv4Addr::new(192, 0, 2, 1), 9))?;
+    Ok(socket.local_addr()?.ip())
+}
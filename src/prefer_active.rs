@@ -0,0 +1,104 @@
+use crate::{main_loop, web_api};
+use futures::{Async, Stream};
+use librespot::{
+    core::session::Session,
+    playback::player::PlayerEvent,
+    protocol::spirc::{Frame, MessageType, PlayStatus},
+};
+use log::{debug, info, warn};
+use std::{collections::HashSet, io, time::Duration};
+use tokio_core::reactor::{Handle, Interval};
+
+/// How often this device checks whether it can make itself the active one.
+const CHECK_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Makes this the active Connect device whenever no device is playing, for
+/// `prefer_active`, so that clients play here without picking it first.
+///
+/// Other devices announce what they play to each other, so a device that says
+/// it is playing rules out taking over right away. Otherwise the Web API is
+/// asked first, and playback is only transferred, without starting it, if
+/// nothing plays anywhere.
+pub(crate) struct PreferActive {
+    interval: Interval,
+    frames: Option<Box<dyn Stream<Item = Frame, Error = ()>>>,
+    /// The other devices that said they are playing.
+    playing: HashSet<String>,
+    active: bool,
+}
+
+impl PreferActive {
+    pub(crate) fn new(handle: &Handle) -> io::Result<PreferActive> {
+        Ok(PreferActive {
+            interval: Interval::new(CHECK_INTERVAL, handle)?,
+            frames: None,
+            playing: HashSet::new(),
+            active: false,
+        })
+    }
+
+    pub(crate) fn connected(&mut self, session: &Session) {
+        self.frames = Some(main_loop::remote_frames(session));
+        self.playing.clear();
+    }
+
+    pub(crate) fn handle_event(&mut self, event: &PlayerEvent) {
+        self.active = main_loop::is_active_after(event, self.active);
+    }
+
+    pub(crate) fn poll(&mut self, session: &Session, handle: &Handle, device_name: &str) {
+        while let Some(Ok(Async::Ready(Some(frame)))) = self.frames.as_mut().map(Stream::poll) {
+            if frame.get_ident() == session.device_id() {
+                continue;
+            }
+            let playing = frame.get_typ() != MessageType::kMessageTypeGoodbye
+                && frame.has_state()
+                && frame.get_state().get_status() == PlayStatus::kPlayStatusPlay;
+            if playing {
+                self.playing.insert(frame.get_ident().to_string());
+            } else if frame.get_typ() == MessageType::kMessageTypeGoodbye || frame.has_state() {
+                self.playing.remove(frame.get_ident());
+            }
+        }
+
+        let mut due = false;
+        while let Ok(Async::Ready(Some(()))) = self.interval.poll() {
+            due = true;
+        }
+        if !due || self.active {
+            return;
+        }
+        if !self.playing.is_empty() {
+            debug!("Another device is playing, not making this one active");
+            return;
+        }
+        take_over(session, handle, device_name.to_string());
+    }
+}
+
+fn take_over(session: &Session, handle: &Handle, device_name: String) {
+    web_api::spawn_with_api(session, handle, main_loop::PLAYBACK_SCOPE, move |sp| {
+        match sp.current_playback(None) {
+            Ok(Some(ref playback)) if playback.is_playing => {
+                return debug!(
+                    "{} is playing, not making this device active",
+                    playback.device.name
+                )
+            }
+            Ok(_) => (),
+            Err(e) => return warn!("Could not check what is playing: {}", e),
+        }
+        let device = match sp.device() {
+            Ok(devices) => devices.devices.into_iter().find(|d| d.name == device_name),
+            Err(e) => return warn!("Could not list the devices: {}", e),
+        };
+        match device {
+            Some(ref device) if device.is_active => (),
+            Some(device) => match sp.transfer_playback(&device.id, false) {
+                Ok(()) => info!("Nothing is playing, made this the active device"),
+                Err(e) => warn!("Could not make this the active device: {}", e),
+            },
+            None => debug!("This device isn't known to Spotify yet"),
+        }
+    });
+}
@@ -0,0 +1,102 @@
+use crate::main_loop::SinkBuilder;
+#[cfg(target_os = "linux")]
+use librespot::playback::audio_backend::Sink;
+#[cfg(unix)]
+use log::info;
+use log::warn;
+#[cfg(unix)]
+use std::io;
+#[cfg(target_os = "linux")]
+use std::sync::Arc;
+
+/// Sets the nice value of the process. Threads started afterwards, like the
+/// one librespot plays the audio on, inherit it.
+#[cfg(unix)]
+pub(crate) fn set_nice(nice: i32) {
+    // On Linux this only changes the calling thread, which is fine as long as
+    // no other threads have been started yet.
+    let result = unsafe { libc::setpriority(libc::PRIO_PROCESS, 0, nice) };
+    if result == 0 {
+        info!("Set the nice value to {}", nice);
+    } else {
+        warn!(
+            "Could not set the nice value to {}: {}",
+            nice,
+            io::Error::last_os_error()
+        );
+    }
+}
+
+#[cfg(not(unix))]
+pub(crate) fn set_nice(_: i32) {
+    warn!("nice is only supported on unix");
+}
+
+/// Wraps `open` so that the thread writing the audio, which is the one
+/// librespot plays on, asks for real-time scheduling with `SCHED_FIFO` before
+/// its first write. The other threads keep the normal scheduling. This needs
+/// root or `CAP_SYS_NICE`, or an `rtprio` limit that allows the priority.
+#[cfg(target_os = "linux")]
+pub(crate) fn realtime(open: SinkBuilder, priority: i32) -> SinkBuilder {
+    Arc::new(move || {
+        Box::new(RealtimeSink {
+            sink: open(),
+            priority,
+            scheduled: false,
+        })
+    })
+}
+
+#[cfg(not(target_os = "linux"))]
+pub(crate) fn realtime(open: SinkBuilder, _: i32) -> SinkBuilder {
+    warn!("rt_priority is only supported on Linux");
+    open
+}
+
+#[cfg(target_os = "linux")]
+struct RealtimeSink {
+    sink: Box<dyn Sink>,
+    priority: i32,
+    scheduled: bool,
+}
+
+#[cfg(target_os = "linux")]
+impl Sink for RealtimeSink {
+    fn start(&mut self) -> io::Result<()> {
+        self.sink.start()
+    }
+
+    fn stop(&mut self) -> io::Result<()> {
+        self.sink.stop()
+    }
+
+    fn write(&mut self, data: &[i16]) -> io::Result<()> {
+        if !self.scheduled {
+            self.scheduled = true;
+            set_realtime(self.priority);
+        }
+        self.sink.write(data)
+    }
+}
+
+/// Switches the calling thread to `SCHED_FIFO`.
+#[cfg(target_os = "linux")]
+fn set_realtime(priority: i32) {
+    let param = libc::sched_param {
+        sched_priority: priority,
+    };
+    let result =
+        unsafe { libc::pthread_setschedparam(libc::pthread_self(), libc::SCHED_FIFO, &param) };
+    if result == 0 {
+        info!(
+            "Playing the audio with real-time scheduling at priority {}",
+            priority
+        );
+    } else {
+        warn!(
+            "Could not switch to real-time scheduling with priority {}: {}",
+            priority,
+            io::Error::from_raw_os_error(result)
+        );
+    }
+}
@@ -1,20 +1,57 @@
 use crate::error::Error;
 use librespot::playback::player::PlayerEvent;
-use log::info;
+use log::{error, info, warn};
 use std::{
     collections::HashMap,
+    env,
+    ffi::OsString,
     io::{self, Read, Write},
+    path::Path,
     process::{Command, ExitStatus, Stdio},
+    sync::Mutex,
+    thread,
+    time::{Duration, Instant},
 };
 
+/// How often `Child::finish` checks whether the command has exited.
+const FINISH_POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+/// The `SSL_CERT_FILE` spotifyd was started with, once `ca_cert` replaced it.
+/// The commands run get this one instead of the bundle.
+static ORIGINAL_SSL_CERT_FILE: Mutex<Option<Option<OsString>>> = Mutex::new(None);
+
+/// Points OpenSSL at the certificates in `path`, for spotifyd only.
+pub(crate) fn set_ssl_cert_file(path: &Path) {
+    let mut original = ORIGINAL_SSL_CERT_FILE.lock().unwrap();
+    if original.is_none() {
+        *original = Some(env::var_os("SSL_CERT_FILE"));
+    }
+    env::set_var("SSL_CERT_FILE", path);
+}
+
+/// Returns a command running `cmd` in `shell`, with the environment spotifyd
+/// was started with.
+fn shell_command(shell: &str, cmd: &str) -> Command {
+    let mut command = Command::new(shell);
+    command.arg("-c").arg(cmd);
+    match *ORIGINAL_SSL_CERT_FILE.lock().unwrap() {
+        Some(Some(ref original)) => {
+            command.env("SSL_CERT_FILE", original);
+        }
+        Some(None) => {
+            command.env_remove("SSL_CERT_FILE");
+        }
+        None => (),
+    }
+    command
+}
+
 /// Blocks while provided command is run in a subprocess using the provided
 /// shell. If successful, returns the contents of the subprocess's `stdout` as a
 /// `String`.
 pub(crate) fn run_program(shell: &str, cmd: &str) -> Result<String, Error> {
     info!("Running {:?} using {:?}", cmd, shell);
-    let output = Command::new(shell)
-        .arg("-c")
-        .arg(cmd)
+    let output = shell_command(shell, cmd)
         .output()
         .map_err(|e| Error::subprocess_with_err(shell, cmd, e))?;
     if !output.status.success() {
@@ -31,9 +68,7 @@ fn spawn_program(shell: &str, cmd: &str, env: HashMap<&str, String>) -> Result<C
         "Running {:?} using {:?} with environment variables {:?}",
         cmd, shell, env
     );
-    let inner = Command::new(shell)
-        .arg("-c")
-        .arg(cmd)
+    let inner = shell_command(shell, cmd)
         .envs(env.iter())
         .stdin(Stdio::piped())
         .stdout(Stdio::piped())
@@ -44,106 +79,71 @@ fn spawn_program(shell: &str, cmd: &str, env: HashMap<&str, String>) -> Result<C
     Ok(child)
 }
 
-/// Spawns provided command in a subprocess using the provided shell.
-/// Various environment variables are included in the subprocess's environment
-/// depending on the `PlayerEvent` that was passed in.
-pub(crate) fn spawn_program_on_event(
+/// The commands run on player events.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct EventPrograms {
+    /// Run for the events that don't have a command of their own.
+    pub(crate) onevent: Option<String>,
+    pub(crate) on_track_change: Option<String>,
+    pub(crate) on_play: Option<String>,
+    pub(crate) on_pause: Option<String>,
+    pub(crate) on_stop: Option<String>,
+    pub(crate) on_volume: Option<String>,
+}
+
+impl EventPrograms {
+    /// Returns the command to run for `event`, if any.
+    pub(crate) fn for_event(&self, event: &PlayerEvent) -> Option<&str> {
+        let specific = match *event {
+            PlayerEvent::Started { .. } | PlayerEvent::Changed { .. } => &self.on_track_change,
+            PlayerEvent::Playing { .. } => &self.on_play,
+            PlayerEvent::Paused { .. } => &self.on_pause,
+            PlayerEvent::Stopped { .. } => &self.on_stop,
+            PlayerEvent::VolumeSet { .. } => &self.on_volume,
+            _ => &None,
+        };
+        specific
+            .as_ref()
+            .or(self.onevent.as_ref())
+            .map(String::as_str)
+    }
+}
+
+/// Returns the name an event is known by to onevent commands and HTTP API
+/// clients.
+pub(crate) fn event_name(event: &PlayerEvent) -> &'static str {
+    match *event {
+        PlayerEvent::Changed { .. } => "change",
+        PlayerEvent::Started { .. } => "start",
+        PlayerEvent::Stopped { .. } => "stop",
+        PlayerEvent::Loading { .. } => "load",
+        PlayerEvent::Playing { .. } => "play",
+        PlayerEvent::Paused { .. } => "pause",
+        PlayerEvent::TimeToPreloadNextTrack { .. } => "preload",
+        PlayerEvent::EndOfTrack { .. } => "endoftrack",
+        PlayerEvent::VolumeSet { .. } => "volumeset",
+        PlayerEvent::Unavailable { .. } => "unavailable",
+    }
+}
+
+/// Spawns `cmd` for an event, with `env` and `payload`, the event as JSON, in
+/// `PLAYER_EVENT_JSON`. The JSON is also written to its stdin, which is
+/// closed after it.
+pub(crate) fn spawn_program_with_payload(
     shell: &str,
     cmd: &str,
-    event: PlayerEvent,
+    mut env: HashMap<&str, String>,
+    payload: &str,
 ) -> Result<Child, Error> {
-    let mut env = HashMap::new();
-    match event {
-        PlayerEvent::Changed {
-            old_track_id,
-            new_track_id,
-        } => {
-            env.insert("OLD_TRACK_ID", old_track_id.to_base62());
-            env.insert("PLAYER_EVENT", "change".to_string());
-            env.insert("TRACK_ID", new_track_id.to_base62());
-        }
-        PlayerEvent::Started {
-            track_id,
-            play_request_id,
-            position_ms,
-        } => {
-            env.insert("PLAYER_EVENT", "start".to_string());
-            env.insert("TRACK_ID", track_id.to_base62());
-            env.insert("PLAY_REQUEST_ID", play_request_id.to_string());
-            env.insert("POSITION_MS", position_ms.to_string());
-        }
-        PlayerEvent::Stopped {
-            track_id,
-            play_request_id,
-        } => {
-            env.insert("PLAYER_EVENT", "stop".to_string());
-            env.insert("TRACK_ID", track_id.to_base62());
-            env.insert("PLAY_REQUEST_ID", play_request_id.to_string());
-        }
-        PlayerEvent::Loading {
-            track_id,
-            play_request_id,
-            position_ms,
-        } => {
-            env.insert("PLAYER_EVENT", "load".to_string());
-            env.insert("TRACK_ID", track_id.to_base62());
-            env.insert("PLAY_REQUEST_ID", play_request_id.to_string());
-            env.insert("POSITION_MS", position_ms.to_string());
-        }
-        PlayerEvent::Playing {
-            track_id,
-            play_request_id,
-            position_ms,
-            duration_ms,
-        } => {
-            env.insert("PLAYER_EVENT", "play".to_string());
-            env.insert("TRACK_ID", track_id.to_base62());
-            env.insert("PLAY_REQUEST_ID", play_request_id.to_string());
-            env.insert("POSITION_MS", position_ms.to_string());
-            env.insert("DURATION_MS", duration_ms.to_string());
-        }
-        PlayerEvent::Paused {
-            track_id,
-            play_request_id,
-            position_ms,
-            duration_ms,
-        } => {
-            env.insert("PLAYER_EVENT", "pause".to_string());
-            env.insert("TRACK_ID", track_id.to_base62());
-            env.insert("PLAY_REQUEST_ID", play_request_id.to_string());
-            env.insert("POSITION_MS", position_ms.to_string());
-            env.insert("DURATION_MS", duration_ms.to_string());
-        }
-        PlayerEvent::TimeToPreloadNextTrack {
-            track_id,
-            play_request_id,
-        } => {
-            env.insert("PLAYER_EVENT", "preload".to_string());
-            env.insert("TRACK_ID", track_id.to_base62());
-            env.insert("PLAY_REQUEST_ID", play_request_id.to_string());
-        }
-        PlayerEvent::EndOfTrack {
-            track_id,
-            play_request_id,
-        } => {
-            env.insert("PLAYER_EVENT", "endoftrack".to_string());
-            env.insert("TRACK_ID", track_id.to_base62());
-            env.insert("PLAY_REQUEST_ID", play_request_id.to_string());
-        }
-        PlayerEvent::VolumeSet { volume } => {
-            env.insert("PLAYER_EVENT", "volumeset".to_string());
-            env.insert("VOLUME", volume.to_string());
-        }
-        PlayerEvent::Unavailable {
-            play_request_id,
-            track_id,
-        } => {
-            env.insert("PLAYER_EVENT", "unavailable".to_string());
-            env.insert("TRACK_ID", track_id.to_base62());
-            env.insert("PLAY_REQUEST_ID", play_request_id.to_string());
-        }
+    env.insert("PLAYER_EVENT_JSON", payload.to_string());
+    let mut child = spawn_program(shell, cmd, env)?;
+    if let Some(mut stdin) = child.stdin.take() {
+        // A command that doesn't read its stdin may have exited already.
+        let _ = stdin
+            .write_all(payload.as_bytes())
+            .and_then(|()| stdin.write_all(b"\n"));
     }
-    spawn_program(shell, cmd, env)
+    Ok(child)
 }
 
 /// Same as a `std::process::Child` except when this `Child` exits:
@@ -191,6 +191,36 @@ impl Child {
         }
     }
 
+    /// Waits up to `grace_period` for the command to exit, then kills it, so
+    /// that it doesn't outlive spotifyd. Either way, the process is reaped.
+    pub(crate) fn finish(mut self, grace_period: Duration) {
+        let deadline = Instant::now() + grace_period;
+        loop {
+            match self.try_wait() {
+                Ok(Some(())) => return,
+                Ok(None) if Instant::now() < deadline => thread::sleep(FINISH_POLL_INTERVAL),
+                Ok(None) => break,
+                Err(e) => {
+                    error!("{}", e);
+                    return;
+                }
+            }
+        }
+        warn!(
+            "{:?} is still running after {}s, killing it",
+            self.cmd,
+            grace_period.as_secs()
+        );
+        self.terminate();
+    }
+
+    /// Kills the command and reaps it.
+    pub(crate) fn terminate(mut self) {
+        if let Err(e) = self.inner.kill().and_then(|_| self.inner.wait()) {
+            error!("{}", Error::subprocess_with_err(&self.shell, &self.cmd, e));
+        }
+    }
+
     fn write_output(&mut self, status: ExitStatus) -> Result<(), Error> {
         if status.success() {
             // If successful, write subprocess's stdout to main process's stdout...
@@ -0,0 +1,110 @@
+use crate::main_loop;
+use futures::{Async, Future, Stream};
+use librespot::{
+    core::{session::Session, spotify_id::SpotifyId, util::url_encode},
+    protocol::spirc::{Frame, MessageType, PlayStatus, State, TrackRef},
+};
+use log::{info, warn};
+use protobuf::Message;
+use tokio_core::reactor::Handle;
+
+/// Sent as the ident of the frames that add to the queue. Spirc ignores
+/// frames that claim to come from itself.
+const IDENT: &str = "spotifyd-queue";
+
+/// Adds tracks to the queue of this device.
+///
+/// Spirc keeps queued tracks in its list of tracks, marked as queued, and
+/// plays them after the current one. Spotify clients add to the queue by
+/// sending that list with the new track in it, which is what this does. The
+/// list is taken from the last state this device sent to the others.
+pub(crate) struct ConnectQueue {
+    states: Option<Box<dyn Stream<Item = State, Error = ()>>>,
+    state: Option<State>,
+}
+
+impl ConnectQueue {
+    pub(crate) fn new() -> ConnectQueue {
+        ConnectQueue {
+            states: None,
+            state: None,
+        }
+    }
+
+    pub(crate) fn connected(&mut self, session: &Session) {
+        self.states = Some(main_loop::own_states(session));
+        self.state = None;
+    }
+
+    pub(crate) fn poll(&mut self) {
+        while let Some(Ok(Async::Ready(Some(state)))) = self.states.as_mut().map(Stream::poll) {
+            self.state = Some(state);
+        }
+    }
+
+    /// Queues `track_id` right after the current track and the ones queued
+    /// before it.
+    pub(crate) fn add(
+        &mut self,
+        session: &Session,
+        handle: &Handle,
+        track_id: SpotifyId,
+    ) -> Result<(), String> {
+        let mut state = match self.state {
+            Some(ref state)
+                if !state.get_track().is_empty()
+                    && state.get_status() != PlayStatus::kPlayStatusStop =>
+            {
+                state.clone()
+            }
+            _ => return Err("nothing is playing on this device".to_string()),
+        };
+
+        let mut track = TrackRef::new();
+        track.set_gid(track_id.to_raw().to_vec());
+        track.set_uri(track_id.to_uri());
+        track.set_queued(true);
+        let mut index = state.get_playing_track_index() as usize + 1;
+        let tracks = state.mut_track();
+        while index < tracks.len() && tracks[index].get_queued() {
+            index += 1;
+        }
+        tracks.insert(index.min(tracks.len()), track);
+        // Spirc sends its own state once it took the new list, but more
+        // tracks can be queued before that.
+        self.state = Some(state.clone());
+
+        let mut frame = Frame::new();
+        frame.set_version(1);
+        frame.set_protocol_version("2.0.0".to_string());
+        frame.set_ident(IDENT.to_string());
+        frame.set_typ(MessageType::kMessageTypeReplace);
+        frame.mut_recipient().push(session.device_id().to_string());
+        frame.set_state(state);
+        let data = frame
+            .write_to_bytes()
+            .map_err(|e| format!("could not encode the queue: {}", e))?;
+        let uri = track_id.to_uri();
+        let target = format!("hm://remote/user/{}/", url_encode(&session.username()));
+        handle.spawn(
+            session
+                .mercury()
+                .send(target, data)
+                .map(move |_| info!("Queued {}", uri))
+                .map_err(|e| warn!("Could not add to the queue: {:?}", e)),
+        );
+        Ok(())
+    }
+}
+
+/// Returns the track `uri` refers to, which has to be a `spotify:track:` URI.
+pub(crate) fn parse_track_uri(uri: &str) -> Result<SpotifyId, String> {
+    let id = match uri.strip_prefix("spotify:track:") {
+        Some(id) => id,
+        None if uri.starts_with("spotify:") => {
+            return Err(format!("{} isn't a track, only tracks can be queued", uri))
+        }
+        None => return Err(format!("{} isn't a Spotify URI", uri)),
+    };
+    SpotifyId::from_base62(id).map_err(|_| format!("{} isn't a valid track URI", uri))
+}
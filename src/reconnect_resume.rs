@@ -0,0 +1,161 @@
+use crate::{
+    main_loop::{self, PLAYBACK_SCOPE},
+    resume::Position,
+    web_api,
+};
+use futures::{Async, Stream};
+use librespot::{core::session::Session, playback::player::PlayerEvent, protocol::spirc::State};
+use log::{info, warn};
+use rspotify::spotify::model::offset::Offset;
+use std::time::Instant;
+use tokio_core::reactor::Handle;
+
+/// What was playing when the connection was lost.
+struct Lost {
+    context_uri: Option<String>,
+    position: Position,
+}
+
+/// Continues the track that was playing when the connection to Spotify was
+/// lost, from about where it was, once spotifyd has logged in again, for
+/// `resume_after_reconnect`.
+///
+/// The new Spirc starts out without a context, so the context is played again
+/// through the Web API, starting at the track. It is told from the state this
+/// device sends to the others. If the context or the track can't be played
+/// anymore, the device stays idle, as it would without this.
+pub(crate) struct ReconnectResume {
+    states: Option<Box<dyn Stream<Item = State, Error = ()>>>,
+    context_uri: Option<String>,
+    current: Option<Position>,
+    lost: Option<Lost>,
+}
+
+impl ReconnectResume {
+    pub(crate) fn new() -> ReconnectResume {
+        ReconnectResume {
+            states: None,
+            context_uri: None,
+            current: None,
+            lost: None,
+        }
+    }
+
+    /// Remembers what is playing, as the connection is about to be, or was,
+    /// lost.
+    pub(crate) fn disconnected(&mut self) {
+        self.states = None;
+        self.lost = self.current.take().map(|position| Lost {
+            context_uri: self.context_uri.take(),
+            position,
+        });
+    }
+
+    pub(crate) fn connected(&mut self, session: &Session, handle: &Handle, device_name: String) {
+        self.states = Some(main_loop::own_states(session));
+        self.context_uri = None;
+        if let Some(lost) = self.lost.take() {
+            resume(session, handle, device_name, lost);
+        }
+    }
+
+    pub(crate) fn handle_event(&mut self, event: &PlayerEvent) {
+        match *event {
+            PlayerEvent::Playing {
+                track_id,
+                position_ms,
+                ..
+            } => {
+                self.current = Some(Position {
+                    track_id,
+                    position_ms,
+                    playing_since: Some(Instant::now()),
+                })
+            }
+            PlayerEvent::Paused {
+                track_id,
+                position_ms,
+                ..
+            } => {
+                self.current = Some(Position {
+                    track_id,
+                    position_ms,
+                    playing_since: None,
+                })
+            }
+            PlayerEvent::Changed { new_track_id, .. } => {
+                self.current = Some(Position {
+                    track_id: new_track_id,
+                    position_ms: 0,
+                    playing_since: None,
+                })
+            }
+            // Playback moved to another device, or the context ended.
+            PlayerEvent::Stopped { .. } => self.current = None,
+            _ => (),
+        }
+    }
+
+    pub(crate) fn poll(&mut self) {
+        while let Some(Ok(Async::Ready(Some(state)))) = self.states.as_mut().map(Stream::poll) {
+            let context_uri = state.get_context_uri();
+            self.context_uri = if context_uri.is_empty() {
+                None
+            } else {
+                Some(context_uri.to_string())
+            };
+        }
+    }
+}
+
+fn resume(session: &Session, handle: &Handle, device_name: String, lost: Lost) {
+    let track_uri = lost.position.track_id.to_uri();
+    let position_ms = lost.position.now_ms();
+    let playing = lost.position.playing_since.is_some();
+    let context_uri = lost.context_uri;
+    let waiting_session = session.clone();
+    web_api::spawn_with_api(session, handle, PLAYBACK_SCOPE, move |sp| {
+        let device_id = match main_loop::wait_for_device(&sp, &waiting_session, &device_name) {
+            Ok(device_id) => device_id,
+            Err(e) => {
+                warn!("Could not continue {} after reconnecting: {}", track_uri, e);
+                return;
+            }
+        };
+        let result = match context_uri {
+            Some(context_uri) => sp.start_playback(
+                Some(device_id.clone()),
+                Some(context_uri),
+                None,
+                Some(Offset {
+                    position: None,
+                    uri: Some(track_uri.clone()),
+                }),
+                Some(position_ms),
+            ),
+            None => sp.start_playback(
+                Some(device_id.clone()),
+                None,
+                Some(vec![track_uri.clone()]),
+                None,
+                Some(position_ms),
+            ),
+        };
+        if let Err(e) = result {
+            warn!(
+                "Could not continue {} after reconnecting, it may not be available anymore: {}",
+                track_uri, e
+            );
+            return;
+        }
+        info!(
+            "Continuing {} at {}ms after reconnecting",
+            track_uri, position_ms
+        );
+        if !playing {
+            if let Err(e) = sp.pause_playback(Some(device_id)) {
+                warn!("Could not pause {} again: {}", track_uri, e);
+            }
+        }
+    });
+}
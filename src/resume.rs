@@ -0,0 +1,137 @@
+use crate::state::State;
+use librespot::{core::spotify_id::SpotifyId, playback::player::PlayerEvent};
+use log::{error, info, warn};
+use std::{path::PathBuf, time::Instant};
+
+/// Where in a track playback is.
+pub(crate) struct Position {
+    pub(crate) track_id: SpotifyId,
+    pub(crate) position_ms: u32,
+    /// When `position_ms` was reported, if the track is still playing.
+    pub(crate) playing_since: Option<Instant>,
+}
+
+impl Position {
+    pub(crate) fn now_ms(&self) -> u32 {
+        let elapsed = self
+            .playing_since
+            .map_or(0, |since| since.elapsed().as_millis() as u32);
+        self.position_ms.saturating_add(elapsed)
+    }
+}
+
+/// Keeps track of where playback is, so that the last track can continue
+/// from the same position after spotifyd restarts.
+///
+/// The position is saved to the state file whenever playback pauses or
+/// stops, and on shutdown. After a restart, it is only used if the first
+/// track that plays is the one that was saved, which is what happens when
+/// the same context is resumed.
+pub(crate) struct PlaybackResume {
+    state_path: PathBuf,
+    saved: Option<(SpotifyId, u32)>,
+    current: Option<Position>,
+}
+
+impl PlaybackResume {
+    pub(crate) fn load(state_path: PathBuf) -> PlaybackResume {
+        let state = State::load(&state_path);
+        let saved = match (state.track_id, state.position_ms) {
+            (Some(track_id), Some(position_ms)) => match SpotifyId::from_base62(&track_id) {
+                Ok(track_id) => Some((track_id, position_ms)),
+                Err(_) => {
+                    warn!("Ignoring invalid track id in the state file: {}", track_id);
+                    None
+                }
+            },
+            _ => None,
+        };
+        PlaybackResume {
+            state_path,
+            saved,
+            current: None,
+        }
+    }
+
+    /// Updates the position from a player event. Returns the position to seek
+    /// to if the saved track has just started playing again.
+    pub(crate) fn handle_event(&mut self, event: &PlayerEvent) -> Option<u32> {
+        match *event {
+            PlayerEvent::Playing {
+                track_id,
+                position_ms,
+                duration_ms,
+                ..
+            } => {
+                self.current = Some(Position {
+                    track_id,
+                    position_ms,
+                    playing_since: Some(Instant::now()),
+                });
+                match self.saved.take() {
+                    Some((saved_id, saved_ms))
+                        if saved_id == track_id
+                            && position_ms < saved_ms
+                            && saved_ms < duration_ms =>
+                    {
+                        info!("Resuming playback at {}ms", saved_ms);
+                        Some(saved_ms)
+                    }
+                    _ => None,
+                }
+            }
+            PlayerEvent::Paused {
+                track_id,
+                position_ms,
+                ..
+            } => {
+                self.current = Some(Position {
+                    track_id,
+                    position_ms,
+                    playing_since: None,
+                });
+                self.save();
+                None
+            }
+            PlayerEvent::Stopped { .. } => {
+                if let Some(ref mut current) = self.current {
+                    current.position_ms = current.now_ms();
+                    current.playing_since = None;
+                }
+                self.save();
+                None
+            }
+            PlayerEvent::Changed { new_track_id, .. } => {
+                self.current = Some(Position {
+                    track_id: new_track_id,
+                    position_ms: 0,
+                    playing_since: None,
+                });
+                None
+            }
+            PlayerEvent::Unavailable { track_id, .. } => {
+                // The saved track was removed or isn't available anymore.
+                if matches!(self.saved, Some((saved_id, _)) if saved_id == track_id) {
+                    info!("The track playback was stopped at is unavailable, not resuming it");
+                    self.saved = None;
+                }
+                None
+            }
+            _ => None,
+        }
+    }
+
+    /// Writes the current position to the state file.
+    pub(crate) fn save(&self) {
+        let current = match self.current {
+            Some(ref current) => current,
+            None => return,
+        };
+        let mut state = State::load(&self.state_path);
+        state.track_id = Some(current.track_id.to_base62());
+        state.position_ms = Some(current.now_ms());
+        if let Err(e) = state.save(&self.state_path) {
+            error!("Could not save the playback position: {}", e);
+        }
+    }
+}
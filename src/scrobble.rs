@@ -0,0 +1,572 @@
+use crate::{
+    config::ScrobbleService,
+    metadata::{fetch_metadata, TrackMetadata},
+};
+use chrono::Utc;
+use futures::{sync::mpsc::UnboundedReceiver, Future, Stream};
+use librespot::{
+    core::{session::Session, spotify_id::SpotifyId},
+    playback::player::PlayerEvent,
+};
+use log::{debug, info, warn};
+use md5::{Digest, Md5};
+use reqwest::{
+    blocking::{Client, Response},
+    StatusCode,
+};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::{
+    cell::RefCell,
+    fs, io,
+    path::{Path, PathBuf},
+    rc::Rc,
+    sync::mpsc::{self, RecvTimeoutError, Sender},
+    thread,
+    time::{Duration, Instant},
+};
+use tokio_core::reactor::Handle;
+
+const LASTFM_URL: &str = "https://ws.audioscrobbler.com/2.0/";
+const LISTENBRAINZ_URL: &str = "https://api.listenbrainz.org/1/submit-listens";
+
+/// Shorter tracks aren't scrobbled.
+const MIN_DURATION_MS: u32 = 30_000;
+
+/// A track counts as played once half of it was listened to, or this much of
+/// a long one.
+const ENOUGH_LISTENED: Duration = Duration::from_secs(4 * 60);
+
+/// How many scrobbles go in a request. Last.fm takes at most 50.
+const BATCH_SIZE: usize = 50;
+
+/// How long to wait before sending the scrobbles again when that failed.
+const RETRY_INTERVAL: Duration = Duration::from_secs(60);
+
+/// How many scrobbles are kept while they can't be sent. The oldest are
+/// dropped beyond that; Last.fm doesn't take scrobbles older than two weeks
+/// anyway.
+const MAX_PENDING: usize = 5000;
+
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// A track that was played, waiting to be sent.
+#[derive(Clone, Deserialize, Serialize)]
+struct Scrobble {
+    uri: String,
+    title: String,
+    artists: Vec<String>,
+    album: Option<String>,
+    duration_ms: u32,
+    /// When playback of the track started, in seconds since 1970.
+    started_at: i64,
+}
+
+/// Why scrobbles couldn't be sent.
+enum Failure {
+    /// Sending them again later may work, e.g. once the network is back, or
+    /// after the credentials were fixed and spotifyd restarted.
+    Retry(String),
+    /// The service won't ever take them.
+    Rejected(String),
+}
+
+/// The track that is playing, and how long it was listened to.
+struct Play {
+    track_id: SpotifyId,
+    started_at: Option<i64>,
+    listened: Duration,
+    playing_since: Option<Instant>,
+    metadata: Rc<RefCell<Option<TrackMetadata>>>,
+}
+
+impl Play {
+    fn pause(&mut self) {
+        if let Some(since) = self.playing_since.take() {
+            self.listened += since.elapsed();
+        }
+    }
+
+    /// Returns the scrobble of the track, if it was listened to for long
+    /// enough.
+    fn scrobble(mut self) -> Option<Scrobble> {
+        self.pause();
+        let metadata = self.metadata.borrow_mut().take();
+        let metadata = match metadata {
+            // Episodes have no artists, and can't be scrobbled.
+            Some(metadata) if !metadata.artists.is_empty() => metadata,
+            Some(_) => return None,
+            None => {
+                debug!(
+                    "Not scrobbling {}, its metadata wasn't found",
+                    self.track_id.to_uri()
+                );
+                return None;
+            }
+        };
+        let duration = Duration::from_millis(u64::from(metadata.duration_ms));
+        if metadata.duration_ms < MIN_DURATION_MS
+            || self.listened < (duration / 2).min(ENOUGH_LISTENED)
+        {
+            return None;
+        }
+        Some(Scrobble {
+            uri: metadata.uri,
+            title: metadata.title,
+            artists: metadata.artists,
+            album: metadata.album,
+            duration_ms: metadata.duration_ms,
+            started_at: self.started_at?,
+        })
+    }
+}
+
+/// Follows the player events to tell which tracks were played, and hands
+/// their scrobbles to the threads that send them.
+struct Scrobbler {
+    handle: Handle,
+    current: Option<Play>,
+    senders: Vec<Sender<Scrobble>>,
+}
+
+impl Scrobbler {
+    fn handle_event(&mut self, session: &Session, event: &PlayerEvent) {
+        match *event {
+            PlayerEvent::Loading { track_id, .. } | PlayerEvent::Started { track_id, .. } => {
+                self.track(session, track_id, false);
+            }
+            PlayerEvent::Changed { new_track_id, .. } => {
+                // The next track starts right away when it was loaded in
+                // advance.
+                let playing =
+                    matches!(self.current, Some(ref play) if play.playing_since.is_some());
+                self.track(session, new_track_id, playing);
+            }
+            PlayerEvent::Playing { track_id, .. } => {
+                self.track(session, track_id, true);
+            }
+            PlayerEvent::Paused { track_id, .. } => {
+                self.track(session, track_id, false);
+                if let Some(ref mut play) = self.current {
+                    play.pause();
+                }
+            }
+            PlayerEvent::Stopped { .. } | PlayerEvent::EndOfTrack { .. } => self.finish(),
+            _ => (),
+        }
+    }
+
+    /// Makes `track_id` the track that is playing, if it isn't already,
+    /// scrobbling the one before.
+    fn track(&mut self, session: &Session, track_id: SpotifyId, playing: bool) {
+        if !matches!(self.current, Some(ref play) if play.track_id == track_id) {
+            self.finish();
+            let metadata = Rc::new(RefCell::new(None));
+            let found = metadata.clone();
+            self.handle
+                .spawn(fetch_metadata(session, track_id).map(move |metadata| {
+                    *found.borrow_mut() = Some(metadata);
+                }));
+            self.current = Some(Play {
+                track_id,
+                started_at: None,
+                listened: Duration::from_secs(0),
+                playing_since: None,
+                metadata,
+            });
+        }
+        match self.current {
+            Some(ref mut play) if playing => {
+                play.started_at = play.started_at.or_else(|| Some(Utc::now().timestamp()));
+                play.playing_since = play.playing_since.or_else(|| Some(Instant::now()));
+            }
+            _ => (),
+        }
+    }
+
+    fn finish(&mut self) {
+        let scrobble = match self.current.take().and_then(Play::scrobble) {
+            Some(scrobble) => scrobble,
+            None => return,
+        };
+        debug!("Scrobbling {}", scrobble.uri);
+        for sender in &self.senders {
+            let _ = sender.send(scrobble.clone());
+        }
+    }
+}
+
+/// Scrobbles the tracks played to each of `services`, following the player
+/// events sent through `events`. Scrobbles that can't be sent are kept in
+/// `cache_path`, if there is one, and sent again later, also after a restart.
+pub(crate) fn start(
+    handle: &Handle,
+    services: Vec<ScrobbleService>,
+    cache_path: Option<PathBuf>,
+    events: UnboundedReceiver<(Session, PlayerEvent)>,
+) {
+    let senders = services
+        .into_iter()
+        .map(|service| {
+            let queue_path = cache_path
+                .as_ref()
+                .map(|path| path.join(queue_file_name(&service)));
+            info!("Scrobbling the tracks played to {}", service);
+            send_in_background(service, queue_path)
+        })
+        .collect();
+    let mut scrobbler = Scrobbler {
+        handle: handle.clone(),
+        current: None,
+        senders,
+    };
+    handle.spawn(events.for_each(move |(session, event)| {
+        scrobbler.handle_event(&session, &event);
+        Ok(())
+    }));
+}
+
+fn queue_file_name(service: &ScrobbleService) -> &'static str {
+    match service {
+        ScrobbleService::LastFm { .. } => "scrobbles_lastfm.json",
+        ScrobbleService::ListenBrainz { .. } => "scrobbles_listenbrainz.json",
+    }
+}
+
+/// Sends the scrobbles sent through the returned channel to `service`, on a
+/// thread of its own. The scrobbles that couldn't be sent yet are kept in
+/// `queue_path`.
+fn send_in_background(service: ScrobbleService, queue_path: Option<PathBuf>) -> Sender<Scrobble> {
+    let (sender, scrobbles) = mpsc::channel();
+    thread::spawn(move || {
+        let client = match Client::builder().timeout(REQUEST_TIMEOUT).build() {
+            Ok(client) => client,
+            Err(e) => return warn!("Could not set up scrobbling to {}: {}", service, e),
+        };
+        let mut pending = queue_path.as_deref().map(load).unwrap_or_default();
+        let mut retry_at = if pending.is_empty() {
+            None
+        } else {
+            info!(
+                "Sending {} scrobbles to {} that were kept from before",
+                pending.len(),
+                service
+            );
+            Some(Instant::now())
+        };
+        let mut failing = false;
+        loop {
+            let received = match retry_at {
+                Some(at) => scrobbles.recv_timeout(at.saturating_duration_since(Instant::now())),
+                None => scrobbles
+                    .recv()
+                    .map_err(|mpsc::RecvError| RecvTimeoutError::Disconnected),
+            };
+            match received {
+                Ok(scrobble) => {
+                    pending.push(scrobble);
+                    if pending.len() > MAX_PENDING {
+                        pending.remove(0);
+                    }
+                    if let Some(ref path) = queue_path {
+                        save(path, &pending);
+                    }
+                    // While sending fails, scrobbles wait for the next try.
+                    if retry_at.is_some() {
+                        continue;
+                    }
+                }
+                Err(RecvTimeoutError::Timeout) => (),
+                Err(RecvTimeoutError::Disconnected) => return,
+            }
+
+            retry_at = None;
+            while !pending.is_empty() {
+                let count = pending.len().min(BATCH_SIZE);
+                match submit(&client, &service, &pending[..count]) {
+                    Ok(()) => {
+                        pending.drain(..count);
+                    }
+                    Err(Failure::Rejected(e)) => {
+                        warn!("{} rejected {} scrobbles: {}", service, count, e);
+                        pending.drain(..count);
+                    }
+                    Err(Failure::Retry(e)) => {
+                        if failing {
+                            debug!("Could not scrobble to {} again: {}", service, e);
+                        } else {
+                            warn!(
+                                "Could not scrobble to {}, trying again every minute: {}",
+                                service, e
+                            );
+                        }
+                        failing = true;
+                        retry_at = Some(Instant::now() + RETRY_INTERVAL);
+                        break;
+                    }
+                }
+            }
+            if failing && retry_at.is_none() {
+                failing = false;
+                info!("Scrobbling to {} works again", service);
+            }
+            if let Some(ref path) = queue_path {
+                save(path, &pending);
+            }
+        }
+    });
+    sender
+}
+
+fn load(path: &Path) -> Vec<Scrobble> {
+    match fs::read(path) {
+        Ok(contents) => serde_json::from_slice(&contents).unwrap_or_else(|e| {
+            warn!("Ignoring the scrobbles kept in {}: {}", path.display(), e);
+            Vec::new()
+        }),
+        Err(_) => Vec::new(),
+    }
+}
+
+fn save(path: &Path, pending: &[Scrobble]) {
+    let saved = if pending.is_empty() {
+        fs::remove_file(path).or_else(|e| match e.kind() {
+            io::ErrorKind::NotFound => Ok(()),
+            _ => Err(e),
+        })
+    } else {
+        serde_json::to_vec(pending)
+            .map_err(io::Error::from)
+            .and_then(|contents| fs::write(path, contents))
+    };
+    if let Err(e) = saved {
+        warn!(
+            "Could not keep the scrobbles to send in {}: {}",
+            path.display(),
+            e
+        );
+    }
+}
+
+fn submit(client: &Client, service: &ScrobbleService, batch: &[Scrobble]) -> Result<(), Failure> {
+    match service {
+        ScrobbleService::LastFm {
+            api_key,
+            api_secret,
+            session_key,
+        } => submit_to_lastfm(client, api_key, api_secret, session_key, batch),
+        ScrobbleService::ListenBrainz { token } => submit_to_listenbrainz(client, token, batch),
+    }
+}
+
+/// Sends `batch` with `track.scrobble`. Last.fm matches tracks better by
+/// their main artist than by all of them.
+fn submit_to_lastfm(
+    client: &Client,
+    api_key: &str,
+    api_secret: &str,
+    session_key: &str,
+    batch: &[Scrobble],
+) -> Result<(), Failure> {
+    let params = lastfm_params(api_key, api_secret, session_key, batch);
+    let response = client.post(LASTFM_URL).form(&params).send();
+    let body = read(response)?;
+    match body["error"].as_u64() {
+        None => Ok(()),
+        // The service is offline, temporarily unavailable, or the rate
+        // limit was exceeded.
+        Some(11) | Some(16) | Some(29) => Err(Failure::Retry(lastfm_error(&body))),
+        // The session key, the API key or the signature is wrong, which
+        // spotifyd can't fix, but the user can.
+        Some(4) | Some(9) | Some(10) | Some(13) | Some(26) => Err(Failure::Retry(format!(
+            "{}, check lastfm_api_key, lastfm_api_secret and lastfm_session_key",
+            lastfm_error(&body)
+        ))),
+        Some(_) => Err(Failure::Rejected(lastfm_error(&body))),
+    }
+}
+
+/// Returns what `track.scrobble` is sent, signed with `api_secret`.
+fn lastfm_params(
+    api_key: &str,
+    api_secret: &str,
+    session_key: &str,
+    batch: &[Scrobble],
+) -> Vec<(String, String)> {
+    let mut params = vec![
+        ("method".to_string(), "track.scrobble".to_string()),
+        ("api_key".to_string(), api_key.to_string()),
+        ("sk".to_string(), session_key.to_string()),
+    ];
+    for (i, scrobble) in batch.iter().enumerate() {
+        params.push((format!("artist[{}]", i), scrobble.artists[0].clone()));
+        params.push((format!("track[{}]", i), scrobble.title.clone()));
+        params.push((format!("timestamp[{}]", i), scrobble.started_at.to_string()));
+        params.push((
+            format!("duration[{}]", i),
+            (scrobble.duration_ms / 1000).to_string(),
+        ));
+        if let Some(ref album) = scrobble.album {
+            params.push((format!("album[{}]", i), album.clone()));
+        }
+    }
+    // The signature is made of all parameters but `format`, sorted by name.
+    params.sort();
+    let mut signed: String = params.iter().map(|(k, v)| format!("{}{}", k, v)).collect();
+    signed.push_str(api_secret);
+    params.push((
+        "api_sig".to_string(),
+        hex::encode(Md5::digest(signed.as_bytes())),
+    ));
+    params.push(("format".to_string(), "json".to_string()));
+    params
+}
+
+fn lastfm_error(body: &serde_json::Value) -> String {
+    body["message"]
+        .as_str()
+        .map_or_else(|| body.to_string(), str::to_string)
+}
+
+/// Sends `batch` to `submit-listens`, as a single listen or an import of
+/// several.
+fn submit_to_listenbrainz(client: &Client, token: &str, batch: &[Scrobble]) -> Result<(), Failure> {
+    let listens: Vec<_> = batch
+        .iter()
+        .map(|scrobble| {
+            let track_url = scrobble
+                .uri
+                .strip_prefix("spotify:track:")
+                .map(|id| format!("https://open.spotify.com/track/{}", id));
+            json!({
+                "listened_at": scrobble.started_at,
+                "track_metadata": {
+                    "artist_name": scrobble.artists.join(", "),
+                    "track_name": scrobble.title,
+                    "release_name": scrobble.album,
+                    "additional_info": {
+                        "duration_ms": scrobble.duration_ms,
+                        "artist_names": scrobble.artists,
+                        "spotify_id": track_url,
+                        "music_service": "spotify.com",
+                        "media_player": "spotifyd",
+                        "submission_client": "spotifyd",
+                        "submission_client_version": env!("CARGO_PKG_VERSION"),
+                    },
+                },
+            })
+        })
+        .collect();
+    let listen_type = if batch.len() == 1 { "single" } else { "import" };
+    let response = client
+        .post(LISTENBRAINZ_URL)
+        .header("Authorization", format!("Token {}", token))
+        .json(&json!({ "listen_type": listen_type, "payload": listens }))
+        .send();
+    read(response).map(drop)
+}
+
+/// Reads the JSON answer of a service, telling apart the requests that may
+/// work when they are sent again.
+fn read(response: reqwest::Result<Response>) -> Result<serde_json::Value, Failure> {
+    let response = response.map_err(|e| Failure::Retry(e.to_string()))?;
+    let status = response.status();
+    let body: serde_json::Value = response.json().unwrap_or_default();
+    let reason = || {
+        body["error"]
+            .as_str()
+            .map_or_else(|| status.to_string(), str::to_string)
+    };
+    match status {
+        _ if status.is_success() => Ok(body),
+        // Last.fm answers errors as JSON, with a status code that can be
+        // anything.
+        _ if body["error"].is_u64() => Ok(body),
+        StatusCode::UNAUTHORIZED => Err(Failure::Retry(format!(
+            "{}, check listenbrainz_token",
+            reason()
+        ))),
+        StatusCode::TOO_MANY_REQUESTS => Err(Failure::Retry(reason())),
+        _ if status.is_server_error() => Err(Failure::Retry(reason())),
+        _ => Err(Failure::Rejected(reason())),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn metadata(artists: &[&str], duration_ms: u32) -> TrackMetadata {
+        TrackMetadata {
+            uri: "spotify:track:4uLU6hMCjMI75M1A2tKUQC".to_string(),
+            title: "Title".to_string(),
+            artists: artists.iter().map(|artist| artist.to_string()).collect(),
+            album: Some("Album".to_string()),
+            cover_url: None,
+            duration_ms,
+            complete: true,
+        }
+    }
+
+    fn play(metadata: TrackMetadata, listened_secs: u64) -> Play {
+        Play {
+            track_id: SpotifyId::from_base62("4uLU6hMCjMI75M1A2tKUQC").unwrap(),
+            started_at: Some(1_600_000_000),
+            listened: Duration::from_secs(listened_secs),
+            playing_since: None,
+            metadata: Rc::new(RefCell::new(Some(metadata))),
+        }
+    }
+
+    #[test]
+    fn test_scrobble() {
+        // Half of a short track.
+        assert!(play(metadata(&["Artist"], 180_000), 89)
+            .scrobble()
+            .is_none());
+        let scrobble = play(metadata(&["Artist"], 180_000), 90).scrobble().unwrap();
+        assert_eq!(scrobble.started_at, 1_600_000_000);
+        assert_eq!(scrobble.artists, ["Artist"]);
+
+        // Four minutes of a long one.
+        assert!(play(metadata(&["Artist"], 600_000), 239)
+            .scrobble()
+            .is_none());
+        assert!(play(metadata(&["Artist"], 600_000), 240)
+            .scrobble()
+            .is_some());
+
+        // Too short, an episode, or no start.
+        assert!(play(metadata(&["Artist"], 20_000), 20).scrobble().is_none());
+        assert!(play(metadata(&[], 180_000), 180).scrobble().is_none());
+        let mut unstarted = play(metadata(&["Artist"], 180_000), 180);
+        unstarted.started_at = None;
+        assert!(unstarted.scrobble().is_none());
+    }
+
+    #[test]
+    fn test_lastfm_params() {
+        let batch = [Scrobble {
+            uri: "spotify:track:4uLU6hMCjMI75M1A2tKUQC".to_string(),
+            title: "Title".to_string(),
+            artists: vec!["Artist".to_string(), "Other".to_string()],
+            album: Some("Album".to_string()),
+            duration_ms: 200_500,
+            started_at: 1_600_000_000,
+        }];
+        let params = lastfm_params("key", "secret", "session", &batch);
+        let param = |name: &str| {
+            params
+                .iter()
+                .find(|(k, _)| k == name)
+                .map(|(_, v)| v.as_str())
+        };
+        assert_eq!(param("artist[0]"), Some("Artist"));
+        assert_eq!(param("duration[0]"), Some("200"));
+        assert_eq!(param("format"), Some("json"));
+        // MD5 of "album[0]Albumapi_keykeyartist[0]Artistduration[0]200
+        // methodtrack.scrobblesksessiontimestamp[0]1600000000track[0]Title"
+        // and the secret.
+        assert_eq!(param("api_sig"), Some("3721acee2ab403ced94a2d496ba332d1"));
+    }
+}
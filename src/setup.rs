@@ -1,42 +1,212 @@
+#[cfg(feature = "http_api")]
+use crate::http_api;
+#[cfg(feature = "scrobble")]
+use crate::scrobble;
+#[cfg(feature = "status_server")]
+use crate::status;
 #[cfg(feature = "alsa_backend")]
-use crate::alsa_mixer;
-use crate::{config, main_loop};
-use futures::{self, Future};
+use crate::{alsa_mixer, alsa_sink::NegotiatingSink};
+use crate::{
+    audio_devices,
+    audio_features::AudioFeatureCache,
+    autoplay::DelayedAutoplay,
+    cache::{self, CacheIndex},
+    config,
+    connect_volume::ConnectVolume,
+    context_volume::ContextVolumes,
+    control,
+    credentials::CredentialStore,
+    device_lost::{self, DeviceWatch},
+    downmix,
+    error::SetupError,
+    event_hooks::EventHooks,
+    explicit::ExplicitFilter,
+    fade::{self, FadeControl},
+    forced_modes::ForcedModes,
+    gain,
+    history::PlayHistory,
+    idle_exit::IdleExit,
+    jack_pause::JackPause,
+    lock::LockPause,
+    main_loop, media_controls,
+    metrics::{self, Metrics},
+    mixer_watch::{MixerWatch, ReadVolume},
+    normalisation::Normalisation,
+    now_playing::{self, DeviceName},
+    null_sink::NullSink,
+    oauth,
+    prefer_active::PreferActive,
+    priority,
+    queue::ConnectQueue,
+    reconnect_resume::ReconnectResume,
+    resume::PlaybackResume,
+    sleep, source_address,
+    systemd::Systemd,
+    tee_sink::TeeSink,
+    track_error::TrackErrors,
+    utils,
+    volume_curve::CurvedMixer,
+    zeroconf, LogTimestamps,
+};
+use futures::{self, Async, Future, Poll};
 #[cfg(feature = "dbus_keyring")]
 use keyring::Keyring;
 use librespot::{
     connect::discovery::discovery,
     core::{
-        authentication::get_credentials,
+        authentication::{get_credentials, Credentials},
         cache::Cache,
-        config::{ConnectConfig, DeviceType, VolumeCtrl},
+        config::{ConnectConfig, DeviceType, SessionConfig, VolumeCtrl},
         session::Session,
     },
     playback::{
         audio_backend::{Sink, BACKENDS},
         mixer::{self, Mixer},
+        player::PlayerEvent,
     },
 };
-use log::{error, info};
+use log::{info, warn};
+#[cfg(feature = "status_server")]
+use std::net::SocketAddr;
 use std::str::FromStr;
-use std::{io, process::exit};
+use std::{
+    cell::{Cell, RefCell},
+    env, fs, io,
+    path::Path,
+    rc::Rc,
+    sync::Arc,
+    time::{Duration, SystemTime},
+};
+#[cfg(feature = "status_server")]
+use tokio_core::net::TcpListener;
 use tokio_core::reactor::Handle;
+use tokio_io::IoStream;
 use tokio_signal::ctrl_c;
+use url::Url;
+
+/// Starts what runs for as long as spotifyd does, before the first run of the
+/// main loop. It is kept when the main loop is restarted after a panic.
+pub(crate) fn services(
+    handle: &Handle,
+    config: &mut config::SpotifydConfig,
+    log_timestamps: LogTimestamps,
+) -> Result<main_loop::Services, SetupError> {
+    if let Some(ref path) = config.ca_cert {
+        use_ca_cert(path)?;
+    }
+    use_bind_address(config)?;
+
+    if let Some(max_age) = config.cache_max_age {
+        match config.cache_path {
+            Some(ref path) => cache::remove_old_files_periodically(path, max_age),
+            None => warn!("cache_max_age is set, but no cache_path is configured"),
+        }
+    }
+
+    if let Some(max_size) = config.max_cache_size {
+        match config.cache_path {
+            Some(ref path) => cache::limit_size_periodically(path, max_size),
+            None => warn!("max_cache_size is set, but no cache_path is configured"),
+        }
+    }
+
+    let audio_features = if config.fetch_audio_features {
+        Some(AudioFeatureCache::new())
+    } else {
+        None
+    };
+    let started_at = SystemTime::now();
+    let mut systemd = Systemd::from_env(handle);
+    // It is only final once the main loop checked that it isn't taken.
+    let device_name = Rc::new(RefCell::new(config.device_name.clone()));
+    let mut status_event_senders = Vec::new();
+    status_event_senders.extend(status_server(
+        config,
+        handle,
+        &device_name,
+        &mut systemd,
+        audio_features.clone(),
+        started_at,
+    )?);
+
+    let (control_sender, control_requests) = control::channel();
+    status_event_senders.extend(http_api(
+        config,
+        handle,
+        &device_name,
+        &mut systemd,
+        audio_features.clone(),
+        control_sender.clone(),
+    )?);
+    status_event_senders.extend(media_controls(config, handle, control_sender.clone()));
+    let metrics = metrics(config, handle, &mut systemd, started_at)?.map(|(metrics, sender)| {
+        status_event_senders.push(sender);
+        metrics
+    });
+    systemd.close_unused_listeners();
+    status_event_senders.extend(scrobbler(config, handle));
+    if let Some(ref path) = config.control_socket {
+        control::listen(path, control_sender.clone()).map_err(|e| {
+            SetupError::ConfigInvalid(format!(
+                "could not listen on control_socket {}: {}",
+                path.display(),
+                e
+            ))
+        })?;
+    }
 
+    Ok(main_loop::Services {
+        systemd: Rc::new(RefCell::new(systemd)),
+        control_sender,
+        control_requests: Rc::new(RefCell::new(control_requests)),
+        status_event_senders,
+        audio_features,
+        metrics,
+        started_at,
+        log_timestamps,
+        device_name,
+    })
+}
+
+/// Sets up the main loop. With `oneshot`, the given URI is played right away
+/// instead of waiting for a client, and the device isn't announced.
 pub(crate) fn initial_state(
     handle: Handle,
-    config: config::SpotifydConfig,
-) -> main_loop::MainLoopState {
+    mut config: config::SpotifydConfig,
+    services: &main_loop::Services,
+    oneshot: Option<main_loop::Oneshot>,
+) -> Result<main_loop::MainLoopState, SetupError> {
+    if let Some(ref device) = config.audio_device {
+        let resolved =
+            match audio_devices::resolve(config.backend.as_deref().unwrap_or("alsa"), device) {
+                // A card that isn't plugged in yet is played on a fallback until
+                // it is.
+                Err(SetupError::ConfigInvalid(e))
+                    if config.on_device_lost == config::DeviceLostAction::Fallback
+                        && !config.fallback_backends.is_empty()
+                        && device.parse::<usize>().is_err() =>
+                {
+                    warn!("{}", e);
+                    device.clone()
+                }
+                resolved => resolved?,
+            };
+        if &resolved != device {
+            info!("Using audio device {} for {}", resolved, device);
+            config.audio_device = Some(resolved);
+        }
+    }
+
     #[cfg(feature = "alsa_backend")]
     let mut mixer = {
         let local_audio_device = config.audio_device.clone();
         let local_control_device = config.control_device.clone();
         let local_mixer = config.mixer.clone();
+        let curve = config.volume_curve;
         match config.volume_controller {
             config::VolumeController::SoftVolume => {
                 info!("Using software volume controller.");
-                Box::new(|| Box::new(mixer::softmixer::SoftMixer::open(None)) as Box<dyn Mixer>)
-                    as Box<dyn FnMut() -> Box<dyn Mixer>>
+                soft_mixer(curve)
             }
             _ => {
                 info!("Using alsa volume controller.");
@@ -52,6 +222,7 @@ pub(crate) fn initial_state(
                             .unwrap_or_else(|| "default".to_string()),
                         mixer: local_mixer.clone().unwrap_or_else(|| "Master".to_string()),
                         linear_scaling: linear,
+                        curve,
                     }) as Box<dyn mixer::Mixer>
                 }) as Box<dyn FnMut() -> Box<dyn Mixer>>
             }
@@ -61,10 +232,90 @@ pub(crate) fn initial_state(
     #[cfg(not(feature = "alsa_backend"))]
     let mut mixer = {
         info!("Using software volume controller.");
-        Box::new(|| Box::new(mixer::softmixer::SoftMixer::open(None)) as Box<dyn Mixer>)
-            as Box<dyn FnMut() -> Box<dyn Mixer>>
+        soft_mixer(config.volume_curve)
+    };
+
+    let mixer_watch = config
+        .mixer_watch
+        .and_then(|every| mixer_watch(&config, every, &handle));
+
+    if config.resume_on_jack_replug && !config.pause_on_jack_unplug {
+        warn!("resume_on_jack_replug is set, but pause_on_jack_unplug isn't");
+    }
+    let jack_pause = if config.pause_on_jack_unplug {
+        let device = config
+            .control_device
+            .as_deref()
+            .or(config.audio_device.as_deref())
+            .unwrap_or("default");
+        JackPause::new(device, &handle, config.resume_on_jack_replug)
+    } else {
+        None
+    };
+
+    if let Some(ref tmp_dir) = config.tmp_dir {
+        use_tmp_dir(tmp_dir)?;
+    }
+
+    if config.metered_bitrate.is_some() && !cfg!(feature = "dbus_mpris") {
+        warn!("metered_bitrate needs the dbus_mpris feature, ignoring it");
+    }
+
+    if config.cache_remove_stubs {
+        match config.cache_path {
+            Some(ref path) => cache::remove_stubs(path),
+            None => warn!("cache_remove_stubs is set, but no cache_path is configured"),
+        }
+    }
+
+    let playback_resume = match config.state_path {
+        Some(ref path) if config.resume_playback => Some(PlaybackResume::load(path.clone())),
+        None if config.resume_playback => {
+            warn!("resume_playback is set, but no cache_path is configured");
+            None
+        }
+        _ => None,
     };
 
+    let context_volumes = match (config.context_volume, &config.state_path) {
+        (Some(mode), Some(path)) => Some(ContextVolumes::new(mode, path.clone())),
+        (Some(_), None) => {
+            warn!("context_volume is set, but no cache_path is configured");
+            None
+        }
+        _ => None,
+    };
+
+    let audio_features = services.audio_features.clone();
+    let metrics = services.metrics.clone();
+    let mut status_event_senders = services.status_event_senders.clone();
+
+    let credential_store = credential_store(&config)?;
+    let credentials = find_credentials(&config, credential_store.as_ref())?;
+    if oneshot.is_some() && credentials.is_none() {
+        return Err(SetupError::ConfigInvalid(
+            "play needs credentials to log in with".to_string(),
+        ));
+    }
+
+    if oneshot.is_none() {
+        check_device_name(&mut config)?;
+    }
+    *services.device_name.borrow_mut() = config.device_name.clone();
+
+    // For the `status` command of the control socket.
+    let now_playing = config.control_socket.as_ref().map(|_| {
+        let (sender, events) = futures::sync::mpsc::unbounded();
+        status_event_senders.push(sender);
+        now_playing::track(
+            &handle,
+            services.device_name.clone(),
+            config.session_config.device_id.clone(),
+            events,
+            Box::new(|_| ()),
+        )
+    });
+
     let cache = config.cache;
     let player_config = config.player_config;
     let session_config = config.session_config;
@@ -72,118 +323,1066 @@ pub(crate) fn initial_state(
     let autoplay = config.autoplay;
     let device_id = session_config.device_id.clone();
 
+    // The mixers apply `volume_curve` themselves.
     #[cfg(feature = "alsa_backend")]
-    let volume_ctrl = if matches!(
-        config.volume_controller,
-        config::VolumeController::AlsaLinear
-    ) {
+    let volume_ctrl = if config.volume_curve.is_some()
+        || matches!(
+            config.volume_controller,
+            config::VolumeController::AlsaLinear
+        ) {
         VolumeCtrl::Linear
     } else {
         VolumeCtrl::default()
     };
 
     #[cfg(not(feature = "alsa_backend"))]
-    let volume_ctrl = VolumeCtrl::default();
+    let volume_ctrl = if config.volume_curve.is_some() {
+        VolumeCtrl::Linear
+    } else {
+        VolumeCtrl::default()
+    };
 
     let zeroconf_port = config.zeroconf_port.unwrap_or(0);
 
+    // librespot only has one of the two compiled in.
+    info!(
+        "Using the {} zeroconf backend.",
+        if cfg!(feature = "dns_sd") {
+            "dns-sd"
+        } else {
+            "libmdns"
+        }
+    );
+
     let device_type: DeviceType = DeviceType::from_str(&config.device_type).unwrap_or_default();
 
     #[allow(clippy::or_fun_call)]
-    let discovery_stream = discovery(
-        &handle,
-        ConnectConfig {
+    let discovery_stream = if oneshot.is_none() {
+        let connect_config = ConnectConfig {
             autoplay,
             name: config.device_name.clone(),
             device_type,
             volume: mixer().volume(),
             volume_ctrl: volume_ctrl.clone(),
-        },
-        device_id,
-        zeroconf_port,
-    )
-    .unwrap();
+        };
+        // With dns-sd, librespot panics when the system service isn't running.
+        let discovery =
+            utils::catch_panic(|| discovery(&handle, connect_config, device_id, zeroconf_port))
+                .unwrap_or_else(|message| Err(io::Error::other(message)));
+        Some(discovery.map_err(SetupError::DiscoveryFailed)?)
+    } else {
+        None
+    };
 
-    let username = config.username;
-    #[allow(unused_mut)] // mut is needed behind the dbus_keyring flag.
-    let mut password = config.password;
-    #[cfg(feature = "dbus_keyring")]
-    {
-        // We only need to check if an actual user has been specified as
-        // spotifyd can run without being signed in too.
-        if username.is_some() && config.use_keyring {
-            info!("Checking keyring for password");
-            let keyring = Keyring::new("spotifyd", username.as_ref().unwrap());
-            let retrieved_password = keyring.get_password();
-            password = password.or_else(|| retrieved_password.ok());
+    let fallbacks = if config.on_device_lost == config::DeviceLostAction::Fallback {
+        if config.fallback_backends.is_empty() {
+            return Err(SetupError::ConfigInvalid(
+                "on_device_lost = \"fallback\" needs fallback_backends or a fallback_backend"
+                    .to_string(),
+            ));
         }
+        fallback_backends(&config.fallback_backends)?
+    } else {
+        Vec::new()
+    };
+    let backend = find_backend(
+        backend.as_ref().map(String::as_ref),
+        config.audio_device.clone(),
+        config.tee_device.clone(),
+        config.format_fallback,
+        config.check_audio_device,
+        config.allow_null_fallback,
+        !fallbacks.is_empty(),
+    )?;
+    let keep_open = config.device_policy == config::DevicePolicy::KeepOpen;
+    if keep_open {
+        info!("Keeping the audio device open while playback is paused");
+    }
+    let (mut wrapped_backend, lost_events) =
+        device_lost::wrap(backend.clone(), fallbacks, keep_open);
+    if config.downmix == config::Downmix::Mono {
+        info!("Mixing the audio down to mono");
+        wrapped_backend = downmix::mono(wrapped_backend);
+    }
+    if let Some(db) = config.device_gain {
+        info!("Applying a gain of {}dB", db);
+        wrapped_backend = gain::apply(wrapped_backend, db);
     }
+    let fade_control = config.fade.map(|duration| {
+        info!(
+            "Fading the audio in and out over {}ms",
+            duration.as_millis()
+        );
+        let control = Arc::new(FadeControl::default());
+        wrapped_backend = fade::apply(wrapped_backend.clone(), duration, control.clone());
+        control
+    });
+    if let Some(ref metrics) = metrics {
+        wrapped_backend = metrics::count_underruns(wrapped_backend, metrics.clone());
+    }
+    if let Some(rt_priority) = config.rt_priority {
+        wrapped_backend = priority::realtime(wrapped_backend, rt_priority);
+    }
+    let device_watch = DeviceWatch::new(config.on_device_lost, backend, lost_events);
 
-    let connection = if let Some(credentials) = get_credentials(
-        username,
-        password,
-        cache.as_ref().and_then(Cache::credentials),
-        |_| {
-            error!("No password found.");
-            exit(1);
-        },
-    ) {
-        Session::connect(
+    let autostart_uri = config
+        .autostart_uri
+        .filter(|_| oneshot.is_none())
+        .filter(|_| {
+            if credentials.is_none() {
+                warn!("autostart_uri is set, but there are no stored credentials to log in with");
+            }
+            credentials.is_some()
+        });
+
+    let connection = if let Some(credentials) = credentials.clone() {
+        connect(
             session_config.clone(),
             credentials,
             cache.clone(),
+            credential_store.clone(),
             handle.clone(),
         )
     } else {
         Box::new(futures::future::empty())
-            as Box<dyn futures::Future<Item = Session, Error = io::Error>>
+            as Box<dyn futures::Future<Item = Session, Error = SetupError>>
+    };
+
+    let sleep_signals = if config.reconnect_on_resume {
+        sleep::signals(&handle)
+    } else {
+        None
     };
+    let reload_signals = reload_signals(&handle);
+    let event_hooks = EventHooks::new(
+        config.onevent,
+        config.shell,
+        config.onevent_grace_period,
+        config.onevent_timeout,
+        config.metadata_fetch.timeout,
+        &handle,
+    );
+
+    if config.resume_on_unlock && !config.pause_on_lock {
+        warn!("resume_on_unlock is set, but pause_on_lock isn't");
+    }
+    let lock_pause = if config.pause_on_lock {
+        LockPause::new(&handle, config.resume_on_unlock)
+    } else {
+        None
+    };
+
+    let prefer_active = if config.prefer_active {
+        PreferActive::new(&handle)
+            .map_err(|e| warn!("Could not set up prefer_active: {}", e))
+            .ok()
+    } else {
+        None
+    };
+
+    let idle_exit = config
+        .exit_on_idle
+        .map(|after| IdleExit::new(after, &handle));
 
-    let backend = find_backend(backend.as_ref().map(String::as_ref));
-    main_loop::MainLoopState {
-        librespot_connection: main_loop::LibreSpotConnection::new(connection, discovery_stream),
+    // Without credentials, clients find the device through zeroconf, which
+    // is announced by now.
+    if credentials.is_none() && oneshot.is_none() {
+        services
+            .systemd
+            .borrow_mut()
+            .ready("Waiting for a client to connect");
+    }
+    Ok(main_loop::MainLoopState {
+        librespot_connection: main_loop::LibreSpotConnection::new(
+            connection,
+            discovery_stream,
+            credentials,
+        ),
         audio_setup: main_loop::AudioSetup {
             mixer,
-            backend,
-            audio_device: config.audio_device.clone(),
+            backend: wrapped_backend,
         },
         spotifyd_state: main_loop::SpotifydState {
-            ctrl_c_stream: Box::new(ctrl_c(&handle).flatten_stream()),
+            ctrl_c_stream: exit_signals(&handle),
             shutting_down: false,
             cache,
+            credential_store,
             device_name: config.device_name,
+            status_device_name: services.device_name.clone(),
+            persist_device_name: config.persist_device_name,
+            state_path: config.state_path,
+            playback_resume,
+            track_errors: TrackErrors::new(
+                config.on_track_error,
+                config.track_error_retries,
+                config.context_unplayable_after,
+            ),
+            normalisation: Rc::new(Cell::new(if player_config.normalisation {
+                Normalisation::Unknown
+            } else {
+                Normalisation::Disabled
+            })),
+            normalisation_check: None,
+            audio_features,
+            explicit_filter: if config.filter_explicit {
+                Some(ExplicitFilter::new())
+            } else {
+                None
+            },
+            context_volumes,
+            connect_volume: if config.ignore_connect_volume {
+                Some(ConnectVolume::new())
+            } else {
+                None
+            },
             player_event_channel: None,
-            player_event_program: config.onevent,
+            mpris_event_sender: None,
+            status_event_senders,
             dbus_mpris_server: None,
         },
         player_config,
+        metered_bitrate: config.metered_bitrate,
         session_config,
         handle,
         initial_volume: config.initial_volume,
+        volume_step: config.volume_step,
+        volume: None,
         volume_ctrl,
-        running_event_program: None,
-        shell: config.shell,
+        event_hooks,
+        systemd: services.systemd.clone(),
         device_type,
         autoplay,
         use_mpris: config.use_mpris,
+        mpris_coalesce_window: config.mpris_coalesce_window,
+        metadata_fetch: config.metadata_fetch,
+        market: config.market,
+        locale: config.locale,
+        passive_startup: config.passive_startup,
+        autostart_uri,
+        autostart_timeout: None,
+        oneshot,
+        sleep_signals,
+        reload_signals,
+        config_values: config.values,
+        lock_pause,
+        jack_pause,
+        device_watch,
+        mixer_watch,
+        fade_control,
+        metrics,
+        delayed_autoplay: config.autoplay_after.map(DelayedAutoplay::new),
+        forced_modes: if config.force_shuffle || config.force_repeat {
+            Some(ForcedModes::new(config.force_shuffle, config.force_repeat))
+        } else {
+            None
+        },
+        resume_timeout: None,
+        reconnect_resume: if config.resume_after_reconnect {
+            Some(ReconnectResume::new())
+        } else {
+            None
+        },
+        control_sender: services.control_sender.clone(),
+        control_requests: services.control_requests.clone(),
+        connect_queue: ConnectQueue::new(),
+        prefer_active,
+        play_history: config.play_history_file.map(PlayHistory::new),
+        cache_index: config.cache_path.clone().map(CacheIndex::new),
+        idle_exit,
+        cache_path: config.cache_path,
+        now_playing,
+        started_at: services.started_at,
+        log_timestamps: services.log_timestamps.clone(),
+    })
+}
+
+type StatusEventSender = futures::sync::mpsc::UnboundedSender<(Session, PlayerEvent)>;
+
+/// Makes the HTTPS connections to the Web API trust the certificate
+/// authorities in `path` instead of the system ones.
+///
+/// The Web API client is created inside rspotify, so the bundle can only be
+/// handed to OpenSSL through `SSL_CERT_FILE`. The connections of librespot
+/// itself don't use TLS, and the commands spotifyd runs don't inherit it.
+#[cfg(not(any(target_os = "macos", target_os = "windows")))]
+fn use_ca_cert(path: &Path) -> Result<(), SetupError> {
+    let pem = fs::read(path).map_err(|e| {
+        SetupError::ConfigInvalid(format!("could not read ca_cert {}: {}", path.display(), e))
+    })?;
+    let certificates = match openssl::x509::X509::stack_from_pem(&pem) {
+        Ok(certificates) if !certificates.is_empty() => certificates,
+        _ => {
+            return Err(SetupError::ConfigInvalid(format!(
+                "ca_cert {} doesn't contain PEM certificates",
+                path.display()
+            )))
+        }
+    };
+    info!(
+        "Trusting the {} certificate authorities in {}",
+        certificates.len(),
+        path.display()
+    );
+    crate::process::set_ssl_cert_file(path);
+    Ok(())
+}
+
+#[cfg(any(target_os = "macos", target_os = "windows"))]
+#[allow(clippy::unnecessary_wraps)]
+fn use_ca_cert(_: &Path) -> Result<(), SetupError> {
+    warn!("ca_cert only works on systems that use OpenSSL, ignoring it");
+    Ok(())
+}
+
+/// Makes librespot connect to Spotify from `bind_address`, through a proxy
+/// that binds its connections to it.
+///
+/// The Web API requests and the zeroconf announcements still go out the way
+/// the system routes them.
+fn use_bind_address(config: &mut config::SpotifydConfig) -> Result<(), SetupError> {
+    let address = match config.bind_address {
+        Some(address) => address,
+        None => return Ok(()),
+    };
+    if config.session_config.proxy.is_some() {
+        return Err(SetupError::ConfigInvalid(
+            "bind_address can't be used together with proxy".to_string(),
+        ));
+    }
+    let proxy = source_address::start(address).map_err(|e| {
+        SetupError::ConfigInvalid(format!(
+            "bind_address {} can't be connected from: {}",
+            address, e
+        ))
+    })?;
+    info!("Connecting to Spotify from {}", address);
+    config.session_config.proxy =
+        Some(Url::parse(&format!("http://{}", proxy)).expect("a socket address is a valid host"));
+    Ok(())
+}
+
+#[cfg(feature = "status_server")]
+fn status_server(
+    config: &config::SpotifydConfig,
+    handle: &Handle,
+    device_name: &DeviceName,
+    systemd: &mut Systemd,
+    audio_features: Option<AudioFeatureCache>,
+    started_at: SystemTime,
+) -> Result<Option<StatusEventSender>, SetupError> {
+    let listener = match listener(
+        handle,
+        systemd,
+        "status",
+        "status_addr",
+        &config.status_addr,
+    )? {
+        Some(listener) => listener,
+        None => return Ok(None),
+    };
+    let (sender, events) = futures::sync::mpsc::unbounded();
+    status::serve(
+        listener,
+        handle,
+        device_name.clone(),
+        config.session_config.device_id.clone(),
+        events,
+        audio_features,
+        config.cache_path.clone(),
+        started_at,
+    )
+    .map_err(|e| SetupError::ConfigInvalid(format!("could not serve the status: {}", e)))?;
+    Ok(Some(sender))
+}
+
+/// Returns the socket systemd passed as `name`, or else listens on the
+/// address set with `option`, if there is one.
+#[cfg(feature = "status_server")]
+fn listener(
+    handle: &Handle,
+    systemd: &mut Systemd,
+    name: &str,
+    option: &str,
+    addr: &Option<String>,
+) -> Result<Option<TcpListener>, SetupError> {
+    if let Some(listener) = systemd.take_listener(name) {
+        let activated = listener
+            .set_nonblocking(true)
+            .and_then(|()| listener.local_addr())
+            .and_then(|addr| TcpListener::from_listener(listener, &addr, handle));
+        return activated.map(Some).map_err(|e| {
+            SetupError::ConfigInvalid(format!(
+                "could not use the socket \"{}\" passed by systemd: {}",
+                name, e
+            ))
+        });
+    }
+    let addr = match addr {
+        Some(addr) => addr,
+        None => return Ok(None),
+    };
+    let addr: SocketAddr = addr.parse().map_err(|_| {
+        SetupError::ConfigInvalid(format!("{} {} isn't an address with a port", option, addr))
+    })?;
+    TcpListener::bind(&addr, handle).map(Some).map_err(|e| {
+        SetupError::ConfigInvalid(format!("could not listen on {} {}: {}", option, addr, e))
+    })
+}
+
+#[cfg(not(feature = "status_server"))]
+#[allow(clippy::unnecessary_wraps)]
+fn status_server(
+    config: &config::SpotifydConfig,
+    _: &Handle,
+    _: &DeviceName,
+    _: &mut Systemd,
+    _: Option<AudioFeatureCache>,
+    _: SystemTime,
+) -> Result<Option<StatusEventSender>, SetupError> {
+    if config.status_addr.is_some() {
+        warn!("status_addr needs the status_server feature, ignoring it");
+    }
+    Ok(None)
+}
+
+#[cfg(feature = "metrics")]
+fn metrics(
+    config: &config::SpotifydConfig,
+    handle: &Handle,
+    systemd: &mut Systemd,
+    started_at: SystemTime,
+) -> Result<Option<(Arc<Metrics>, StatusEventSender)>, SetupError> {
+    let listener = match listener(
+        handle,
+        systemd,
+        "metrics",
+        "metrics_address",
+        &config.metrics_address,
+    )? {
+        Some(listener) => listener,
+        None => return Ok(None),
+    };
+    let metrics = Arc::new(Metrics::default());
+    let (sender, events) = futures::sync::mpsc::unbounded();
+    metrics::serve(
+        listener,
+        handle,
+        metrics.clone(),
+        events,
+        config.cache_path.clone(),
+        started_at,
+    )
+    .map_err(|e| SetupError::ConfigInvalid(format!("could not serve the metrics: {}", e)))?;
+    Ok(Some((metrics, sender)))
+}
+
+#[cfg(not(feature = "metrics"))]
+#[allow(clippy::unnecessary_wraps)]
+fn metrics(
+    config: &config::SpotifydConfig,
+    _: &Handle,
+    _: &mut Systemd,
+    _: SystemTime,
+) -> Result<Option<(Arc<Metrics>, StatusEventSender)>, SetupError> {
+    if config.metrics_address.is_some() {
+        warn!("metrics_address needs the metrics feature, ignoring it");
+    }
+    Ok(None)
+}
+
+#[cfg(feature = "scrobble")]
+fn scrobbler(config: &config::SpotifydConfig, handle: &Handle) -> Option<StatusEventSender> {
+    if config.scrobble_services.is_empty() {
+        return None;
+    }
+    if config.cache_path.is_none() {
+        warn!("Without a cache_path, scrobbles that can't be sent are lost when spotifyd exits");
+    }
+    let (sender, events) = futures::sync::mpsc::unbounded();
+    scrobble::start(
+        handle,
+        config.scrobble_services.clone(),
+        config.cache_path.clone(),
+        events,
+    );
+    Some(sender)
+}
+
+#[cfg(not(feature = "scrobble"))]
+fn scrobbler(config: &config::SpotifydConfig, _: &Handle) -> Option<StatusEventSender> {
+    if !config.scrobble_services.is_empty() {
+        warn!(
+            "Scrobbling needs the scrobble feature, ignoring lastfm_api_key and listenbrainz_token"
+        );
+    }
+    None
+}
+
+#[cfg(feature = "http_api")]
+fn http_api(
+    config: &config::SpotifydConfig,
+    handle: &Handle,
+    device_name: &DeviceName,
+    systemd: &mut Systemd,
+    audio_features: Option<AudioFeatureCache>,
+    requests: control::RequestSender,
+) -> Result<Option<StatusEventSender>, SetupError> {
+    let listener = match listener(
+        handle,
+        systemd,
+        "http_api",
+        "http_api_address",
+        &config.http_api_address,
+    )? {
+        Some(listener) => listener,
+        None => return Ok(None),
+    };
+    let (sender, events) = futures::sync::mpsc::unbounded();
+    http_api::serve(
+        listener,
+        handle,
+        device_name.clone(),
+        config.session_config.device_id.clone(),
+        events,
+        audio_features,
+        requests,
+        config.http_api_token.clone(),
+        config.http_api_origins.clone(),
+    )
+    .map_err(|e| SetupError::ConfigInvalid(format!("could not serve the HTTP API: {}", e)))?;
+    Ok(Some(sender))
+}
+
+#[cfg(not(feature = "http_api"))]
+#[allow(clippy::unnecessary_wraps)]
+fn http_api(
+    config: &config::SpotifydConfig,
+    _: &Handle,
+    _: &DeviceName,
+    _: &mut Systemd,
+    _: Option<AudioFeatureCache>,
+    _: control::RequestSender,
+) -> Result<Option<StatusEventSender>, SetupError> {
+    if config.http_api_address.is_some() {
+        warn!("http_api_address needs the http_api feature, ignoring it");
+    }
+    if config.http_api_token.is_some() || !config.http_api_origins.is_empty() {
+        warn!("http_api_token and http_api_origins need the http_api feature, ignoring them");
+    }
+    Ok(None)
+}
+
+/// Shows what is playing in the media controls of the OS, with the
+/// `media_controls` feature.
+fn media_controls(
+    config: &config::SpotifydConfig,
+    handle: &Handle,
+    requests: control::RequestSender,
+) -> Option<StatusEventSender> {
+    if !cfg!(feature = "media_controls") {
+        return None;
+    }
+    let (sender, events) = futures::sync::mpsc::unbounded();
+    match media_controls::start(handle, &config.device_name, events, requests) {
+        Ok(()) => {
+            info!("Showing what is playing in the media controls of the OS");
+            Some(sender)
+        }
+        Err(e) => {
+            warn!("Could not set up the media controls: {}", e);
+            None
+        }
+    }
+}
+
+/// Returns the software mixer, which follows `curve` if there is one.
+fn soft_mixer(curve: Option<config::VolumeCurve>) -> Box<dyn FnMut() -> Box<dyn Mixer>> {
+    match curve {
+        Some(curve) => Box::new(move || Box::new(CurvedMixer::new(curve)) as Box<dyn Mixer>),
+        None => Box::new(|| Box::new(mixer::softmixer::SoftMixer::open(None)) as Box<dyn Mixer>),
+    }
+}
+
+/// Returns the watch for `mixer_watch`, which needs an alsa volume controller.
+fn mixer_watch(
+    config: &config::SpotifydConfig,
+    every: Duration,
+    handle: &Handle,
+) -> Option<MixerWatch> {
+    let read = match mixer_reader(config) {
+        Some(read) => read,
+        None => {
+            warn!("mixer_watch needs the alsa or alsa_linear volume controller, ignoring it");
+            return None;
+        }
+    };
+    if read().is_none() {
+        warn!("Could not read the mixer volume, mixer_watch may not notice changes");
+    }
+    info!("Watching the mixer for volume changes every {:?}", every);
+    MixerWatch::new(read, every, handle)
+        .map_err(|e| warn!("Could not watch the mixer: {}", e))
+        .ok()
+}
+
+#[cfg(feature = "alsa_backend")]
+fn mixer_reader(config: &config::SpotifydConfig) -> Option<ReadVolume> {
+    let linear_scaling = match config.volume_controller {
+        config::VolumeController::Alsa => false,
+        config::VolumeController::AlsaLinear => true,
+        config::VolumeController::SoftVolume => return None,
+    };
+    let mixer = alsa_mixer::AlsaMixer {
+        device: config
+            .control_device
+            .clone()
+            .or_else(|| config.audio_device.clone())
+            .unwrap_or_else(|| "default".to_string()),
+        mixer: config.mixer.clone().unwrap_or_else(|| "Master".to_string()),
+        linear_scaling,
+        curve: config.volume_curve,
+    };
+    // Failures are only logged at debug level, as they'd repeat every interval.
+    Some(Box::new(move || {
+        mixer
+            .read_volume()
+            .map_err(|e| log::debug!("Could not read the mixer volume: {}", e))
+            .ok()
+    }))
+}
+
+#[cfg(not(feature = "alsa_backend"))]
+fn mixer_reader(_: &config::SpotifydConfig) -> Option<ReadVolume> {
+    None
+}
+
+/// A stream that yields each time spotifyd gets `SIGHUP`, which asks it to
+/// reload its config.
+#[cfg(unix)]
+fn reload_signals(handle: &Handle) -> Option<Box<dyn futures::Stream<Item = (), Error = ()>>> {
+    use futures::Stream;
+    use tokio_signal::unix::{Signal, SIGHUP};
+
+    let signals = Signal::new(SIGHUP, handle)
+        .flatten_stream()
+        .map(drop)
+        .map_err(|e| warn!("Stopped listening for SIGHUP: {}", e));
+    Some(Box::new(signals))
+}
+
+#[cfg(not(unix))]
+fn reload_signals(_: &Handle) -> Option<Box<dyn futures::Stream<Item = (), Error = ()>>> {
+    None
+}
+
+/// A stream that yields each time spotifyd is asked to exit. Under systemd
+/// that is `SIGTERM`, so it is handled like Ctrl+C with the `systemd` feature.
+#[cfg(all(unix, feature = "systemd"))]
+fn exit_signals(handle: &Handle) -> IoStream<()> {
+    use futures::Stream;
+    use tokio_signal::unix::{Signal, SIGTERM};
+
+    let terminate = Signal::new(SIGTERM, handle).flatten_stream().map(drop);
+    Box::new(ctrl_c(handle).flatten_stream().select(terminate))
+}
+
+#[cfg(not(all(unix, feature = "systemd")))]
+fn exit_signals(handle: &Handle) -> IoStream<()> {
+    Box::new(ctrl_c(handle).flatten_stream())
+}
+
+/// Makes temporary files, like the ones librespot downloads audio into, go to
+/// `path`. It is created if needed and must be writable.
+fn use_tmp_dir(path: &Path) -> Result<(), SetupError> {
+    let invalid =
+        |e: io::Error| SetupError::ConfigInvalid(format!("tmp_dir {}: {}", path.display(), e));
+    fs::create_dir_all(path).map_err(invalid)?;
+    let test_file = path.join(format!(".spotifyd-{}", std::process::id()));
+    fs::write(&test_file, b"").map_err(invalid)?;
+    let _ = fs::remove_file(&test_file);
+
+    info!("Using {} for temporary files", path.display());
+    // `env::temp_dir` reads TMPDIR on Unix and TMP or TEMP on Windows.
+    for var in &["TMPDIR", "TMP", "TEMP"] {
+        env::set_var(var, path);
+    }
+    Ok(())
+}
+
+/// How long other devices have to answer when looking for duplicate names.
+const DUPLICATE_SEARCH_TIME: Duration = Duration::from_secs(1);
+
+/// The highest number `on_duplicate = "rename"` adds to the name.
+const MAX_DUPLICATE_NUMBER: u32 = 99;
+
+/// Looks for another device announced with the same name. Both would get the
+/// same device id, which makes them take over each other's playback.
+fn check_device_name(config: &mut config::SpotifydConfig) -> Result<(), SetupError> {
+    if config.on_duplicate == config::DuplicateAction::Allow {
+        return Ok(());
+    }
+    let names = match zeroconf::find_devices(DUPLICATE_SEARCH_TIME) {
+        Ok(names) => names,
+        Err(e) => {
+            warn!("Could not look for devices with the same name: {}", e);
+            return Ok(());
+        }
+    };
+    let is_taken = |name: &str| names.iter().any(|other| other.eq_ignore_ascii_case(name));
+    if !is_taken(&config.device_name) {
+        return Ok(());
+    }
+
+    match config.on_duplicate {
+        config::DuplicateAction::Rename => {
+            let name = (2..=MAX_DUPLICATE_NUMBER)
+                .map(|n| format!("{}-{}", config.device_name, n))
+                .find(|name| config::is_valid_device_name(name) && !is_taken(name))
+                .ok_or_else(|| {
+                    SetupError::DiscoveryFailed(io::Error::new(
+                        io::ErrorKind::AlreadyExists,
+                        format!(
+                            "the names \"{}-2\" to \"{}-{}\" are taken as well",
+                            config.device_name, config.device_name, MAX_DUPLICATE_NUMBER
+                        ),
+                    ))
+                })?;
+            warn!(
+                "Another device is already called \"{}\", using \"{}\" instead",
+                config.device_name, name
+            );
+            config.session_config.device_id = config::device_id(&name);
+            config.device_name = name;
+            Ok(())
+        }
+        _ => Err(SetupError::DiscoveryFailed(io::Error::new(
+            io::ErrorKind::AlreadyExists,
+            format!(
+                "another device is already called \"{}\"",
+                config.device_name
+            ),
+        ))),
+    }
+}
+
+/// Logs in with the configured or cached credentials, for `spotifyd auth-test`.
+/// Nothing is written back, so the cached credentials stay as they are.
+pub(crate) fn auth_test(
+    handle: Handle,
+    mut config: config::SpotifydConfig,
+) -> Box<dyn Future<Item = Session, Error = SetupError>> {
+    if let Err(e) = use_bind_address(&mut config) {
+        return Box::new(futures::future::err(e));
+    }
+    let credential_store = match credential_store(&config) {
+        Ok(credential_store) => credential_store,
+        Err(e) => return Box::new(futures::future::err(e)),
+    };
+    match find_credentials(&config, credential_store.as_ref()) {
+        Ok(Some(credentials)) => connect(config.session_config, credentials, None, None, handle),
+        Ok(None) => Box::new(futures::future::err(SetupError::ConfigInvalid(
+            "no credentials were configured and none are cached".to_string(),
+        ))),
+        Err(e) => Box::new(futures::future::err(e)),
+    }
+}
+
+/// Logs in through the browser and connects with the token, so that the
+/// credentials Spotify answers with are cached, for `spotifyd authenticate`.
+pub(crate) fn authenticate(
+    handle: Handle,
+    mut config: config::SpotifydConfig,
+) -> Result<Box<dyn Future<Item = Session, Error = SetupError>>, SetupError> {
+    use_bind_address(&mut config)?;
+    let credential_store = credential_store(&config)?.ok_or_else(|| {
+        SetupError::ConfigInvalid(
+            "authenticate needs a cache_path to keep the credentials in".to_string(),
+        )
+    })?;
+    let credentials = oauth::authenticate()?;
+    Ok(connect(
+        config.session_config,
+        credentials,
+        config.cache,
+        Some(credential_store),
+        handle,
+    ))
+}
+
+/// Connects to Spotify, telling authentication and network failures apart.
+pub(crate) fn connect(
+    session_config: SessionConfig,
+    credentials: Credentials,
+    cache: Option<Cache>,
+    credential_store: Option<CredentialStore>,
+    handle: Handle,
+) -> Box<dyn Future<Item = Session, Error = SetupError>> {
+    Box::new(Connect(
+        Session::connect(session_config, credentials, cache, handle),
+        credential_store,
+    ))
+}
+
+/// Also encrypts the credentials librespot saves once the login succeeded.
+struct Connect(
+    Box<dyn Future<Item = Session, Error = io::Error>>,
+    Option<CredentialStore>,
+);
+
+impl Future for Connect {
+    type Item = Session;
+    type Error = SetupError;
+
+    fn poll(&mut self) -> Poll<Session, SetupError> {
+        // librespot panics instead of returning an error when the login is rejected,
+        // and the login is the only thing that happens until the session is ready.
+        let inner = &mut self.0;
+        match utils::catch_panic(|| inner.poll()) {
+            Ok(Ok(Async::Ready(session))) => {
+                if let Some(ref credential_store) = self.1 {
+                    credential_store.secure();
+                }
+                Ok(Async::Ready(session))
+            }
+            Ok(result) => result.map_err(SetupError::NetworkUnavailable),
+            Err(message) => Err(SetupError::AuthFailed(message)),
+        }
+    }
+}
+
+/// Returns where the credentials are cached, if they are.
+fn credential_store(
+    config: &config::SpotifydConfig,
+) -> Result<Option<CredentialStore>, SetupError> {
+    let passphrase_cmd = config.credentials_passphrase_cmd.as_deref();
+    match config.cache_path {
+        Some(ref cache_path) => {
+            let credential_store =
+                CredentialStore::new(cache_path.clone(), passphrase_cmd, &config.shell)?;
+            #[cfg(feature = "dbus_keyring")]
+            let credential_store = if config.use_keyring {
+                credential_store.in_keyring()
+            } else {
+                credential_store
+            };
+            Ok(Some(credential_store))
+        }
+        None => {
+            if passphrase_cmd.is_some() {
+                warn!("credentials_passphrase_cmd is set, but no cache_path is configured");
+            }
+            Ok(None)
+        }
+    }
+}
+
+/// Returns the credentials to log in with. Credentials given in the config
+/// (or the keyring) take priority over the ones stored in the cache.
+fn find_credentials(
+    config: &config::SpotifydConfig,
+    credential_store: Option<&CredentialStore>,
+) -> Result<Option<Credentials>, SetupError> {
+    let username = config.username.clone();
+    #[allow(unused_mut)] // mut is needed behind the dbus_keyring flag.
+    let mut password = config.password.clone();
+    #[cfg(feature = "dbus_keyring")]
+    {
+        // We only need to check if an actual user has been specified as
+        // spotifyd can run without being signed in too.
+        if username.is_some() && config.use_keyring {
+            info!("Checking keyring for password");
+            let keyring = Keyring::new("spotifyd", username.as_ref().unwrap());
+            let retrieved_password = keyring.get_password();
+            password = password.or_else(|| retrieved_password.ok());
+        }
+    }
+
+    let mut password_missing = false;
+    let credentials = get_credentials(
+        username,
+        password,
+        credential_store
+            .map(CredentialStore::load)
+            .transpose()?
+            .flatten(),
+        |_| {
+            password_missing = true;
+            String::new()
+        },
+    );
+    if password_missing {
+        return Err(SetupError::ConfigInvalid(
+            "a username was given, but no password was found".to_string(),
+        ));
     }
+    Ok(credentials)
 }
 
-fn find_backend(name: Option<&str>) -> fn(Option<String>) -> Box<dyn Sink> {
-    match name {
-        Some(name) => {
-            BACKENDS
-                .iter()
-                .find(|backend| name == backend.0)
-                .unwrap_or_else(|| panic!("Unknown backend: {}.", name))
-                .1
+fn find_backend(
+    name: Option<&str>,
+    audio_device: Option<String>,
+    tee_device: Option<String>,
+    format_fallback: bool,
+    check_audio_device: bool,
+    allow_null_fallback: bool,
+    has_fallbacks: bool,
+) -> Result<main_loop::SinkBuilder, SetupError> {
+    // The fallbacks are chosen by whether the device opens at startup.
+    let check = check_audio_device || allow_null_fallback || has_fallbacks;
+    match open_backend(
+        name,
+        audio_device.clone(),
+        tee_device,
+        format_fallback,
+        check,
+    ) {
+        // The device is tried again whenever playback starts, so it may be
+        // plugged in later.
+        Err(SetupError::AudioDeviceUnavailable(e)) if has_fallbacks && name != Some("tee") => {
+            warn!(
+                "Could not open the audio device, playing on the fallback backends until it is back: {}",
+                e
+            );
+            let (_, backend) = match name {
+                Some(name) => named_backend(name)?,
+                None => default_backend()?,
+            };
+            Ok(Arc::new(move || backend(audio_device.clone())))
+        }
+        Err(SetupError::AudioDeviceUnavailable(e)) if allow_null_fallback => {
+            warn!(
+                "Could not open the audio device, discarding the audio as allow_null_fallback is set: {}",
+                e
+            );
+            Ok(Arc::new(|| Box::new(NullSink::new())))
         }
+        result => result,
+    }
+}
+
+fn open_backend(
+    name: Option<&str>,
+    audio_device: Option<String>,
+    tee_device: Option<String>,
+    format_fallback: bool,
+    check: bool,
+) -> Result<main_loop::SinkBuilder, SetupError> {
+    if name == Some("tee") {
+        let (primary_name, primary) = default_backend()?;
+        let (secondary_name, secondary) = named_backend("pipe")?;
+        if check {
+            check_audio_device(primary, &audio_device)?;
+        }
+        info!(
+            "Copying the audio from the {} backend to {}.",
+            primary_name,
+            tee_device.as_deref().unwrap_or("stdout")
+        );
+        return Ok(Arc::new(move || {
+            Box::new(TeeSink::new(
+                primary_name,
+                primary(audio_device.clone()),
+                secondary_name,
+                secondary(tee_device.clone()),
+            ))
+        }));
+    }
+
+    let (name, backend) = match name {
+        Some(name) => named_backend(name)?,
         None => {
-            let &(name, back) = BACKENDS
-                .first()
-                .expect("No backends were enabled at build time");
+            let (name, backend) = default_backend()?;
             info!("No backend specified, defaulting to: {}.", name);
-            back
+            (name, backend)
         }
+    };
+    if format_fallback {
+        if let Some(negotiating) = negotiating_backend(name, &audio_device, check)? {
+            return Ok(negotiating);
+        }
+        warn!("format_fallback only applies to the alsa backend, ignoring it");
+    }
+    // These open a file or start a program, which shouldn't happen more than needed.
+    if check && name != "pipe" && name != "subprocess" {
+        check_audio_device(backend, &audio_device)?;
+    }
+    Ok(Arc::new(move || backend(audio_device.clone())))
+}
+
+type BackendEntry = (&'static str, fn(Option<String>) -> Box<dyn Sink>);
+
+/// Returns the backends of `fallback_backends`, which aren't opened until
+/// they are needed, as the devices may come and go.
+fn fallback_backends(
+    entries: &[(String, Option<String>)],
+) -> Result<Vec<device_lost::Fallback>, SetupError> {
+    entries
+        .iter()
+        .map(|(name, device)| {
+            let (name, open) = named_backend(name)?;
+            let description = match device {
+                Some(device) => format!("{} backend on {}", name, device),
+                None => format!("{} backend", name),
+            };
+            let device = device.clone();
+            let open: main_loop::SinkBuilder = Arc::new(move || open(device.clone()));
+            Ok((description, open))
+        })
+        .collect()
+}
+
+fn named_backend(name: &str) -> Result<BackendEntry, SetupError> {
+    BACKENDS
+        .iter()
+        .find(|backend| name == backend.0)
+        .copied()
+        .ok_or_else(|| SetupError::ConfigInvalid(format!("unknown backend: {}", name)))
+}
+
+fn default_backend() -> Result<BackendEntry, SetupError> {
+    BACKENDS.first().copied().ok_or_else(|| {
+        SetupError::ConfigInvalid("no backends were enabled at build time".to_string())
+    })
+}
+
+/// Returns the sink for `format_fallback`, if `name` is the alsa backend.
+#[cfg(feature = "alsa_backend")]
+fn negotiating_backend(
+    name: &str,
+    audio_device: &Option<String>,
+    check: bool,
+) -> Result<Option<main_loop::SinkBuilder>, SetupError> {
+    if name != "alsa" {
+        return Ok(None);
+    }
+    if check {
+        check_sink(Box::new(NegotiatingSink::new(audio_device.clone())))?;
     }
+    let audio_device = audio_device.clone();
+    Ok(Some(Arc::new(move || {
+        Box::new(NegotiatingSink::new(audio_device.clone())) as Box<dyn Sink>
+    })))
+}
+
+#[cfg(not(feature = "alsa_backend"))]
+fn negotiating_backend(
+    _: &str,
+    _: &Option<String>,
+    _: bool,
+) -> Result<Option<main_loop::SinkBuilder>, SetupError> {
+    Ok(None)
+}
+
+/// Opens the audio device once, so that a missing device is reported at
+/// startup instead of when the first track plays.
+fn check_audio_device(
+    backend: fn(Option<String>) -> Box<dyn Sink>,
+    device: &Option<String>,
+) -> Result<(), SetupError> {
+    check_sink(backend(device.clone()))
+}
+
+fn check_sink(mut sink: Box<dyn Sink>) -> Result<(), SetupError> {
+    sink.start().map_err(SetupError::AudioDeviceUnavailable)?;
+    sink.stop().map_err(SetupError::AudioDeviceUnavailable)
 }
@@ -0,0 +1,57 @@
+#[cfg(feature = "dbus_mpris")]
+use dbus::{BusType, Connection, MessageType};
+#[cfg(feature = "dbus_mpris")]
+use dbus_tokio::AConnection;
+use futures::Stream;
+#[cfg(feature = "dbus_mpris")]
+use log::info;
+#[cfg(not(feature = "dbus_mpris"))]
+use log::warn;
+#[cfg(feature = "dbus_mpris")]
+use std::rc::Rc;
+use tokio_core::reactor::Handle;
+
+#[cfg(feature = "dbus_mpris")]
+const LOGIN_MANAGER: &str = "org.freedesktop.login1.Manager";
+
+/// Tells when the system is about to sleep (`true`) and when it has woken up
+/// again (`false`), as announced by logind. Returns `None` if logind can't be
+/// reached.
+#[cfg(feature = "dbus_mpris")]
+pub(crate) fn signals(handle: &Handle) -> Option<Box<dyn Stream<Item = bool, Error = ()>>> {
+    let connection = Connection::get_private(BusType::System).and_then(|connection| {
+        connection.add_match(&format!(
+            "type='signal',interface='{}',member='PrepareForSleep'",
+            LOGIN_MANAGER
+        ))?;
+        Ok(connection)
+    });
+    let messages = match connection {
+        Ok(connection) => AConnection::new(Rc::new(connection), handle.clone())
+            .map_err(|e| e.to_string())
+            .and_then(|connection| connection.messages().map_err(str::to_string)),
+        Err(e) => Err(e.to_string()),
+    };
+    match messages {
+        Ok(messages) => Some(Box::new(messages.filter_map(|message| {
+            let is_sleep_signal = message.msg_type() == MessageType::Signal
+                && message.interface().as_deref() == Some(LOGIN_MANAGER)
+                && message.member().as_deref() == Some("PrepareForSleep");
+            if is_sleep_signal {
+                message.get1::<bool>()
+            } else {
+                None
+            }
+        }))),
+        Err(e) => {
+            info!("Could not listen for the system going to sleep: {}", e);
+            None
+        }
+    }
+}
+
+#[cfg(not(feature = "dbus_mpris"))]
+pub(crate) fn signals(_: &Handle) -> Option<Box<dyn Stream<Item = bool, Error = ()>>> {
+    warn!("reconnect_on_resume needs the dbus_mpris feature, ignoring it");
+    None
+}
@@ -0,0 +1,209 @@
+use log::{debug, warn};
+use net2::TcpBuilder;
+use std::{
+    io::{self, Read, Write},
+    net::{IpAddr, Ipv4Addr, Shutdown, SocketAddr, TcpListener, TcpStream, ToSocketAddrs},
+    thread,
+};
+use url::Url;
+
+/// How long the head of a request may be.
+const MAX_HEAD: usize = 16 * 1024;
+
+/// The host librespot asks for the access points, over plain HTTP.
+const RESOLVER_HOST: &str = "apresolve.spotify.com";
+
+/// The ports the access points listen on, which librespot connects to with
+/// CONNECT.
+const ACCESS_POINT_PORTS: [u16; 3] = [443, 4070, 80];
+
+/// Fails unless `address` belongs to this device, so connections can be made
+/// from it.
+pub(crate) fn check(address: IpAddr) -> io::Result<()> {
+    TcpListener::bind((address, 0)).map(drop)
+}
+
+/// Starts a proxy on the loopback interface that makes its connections from
+/// `address`, for `bind_address`, and returns where it listens.
+///
+/// librespot opens its connections itself, with no way to pick the address
+/// they are made from. It can be given an HTTP proxy though, which it uses
+/// both to find an access point and to connect to it, so that proxy is this.
+pub(crate) fn start(address: IpAddr) -> io::Result<SocketAddr> {
+    check(address)?;
+    let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0))?;
+    let local = listener.local_addr()?;
+    thread::spawn(move || {
+        for client in listener.incoming() {
+            match client {
+                Ok(client) => {
+                    thread::spawn(move || {
+                        if let Err(e) = forward(client, address) {
+                            debug!("Connection through bind_address {} failed: {}", address, e);
+                        }
+                    });
+                }
+                Err(e) => warn!("Could not accept a connection for bind_address: {}", e),
+            }
+        }
+    });
+    Ok(local)
+}
+
+/// Handles a single request, either a CONNECT or a plain one with the whole
+/// URL, which is what librespot sends. Requests for anything else than the
+/// resolver and the access points are refused, so other programs can't use the
+/// proxy to get out through `bind_address`.
+fn forward(mut client: TcpStream, source: IpAddr) -> io::Result<()> {
+    let (head, rest) = read_head(&mut client)?;
+    let mut lines = head.split("\r\n");
+    let mut request = lines.next().unwrap_or_default().split(' ');
+    let (method, target, version) = match (request.next(), request.next(), request.next()) {
+        (Some(method), Some(target), Some(version)) => (method, target, version),
+        _ => return Err(invalid(format!("invalid request {:?}", head))),
+    };
+
+    let mut server = if method == "CONNECT" {
+        let allowed = match target.rsplit_once(':').map(|(_, port)| port.parse()) {
+            Some(Ok(port)) => ACCESS_POINT_PORTS.contains(&port),
+            _ => false,
+        };
+        if !allowed {
+            client.write_all(b"HTTP/1.1 403 Forbidden\r\n\r\n")?;
+            return Err(invalid(format!("refused to connect to {}", target)));
+        }
+        let server = connect_from(source, target);
+        let reply: &[u8] = if server.is_ok() {
+            b"HTTP/1.1 200 Connection established\r\n\r\n"
+        } else {
+            b"HTTP/1.1 502 Bad Gateway\r\n\r\n"
+        };
+        client.write_all(reply)?;
+        server?
+    } else {
+        let url = Url::parse(target).map_err(|e| invalid(format!("{}: {}", target, e)))?;
+        let host = url
+            .host_str()
+            .ok_or_else(|| invalid(format!("{} has no host", target)))?;
+        let port = url.port_or_known_default().unwrap_or(80);
+        if url.scheme() != "http" || host != RESOLVER_HOST || port != 80 {
+            client.write_all(b"HTTP/1.1 403 Forbidden\r\n\r\n")?;
+            return Err(invalid(format!(
+                "refused to forward a request to {}",
+                target
+            )));
+        }
+        let mut server = connect_from(source, &format!("{}:{}", host, port))?;
+        let mut path = url.path().to_string();
+        if let Some(query) = url.query() {
+            path.push('?');
+            path.push_str(query);
+        }
+        let mut head = format!("{} {} {}\r\n", method, path, version);
+        for line in lines.filter(|line| !line.to_ascii_lowercase().starts_with("proxy-")) {
+            head.push_str(line);
+            head.push_str("\r\n");
+        }
+        server.write_all(head.as_bytes())?;
+        server
+    };
+    server.write_all(&rest)?;
+    splice(client, server)
+}
+
+/// Reads up to the empty line that ends the head of a request, returning the
+/// head without it and whatever was read after it.
+fn read_head(client: &mut TcpStream) -> io::Result<(String, Vec<u8>)> {
+    let mut read = Vec::new();
+    let mut buffer = [0; 4096];
+    loop {
+        if let Some(end) = read.windows(4).position(|window| window == b"\r\n\r\n") {
+            let rest = read.split_off(end + 4);
+            read.truncate(end);
+            return Ok((String::from_utf8_lossy(&read).into_owned(), rest));
+        }
+        if read.len() > MAX_HEAD {
+            return Err(invalid("the request is too long".to_string()));
+        }
+        match client.read(&mut buffer)? {
+            0 => return Err(io::ErrorKind::UnexpectedEof.into()),
+            n => read.extend_from_slice(&buffer[..n]),
+        }
+    }
+}
+
+/// Connects to `target`, a host and a port, from `source`.
+fn connect_from(source: IpAddr, target: &str) -> io::Result<TcpStream> {
+    let mut failed = None;
+    // Only addresses of the same family can be reached from `source`.
+    for address in target
+        .to_socket_addrs()?
+        .filter(|address| address.is_ipv4() == source.is_ipv4())
+    {
+        let builder = if source.is_ipv4() {
+            TcpBuilder::new_v4()?
+        } else {
+            TcpBuilder::new_v6()?
+        };
+        builder.bind((source, 0))?;
+        match builder.connect(address) {
+            Ok(stream) => return Ok(stream),
+            Err(e) => failed = Some(e),
+        }
+    }
+    Err(failed.unwrap_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::AddrNotAvailable,
+            format!(
+                "{} has no {} address",
+                target,
+                if source.is_ipv4() { "IPv4" } else { "IPv6" }
+            ),
+        )
+    }))
+}
+
+/// Copies data both ways until either side closes its connection.
+fn splice(client: TcpStream, server: TcpStream) -> io::Result<()> {
+    let (mut client_reader, mut server_writer) = (client.try_clone()?, server.try_clone()?);
+    let upload = thread::spawn(move || {
+        let _ = io::copy(&mut client_reader, &mut server_writer);
+        let _ = server_writer.shutdown(Shutdown::Write);
+    });
+    let (mut server_reader, mut client_writer) = (server, client);
+    let result = io::copy(&mut server_reader, &mut client_writer);
+    let _ = client_writer.shutdown(Shutdown::Both);
+    let _ = upload.join();
+    result.map(drop)
+}
+
+fn invalid(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request(proxy: SocketAddr, request: &str) -> String {
+        let mut stream = TcpStream::connect(proxy).unwrap();
+        stream.write_all(request.as_bytes()).unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        response
+    }
+
+    #[test]
+    fn test_refuses_other_targets() {
+        let proxy = start(Ipv4Addr::LOCALHOST.into()).unwrap();
+        for refused in &[
+            "CONNECT example.com:22 HTTP/1.1\r\n\r\n",
+            "CONNECT example.com HTTP/1.1\r\n\r\n",
+            "GET http://example.com/ HTTP/1.1\r\n\r\n",
+            "GET http://apresolve.spotify.com:8080/ HTTP/1.1\r\n\r\n",
+            "GET https://apresolve.spotify.com/ HTTP/1.1\r\n\r\n",
+        ] {
+            assert!(request(proxy, refused).starts_with("HTTP/1.1 403"));
+        }
+    }
+}
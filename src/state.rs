@@ -0,0 +1,63 @@
+use log::warn;
+use serde::{Deserialize, Serialize};
+use std::{
+    fs, io,
+    path::{Path, PathBuf},
+};
+
+const STATE_FILE_NAME: &str = "state.toml";
+
+/// Values that spotifyd remembers between runs. They are stored next to the
+/// credentials in the cache directory.
+#[derive(Debug, Default, Deserialize, Serialize)]
+pub(crate) struct State {
+    /// The name the device was renamed to from a Spotify client.
+    pub(crate) device_name: Option<String>,
+    /// The id the device is announced with, as 40 hex digits.
+    pub(crate) device_id: Option<String>,
+    /// A random UUID standing for this installation, for `{uuid}`.
+    pub(crate) install_id: Option<String>,
+    /// The track that was playing when playback last stopped, as a base62 id.
+    pub(crate) track_id: Option<String>,
+    /// How far into `track_id` playback had got, in milliseconds.
+    pub(crate) position_ms: Option<u32>,
+    /// The volumes remembered with `context_volume`, least recently used first.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub(crate) context_volumes: Vec<RememberedVolume>,
+}
+
+/// The volume a context was last played at.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub(crate) struct RememberedVolume {
+    pub(crate) context: String,
+    pub(crate) volume: u16,
+}
+
+impl State {
+    /// Returns the path of the state file inside the cache directory.
+    pub(crate) fn path(cache_path: &Path) -> PathBuf {
+        cache_path.join(STATE_FILE_NAME)
+    }
+
+    /// Loads the state file, falling back to an empty state if it doesn't
+    /// exist or can't be read.
+    pub(crate) fn load(path: &Path) -> State {
+        match fs::read_to_string(path) {
+            Ok(content) => toml::from_str(&content).unwrap_or_else(|e| {
+                warn!("Ignoring invalid state file {}: {}", path.display(), e);
+                State::default()
+            }),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => State::default(),
+            Err(e) => {
+                warn!("Could not read state file {}: {}", path.display(), e);
+                State::default()
+            }
+        }
+    }
+
+    pub(crate) fn save(&self, path: &Path) -> io::Result<()> {
+        let content =
+            toml::to_string(self).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        fs::write(path, content)
+    }
+}
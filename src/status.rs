@@ -0,0 +1,112 @@
+use crate::{
+    audio_features::AudioFeatureCache,
+    cache::{self, CacheStats},
+    http_server,
+    now_playing::{self, DeviceName, NowPlaying},
+};
+use futures::sync::mpsc::UnboundedReceiver;
+use librespot::{core::session::Session, playback::player::PlayerEvent};
+use log::info;
+use std::{io, path::PathBuf, time::SystemTime};
+use tokio_core::{net::TcpListener, reactor::Handle};
+
+/// Where the cache statistics served on `/cache` come from.
+struct CacheSource {
+    path: PathBuf,
+    started_at: SystemTime,
+}
+
+/// Answers a single HTTP request with its status and body. Only `GET /`,
+/// `GET /status`, `GET /cache` and `GET /cache/tracks` are served.
+fn respond(
+    request: &str,
+    now_playing: &NowPlaying,
+    audio_features: Option<&AudioFeatureCache>,
+    cache: Option<&CacheSource>,
+) -> (&'static str, String) {
+    let mut words = request.split_whitespace();
+    match (words.next(), words.next()) {
+        (Some("GET"), Some("/")) | (Some("GET"), Some("/status")) => (
+            "200 OK",
+            serde_json::to_string(&now_playing.snapshot(audio_features)).unwrap_or_default(),
+        ),
+        (Some("GET"), Some("/cache")) => match cache {
+            Some(cache) => match CacheStats::collect(&cache.path, cache.started_at) {
+                Ok(stats) => ("200 OK", serde_json::to_string(&stats).unwrap_or_default()),
+                Err(e) => (
+                    "500 Internal Server Error",
+                    serde_json::json!({ "error": format!("could not read the cache: {}", e) })
+                        .to_string(),
+                ),
+            },
+            None => (
+                "404 Not Found",
+                r#"{"error":"no cache_path is configured"}"#.to_string(),
+            ),
+        },
+        (Some("GET"), Some("/cache/tracks")) => match cache {
+            Some(cache) => match cache::cached_tracks(&cache.path) {
+                Ok(uris) => ("200 OK", serde_json::json!({ "tracks": uris }).to_string()),
+                Err(e) => (
+                    "500 Internal Server Error",
+                    serde_json::json!({
+                        "error": format!("could not read the list of cached tracks: {}", e)
+                    })
+                    .to_string(),
+                ),
+            },
+            None => (
+                "404 Not Found",
+                r#"{"error":"no cache_path is configured"}"#.to_string(),
+            ),
+        },
+        (Some("GET"), _) => ("404 Not Found", r#"{"error":"not found"}"#.to_string()),
+        _ => (
+            "405 Method Not Allowed",
+            r#"{"error":"only GET is supported"}"#.to_string(),
+        ),
+    }
+}
+
+/// Serves what is playing as JSON on `listener`, and what is in the cache at
+/// `cache_path`. The endpoints are read-only; the state is kept up to date from
+/// the player events sent through `events`.
+#[allow(clippy::too_many_arguments)]
+pub(crate) fn serve(
+    listener: TcpListener,
+    handle: &Handle,
+    device_name: DeviceName,
+    device_id: String,
+    events: UnboundedReceiver<(Session, PlayerEvent)>,
+    audio_features: Option<AudioFeatureCache>,
+    cache_path: Option<PathBuf>,
+    started_at: SystemTime,
+) -> io::Result<()> {
+    let addr = listener.local_addr()?;
+    info!("Serving the playback status on http://{}/status", addr);
+
+    let now_playing = now_playing::track(handle, device_name, device_id, events, Box::new(|_| ()));
+    let cache = cache_path.map(|path| CacheSource { path, started_at });
+
+    http_server::serve(
+        listener,
+        handle,
+        "the status server",
+        move |socket, request| {
+            let (status, body) = respond(
+                &request,
+                &now_playing.borrow(),
+                audio_features.as_ref(),
+                cache.as_ref(),
+            );
+            http_server::reply(
+                socket,
+                status,
+                "application/json",
+                "Access-Control-Allow-Origin: *\r\nAllow: GET\r\n",
+                body,
+            )
+        },
+    );
+    Ok(())
+}
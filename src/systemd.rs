@@ -0,0 +1,199 @@
+use futures::{Async, Stream};
+#[cfg(all(unix, feature = "systemd"))]
+use log::debug;
+use log::warn;
+use std::{collections::HashMap, net::TcpListener};
+use tokio_core::reactor::{Handle, Interval};
+
+/// Talks to systemd when spotifyd runs as a `Type=notify` service, with the
+/// `systemd` feature: it says when the device is ready and when spotifyd
+/// stops, pings the watchdog, and hands out the sockets passed by socket
+/// activation.
+///
+/// What systemd says in the environment is taken out of it, so the commands
+/// spotifyd runs don't act on it. Without the feature, this does nothing.
+pub(crate) struct Systemd {
+    #[cfg(all(unix, feature = "systemd"))]
+    notify_socket: Option<String>,
+    watchdog: Option<Interval>,
+    /// The sockets passed by socket activation, by their `FileDescriptorName`.
+    listeners: HashMap<String, TcpListener>,
+    ready: bool,
+    stopping: bool,
+}
+
+impl Systemd {
+    #[cfg(all(unix, feature = "systemd"))]
+    pub(crate) fn from_env(handle: &Handle) -> Systemd {
+        use std::{env, time::Duration};
+
+        let notify_socket = env::var("NOTIFY_SOCKET").ok();
+        // The watchdog is pinged twice as often as systemd wants, so a ping
+        // that is a little late doesn't count as a hang.
+        let watchdog = env::var("WATCHDOG_USEC")
+            .ok()
+            .filter(|_| is_for_this_process("WATCHDOG_PID"))
+            .and_then(|usec| usec.parse::<u64>().ok())
+            .filter(|&usec| usec > 0)
+            .and_then(|usec| Interval::new(Duration::from_micros(usec / 2), handle).ok());
+        let listeners = activated_listeners();
+        for name in &[
+            "NOTIFY_SOCKET",
+            "WATCHDOG_USEC",
+            "WATCHDOG_PID",
+            "LISTEN_PID",
+            "LISTEN_FDS",
+            "LISTEN_FDNAMES",
+        ] {
+            env::remove_var(name);
+        }
+        if watchdog.is_some() {
+            debug!("Pinging the systemd watchdog");
+        }
+        Systemd {
+            notify_socket,
+            watchdog,
+            listeners,
+            ready: false,
+            stopping: false,
+        }
+    }
+
+    #[cfg(not(all(unix, feature = "systemd")))]
+    pub(crate) fn from_env(_: &Handle) -> Systemd {
+        Systemd {
+            watchdog: None,
+            listeners: HashMap::new(),
+            ready: false,
+            stopping: false,
+        }
+    }
+
+    /// Returns the socket passed with `FileDescriptorName=name`, if there is
+    /// one.
+    #[cfg(feature = "status_server")]
+    pub(crate) fn take_listener(&mut self, name: &str) -> Option<TcpListener> {
+        self.listeners.remove(name)
+    }
+
+    /// Closes the passed sockets nothing took, once everything that listens
+    /// was set up.
+    pub(crate) fn close_unused_listeners(&mut self) {
+        for (name, listener) in self.listeners.drain() {
+            warn!(
+                "Closing the socket {} passed by systemd, as there is nothing named \"{}\" \
+                 to serve on it. Name the socket \"status\", \"http_api\" or \
+                 \"metrics\" with FileDescriptorName=",
+                listener
+                    .local_addr()
+                    .map_or_else(|_| "?".to_string(), |addr| addr.to_string()),
+                name
+            );
+        }
+    }
+
+    /// Tells systemd that the device can be found by clients, which it only
+    /// needs to hear once. After that, only `status` is updated.
+    pub(crate) fn ready(&mut self, status: &str) {
+        if self.ready {
+            self.status(status);
+        } else {
+            self.ready = true;
+            self.notify(&format!("READY=1\nSTATUS={}", status));
+        }
+    }
+
+    pub(crate) fn status(&self, status: &str) {
+        self.notify(&format!("STATUS={}", status));
+    }
+
+    pub(crate) fn stopping(&mut self) {
+        if !self.stopping {
+            self.stopping = true;
+            self.notify("STOPPING=1");
+        }
+    }
+
+    /// Pings the watchdog when it is time to.
+    pub(crate) fn poll(&mut self) {
+        let mut ping = false;
+        while let Some(Ok(Async::Ready(Some(())))) = self.watchdog.as_mut().map(Stream::poll) {
+            ping = true;
+        }
+        if ping {
+            self.notify("WATCHDOG=1");
+        }
+    }
+
+    #[cfg(all(unix, feature = "systemd"))]
+    fn notify(&self, state: &str) {
+        use std::os::unix::net::UnixDatagram;
+
+        let path = match self.notify_socket {
+            Some(ref path) => path,
+            None => return,
+        };
+        let sent = UnixDatagram::unbound().and_then(|socket| {
+            // Sockets in the abstract namespace start with an @.
+            #[cfg(target_os = "linux")]
+            if let Some(name) = path.strip_prefix('@') {
+                use std::os::{linux::net::SocketAddrExt, unix::net::SocketAddr};
+                let addr = SocketAddr::from_abstract_name(name)?;
+                return socket.send_to_addr(state.as_bytes(), &addr);
+            }
+            socket.send_to(state.as_bytes(), path)
+        });
+        if let Err(e) = sent {
+            warn!("Could not notify systemd of {:?}: {}", state, e);
+        }
+    }
+
+    #[cfg(not(all(unix, feature = "systemd")))]
+    fn notify(&self, _: &str) {}
+}
+
+/// Whether the variable `name`, if set, names this process.
+#[cfg(all(unix, feature = "systemd"))]
+fn is_for_this_process(name: &str) -> bool {
+    match std::env::var(name) {
+        Ok(pid) => pid.parse() == Ok(std::process::id()),
+        Err(_) => true,
+    }
+}
+
+/// Takes the TCP sockets passed by socket activation, which start at file
+/// descriptor 3.
+#[cfg(all(unix, feature = "systemd"))]
+fn activated_listeners() -> HashMap<String, TcpListener> {
+    use std::{env, os::unix::io::FromRawFd};
+
+    const FIRST_FD: i32 = 3;
+
+    let mut listeners = HashMap::new();
+    if env::var("LISTEN_PID").is_err() || !is_for_this_process("LISTEN_PID") {
+        return listeners;
+    }
+    let count = env::var("LISTEN_FDS")
+        .ok()
+        .and_then(|count| count.parse::<i32>().ok())
+        .unwrap_or(0);
+    let names = env::var("LISTEN_FDNAMES").unwrap_or_default();
+    let mut names = names.split(':');
+    for fd in FIRST_FD..FIRST_FD + count {
+        let name = names.next().unwrap_or("unknown").to_string();
+        // Commands spotifyd runs shouldn't get the sockets.
+        unsafe { libc::fcntl(fd, libc::F_SETFD, libc::FD_CLOEXEC) };
+        let listener = unsafe { TcpListener::from_raw_fd(fd) };
+        match listener.local_addr() {
+            Ok(addr) => {
+                debug!("systemd passed the socket \"{}\" on {}", name, addr);
+                listeners.insert(name, listener);
+            }
+            Err(e) => warn!(
+                "Ignoring the socket \"{}\" passed by systemd, only TCP sockets are supported: {}",
+                name, e
+            ),
+        }
+    }
+    listeners
+}
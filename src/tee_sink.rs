@@ -0,0 +1,96 @@
+use librespot::playback::audio_backend::Sink;
+use log::error;
+use std::io;
+
+struct Output {
+    name: &'static str,
+    sink: Box<dyn Sink>,
+    failed: bool,
+}
+
+/// A sink that writes the same audio to two other sinks.
+///
+/// When one of the outputs fails, it is skipped until playback is started
+/// again, so a broken pipe won't interrupt the local speakers (or the other
+/// way around). Only when both outputs fail is the error passed on.
+pub struct TeeSink {
+    outputs: [Output; 2],
+}
+
+impl TeeSink {
+    pub fn new(
+        primary_name: &'static str,
+        primary: Box<dyn Sink>,
+        secondary_name: &'static str,
+        secondary: Box<dyn Sink>,
+    ) -> TeeSink {
+        TeeSink {
+            outputs: [
+                Output {
+                    name: primary_name,
+                    sink: primary,
+                    failed: false,
+                },
+                Output {
+                    name: secondary_name,
+                    sink: secondary,
+                    failed: false,
+                },
+            ],
+        }
+    }
+
+    fn all_failed(&self) -> bool {
+        self.outputs.iter().all(|output| output.failed)
+    }
+}
+
+impl Sink for TeeSink {
+    fn start(&mut self) -> io::Result<()> {
+        let mut result = Ok(());
+        for output in &mut self.outputs {
+            output.failed = false;
+            if let Err(e) = output.sink.start() {
+                error!("Could not start the {} output: {}", output.name, e);
+                output.failed = true;
+                result = Err(e);
+            }
+        }
+
+        if self.all_failed() {
+            result
+        } else {
+            Ok(())
+        }
+    }
+
+    fn stop(&mut self) -> io::Result<()> {
+        // The player panics when stopping fails, so errors are only logged.
+        for output in self.outputs.iter_mut().filter(|output| !output.failed) {
+            if let Err(e) = output.sink.stop() {
+                error!("Could not stop the {} output: {}", output.name, e);
+            }
+        }
+        Ok(())
+    }
+
+    fn write(&mut self, data: &[i16]) -> io::Result<()> {
+        let mut result = Ok(());
+        for output in self.outputs.iter_mut().filter(|output| !output.failed) {
+            if let Err(e) = output.sink.write(data) {
+                error!(
+                    "Could not write to the {} output, skipping it until playback restarts: {}",
+                    output.name, e
+                );
+                output.failed = true;
+                result = Err(e);
+            }
+        }
+
+        if self.all_failed() {
+            result
+        } else {
+            Ok(())
+        }
+    }
+}
@@ -0,0 +1,146 @@
+use crate::config::TrackErrorAction;
+use librespot::{
+    connect::spirc::Spirc, core::spotify_id::SpotifyId, playback::player::PlayerEvent,
+};
+use log::warn;
+
+/// Playback was stopped because too many tracks in a row couldn't be played.
+pub(crate) struct ContextUnplayable {
+    pub(crate) skipped: u32,
+    pub(crate) first_track_id: SpotifyId,
+}
+
+/// Reacts to tracks that fail to load.
+///
+/// librespot doesn't report these as errors: the player ends the track while
+/// it is still loading, and Spirc moves on to the next one. A track that ends
+/// without having played is therefore taken as a load failure.
+pub(crate) struct TrackErrors {
+    action: TrackErrorAction,
+    max_retries: u8,
+    /// Playback is stopped once this many tracks in a row were skipped.
+    max_skipped: Option<u32>,
+    loading: Option<SpotifyId>,
+    /// The track being retried and how often it has been retried.
+    retries: Option<(SpotifyId, u8)>,
+    /// How many tracks were skipped since one last played, and the first of them.
+    skipped: Option<(u32, SpotifyId)>,
+}
+
+impl TrackErrors {
+    pub(crate) fn new(
+        action: TrackErrorAction,
+        max_retries: u8,
+        max_skipped: Option<u32>,
+    ) -> TrackErrors {
+        TrackErrors {
+            action,
+            max_retries,
+            max_skipped,
+            loading: None,
+            retries: None,
+            skipped: None,
+        }
+    }
+
+    /// Returns what happened if playback was stopped because the context
+    /// can't be played.
+    pub(crate) fn handle_event(
+        &mut self,
+        event: &PlayerEvent,
+        spirc: &Spirc,
+    ) -> Option<ContextUnplayable> {
+        match *event {
+            PlayerEvent::Loading { track_id, .. } => self.loading = Some(track_id),
+            PlayerEvent::Playing { .. } => {
+                self.loading = None;
+                self.retries = None;
+                self.skipped = None;
+            }
+            // Playback was moved to another device.
+            PlayerEvent::Stopped { .. } => self.skipped = None,
+            PlayerEvent::Unavailable { track_id, .. } => {
+                warn!(
+                    "Track {} is unavailable and will be skipped",
+                    track_id.to_base62()
+                );
+                return self.track_skipped(track_id, spirc);
+            }
+            PlayerEvent::EndOfTrack { track_id, .. } if self.loading == Some(track_id) => {
+                self.loading = None;
+                if self.load_failed(track_id, spirc) {
+                    return self.track_skipped(track_id, spirc);
+                }
+            }
+            _ => (),
+        }
+        None
+    }
+
+    fn track_skipped(&mut self, track_id: SpotifyId, spirc: &Spirc) -> Option<ContextUnplayable> {
+        let (skipped, first_track_id) = match self.skipped {
+            Some((skipped, first_track_id)) => (skipped + 1, first_track_id),
+            None => (1, track_id),
+        };
+        match self.max_skipped {
+            Some(max_skipped) if skipped >= max_skipped => {
+                warn!(
+                    "Skipped {} tracks in a row that couldn't be played, starting with {}, \
+                     stopping playback",
+                    skipped,
+                    first_track_id.to_base62()
+                );
+                // Pressing play again goes on with the next tracks.
+                self.skipped = None;
+                spirc.pause();
+                Some(ContextUnplayable {
+                    skipped,
+                    first_track_id,
+                })
+            }
+            _ => {
+                self.skipped = Some((skipped, first_track_id));
+                None
+            }
+        }
+    }
+
+    /// Returns whether the track was skipped.
+    fn load_failed(&mut self, track_id: SpotifyId, spirc: &Spirc) -> bool {
+        let id = track_id.to_base62();
+        match self.action {
+            TrackErrorAction::Skip => {
+                warn!("Could not load track {}, skipping it", id);
+                true
+            }
+            TrackErrorAction::Stop => {
+                warn!("Could not load track {}, stopping playback", id);
+                spirc.pause();
+                false
+            }
+            TrackErrorAction::Retry => {
+                let attempt = match self.retries {
+                    Some((retried, attempt)) if retried == track_id => attempt + 1,
+                    _ => 1,
+                };
+                if attempt > self.max_retries {
+                    warn!(
+                        "Could not load track {}, skipping it after {} retries",
+                        id, self.max_retries
+                    );
+                    self.retries = None;
+                    return true;
+                }
+                warn!(
+                    "Could not load track {}, retrying ({}/{})",
+                    id, attempt, self.max_retries
+                );
+                self.retries = Some((track_id, attempt));
+                // Spirc has already moved on; going back from the start of the
+                // next track loads the failed one again.
+                spirc.prev();
+                false
+            }
+        }
+    }
+}
@@ -1,5 +1,10 @@
 use log::trace;
-use std::env;
+use std::{
+    any::Any,
+    cell::Cell,
+    env,
+    panic::{self, AssertUnwindSafe},
+};
 #[cfg(target_os = "macos")]
 use whoami;
 
@@ -81,6 +86,40 @@ pub(crate) fn get_shell() -> Option<String> {
     Some("cmd".to_string())
 }
 
+/// Returns the message a panic was started with, if it has one.
+pub(crate) fn panic_message(payload: &(dyn Any + Send)) -> Option<&str> {
+    match (
+        payload.downcast_ref::<String>(),
+        payload.downcast_ref::<&str>(),
+    ) {
+        (Some(s), _) => Some(&**s),
+        (_, Some(&s)) => Some(s),
+        _ => None,
+    }
+}
+
+thread_local! {
+    static CATCHING_PANICS: Cell<bool> = const { Cell::new(false) };
+}
+
+/// Runs `f`, turning a panic into an error with its message. The panic hook
+/// doesn't report panics caught like this, see [`catching_panics`].
+pub(crate) fn catch_panic<R>(f: impl FnOnce() -> R) -> Result<R, String> {
+    let catching = CATCHING_PANICS.with(|c| c.replace(true));
+    let result = panic::catch_unwind(AssertUnwindSafe(f));
+    CATCHING_PANICS.with(|c| c.set(catching));
+    result.map_err(|payload| {
+        panic_message(&*payload)
+            .unwrap_or("unknown error")
+            .to_string()
+    })
+}
+
+/// Whether a panic on this thread is about to be caught by [`catch_panic`].
+pub(crate) fn catching_panics() -> bool {
+    CATCHING_PANICS.with(Cell::get)
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -106,4 +145,14 @@ mod tests {
         let shell = get_shell_ffi();
         assert_eq!(shell.is_some(), true);
     }
+
+    #[test]
+    fn test_catch_panic() {
+        assert_eq!(catch_panic(|| 1), Ok(1));
+        assert_eq!(
+            catch_panic(|| -> () { panic!("Authentication failed") }),
+            Err("Authentication failed".to_string())
+        );
+        assert!(!catching_panics());
+    }
 }
@@ -0,0 +1,139 @@
+use crate::config::VolumeCurve;
+use librespot::playback::mixer::{softmixer::SoftMixer, AudioFilter, Mixer, MixerConfig};
+
+/// How far below full volume the logarithmic curve starts, in dB, unless the
+/// mixer covers less than that.
+pub(crate) const LOG_RANGE_DB: f64 = 60.0;
+
+/// Returns the amplitude, between 0 and 1, for a volume between 0 and 65535.
+/// The logarithmic curve spans `range_db`.
+pub(crate) fn amplitude(curve: VolumeCurve, volume: u16, range_db: f64) -> f64 {
+    let volume = f64::from(volume) / f64::from(u16::MAX);
+    match curve {
+        VolumeCurve::Linear => volume,
+        VolumeCurve::Log if volume <= 0.0 => 0.0,
+        VolumeCurve::Log => db_to_amplitude((volume - 1.0) * range_db),
+        VolumeCurve::Exponent(exponent) => volume.powf(exponent),
+    }
+}
+
+/// Returns the volume, between 0 and 65535, that `amplitude` gives the
+/// amplitude for.
+pub(crate) fn volume(curve: VolumeCurve, amplitude: f64, range_db: f64) -> u16 {
+    let amplitude = amplitude.clamp(0.0, 1.0);
+    let volume = match curve {
+        VolumeCurve::Linear => amplitude,
+        VolumeCurve::Log if amplitude <= 0.0 => 0.0,
+        VolumeCurve::Log => (1.0 + amplitude_to_db(amplitude) / range_db).max(0.0),
+        VolumeCurve::Exponent(exponent) => amplitude.powf(1.0 / exponent),
+    };
+    (volume * f64::from(u16::MAX)).round() as u16
+}
+
+pub(crate) fn db_to_amplitude(db: f64) -> f64 {
+    10f64.powf(db / 20.0)
+}
+
+/// The gain of `amplitude` in dB, which is minus infinity at 0.
+pub(crate) fn amplitude_to_db(amplitude: f64) -> f64 {
+    20.0 * amplitude.log10()
+}
+
+/// The software mixer, with the volume mapped through `volume_curve`.
+///
+/// Spirc is told to pass the volume on as it is, so that this is the only
+/// curve applied.
+pub(crate) struct CurvedMixer {
+    mixer: SoftMixer,
+    curve: VolumeCurve,
+}
+
+impl CurvedMixer {
+    pub(crate) fn new(curve: VolumeCurve) -> CurvedMixer {
+        CurvedMixer {
+            mixer: SoftMixer::open(None),
+            curve,
+        }
+    }
+}
+
+impl Mixer for CurvedMixer {
+    fn open(config: Option<MixerConfig>) -> CurvedMixer {
+        CurvedMixer {
+            mixer: SoftMixer::open(config),
+            curve: VolumeCurve::Linear,
+        }
+    }
+
+    fn start(&self) {
+        self.mixer.start()
+    }
+
+    fn stop(&self) {
+        self.mixer.stop()
+    }
+
+    fn volume(&self) -> u16 {
+        let amplitude = f64::from(self.mixer.volume()) / f64::from(u16::MAX);
+        volume(self.curve, amplitude, LOG_RANGE_DB)
+    }
+
+    fn set_volume(&self, volume: u16) {
+        let amplitude = amplitude(self.curve, volume, LOG_RANGE_DB);
+        self.mixer
+            .set_volume((amplitude * f64::from(u16::MAX)).round() as u16)
+    }
+
+    fn get_audio_filter(&self) -> Option<Box<dyn AudioFilter + Send>> {
+        self.mixer.get_audio_filter()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CURVES: [VolumeCurve; 4] = [
+        VolumeCurve::Linear,
+        VolumeCurve::Log,
+        VolumeCurve::Exponent(2.0),
+        VolumeCurve::Exponent(0.5),
+    ];
+
+    #[test]
+    fn test_round_trip() {
+        for &curve in &CURVES {
+            for volume in (0..=u16::MAX).step_by(257) {
+                let back =
+                    self::volume(curve, amplitude(curve, volume, LOG_RANGE_DB), LOG_RANGE_DB);
+                assert!(
+                    (i32::from(back) - i32::from(volume)).abs() <= 1,
+                    "{:?}: {} came back as {}",
+                    curve,
+                    volume,
+                    back
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_endpoints() {
+        for &curve in &CURVES {
+            assert_eq!(amplitude(curve, 0, LOG_RANGE_DB), 0.0, "{:?}", curve);
+            assert_eq!(amplitude(curve, u16::MAX, LOG_RANGE_DB), 1.0, "{:?}", curve);
+            assert_eq!(volume(curve, 0.0, LOG_RANGE_DB), 0, "{:?}", curve);
+            assert_eq!(volume(curve, 1.0, LOG_RANGE_DB), u16::MAX, "{:?}", curve);
+        }
+    }
+
+    #[test]
+    fn test_log_silence() {
+        assert_eq!(amplitude_to_db(0.0), f64::NEG_INFINITY);
+        assert_eq!(volume(VolumeCurve::Log, 0.0, LOG_RANGE_DB), 0);
+        // Quieter than the range also gives 0, and not a wrapped value.
+        assert_eq!(volume(VolumeCurve::Log, 1e-9, LOG_RANGE_DB), 0);
+        assert_eq!(volume(VolumeCurve::Log, -1.0, LOG_RANGE_DB), 0);
+        assert!(amplitude(VolumeCurve::Log, 1, LOG_RANGE_DB) > 0.0);
+    }
+}
@@ -0,0 +1,37 @@
+use futures::Future;
+use librespot::core::{keymaster, session::Session};
+use log::warn;
+use rspotify::spotify::client::Spotify;
+use std::{env, thread};
+use tokio_core::reactor::Handle;
+
+const CLIENT_ID: &str = "2c1ea588dfbc4a989e2426f8385297c3";
+
+/// The client id used to request Web API tokens. It can be overridden with
+/// the `SPOTIFYD_CLIENT_ID` environment variable.
+pub(crate) fn client_id() -> String {
+    env::var("SPOTIFYD_CLIENT_ID").unwrap_or_else(|_| CLIENT_ID.to_string())
+}
+
+/// Requests a token with the given scope and runs `f` with a Web API client
+/// on a separate thread, as the client blocks while it waits for Spotify.
+/// Nothing is run if spotifyd reconnected while the token was requested.
+pub(crate) fn spawn_with_api<F>(session: &Session, handle: &Handle, scope: &str, f: F)
+where
+    F: FnOnce(Spotify) + Send + 'static,
+{
+    let token = keymaster::get_token(session, &client_id(), scope);
+    let session = session.clone();
+    handle.spawn(
+        token
+            .map(move |token| {
+                if session.is_invalid() {
+                    return;
+                }
+                thread::spawn(move || {
+                    f(Spotify::default().access_token(&token.access_token).build())
+                });
+            })
+            .map_err(|e| warn!("Could not get a Web API token: {:?}", e)),
+    );
+}
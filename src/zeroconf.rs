@@ -0,0 +1,112 @@
+use std::{
+    io,
+    net::{Ipv4Addr, SocketAddrV4, UdpSocket},
+    time::{Duration, Instant},
+};
+
+const MDNS_GROUP: Ipv4Addr = Ipv4Addr::new(224, 0, 0, 251);
+const MDNS_PORT: u16 = 5353;
+const SERVICE_NAME: &str = "_spotify-connect._tcp.local";
+const TYPE_PTR: u16 = 12;
+/// The IN class with the bit set that asks for the answers to be sent back
+/// to us directly instead of to the multicast group.
+const CLASS_IN_UNICAST: u16 = 0x8001;
+
+/// Asks the network for Spotify Connect devices over mDNS and returns the
+/// names of those that answer within `wait`.
+pub(crate) fn find_devices(wait: Duration) -> io::Result<Vec<String>> {
+    let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0))?;
+    socket.send_to(&query(), SocketAddrV4::new(MDNS_GROUP, MDNS_PORT))?;
+
+    let deadline = Instant::now() + wait;
+    let mut names = Vec::new();
+    let mut buffer = [0; 9000];
+    loop {
+        let now = Instant::now();
+        if now >= deadline {
+            return Ok(names);
+        }
+        socket.set_read_timeout(Some(deadline - now))?;
+        let len = match socket.recv_from(&mut buffer) {
+            Ok((len, _)) => len,
+            Err(ref e)
+                if e.kind() == io::ErrorKind::WouldBlock || e.kind() == io::ErrorKind::TimedOut =>
+            {
+                return Ok(names)
+            }
+            Err(e) => return Err(e),
+        };
+        for name in parse_response(&buffer[..len]).unwrap_or_default() {
+            if !names.contains(&name) {
+                names.push(name);
+            }
+        }
+    }
+}
+
+fn query() -> Vec<u8> {
+    // An id of 0, no flags and a single question.
+    let mut packet = vec![0, 0, 0, 0, 0, 1, 0, 0, 0, 0, 0, 0];
+    for label in SERVICE_NAME.split('.') {
+        packet.push(label.len() as u8);
+        packet.extend_from_slice(label.as_bytes());
+    }
+    packet.push(0);
+    packet.extend_from_slice(&TYPE_PTR.to_be_bytes());
+    packet.extend_from_slice(&CLASS_IN_UNICAST.to_be_bytes());
+    packet
+}
+
+/// Returns the device names pointed to by the PTR records of a response, or
+/// `None` if the packet is malformed.
+fn parse_response(packet: &[u8]) -> Option<Vec<String>> {
+    let read_u16 = |at: usize| Some(u16::from_be_bytes([*packet.get(at)?, *packet.get(at + 1)?]));
+
+    let mut names = Vec::new();
+    if packet.get(2)? & 0x80 == 0 {
+        // A query from someone else.
+        return Some(names);
+    }
+    let questions = read_u16(4)?;
+    let records = read_u16(6)? as usize + read_u16(8)? as usize + read_u16(10)? as usize;
+
+    let mut at = 12;
+    for _ in 0..questions {
+        at = read_name(packet, at)?.1 + 4;
+    }
+    for _ in 0..records {
+        let (_, end) = read_name(packet, at)?;
+        let data = end + 10;
+        if read_u16(end)? == TYPE_PTR {
+            let (labels, _) = read_name(packet, data)?;
+            if labels.len() > 1 && labels[1..].join(".").eq_ignore_ascii_case(SERVICE_NAME) {
+                names.push(labels[0].clone());
+            }
+        }
+        at = data + read_u16(end + 8)? as usize;
+    }
+    Some(names)
+}
+
+/// Reads a name that may be compressed, returning its labels and the offset
+/// right after it.
+fn read_name(packet: &[u8], mut at: usize) -> Option<(Vec<String>, usize)> {
+    let mut labels = Vec::new();
+    let mut end = None;
+    // Compression pointers could form a loop, so only so many are followed.
+    for _ in 0..128 {
+        let len = *packet.get(at)? as usize;
+        if len == 0 {
+            return Some((labels, end.unwrap_or(at + 1)));
+        }
+        if len & 0xc0 == 0xc0 {
+            end = end.or(Some(at + 2));
+            at = (len & 0x3f) << 8 | *packet.get(at + 1)? as usize;
+        } else {
+            let label = packet.get(at + 1..at + 1 + len)?;
+            labels.push(String::from_utf8_lossy(label).into_owned());
+            at += 1 + len;
+        }
+    }
+    None
+}